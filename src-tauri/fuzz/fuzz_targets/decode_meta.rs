@@ -0,0 +1,11 @@
+// Fuzzes the vault header parser with arbitrary bytes.
+// Run with `cargo fuzz run decode_meta` from src-tauri. Any panic is a bug: decode_meta must return an error instead.
+#![no_main]
+
+use legacies_app_fuzz::meta::decode_meta;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let mut reader = data;
+    let _ = decode_meta(&mut reader);
+});
@@ -0,0 +1,19 @@
+// Fuzzes the load_meta command (without the version check) with arbitrary vault files.
+// Run with `cargo fuzz run load_meta` from src-tauri.
+#![no_main]
+
+use std::env::temp_dir;
+use std::fs;
+
+use legacies_app_fuzz::loadmeta::read_meta;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let mut path = temp_dir();
+    path.push(format!("fuzz-load-meta-{}.vault", std::process::id()));
+    fs::write(&path, data).expect("Failed to write fuzz input");
+
+    let _ = read_meta(path.to_str().unwrap().to_string());
+
+    let _ = fs::remove_file(&path);
+});
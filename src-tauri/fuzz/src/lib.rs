@@ -0,0 +1,34 @@
+// The app is a binary crate, so the fuzz targets can't depend on it directly.
+// Instead, the modules needed for header parsing are included from the app source.
+#![allow(dead_code)]
+
+#[path = "../../src/armor.rs"]
+mod armor;
+#[path = "../../src/constants.rs"]
+mod constants;
+#[path = "../../src/crypto.rs"]
+mod crypto;
+#[path = "../../src/error.rs"]
+mod error;
+#[path = "../../src/lineage.rs"]
+mod lineage;
+#[path = "../../src/commands/loadmeta.rs"]
+pub mod loadmeta;
+#[path = "../../src/meta.rs"]
+pub mod meta;
+#[path = "../../src/mnemonic.rs"]
+mod mnemonic;
+#[path = "../../src/passphrase.rs"]
+mod passphrase;
+#[path = "../../src/piece.rs"]
+mod piece;
+#[path = "../../src/recipient.rs"]
+mod recipient;
+#[path = "../../src/signature.rs"]
+mod signature;
+#[path = "../../src/util.rs"]
+mod util;
+#[path = "../../src/vault.rs"]
+mod vault;
+#[path = "../../src/vss.rs"]
+mod vss;
@@ -0,0 +1,342 @@
+use crate::error::{make_error, AppError, AppResult};
+use crate::signature::format_fingerprint;
+use crate::vault::PublicInfo;
+
+/*
+   Armored keys
+   Main keys and key pieces can be saved as text which explains itself to whoever finds it:
+
+   ---- BEGIN DIGITAL VAULT KEYSHARE ----
+   An explanation of what the key is and how to use it.
+   Owner: The vault owner's name
+   Fingerprint: The fingerprint of the owner's signing key, for vaults which are signed
+   Scheme: This is a 3 of 5 key sharing scheme...
+   Comment: The comment for this key piece
+
+   1:2a:ff:...
+   CRC: 1a2b3c
+   ---- END DIGITAL VAULT KEYSHARE ----
+
+   The key is written as hex bytes separated by colons, like keys copied from the app, over as many lines as needed.
+   The CRC is the OpenPGP CRC-24 of the key bytes, so a key that was cut short or mistyped is caught.
+   Everything before the blank line is for people to read, and is ignored when the key is read back.
+*/
+
+const DASHES: &str = "----";
+const MAIN_KEY_TEXT: &str = "DIGITAL VAULT MAIN KEY";
+const KEYSHARE_TEXT: &str = "DIGITAL VAULT KEYSHARE";
+const CRC_PREFIX: &str = "CRC: ";
+// Key bytes per line.
+const BYTES_PER_LINE: usize = 16;
+
+const MAIN_KEY_EXPLANATION: &str =
+    "This is the main key for a digital vault, which contains an individual's personal data.\n\
+You need it to open and update the vault. Keep it safe, and do not share it.";
+const KEYSHARE_EXPLANATION: &str = "This is a key piece from a digital vault. It is used to access someone's personal data if they die or in other emergency circumstances.\n\
+To use it, you need to combine it with other key pieces. These will have been given to other people.";
+const CLOUD_TO_USE: &str =
+    "To use this key, you will need the vault file or an internet connection.";
+const OFFLINE_TO_USE: &str =
+    "To use this key, you will need the Digital Legacies application and the vault file.";
+
+/// What an armored key holds.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ArmorKind {
+    MainKey,
+    KeyPiece,
+}
+
+impl ArmorKind {
+    fn text(&self) -> &'static str {
+        return match self {
+            ArmorKind::MainKey => MAIN_KEY_TEXT,
+            ArmorKind::KeyPiece => KEYSHARE_TEXT,
+        };
+    }
+
+    fn begin_line(&self) -> String {
+        return format!("{} BEGIN {} {}", DASHES, self.text(), DASHES);
+    }
+
+    fn end_line(&self) -> String {
+        return format!("{} END {} {}", DASHES, self.text(), DASHES);
+    }
+}
+
+/// Writes a key as armored text, explaining what it is using the vault's public info.
+/// The comment is the one given to the key in the share configuration, if there is one.
+pub fn armor(
+    kind: ArmorKind,
+    key: &[u8],
+    info: &PublicInfo,
+    comment: Option<&str>,
+    cloud: bool,
+) -> String {
+    let mut lines: Vec<String> = Vec::from([kind.begin_line()]);
+    lines.push(
+        match kind {
+            ArmorKind::MainKey => MAIN_KEY_EXPLANATION,
+            ArmorKind::KeyPiece => KEYSHARE_EXPLANATION,
+        }
+        .to_string(),
+    );
+    lines.push(if cloud { CLOUD_TO_USE } else { OFFLINE_TO_USE }.to_string());
+    lines.push(format!("Owner: {}", one_line(&info.name)));
+    if !info.owner_fingerprint.is_empty() {
+        lines.push(format!(
+            "Fingerprint: {}",
+            format_fingerprint(&info.owner_fingerprint)
+        ));
+    }
+    lines.push(format!("Scheme: {}", scheme_comment(info)));
+    if let Some(comment) = comment {
+        lines.push(format!("Comment: {}", one_line(comment)));
+    }
+
+    // The key itself follows the blank line.
+    lines.push(String::new());
+    for chunk in key.chunks(BYTES_PER_LINE) {
+        let hex: Vec<String> = chunk.iter().map(|b| format!("{:x}", b)).collect();
+        lines.push(hex.join(":"));
+    }
+    lines.push(format!("{}{:06x}", CRC_PREFIX, crc24(key)));
+    lines.push(kind.end_line());
+    return lines.join("\n") + "\n";
+}
+
+/// Reads the key out of armored text, checking it is the kind expected and that the CRC matches.
+pub fn dearmor(text: &str, kind: ArmorKind) -> AppResult<Vec<u8>> {
+    let lines: Vec<&str> = text.lines().map(|line| line.trim()).collect();
+    let begin = lines.iter().position(|line| *line == kind.begin_line());
+    let begin = match begin {
+        Some(begin) => begin,
+        None => {
+            let other = match kind {
+                ArmorKind::MainKey => ArmorKind::KeyPiece,
+                ArmorKind::KeyPiece => ArmorKind::MainKey,
+            };
+            if lines.contains(&other.begin_line().as_str()) {
+                return Err(make_error(
+                    "armor_kind",
+                    match kind {
+                        ArmorKind::MainKey => "This is a key piece, not a main key.",
+                        ArmorKind::KeyPiece => "This is a main key, not a key piece.",
+                    },
+                ));
+            }
+            return Err(damaged("The start of the key is missing."));
+        }
+    };
+    let end = lines[begin..]
+        .iter()
+        .position(|line| *line == kind.end_line())
+        .ok_or_else(|| damaged("The end of the key is missing."))?;
+    let body = &lines[begin + 1..begin + end];
+
+    // Skip the text for people to read.
+    let blank = body
+        .iter()
+        .position(|line| line.is_empty())
+        .ok_or_else(|| damaged("The key is missing."))?;
+    let key_lines: Vec<&str> = body[blank..]
+        .iter()
+        .copied()
+        .filter(|line| !line.is_empty())
+        .collect();
+    let (crc_line, key_lines) = key_lines
+        .split_last()
+        .ok_or_else(|| damaged("The key is missing."))?;
+    let stored_crc = crc_line
+        .strip_prefix(CRC_PREFIX)
+        .and_then(|crc| u32::from_str_radix(crc, 16).ok())
+        .ok_or_else(|| damaged("The CRC is missing."))?;
+
+    let mut key: Vec<u8> = Vec::new();
+    for line in key_lines {
+        for byte in line.split(':') {
+            key.push(
+                u8::from_str_radix(byte, 16)
+                    .map_err(|_e| damaged("The key has characters which should not be there."))?,
+            );
+        }
+    }
+    if crc24(&key) != stored_crc {
+        return Err(damaged(
+            "The key does not match its CRC, so part of it is wrong or missing.",
+        ));
+    }
+    return Ok(key);
+}
+
+/// Whether a key looks like armored text rather than bytes.
+pub fn is_armored(key: &[u8]) -> bool {
+    return key
+        .trim_ascii_start()
+        .starts_with(format!("{} BEGIN DIGITAL VAULT ", DASHES).as_bytes());
+}
+
+/// Describes the M of N scheme of a vault, including any required circles.
+pub fn scheme_comment(info: &PublicInfo) -> String {
+    let config = &info.share_config;
+    let total: usize = config
+        .circles
+        .iter()
+        .map(|circle| {
+            (0..circle.key_comments.len())
+                .map(|member| usize::from(circle.weight(member)))
+                .sum::<usize>()
+        })
+        .sum();
+    let mut comment = format!(
+        "This is a {} of {} key sharing scheme. {} key shares exist, and {} must be combined to unlock the data.",
+        config.required, total, total, config.required
+    );
+
+    let required: Vec<String> = config
+        .circles
+        .iter()
+        .filter(|circle| circle.required)
+        .map(|circle| {
+            format!(
+                "{} ({} of {})",
+                one_line(&circle.name),
+                circle.threshold.max(1),
+                circle.key_comments.len()
+            )
+        })
+        .collect();
+    if !required.is_empty() {
+        comment.push_str(&format!(
+            " These must include members of: {}.",
+            required.join(", ")
+        ));
+    }
+    return comment;
+}
+
+fn damaged(problem: &str) -> AppError {
+    return make_error(
+        "armor",
+        &format!(
+            "The key text is damaged. {} Check that it was copied in full.",
+            problem
+        ),
+    );
+}
+
+// Names and comments must stay on their own line.
+fn one_line(text: &str) -> String {
+    return text.replace(['\r', '\n'], " ");
+}
+
+// The OpenPGP CRC-24 (RFC 4880, section 6.1).
+fn crc24(data: &[u8]) -> u32 {
+    const CRC24_INIT: u32 = 0xB704CE;
+    const CRC24_POLY: u32 = 0x1864CFB;
+
+    let mut crc = CRC24_INIT;
+    for byte in data {
+        crc ^= u32::from(*byte) << 16;
+        for _ in 0..8 {
+            crc <<= 1;
+            if crc & 0x1000000 != 0 {
+                crc ^= CRC24_POLY;
+            }
+        }
+    }
+    return crc & 0xFFFFFF;
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::armor::{armor, crc24, dearmor, is_armored, scheme_comment, ArmorKind};
+    use crate::crypto::tests::{get_basic_combo, get_public_info};
+    use crate::vault::PublicInfo;
+
+    fn get_info() -> PublicInfo {
+        let mut circles = get_basic_combo();
+        circles[0].required = true;
+        circles[0].threshold = 2;
+        circles[0].weights = Vec::from([2]);
+        return PublicInfo {
+            name: "Test\nName".to_string(),
+            ..get_public_info(circles, 3)
+        };
+    }
+
+    #[test]
+    fn crc() {
+        assert_eq!(crc24(b""), 0xB704CE);
+        assert_eq!(crc24(b"123456789"), 0x21CF02);
+    }
+
+    #[test]
+    fn round_trip() {
+        let info = get_info();
+        let key: Vec<u8> = (0..=255).collect();
+        let text = armor(ArmorKind::KeyPiece, &key, &info, Some("Alice"), false);
+
+        assert!(is_armored(text.as_bytes()));
+        assert!(text.contains("Owner: Test Name\n"));
+        assert!(text.contains("Comment: Alice\n"));
+        assert!(!text.contains("Fingerprint: "));
+        assert!(text.contains("3 of 6 key sharing scheme"));
+        assert!(text.contains("members of: Friends (2 of 5)"));
+        assert_eq!(
+            dearmor(&text, ArmorKind::KeyPiece).expect("Failed to read"),
+            key
+        );
+
+        // Surrounded by other text, with Windows line endings and indented.
+        let pasted = format!(
+            "Here is my key:\r\n{}\r\nThanks",
+            text.lines()
+                .map(|line| format!("  {}", line))
+                .collect::<Vec<String>>()
+                .join("\r\n")
+        );
+        assert_eq!(
+            dearmor(&pasted, ArmorKind::KeyPiece).expect("Failed to read"),
+            key
+        );
+
+        let text = armor(ArmorKind::MainKey, &key[..32], &info, None, true);
+        assert!(!text.contains("Comment:"));
+        assert_eq!(
+            dearmor(&text, ArmorKind::MainKey).expect("Failed to read"),
+            &key[..32]
+        );
+        let err = dearmor(&text, ArmorKind::KeyPiece).expect_err("Read the wrong kind of key");
+        assert_eq!(err.error_type, "armor_kind");
+    }
+
+    #[test]
+    fn damage_caught() {
+        let info = get_info();
+        let key: Vec<u8> = (0..40).collect();
+        let text = armor(ArmorKind::KeyPiece, &key, &info, Some("Alice"), false);
+
+        // A changed byte, a missing line, a missing end and a bad character.
+        let changed = text.replace("\n0:1:2:", "\n0:1:3:");
+        let lines: Vec<&str> = text.lines().collect();
+        let missing_line = [&lines[..lines.len() - 4], &lines[lines.len() - 3..]].concat();
+        let cases = [
+            changed,
+            missing_line.join("\n"),
+            lines[..lines.len() - 1].join("\n"),
+            text.replace("\n0:1:2:", "\n0:1:x:"),
+        ];
+        for damaged in cases.iter() {
+            assert_ne!(damaged, &text);
+            let err = dearmor(damaged, ArmorKind::KeyPiece).expect_err("Accepted a damaged key");
+            assert_eq!(err.error_type, "armor");
+        }
+
+        // The text for people to read can change.
+        let edited = text.replace("Comment: Alice", "Comment: Bob");
+        assert_eq!(dearmor(&edited, ArmorKind::KeyPiece).unwrap(), key);
+
+        assert!(!is_armored(&key));
+        assert!(scheme_comment(&info).starts_with("This is a 3 of 6 "));
+    }
+}
@@ -0,0 +1,56 @@
+use crate::armor::{armor, ArmorKind};
+use crate::crypto::KeyPiece;
+use crate::error::AppResult;
+use crate::passphrase::{protect, unwrap_main_key, PROTECTED_MAIN_KEY_MAGIC};
+use crate::piece::{is_protected_piece, unwrap_piece};
+use crate::vault::PublicInfo;
+
+/// Writes a key piece as armored text to be saved or sent, using the public info from load_meta.
+/// The text explains what the piece is, and includes the comment given to its member.
+/// Pieces protected by a passphrase don't say who they are for, so they have no comment.
+#[tauri::command]
+pub fn armor_piece(info: PublicInfo, key: KeyPiece, cloud: bool) -> AppResult<String> {
+    if is_protected_piece(&key) {
+        return Ok(armor(ArmorKind::KeyPiece, &key, &info, None, cloud));
+    }
+    let (piece_info, _raw) = unwrap_piece(&key, 1)?;
+    let comment = piece_info.and_then(|piece_info| {
+        return info
+            .share_config
+            .circles
+            .get(usize::from(piece_info.circle))?
+            .key_comments
+            .get(usize::from(piece_info.member))
+            .cloned();
+    });
+    return Ok(armor(
+        ArmorKind::KeyPiece,
+        &key,
+        &info,
+        comment.as_deref(),
+        cloud,
+    ));
+}
+
+/// Writes a main key as armored text to be saved. For cloud vaults, the key includes the owner token.
+/// With a passphrase, the key is protected by it, so the owner doesn't need to keep the raw key safe on its own.
+#[tauri::command]
+pub fn armor_main_key(
+    info: PublicInfo,
+    key: Vec<u8>,
+    cloud: bool,
+    passphrase: Option<String>,
+) -> AppResult<String> {
+    let key = match passphrase {
+        Some(passphrase) => protect(PROTECTED_MAIN_KEY_MAGIC, &key, &passphrase)?,
+        None => key,
+    };
+    return Ok(armor(ArmorKind::MainKey, &key, &info, None, cloud));
+}
+
+/// Reads a main key back out of armored text, so the owner token of a cloud vault can be used to find it.
+/// Protected keys need their passphrase, and give a passphrase_needed error without it.
+#[tauri::command]
+pub fn read_main_key(key: String, passphrase: Option<String>) -> AppResult<Vec<u8>> {
+    return unwrap_main_key(key.as_bytes(), passphrase.as_deref());
+}
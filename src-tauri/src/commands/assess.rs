@@ -0,0 +1,289 @@
+use std::collections::{HashMap, HashSet};
+
+use serde::Serialize;
+
+use crate::crypto::{split_piece, KeyPiece};
+use crate::error::{AppError, AppResult};
+use crate::piece::check_piece;
+use crate::recipient::open_sealed_pieces;
+use crate::vault::PublicInfo;
+
+/// How close a set of key pieces is to unlocking a vault.
+#[derive(Serialize, Debug)]
+pub struct UnlockAssessment {
+    // Distinct shares held across all valid pieces. Weighted pieces hold more than one.
+    pub(crate) shares_held: usize,
+    pub(crate) shares_needed: u16,
+    pub(crate) more_needed: usize,
+    // Required circles which do not yet have enough members.
+    pub(crate) missing_circles: Vec<MissingCircle>,
+    // Pieces from before pieces had envelopes. These count towards the shares, but can't be matched to a circle.
+    pub(crate) unidentified_pieces: usize,
+    // Pieces which are damaged or for another vault. These are left out of the assessment, and when unlocking.
+    pub(crate) invalid_pieces: Vec<AppError>,
+    // Whether combining should succeed.
+    pub(crate) ready: bool,
+}
+
+#[derive(Serialize, Debug)]
+pub struct MissingCircle {
+    pub(crate) name: String,
+    pub(crate) more_needed: u16,
+}
+
+/// Reports how many more pieces are needed to unlock a vault, and which required circles are still missing.
+/// Takes the public info from load_meta, the pieces gathered so far and the identity files for any sealed pieces.
+/// Nothing is combined or decrypted.
+#[tauri::command]
+pub fn assess_unlock(
+    info: PublicInfo,
+    keys: Vec<KeyPiece>,
+    identities: Option<String>,
+) -> AppResult<UnlockAssessment> {
+    let keys = open_sealed_pieces(&keys, identities.as_deref())?;
+    let mut individual_shares: HashSet<Vec<u8>> = HashSet::new();
+    // Members held of each circle, by circle index.
+    let mut circle_members: HashMap<u8, HashSet<u16>> = HashMap::new();
+    let mut unidentified_pieces = 0;
+    let mut invalid_pieces: Vec<AppError> = Vec::new();
+
+    for (index, piece) in keys.iter().enumerate() {
+        let checked = check_piece(piece, index + 1, &info)
+            .and_then(|(piece_info, raw)| Ok((piece_info, split_piece(&raw)?)));
+        let (piece_info, parts) = match checked {
+            Ok(checked) => checked,
+            Err(e) => {
+                invalid_pieces.push(e);
+                continue;
+            }
+        };
+
+        individual_shares.extend(parts.shares);
+        match piece_info {
+            Some(piece_info) => {
+                circle_members
+                    .entry(piece_info.circle)
+                    .or_default()
+                    .insert(piece_info.member);
+            }
+            None => unidentified_pieces += 1,
+        }
+    }
+
+    let mut missing_circles: Vec<MissingCircle> = Vec::new();
+    for (circle_index, circle) in info.share_config.circles.iter().enumerate() {
+        if !circle.required {
+            continue;
+        }
+        let held = u8::try_from(circle_index)
+            .ok()
+            .and_then(|i| circle_members.get(&i))
+            .map_or(0, |members| members.len());
+        let held = u16::try_from(held)?;
+        if held < circle.threshold {
+            missing_circles.push(MissingCircle {
+                name: circle.name.clone(),
+                more_needed: circle.threshold - held,
+            });
+        }
+    }
+
+    let shares_needed = info.share_config.required;
+    let more_needed = usize::from(shares_needed).saturating_sub(individual_shares.len());
+    // Pieces without an envelope may be from a missing circle, so give them the benefit of the doubt.
+    let circles_ready = missing_circles.is_empty() || unidentified_pieces > 0;
+
+    return Ok(UnlockAssessment {
+        shares_held: individual_shares.len(),
+        shares_needed,
+        more_needed,
+        ready: more_needed == 0 && circles_ready,
+        missing_circles,
+        unidentified_pieces,
+        invalid_pieces,
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use age::secrecy::ExposeSecret;
+    use age::x25519;
+
+    use crate::commands::assess::assess_unlock;
+    use crate::crypto::tests::get_public_info;
+    use crate::crypto::{combine_keys, generate_circle_keys, generate_key, KeyPiece};
+    use crate::piece::unwrap_piece;
+    use crate::vault::{Circle, PublicInfo};
+
+    // 2 of 3 siblings (the first with weight 2), and 4 keys in total.
+    fn get_info() -> PublicInfo {
+        let circles = Vec::from([
+            Circle {
+                required: true,
+                key_comments: Vec::from(["a".to_string(), "b".to_string(), "c".to_string()]),
+                name: "Siblings".to_string(),
+                keys: None,
+                threshold: 2,
+                weights: Vec::from([2]),
+                recipients: Vec::new(),
+                sealed_keys: None,
+            },
+            Circle {
+                required: false,
+                key_comments: Vec::from(["a".to_string(), "b".to_string()]),
+                name: "Friends".to_string(),
+                keys: None,
+                threshold: 1,
+                weights: Vec::new(),
+                recipients: Vec::new(),
+                sealed_keys: None,
+            },
+        ]);
+        return get_public_info(circles, 4);
+    }
+
+    fn get_keys(info: &mut PublicInfo) -> Vec<Vec<KeyPiece>> {
+        let key = generate_key();
+        let (circles, commitments, _coefficients) = generate_circle_keys(
+            &key,
+            &[],
+            info.share_config.circles.clone(),
+            info.share_config.required,
+        )
+        .expect("Did not receive circles with keys.");
+        let keys: Vec<Vec<KeyPiece>> = circles
+            .into_iter()
+            .map(|c| c.keys.expect("Keys not set"))
+            .collect();
+        let (piece_info, _raw) = unwrap_piece(&keys[0][0], 1).unwrap();
+        info.key_id = piece_info.unwrap().key_id;
+        info.commitments = commitments;
+        return keys;
+    }
+
+    #[test]
+    fn progress() {
+        let mut info = get_info();
+        let keys = get_keys(&mut info);
+
+        let assessment = assess_unlock(info.clone(), Vec::new(), None).expect("Failed to assess");
+        assert_eq!(assessment.shares_held, 0);
+        assert_eq!(assessment.more_needed, 4);
+        assert_eq!(assessment.missing_circles.len(), 1);
+        assert_eq!(assessment.missing_circles[0].name, "Siblings");
+        assert_eq!(assessment.missing_circles[0].more_needed, 2);
+        assert!(!assessment.ready);
+
+        // The weighted sibling counts twice, and the same piece given twice only counts once.
+        let some = Vec::from([keys[0][0].clone(), keys[0][0].clone(), keys[1][0].clone()]);
+        let assessment = assess_unlock(info.clone(), some.clone(), None).expect("Failed to assess");
+        assert_eq!(assessment.shares_held, 3);
+        assert_eq!(assessment.more_needed, 1);
+        assert_eq!(assessment.missing_circles[0].more_needed, 1);
+        assert!(!assessment.ready);
+
+        let mut enough = some.clone();
+        enough.push(keys[0][2].clone());
+        let assessment =
+            assess_unlock(info.clone(), enough.clone(), None).expect("Failed to assess");
+        assert_eq!(assessment.shares_held, 4);
+        assert_eq!(assessment.more_needed, 0);
+        assert!(assessment.missing_circles.is_empty());
+        assert!(assessment.ready);
+        assert!(combine_keys(enough).is_ok());
+
+        // Enough shares, but only one sibling.
+        let one_sibling = Vec::from([keys[0][0].clone(), keys[1][0].clone(), keys[1][1].clone()]);
+        let assessment = assess_unlock(info, one_sibling, None).expect("Failed to assess");
+        assert_eq!(assessment.more_needed, 0);
+        assert_eq!(assessment.missing_circles[0].name, "Siblings");
+        assert!(!assessment.ready);
+    }
+
+    #[test]
+    fn invalid_pieces() {
+        let mut info = get_info();
+        let keys = get_keys(&mut info);
+        let mut other_info = get_info();
+        let other_keys = get_keys(&mut other_info);
+
+        let mut damaged = keys[1][1].clone();
+        damaged[20] ^= 1;
+        let pieces = Vec::from([
+            keys[0][0].clone(),
+            other_keys[0][1].clone(),
+            damaged,
+            vec![1, 2, 3],
+        ]);
+        let assessment = assess_unlock(info, pieces, None).expect("Failed to assess");
+        assert_eq!(assessment.shares_held, 2);
+        assert_eq!(assessment.unidentified_pieces, 0);
+        let error_types: Vec<&str> = assessment
+            .invalid_pieces
+            .iter()
+            .map(|e| e.error_type.as_str())
+            .collect();
+        assert_eq!(
+            error_types,
+            Vec::from(["piece_foreign", "piece_checksum", "combine"])
+        );
+        assert!(!assessment.ready);
+    }
+
+    #[test]
+    fn sealed_pieces() {
+        let mut info = get_info();
+        let sibling = x25519::Identity::generate();
+        let friend = x25519::Identity::generate();
+        info.share_config.circles[0].recipients =
+            Vec::from([sibling.to_public().to_string(), String::new()]);
+        info.share_config.circles[1].recipients =
+            Vec::from(["".to_string(), format!(" {} ", friend.to_public())]);
+        let (circles, commitments, _coefficients) =
+            generate_circle_keys(&generate_key(), &[], info.share_config.circles.clone(), 4)
+                .expect("Failed to make keys");
+        info.commitments = commitments;
+        info.key_id = unwrap_piece(&circles[0].keys.as_ref().unwrap()[0], 1)
+            .unwrap()
+            .0
+            .unwrap()
+            .key_id;
+
+        // Only members with a public key get a sealed piece.
+        let siblings = circles[0].sealed_keys.clone().expect("Pieces not sealed");
+        let friends = circles[1].sealed_keys.clone().expect("Pieces not sealed");
+        assert!(!siblings[0].is_empty());
+        assert!(siblings[1].is_empty() && siblings[2].is_empty());
+        assert!(friends[0].is_empty() && !friends[1].is_empty());
+
+        let pieces = Vec::from([
+            siblings[0].clone().into_bytes(),
+            circles[0].keys.as_ref().unwrap()[1].clone(),
+            friends[1].clone().into_bytes(),
+        ]);
+        let identities = format!(
+            "{}\n{}\n",
+            sibling.to_string().expose_secret(),
+            friend.to_string().expose_secret()
+        );
+        let assessment = assess_unlock(info.clone(), pieces.clone(), Some(identities))
+            .expect("Failed to assess");
+        assert!(assessment.ready);
+
+        // Without the friend's identity, their piece can't be used.
+        let assessment = assess_unlock(
+            info.clone(),
+            pieces,
+            Some(sibling.to_string().expose_secret().to_string()),
+        )
+        .expect("Failed to assess");
+        assert_eq!(assessment.invalid_pieces[0].error_type, "piece_sealed");
+        assert!(!assessment.ready);
+
+        // A public key which is not valid is caught before any pieces are made.
+        info.share_config.circles[1].recipients = Vec::from(["age1nope".to_string()]);
+        let err = generate_circle_keys(&generate_key(), &[], info.share_config.circles, 4)
+            .expect_err("Accepted a bad public key");
+        assert_eq!(err.error_type, "bad_recipient");
+    }
+}
@@ -1,24 +1,45 @@
-use std::fs::{remove_dir_all, File};
-use std::io::{BufWriter, Write};
-use std::path::PathBuf;
+use std::collections::BTreeSet;
+use std::fs::{metadata, read_dir, remove_dir_all, remove_file, File};
+use std::io::{self, BufReader, BufWriter};
+use std::path::{Path, PathBuf};
 
 use rmp_serde::Serializer;
 use serde::{Deserialize, Serialize};
-use tar::{Builder, Header};
-
-use crate::constants::{META_FILE_NAME, PRIVACY_FILE_NAME};
-use crate::crypto::{encrypt_file, generate_circle_keys, generate_cloud_creds, generate_nonce};
-use crate::error::{AppError, AppResult};
-use crate::meta::encode_meta;
-use crate::util::get_random_file_path;
-use crate::vault::{CloudKeyData, KeyCollection, PublicInfo, Vault};
+use tar::{Archive, Builder, Header};
+
+use crate::constants::{BACKUP_FOLDER, META_FILE_NAME, PRIVACY_FILE_NAME};
+use crate::crypto::{
+    add_circle_members, derive_content_key, derive_key_commitment, derive_key_id,
+    derive_lookup_token, derive_owner_state_key, derive_owner_token,
+    generate_circle_keys, generate_cloud_creds, generate_key, generate_stream_nonce, open_sealed,
+    seal, EncryptWriter,
+};
+use crate::error::{make_error, AppError, AppResult};
+use crate::lineage::{
+    back_up, first_version, hash_file, next_version, release_version, reserve_version,
+};
+use crate::meta::{
+    decode_meta, decode_owner_state, encode_meta, encode_owner_state, write_signature,
+};
+use crate::signature::{
+    check_can_sign, owner_fingerprint, sign_vault, verify_vault_file, HashWriter,
+};
+use crate::util::{get_partial_path, get_random_file_path, get_versions_path, persist_file};
+use crate::vault::{
+    CloudKeyData, KeyCollection, Lineage, OwnerState, PublicInfo, ShareCommitments, Vault,
+};
 
 #[derive(Deserialize, Serialize, Debug)]
 pub struct CreateResponse {
     pub(crate) keys: KeyCollection,
-    cloud_keys: Option<CloudKeyData>,
+    pub(crate) cloud_keys: Option<CloudKeyData>,
     // path to the created file so the frontend can copy it out.
     pub(crate) path: String,
+    pub(crate) lineage: Lineage,
+    // path to the copy of the version an update replaced.
+    pub(crate) backup: Option<String>,
+    // Anything which went wrong after the vault was safely written. The vault was still made.
+    pub(crate) warnings: Vec<AppError>,
 }
 
 const PRIVACY_TEXT: &str = "This folder contains the contents of {name}'s digital vault. This is data that they imported and encrypted, and shared the keys with you.
@@ -53,24 +74,220 @@ pub async fn create(app_handle: tauri::AppHandle, vault: Vault) -> AppResult<Cre
         .ok_or(data_dir_error.clone())?;
     dir.push(&vault.vault_folder);
 
+    let versions_path = get_versions_path(&app_handle)?;
     let output_file_path = get_random_file_path(app_handle)?;
 
-    return do_create(vault, dir, output_file_path);
+    return do_create(&versions_path, vault, dir, output_file_path);
 }
 
 // Does the actual command actions.
 // Seperated to allow for testing.
 pub fn do_create(
+    versions_path: &Path,
     mut vault: Vault,
     files_dir: PathBuf,
     output_file_path: PathBuf,
 ) -> AppResult<CreateResponse> {
-    let mut archive = Builder::new(Vec::new());
+    let cloud_creds: CloudKeyData;
+
+    // An update follows on from the version it was opened from. It is never put in the archive.
+    let previous = vault.previous.take();
+    let lineage = match &previous {
+        Some(previous) => next_version(previous)?,
+        None if vault.keys.is_some() => {
+            return Err(make_error(
+                "previous_version",
+                "The vault being updated was not opened from a file. Open the vault again to update it.",
+            ))
+        }
+        None => first_version(),
+    };
+
+    // Generate or retrieve keys
+    let keys = if vault.keys.is_none() {
+        let (key, creds) = generate_cloud_creds();
+        cloud_creds = creds;
+
+        let mut keys = KeyCollection {
+            main: key,
+            share_keys: Vec::new(),
+            commitments: ShareCommitments::default(),
+            coefficients: None,
+            pieces_rebuild_main: false,
+            signing_seed: Some(generate_key()),
+        };
+        // Key pieces rebuild the recipient secret, not the main key. They carry the owner's fingerprint.
+        let (circles_with_keys, commitments, coefficients) = generate_circle_keys(
+            &keys.recipient_secret(),
+            &owner_fingerprint(&keys),
+            vault.share_config.circles.clone(),
+            vault.share_config.required,
+        )?;
+        keys.share_keys = circles_with_keys;
+        keys.commitments = commitments;
+        keys.coefficients = Some(coefficients);
+        keys
+    } else {
+        let mut keys = vault.keys.clone().unwrap();
+        // Members added to circles since the vault was made get pieces of their own. Everyone else keeps theirs.
+        keys.share_keys = add_circle_members(
+            &keys.recipient_secret(),
+            &keys,
+            vault.share_config.circles.clone(),
+            vault.share_config.required,
+        )?;
+        // Version 1 vaults used bytes of the recipient secret as the lookup token, so it is always derived again.
+        // The owner token is kept, as the server knows the vault by it.
+        let owner_token = match &vault.cloud_creds {
+            Some(creds) => creds.owner_token.clone(),
+            None => derive_owner_token(&keys.main),
+        };
+        cloud_creds = CloudKeyData {
+            owner_token,
+            share_token: derive_lookup_token(&keys.recipient_secret()),
+        };
+        keys
+    };
+    // Nothing is written for a vault that can't be signed.
+    check_can_sign(&keys)?;
+    let str_path: String = output_file_path.to_str().unwrap().parse().unwrap();
+
+    // The vault is written under another name, and read back, before anything is kept or removed.
+    // If anything fails, the staged files are left as they were and the partial vault is removed.
+    let partial_path = get_partial_path(&output_file_path);
+    let written = write_vault(
+        vault,
+        &keys,
+        &cloud_creds,
+        &lineage,
+        &files_dir,
+        &partial_path,
+        &str_path,
+    )
+    .and_then(|_| check_written(&partial_path, &keys, &files_dir));
+    if written.is_err() {
+        let _ = remove_file(&partial_path);
+    }
+    written?;
+
+    // Remember the new version before it is kept, so the one it replaced can't be passed off as the latest,
+    // and an update can't be made twice from the same version.
+    let latest = match reserve_version(versions_path, &lineage) {
+        Ok(latest) => latest,
+        Err(e) => {
+            let _ = remove_file(&partial_path);
+            return Err(e);
+        }
+    };
+
+    // Keep the version this replaced, next to the new vault file.
+    let backup = match &previous {
+        Some(previous) => {
+            let backup_folder = output_file_path.with_file_name(BACKUP_FOLDER);
+            match back_up(previous, &lineage, &backup_folder) {
+                Ok(backup_path) => Some(backup_path),
+                Err(e) => {
+                    let _ = remove_file(&partial_path);
+                    let _ = release_version(versions_path, &lineage, latest);
+                    return Err(e);
+                }
+            }
+        }
+        None => None,
+    };
+
+    if let Err(e) = persist_file(&partial_path, &output_file_path) {
+        let _ = remove_file(&partial_path);
+        if let Some(backup_path) = &backup {
+            let _ = remove_file(backup_path);
+        }
+        let _ = release_version(versions_path, &lineage, latest);
+        return Err(e);
+    }
+
+    // Delete files from FS (internal folder)
+    // The vault is safely written by now, and its keys are only in this response, so it is returned regardless.
+    let mut warnings = Vec::new();
+    if let Err(e) = remove_dir_all(&files_dir) {
+        warnings.push(make_error(
+            "staged_files",
+            &format!(
+                "The vault was made, but the files copied into it could not be removed from {}: {}",
+                files_dir.display(),
+                e
+            ),
+        ));
+    }
+
+    return Ok(CreateResponse {
+        keys,
+        path: str_path,
+        cloud_keys: Some(cloud_creds),
+        lineage,
+        backup: backup.map(|path| path.to_string_lossy().to_string()),
+        warnings,
+    });
+}
+
+// Writes the encrypted vault to the file given, and syncs it.
+fn write_vault(
+    mut vault: Vault,
+    keys: &KeyCollection,
+    cloud_creds: &CloudKeyData,
+    lineage: &Lineage,
+    files_dir: &Path,
+    file_path: &Path,
+    str_path: &str,
+) -> AppResult<()> {
+    let content_key = derive_content_key(&keys.recipient_secret());
+
+    let cipher = vault.cipher;
+    let nonce = generate_stream_nonce(cipher);
+    // Write meta, comments etc.
+    let output_file = File::create(file_path)?;
+
+    let mut writer = BufWriter::new(output_file);
+    let public_meta = PublicInfo {
+        name: vault.personal_info.name.clone(),
+        email_address: vault.personal_info.email_address.clone(),
+        // Clone the original share_config, so it's the original circles - not the new ones we just made with keys
+        share_config: vault.share_config.clone(),
+        nonce: nonce.clone(),
+        path: str_path.to_string(),
+        key_id: derive_key_id(&keys.recipient_secret()),
+        commitments: keys.commitments.clone(),
+        owner_fingerprint: owner_fingerprint(keys),
+        key_commitment: derive_key_commitment(&content_key),
+        lineage: lineage.clone(),
+    };
+
+    let aad = encode_meta(&mut writer, public_meta, cipher)?;
+    // Everything after the signature block is hashed as it is written, so the owner can sign it at the end.
+    let mut writer = HashWriter::new(writer);
+
+    // The key pieces and cloud credentials are only for the owner, so they are sealed under the main key.
+    // Recipients rebuild the vault key, which cannot open this.
+    {
+        let owner_state = OwnerState {
+            keys: keys.clone(),
+            cloud_creds: Some(cloud_creds.clone()),
+        };
+        let mut state_buffer = Vec::new();
+        owner_state.serialize(&mut Serializer::new(&mut state_buffer))?;
+
+        let sealed = seal(&derive_owner_state_key(&keys.main), &state_buffer, &aad)?;
+        encode_owner_state(&mut writer, &sealed)?;
+    }
+
+    // The archive is written straight into the encrypted stream, so the vault is never held in memory.
+    // The archive is entirely encrypted deliberately to obscure the file structure
+    let encryptor = EncryptWriter::new(cipher, &content_key, &nonce, &aad, writer)?;
+    let mut archive = Builder::new(encryptor);
 
     // Load files
 
     // Load the folder into the archive.
-    archive.append_dir_all(".", &files_dir)?;
+    archive.append_dir_all(".", files_dir)?;
 
     {
         // Get values or an empty string. We need to clone as an unwrap moves the value.
@@ -78,7 +295,9 @@ pub fn do_create(
         let address = cloned_personal_info.address.unwrap_or("".to_string());
         let phone = cloned_personal_info.phone_number.unwrap_or("".to_string());
         let guidance = cloned_personal_info.guidance_doc.unwrap_or("".to_string());
-        let legal_name = cloned_personal_info.full_legal_name.unwrap_or("".to_string());
+        let legal_name = cloned_personal_info
+            .full_legal_name
+            .unwrap_or("".to_string());
 
         // Add privacy note with details added in the personal information screen.
         let filled_notice = PRIVACY_TEXT
@@ -98,31 +317,13 @@ pub fn do_create(
         archive.append(&header, bytes)?;
     }
 
-    let mut cloud_creds: Option<CloudKeyData> = None;
-
-    // Generate or retrieve keys
-    let keys = if vault.keys.is_none() {
-        let (key, creds) = generate_cloud_creds();
-        cloud_creds = Some(creds);
-
-        let circles_with_keys = generate_circle_keys(
-            &key,
-            vault.share_config.circles.clone(),
-            vault.share_config.required,
-        )?;
-        let res = KeyCollection {
-            main: key,
-            share_keys: circles_with_keys,
-        };
-        vault.keys = Some(res.clone());
-        res
-    } else {
-        vault.keys.clone().unwrap()
-    };
-
     // Add meta information
-    // Serialize the entire vault struct and put it into a special file within the tar archive.
+    // Serialize the vault struct and put it into a special file within the tar archive.
+    // Everyone who unlocks the vault can read this, so the owner-only keys and credentials are left out.
     {
+        vault.keys = None;
+        vault.cloud_creds = None;
+
         let mut meta_buffer = Vec::new();
         vault.serialize(&mut Serializer::new(&mut meta_buffer))?;
 
@@ -133,38 +334,200 @@ pub fn do_create(
 
         archive.append(&header, &meta_buffer[..])?;
     }
-    // Archive is now complete
-    archive.finish()?;
+    // Archive is now complete. Finishing the stream seals the final chunk.
+    let encryptor = archive.into_inner()?;
+    let (writer, digest) = encryptor.finish()?.finish();
+
+    let mut output_file = writer.into_inner().map_err(|e| e.into_error())?;
+    write_signature(&mut output_file, &aad, &sign_vault(keys, &aad, &digest))?;
+    // Make sure it is all on disk before it replaces anything.
+    output_file.sync_all()?;
+    return Ok(());
+}
 
-    let nonce = generate_nonce();
-    // Write meta, comments etc.
-    let output_file = File::create(&output_file_path)?;
+// Reads a written vault back with its main key, and checks it holds every staged file, unchanged.
+// Only then is it safe to remove the staged files, as they may be the only other copy.
+fn check_written(file_path: &Path, keys: &KeyCollection, files_dir: &Path) -> AppResult<()> {
+    // The signature covers the whole file, so anything lost or changed in writing is caught here.
+    let header = verify_vault_file(&file_path.to_string_lossy())?;
+    let mut reader = BufReader::new(File::open(file_path)?);
+    decode_meta(&mut reader)?;
+    let sealed = decode_owner_state(&mut reader)?;
+    open_sealed(&derive_owner_state_key(&keys.main), &sealed, &header.raw)?;
+
+    let content_key = header.content_key(&keys.recipient_secret());
+    header.check_content_key(&content_key)?;
+    let decryptor = header.decryptor(&content_key, reader)?;
+    let mut tar = Archive::new(decryptor);
+
+    let mut staged = BTreeSet::new();
+    find_files(files_dir, Path::new(""), &mut staged)?;
+    for entry in tar.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.to_path_buf();
+        // The privacy notice and meta information are added, so they aren't staged.
+        if !staged.remove(&path) {
+            continue;
+        }
+        let mut hasher = HashWriter::new(io::sink());
+        io::copy(&mut entry, &mut hasher)?;
+        let (_sink, digest) = hasher.finish();
+        if digest != hash_file(&files_dir.join(&path))? {
+            return Err(not_written());
+        }
+    }
+    tar.into_inner().finish()?;
 
-    let str_path: String = output_file_path.to_str().unwrap().parse().unwrap();
+    if !staged.is_empty() {
+        return Err(not_written());
+    }
+    return Ok(());
+}
 
-    let mut writer = BufWriter::new(output_file);
-    let public_meta = PublicInfo {
-        name: vault.personal_info.name,
-        email_address: vault.personal_info.email_address,
-        // Clone the original share_config, so it's the original circles - not the new ones we just made with keys
-        share_config: vault.share_config.clone(),
-        nonce: Vec::from(nonce.as_slice()),
-        path: str_path.clone(),
-    };
+// Every file under the folder, relative to the top folder. Links are followed, as they are when archiving.
+fn find_files(folder: &Path, relative: &Path, files: &mut BTreeSet<PathBuf>) -> AppResult<()> {
+    for entry in read_dir(folder)? {
+        let entry = entry?;
+        let path = relative.join(entry.file_name());
+        if metadata(entry.path())?.is_dir() {
+            find_files(&entry.path(), &path, files)?;
+        } else {
+            files.insert(path);
+        }
+    }
+    return Ok(());
+}
 
-    let aad = encode_meta(&mut writer, public_meta);
+fn not_written() -> AppError {
+    return make_error(
+        "not_written",
+        "The vault did not match your files when it was read back, so nothing was changed. Try again.",
+    );
+}
 
-    // Encrypt it - The archive is entirely encrypted deliberately to obscure the file structure
-    let encrypted = encrypt_file(&keys.main, &archive.into_inner().unwrap(), &aad, nonce)?;
+#[cfg(test)]
+pub(crate) mod test {
+    use std::path::Path;
+    use std::{env, fs};
+
+    use crate::commands::create::{check_written, do_create};
+    use crate::crypto::tests::get_basic_combo;
+    use crate::crypto::Cipher;
+    use crate::lineage::test::temp_versions_path;
+    use crate::util::{get_partial_path, get_random_file_name};
+    use crate::vault::{PersonalInfo, ShareConfiguration, Vault, VaultType};
+
+    /// A new offline vault with the basic circles, for tests.
+    pub(crate) fn get_vault() -> Vault {
+        return Vault {
+            vault_type: VaultType::Offline,
+            personal_info: PersonalInfo {
+                name: "Test".to_string(),
+                email_address: "test@example.com".to_string(),
+                full_legal_name: None,
+                phone_number: None,
+                guidance_doc: None,
+                address: None,
+            },
+            share_config: ShareConfiguration {
+                required: 3,
+                circles: get_basic_combo(),
+            },
+            vault_folder: "/foo/bar".to_string(),
+            alert_duration: 0,
+            reminder_period: 0,
+            keys: None,
+            cloud_creds: None,
+            cipher: Cipher::default(),
+            previous: None,
+        };
+    }
 
-    writer.write(&encrypted.ciphertext)?;
+    fn stage_files(files: &Path) {
+        fs::create_dir_all(files.join("letters")).unwrap();
+        fs::write(files.join("will.txt"), "Everything to the cat").unwrap();
+        fs::write(files.join("letters").join("family.txt"), "To my family").unwrap();
+    }
 
-    // Delete files from FS (internal folder)
-    remove_dir_all(files_dir)?;
+    #[test]
+    fn written_vault_checked() {
+        let versions = temp_versions_path();
+        let mut files = env::temp_dir();
+        files.push(get_random_file_name().unwrap() + "files");
+        stage_files(&files);
+        let mut output_file = env::temp_dir();
+        output_file.push(get_random_file_name().unwrap());
+
+        let created = do_create(&versions, get_vault(), files.clone(), output_file.clone())
+            .expect("Failed to create");
+        assert!(output_file.exists());
+        assert!(!get_partial_path(&output_file).exists());
+        assert!(!files.exists());
+        assert!(created.warnings.is_empty());
+
+        // The same files match the vault. Changed or extra ones don't.
+        stage_files(&files);
+        check_written(&output_file, &created.keys, &files).expect("Files did not match");
+        fs::write(files.join("letters").join("family.txt"), "To my friends").unwrap();
+        let err =
+            check_written(&output_file, &created.keys, &files).expect_err("Matched a changed file");
+        assert_eq!(err.error_type, "not_written");
+        stage_files(&files);
+        fs::write(files.join("extra.txt"), "Not in the vault").unwrap();
+        let err =
+            check_written(&output_file, &created.keys, &files).expect_err("Matched an extra file");
+        assert_eq!(err.error_type, "not_written");
+
+        // A vault that was damaged after it was written.
+        fs::remove_file(files.join("extra.txt")).unwrap();
+        let mut bytes = fs::read(&output_file).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 1;
+        fs::write(&output_file, bytes).unwrap();
+        check_written(&output_file, &created.keys, &files).expect_err("Matched a damaged vault");
+
+        fs::remove_dir_all(files).unwrap();
+        fs::remove_file(output_file).unwrap();
+        let _ = fs::remove_file(versions);
+    }
 
-    return Ok(CreateResponse {
-        keys,
-        path: str_path,
-        cloud_keys: cloud_creds,
-    });
+    // A failed create leaves the staged files, and no vault.
+    #[test]
+    fn failed_create_rolled_back() {
+        let versions = temp_versions_path();
+        let mut files = env::temp_dir();
+        files.push(get_random_file_name().unwrap() + "files");
+        stage_files(&files);
+        let mut output_file = env::temp_dir();
+        output_file.push(get_random_file_name().unwrap());
+
+        let mut vault = get_vault();
+        vault.share_config.required = 0;
+        do_create(&versions, vault, files.clone(), output_file.clone())
+            .expect_err("Created a bad vault");
+        assert!(!output_file.exists());
+        assert!(!get_partial_path(&output_file).exists());
+        assert_eq!(
+            fs::read_to_string(files.join("will.txt")).unwrap(),
+            "Everything to the cat"
+        );
+
+        // The output can't be written, as it is in a folder which doesn't exist.
+        let mut missing = env::temp_dir();
+        missing.push(get_random_file_name().unwrap() + "missing");
+        missing.push("vault");
+        do_create(&versions, get_vault(), files.clone(), missing.clone())
+            .expect_err("Wrote to a missing folder");
+        assert!(files.join("letters").join("family.txt").exists());
+
+        // Nor is a vault kept if its version can't be remembered.
+        do_create(&missing, get_vault(), files.clone(), output_file.clone())
+            .expect_err("Kept a vault without remembering its version");
+        assert!(!output_file.exists());
+        assert!(!get_partial_path(&output_file).exists());
+        assert!(files.join("letters").join("family.txt").exists());
+
+        fs::remove_dir_all(files).unwrap();
+        let _ = fs::remove_file(versions);
+    }
 }
@@ -1,16 +1,26 @@
-use std::fs::File;
-use std::io::BufReader;
 use std::path::{Path, PathBuf};
 
 use crate::error::{AppError, AppResult};
-use crate::meta::decode_meta;
-use crate::util::get_random_file_path;
+use crate::lineage::check_version;
+use crate::signature::verify_vault_file;
+use crate::util::{get_random_file_path, get_versions_path};
 use crate::vault::PublicInfo;
 
 /// Given the path to a meta file, load the meta information and return it.
 /// This command is used to get the number of keypieces to unlock a vault.
+/// The owner's signature is checked first, but whoever made the vault chose both the info and the key it is signed
+/// with. The owner fingerprint in the info only shows who signed it, so it must be compared with one from the owner,
+/// such as on a key piece.
+/// The info includes the vault's version lineage. A version older than one seen on this device before is refused.
 #[tauri::command]
-pub fn load_meta(file_path: String) -> AppResult<PublicInfo> {
+pub fn load_meta(app_handle: tauri::AppHandle, file_path: String) -> AppResult<PublicInfo> {
+    let info = read_meta(file_path)?;
+    check_version(&get_versions_path(&app_handle)?, &info.lineage)?;
+    return Ok(info);
+}
+
+/// Reads and checks the meta information of a vault file, without checking its version.
+pub fn read_meta(file_path: String) -> AppResult<PublicInfo> {
     let current_path = Path::new(&file_path);
 
     if !current_path.exists() {
@@ -21,10 +31,8 @@ pub fn load_meta(file_path: String) -> AppResult<PublicInfo> {
         });
     }
 
-    let mut reader = BufReader::new(File::open(current_path)?);
-
-    let (_, meta) = decode_meta(&mut reader)?;
-    return Ok(meta);
+    let header = verify_vault_file(&file_path)?;
+    return Ok(header.info);
 }
 
 #[tauri::command]
@@ -37,13 +45,15 @@ mod test {
     use std::{env, fs};
 
     use crate::commands::create::do_create;
-    use crate::commands::loadmeta::load_meta;
-    use crate::crypto::tests::get_basic_combo;
+    use crate::commands::create::test::get_vault;
+    use crate::commands::loadmeta::read_meta;
+    use crate::lineage::test::temp_versions_path;
+    use crate::meta::FORMAT_VERSION;
     use crate::util::get_random_file_name;
-    use crate::vault::{PersonalInfo, ShareConfiguration, Vault, VaultType};
 
     #[test]
     fn sample_load_meta() {
+        let versions = temp_versions_path();
         let mut empty_folder = env::temp_dir();
         empty_folder.push(get_random_file_name().unwrap() + "empty");
         fs::create_dir_all(&empty_folder).expect("Failed to create empty folder for testing");
@@ -51,37 +61,27 @@ mod test {
         let mut output_file = env::temp_dir();
         output_file.push(get_random_file_name().expect("Failed to get output file"));
 
-        let sample_vault_result = do_create(
-            Vault {
-                vault_type: VaultType::Offline,
-                personal_info: PersonalInfo {
-                    name: "Test".to_string(),
-                    email_address: "test@example.com".to_string(),
-                    full_legal_name: None,
-                    phone_number: None,
-                    guidance_doc: None,
-                    address: None,
-                },
-                share_config: ShareConfiguration {
-                    required: 3,
-                    circles: get_basic_combo(),
-                },
-                vault_folder: "/foo/bar".to_string(),
-                alert_duration: 0,
-                reminder_period: 0,
-                keys: None,
-            },
-            empty_folder,
-            output_file,
-        );
+        let sample_vault_result = do_create(&versions, get_vault(), empty_folder, output_file);
 
         let res = sample_vault_result.expect("Could not create vault to test meta");
         let path = res.path.clone();
-        let meta = load_meta(res.path).expect("Failed to load meta");
+        let meta = read_meta(res.path).expect("Failed to load meta");
 
         assert_eq!(meta.email_address, "test@example.com");
         assert_eq!(meta.name, "Test");
 
         fs::remove_file(path).unwrap();
+        let _ = fs::remove_file(versions);
+    }
+
+    // Every whole vault in the fuzzing corpus (see fuzz/) is a real vault, one for each format version.
+    #[test]
+    fn corpus_vaults() {
+        let corpus = concat!(env!("CARGO_MANIFEST_DIR"), "/fuzz/corpus/load_meta");
+        for version in 0..=FORMAT_VERSION {
+            let path = format!("{}/v{}_vault", corpus, version);
+            let meta = read_meta(path).expect("Failed to load meta");
+            assert_eq!(meta.name, "Test");
+        }
     }
 }
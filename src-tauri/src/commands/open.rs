@@ -1,34 +1,57 @@
 use std::ffi::OsString;
-use std::fs::File;
-use std::io::{BufReader, Cursor, Read};
+use std::fs::{remove_dir_all, File};
+use std::io::BufReader;
 use std::path::PathBuf;
 use std::time::SystemTime;
 
 use tar::Archive;
 
 use crate::constants::OPENED_VAULT_FOLDER;
-use crate::crypto::{decrypt_file, EncryptedFile};
-use crate::error::{app_error, AppError, AppResult};
-use crate::meta::decode_meta;
-use crate::util::read_meta_file;
-use crate::vault::Vault;
+use crate::crypto::{derive_owner_state_key, open_sealed, Key, ENCRYPTION_KEY_LEN};
+use crate::error::{app_error, make_error, AppError, AppResult};
+use crate::lineage::check_version;
+use crate::meta::decode_owner_state;
+use crate::passphrase::unwrap_main_key;
+use crate::signature::{owner_fingerprint, verify_vault_start};
+use crate::util::{get_versions_path, read_meta_file};
+use crate::vault::{OwnerState, PreviousVersion, Vault};
 
 /// Open a vault using a main key
-/// Takes the path to a vault file and a key.
+/// Takes the path to a vault file and a key, which may be the armored text of a saved key file.
+/// Key files protected by a passphrase need the passphrase too.
 #[tauri::command]
-pub fn open(app_handle: tauri::AppHandle, file_path: String, key: Vec<u8>) -> AppResult<Vault> {
+pub fn open(
+    app_handle: tauri::AppHandle,
+    file_path: String,
+    key: Vec<u8>,
+    passphrase: Option<String>,
+) -> AppResult<Vault> {
     let mut new_path = app_handle
         .path_resolver()
         .app_data_dir()
         .ok_or(app_error("Could not get data directory".to_string()))?;
     new_path.push(OPENED_VAULT_FOLDER);
+    let versions_path = get_versions_path(&app_handle)?;
 
-    return do_open(new_path, file_path, key);
+    let vault = do_open(new_path, file_path, key, passphrase.as_deref())?;
+    // Updating an old copy would lose everything since, so it is refused.
+    if let Some(previous) = &vault.previous {
+        if let Err(e) = check_version(&versions_path, &previous.lineage) {
+            let _ = remove_dir_all(&vault.vault_folder);
+            return Err(e);
+        }
+    }
+    return Ok(vault);
 }
 
 /// Does all the actual command functionality.
 /// Seperated so that app_handle is not used, so it is really easy to test.
-fn do_open(mut new_path: PathBuf, file_path: String, key: Vec<u8>) -> AppResult<Vault> {
+pub(crate) fn do_open(
+    mut new_path: PathBuf,
+    file_path: String,
+    key: Vec<u8>,
+    passphrase: Option<&str>,
+) -> AppResult<Vault> {
     let folder_name = SystemTime::now()
         .duration_since(SystemTime::UNIX_EPOCH)?
         .as_secs();
@@ -37,27 +60,87 @@ fn do_open(mut new_path: PathBuf, file_path: String, key: Vec<u8>) -> AppResult<
 
     // So we have the path to an encrypted vault and a main key.
     // Decrypt it and then decompress it
-    let mut reader = BufReader::new(File::open(&file_path)?);
+    let mut file = BufReader::new(File::open(&file_path)?);
 
-    // Decrypt
-    let (raw_meta, meta) = decode_meta(&mut reader)?;
+    // Check the vault is the owner's before decrypting anything. It is then read again from the same file, and must
+    // not have changed since.
+    let verified = verify_vault_start(&mut file)?;
+    let mut reader = verified.reread(file)?;
+    let header = &verified.header;
+    // The update will follow on from this version.
+    let previous = PreviousVersion {
+        path: file_path.clone(),
+        hash: verified.file_hash.clone(),
+        lineage: header.info.lineage.clone(),
+    };
 
-    let mut buff = Vec::new();
-    reader.read_to_end(&mut buff)?;
+    // Saved key files are armored, and cloud main keys carry the owner token after the key.
+    let mut key = unwrap_main_key(&key, passphrase)?;
+    key.truncate(ENCRYPTION_KEY_LEN);
+    let main: Key = key.as_slice().try_into()?;
 
-    let file = EncryptedFile {
-        ciphertext: buff,
-        nonce: meta.nonce,
-        aad: raw_meta,
+    // The owner state holds the keys needed to update the vault. Only the main key can open it.
+    // Vaults made before it kept the keys in the archive instead.
+    let owner_state: Option<OwnerState> = if header.has_owner_state() {
+        let sealed = decode_owner_state(&mut reader)?;
+        let owner_state_bytes = open_sealed(&derive_owner_state_key(&main), &sealed, &header.raw)
+            .map_err(|_e| {
+                make_error(
+                    "crypto",
+                    "Decryption failed. Is this the main key for this vault? Key pieces cannot be used to edit a vault.",
+                )
+            })?;
+        Some(rmp_serde::from_slice(&owner_state_bytes)?)
+    } else {
+        None
     };
+    // The signature only shows the vault was signed with the key in it, so check that key is the owner's.
+    let signer = owner_state
+        .as_ref()
+        .map(|owner_state| owner_fingerprint(&owner_state.keys));
+    if !header.info.owner_fingerprint.is_empty()
+        && signer.as_ref() != Some(&header.info.owner_fingerprint)
+    {
+        return Err(make_error(
+            "bad_signature",
+            "This vault was not signed with this main key. It may not have come from you, so do not trust it.",
+        ));
+    }
 
-    let decrypted = decrypt_file(key.as_slice(), file)?;
+    // Vaults updated from older vaults keep the older pieces, which the owner state records.
+    let recipient_secret = match &owner_state {
+        Some(owner_state) => owner_state.keys.recipient_secret(),
+        None => header.recipient_secret(&main),
+    };
+    let content_key = header.content_key(&recipient_secret);
+    header.check_content_key(&content_key)?;
 
-    let mut tar = Archive::new(Cursor::new(decrypted));
-    tar.unpack(&new_path)?;
+    // Decrypt while unpacking, so only one chunk of the vault is in memory at a time.
+    let decryptor = header.decryptor(&content_key, reader)?;
+    let mut tar = Archive::new(decryptor);
+    let unpacked = tar.unpack(&new_path);
 
-    // Load meta
+    // Always check the whole stream was authentic, as tar may not read up to the final chunk.
+    let authentic = tar
+        .into_inner()
+        .finish()
+        .and_then(|reader| verified.check_unchanged(reader));
+    if authentic.is_err() || unpacked.is_err() {
+        // Don't leave a partially unpacked vault behind.
+        let _ = remove_dir_all(&new_path);
+    }
+    authentic?;
+    unpacked?;
+
+    // Load meta, and restore the owner-only parts that are kept out of the archive.
     let mut vault = read_meta_file(&new_path)?;
+    if let Some(owner_state) = owner_state {
+        vault.keys = Some(owner_state.keys);
+        vault.cloud_creds = owner_state.cloud_creds;
+    } else if let Some(keys) = vault.keys.as_mut() {
+        keys.pieces_rebuild_main = true;
+    }
+    vault.previous = Some(previous);
 
     // In practice, this error should not happen (no unicode path)
     // But I guess you could say that for most errors...
@@ -75,36 +158,29 @@ fn do_open(mut new_path: PathBuf, file_path: String, key: Vec<u8>) -> AppResult<
 // Tests
 #[cfg(test)]
 mod test {
+    use std::path::{Path, PathBuf};
     use std::{env, fs};
 
+    use crate::armor::{armor, ArmorKind};
     use crate::commands::create::do_create;
+    use crate::commands::create::test::get_vault;
+    use crate::commands::loadmeta::read_meta;
     use crate::commands::open::do_open;
-    use crate::crypto::tests::get_basic_combo;
+    use crate::commands::rekey::do_rekey;
+    use crate::commands::unlock::do_unlock;
+    use crate::crypto::{combine_keys, derive_recipient_secret, KeyPiece, STREAM_CHUNK_LEN};
+    use crate::lineage::hash_file;
+    use crate::lineage::test::temp_versions_path;
+    use crate::passphrase::tests::TEST_KDF_PARAMS;
+    use crate::passphrase::{protect_with, PROTECTED_MAIN_KEY_MAGIC};
     use crate::util::get_random_file_name;
-    use crate::vault::{PersonalInfo, ShareConfiguration, Vault, VaultType};
+    use crate::vault::{Vault, VaultType};
 
     // Very simple. Empty vault!
     #[test]
     fn simple_open() {
-        let vault_info_in = Vault {
-            vault_type: VaultType::Offline,
-            personal_info: PersonalInfo {
-                name: "Test".to_string(),
-                email_address: "test@example.com".to_string(),
-                full_legal_name: None,
-                phone_number: None,
-                guidance_doc: None,
-                address: None,
-            },
-            share_config: ShareConfiguration {
-                required: 3,
-                circles: get_basic_combo(),
-            },
-            vault_folder: "/foo/bar".to_string(),
-            alert_duration: 0,
-            reminder_period: 0,
-            keys: None,
-        };
+        let versions = temp_versions_path();
+        let vault_info_in = get_vault();
 
         let copy_for_assert = vault_info_in.clone();
 
@@ -116,7 +192,7 @@ mod test {
         let mut output_file = env::temp_dir();
         output_file.push(get_random_file_name().expect("Failed to get output file"));
 
-        let sample_vault_result = do_create(vault_info_in, empty_folder, output_file);
+        let sample_vault_result = do_create(&versions, vault_info_in, empty_folder, output_file);
 
         assert!(sample_vault_result.is_ok());
         let res = sample_vault_result.unwrap();
@@ -124,7 +200,7 @@ mod test {
 
         // Actual test - open it using the main key.
         let open_result =
-            do_open(unpack_dir, res.path, res.keys.main.to_vec()).expect("Failed to open");
+            do_open(unpack_dir, res.path, res.keys.main.to_vec(), None).expect("Failed to open");
         assert_eq!(open_result.alert_duration, copy_for_assert.alert_duration);
         assert_eq!(open_result.reminder_period, copy_for_assert.reminder_period);
         assert_eq!(open_result.vault_type, copy_for_assert.vault_type);
@@ -140,5 +216,357 @@ mod test {
         assert_eq!(open_result.alert_duration, copy_for_assert.alert_duration);
 
         fs::remove_file(path).unwrap();
+        let _ = fs::remove_file(versions);
+    }
+
+    // Vault contents spanning several stream chunks.
+    #[test]
+    fn multi_chunk_open() {
+        let versions = temp_versions_path();
+        let vault_info_in = get_vault();
+
+        let mut files_folder = env::temp_dir();
+        files_folder.push(get_random_file_name().unwrap() + "files");
+        fs::create_dir_all(&files_folder).expect("Failed to create folder for testing");
+
+        let contents: Vec<u8> = (0..STREAM_CHUNK_LEN * 3 + 100)
+            .map(|i| (i % 253) as u8)
+            .collect();
+        let mut file_path = files_folder.clone();
+        file_path.push("photo.jpg");
+        fs::write(&file_path, &contents).expect("Failed to write test file");
+
+        let mut unpack_dir = env::temp_dir();
+        unpack_dir.push(get_random_file_name().unwrap() + "open");
+
+        let mut output_file = env::temp_dir();
+        output_file.push(get_random_file_name().expect("Failed to get output file"));
+
+        let res = do_create(&versions, vault_info_in, files_folder, output_file)
+            .expect("Failed to create");
+        let path = res.path.clone();
+
+        let open_result =
+            do_open(unpack_dir, res.path, res.keys.main.to_vec(), None).expect("Failed to open");
+
+        let mut opened_file = std::path::PathBuf::from(&open_result.vault_folder);
+        opened_file.push("photo.jpg");
+        let opened_contents = fs::read(opened_file).expect("File missing from opened vault");
+        assert_eq!(opened_contents, contents, "Opened file does not match");
+
+        fs::remove_dir_all(open_result.vault_folder).unwrap();
+        fs::remove_file(path).unwrap();
+        let _ = fs::remove_file(versions);
+    }
+
+    // The owner state is restored, and only the main key can open the vault for editing.
+    #[test]
+    fn open_restores_owner_state() {
+        let versions = temp_versions_path();
+        let vault_info_in = Vault {
+            vault_type: VaultType::Cloud,
+            ..get_vault()
+        };
+
+        let mut empty_folder = env::temp_dir();
+        empty_folder.push(get_random_file_name().unwrap() + "empty");
+        fs::create_dir_all(&empty_folder).expect("Failed to create empty folder for testing");
+
+        let mut unpack_dir = env::temp_dir();
+        unpack_dir.push(get_random_file_name().unwrap() + "open");
+
+        let mut output_file = env::temp_dir();
+        output_file.push(get_random_file_name().expect("Failed to get output file"));
+
+        let res = do_create(&versions, vault_info_in, empty_folder, output_file)
+            .expect("Failed to create");
+        let path = res.path.clone();
+
+        // The key rebuilt from key pieces decrypts the contents, but must not give access to the owner state.
+        let mut keys_only: Vec<Vec<u8>> = Vec::new();
+        for c in res.keys.share_keys.iter() {
+            if let Some(keys) = &c.keys {
+                keys_only.extend(keys.clone());
+            }
+        }
+        let combined = combine_keys(keys_only).expect("Failed to combine keys");
+        assert_eq!(combined, derive_recipient_secret(&res.keys.main));
+        let err = do_open(unpack_dir.clone(), path.clone(), combined.to_vec(), None)
+            .err()
+            .expect("Opened with a combined key");
+        assert_eq!(err.error_type, "crypto");
+
+        // A saved key file, with the cloud owner token after the main key.
+        let created_creds = res.cloud_keys.expect("Expected cloud credentials");
+        let mut full_key = res.keys.main.to_vec();
+        full_key.extend_from_slice(&created_creds.owner_token);
+        let info = read_meta(path.clone()).expect("Failed to load meta");
+        let armored = armor(ArmorKind::MainKey, &full_key, &info, None, true);
+
+        let open_result = do_open(unpack_dir.clone(), path.clone(), armored.into_bytes(), None)
+            .expect("Failed to open with armored key");
+        let keys = open_result.keys.expect("Keys were not restored");
+        assert_eq!(keys.main, res.keys.main);
+        assert_eq!(keys.share_keys.len(), res.keys.share_keys.len());
+        let creds = open_result
+            .cloud_creds
+            .expect("Cloud credentials were not restored");
+        assert_eq!(creds.owner_token, created_creds.owner_token);
+        assert_eq!(creds.share_token, created_creds.share_token);
+        fs::remove_dir_all(open_result.vault_folder).unwrap();
+
+        // A key file protected by a passphrase, which the owner can keep instead of the raw key.
+        let protected = protect_with(
+            PROTECTED_MAIN_KEY_MAGIC,
+            &full_key,
+            "owner",
+            TEST_KDF_PARAMS,
+        )
+        .unwrap();
+        let armored = armor(ArmorKind::MainKey, &protected, &info, None, true).into_bytes();
+        let err = do_open(unpack_dir.clone(), path.clone(), armored.clone(), None)
+            .err()
+            .expect("Opened without the passphrase");
+        assert_eq!(err.error_type, "passphrase_needed");
+        let err = do_open(
+            unpack_dir.clone(),
+            path.clone(),
+            armored.clone(),
+            Some("Owner"),
+        )
+        .err()
+        .expect("Opened with the wrong passphrase");
+        assert_eq!(err.error_type, "wrong_passphrase");
+        let open_result = do_open(unpack_dir, path.clone(), armored, Some("owner"))
+            .expect("Failed to open with protected key");
+        assert_eq!(
+            open_result.keys.expect("Keys were not restored").main,
+            res.keys.main
+        );
+        fs::remove_dir_all(open_result.vault_folder).unwrap();
+        fs::remove_file(path).unwrap();
+        let _ = fs::remove_file(versions);
+    }
+
+    // Members added when updating get pieces which unlock along with everyone else's unchanged ones.
+    #[test]
+    fn update_adds_members() {
+        let versions = temp_versions_path();
+        let vault_info_in = get_vault();
+
+        let mut files = env::temp_dir();
+        files.push(get_random_file_name().unwrap() + "files");
+        fs::create_dir_all(&files).unwrap();
+        fs::write(files.join("letter.txt"), "To my family").unwrap();
+        let mut output_file = env::temp_dir();
+        output_file.push(get_random_file_name().unwrap());
+        let res =
+            do_create(&versions, vault_info_in, files, output_file).expect("Failed to create");
+        let old_pieces = res.keys.share_keys[0].keys.clone().unwrap();
+
+        let mut unpack_dir = env::temp_dir();
+        unpack_dir.push(get_random_file_name().unwrap() + "open");
+        let mut vault = do_open(unpack_dir, res.path.clone(), res.keys.main.to_vec(), None)
+            .expect("Failed to open");
+        vault.share_config.circles[0]
+            .key_comments
+            .push("f".to_string());
+        let files = PathBuf::from(&vault.vault_folder);
+        let mut output_file = env::temp_dir();
+        output_file.push(get_random_file_name().unwrap());
+        let updated = do_create(&versions, vault, files, output_file).expect("Failed to update");
+
+        let pieces = updated.keys.share_keys[0].keys.clone().unwrap();
+        assert_eq!(pieces.len(), 6);
+        assert_eq!(pieces[..5], old_pieces);
+        assert_eq!(updated.keys.main, res.keys.main);
+
+        let unlocked = do_unlock(
+            &versions,
+            updated.path.clone(),
+            Vec::from([
+                pieces[5].clone(),
+                old_pieces[0].clone(),
+                old_pieces[3].clone(),
+            ]),
+            Some(".".to_string()),
+            None,
+        )
+        .expect("Failed to unlock");
+        let letter = fs::read_to_string(PathBuf::from(&unlocked.path).join("letter.txt"));
+        assert_eq!(letter.unwrap(), "To my family");
+        fs::remove_dir_all(unlocked.path).unwrap();
+        fs::remove_file(res.path).unwrap();
+        fs::remove_file(updated.path).unwrap();
+        let _ = fs::remove_file(versions);
+    }
+
+    // Vaults made before the owner state (see meta.rs) open with their main key, and the keys are
+    // read from the archive. Their pieces rebuild the main key, so they must be rekeyed before they are updated.
+    #[test]
+    fn opens_older_vaults() {
+        let versions = temp_versions_path();
+        let fixtures = Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures");
+        for name in ["baseline", "version1"] {
+            let main = fs::read(fixtures.join(format!("{}_main_key", name))).unwrap();
+            let pieces: Vec<KeyPiece> = serde_json::from_slice(
+                &fs::read(fixtures.join(format!("{}_pieces.json", name))).unwrap(),
+            )
+            .unwrap();
+            let mut unpack_dir = env::temp_dir();
+            unpack_dir.push(get_random_file_name().unwrap() + "open");
+
+            let path = fixtures
+                .join(format!("{}.vault", name))
+                .to_string_lossy()
+                .to_string();
+            let vault = do_open(unpack_dir.clone(), path.clone(), main.clone(), None)
+                .expect("Failed to open");
+            assert_eq!(vault.personal_info.name, "Test");
+            let keys = vault.keys.clone().expect("Keys were not restored");
+            assert_eq!(keys.main.to_vec(), main);
+            assert_eq!(keys.share_keys[1].keys.as_ref().unwrap().len(), 2);
+            let letter = fs::read_to_string(Path::new(&vault.vault_folder).join("letter.txt"));
+            assert_eq!(letter.unwrap(), "To my family");
+
+            // Anyone holding enough of the old pieces can rebuild the main key, so an update is refused.
+            let files = PathBuf::from(&vault.vault_folder);
+            let mut output_file = env::temp_dir();
+            output_file.push(get_random_file_name().unwrap());
+            let err = do_create(&versions, vault, files, output_file.clone())
+                .expect_err("Updated a vault whose pieces rebuild the main key");
+            assert_eq!(err.error_type, "rekey_required");
+            assert!(!output_file.exists());
+
+            // Rekeying gives it new pieces and a signing key of its own, and the old pieces stop working.
+            let rekeyed = do_rekey(
+                &versions,
+                unpack_dir.clone(),
+                path.clone(),
+                main.clone(),
+                None,
+                &[],
+                output_file,
+            )
+            .expect("Failed to rekey");
+            let keys = &rekeyed.vault.keys;
+            assert!(!keys.pieces_rebuild_main);
+            assert!(keys.signing_seed.is_some());
+            let new_pieces: Vec<KeyPiece> = keys
+                .share_keys
+                .iter()
+                .flat_map(|circle| circle.keys.clone().unwrap())
+                .collect();
+            let unlocked = do_unlock(
+                &versions,
+                rekeyed.vault.path.clone(),
+                new_pieces,
+                Some(unpack_dir.to_string_lossy().to_string()),
+                None,
+            )
+            .expect("Failed to unlock the rekeyed vault");
+            let letter = fs::read_to_string(Path::new(&unlocked.path).join("letter.txt"));
+            assert_eq!(letter.unwrap(), "To my family");
+            assert!(do_unlock(
+                &versions,
+                rekeyed.vault.path.clone(),
+                Vec::from([pieces[1].clone(), pieces[2].clone(), pieces[3].clone()]),
+                Some(unpack_dir.to_string_lossy().to_string()),
+                None,
+            )
+            .is_err());
+
+            fs::remove_dir_all(unpack_dir).unwrap();
+            fs::remove_file(rekeyed.vault.backup.unwrap()).unwrap();
+            fs::remove_file(rekeyed.vault.path).unwrap();
+        }
+        let _ = fs::remove_file(versions);
+    }
+
+    // An update is the next version of the vault it was opened from, which is kept as a backup.
+    #[test]
+    fn update_follows_on() {
+        let versions = temp_versions_path();
+        let vault_info_in = get_vault();
+
+        let mut empty_folder = env::temp_dir();
+        empty_folder.push(get_random_file_name().unwrap() + "empty");
+        fs::create_dir_all(&empty_folder).unwrap();
+        let mut output_file = env::temp_dir();
+        output_file.push(get_random_file_name().unwrap());
+        let first = do_create(&versions, vault_info_in, empty_folder, output_file)
+            .expect("Failed to create");
+        assert_eq!(first.lineage.version, 1);
+        assert!(first.lineage.previous_hash.is_empty());
+        assert_eq!(first.backup, None);
+        let first_hash = hash_file(Path::new(&first.path)).unwrap();
+
+        let mut unpack_dir = env::temp_dir();
+        unpack_dir.push(get_random_file_name().unwrap() + "open");
+        let vault = do_open(
+            unpack_dir.clone(),
+            first.path.clone(),
+            first.keys.main.to_vec(),
+            None,
+        )
+        .expect("Failed to open");
+        let files = PathBuf::from(&vault.vault_folder);
+        let mut output_file = env::temp_dir();
+        output_file.push(get_random_file_name().unwrap());
+        let second = do_create(&versions, vault, files, output_file).expect("Failed to update");
+
+        assert_eq!(second.lineage.vault_id, first.lineage.vault_id);
+        assert_eq!(second.lineage.version, 2);
+        assert_eq!(second.lineage.previous_hash, first_hash);
+        assert_eq!(
+            read_meta(second.path.clone()).unwrap().lineage,
+            second.lineage
+        );
+        let backup = second.backup.expect("No backup was kept");
+        assert_eq!(hash_file(Path::new(&backup)).unwrap(), first_hash);
+
+        // The file opened can't be swapped for another before the update is made.
+        let vault = do_open(
+            unpack_dir.clone(),
+            second.path.clone(),
+            first.keys.main.to_vec(),
+            None,
+        )
+        .expect("Failed to open");
+        let files = PathBuf::from(&vault.vault_folder);
+        fs::copy(&first.path, &second.path).unwrap();
+        let mut output_file = env::temp_dir();
+        output_file.push(get_random_file_name().unwrap());
+        let err = do_create(&versions, vault.clone(), files.clone(), output_file.clone())
+            .expect_err("Updated from a swapped file");
+        assert_eq!(err.error_type, "previous_version");
+
+        // Nor can a vault be updated without the version it came from.
+        let unlinked = Vault {
+            previous: None,
+            ..vault
+        };
+        let err = do_create(&versions, unlinked, files.clone(), output_file.clone())
+            .expect_err("Updated without a previous version");
+        assert_eq!(err.error_type, "previous_version");
+
+        // Nor can it be updated twice from the same version.
+        let vault = do_open(
+            unpack_dir.clone(),
+            first.path.clone(),
+            first.keys.main.to_vec(),
+            None,
+        )
+        .expect("Failed to open");
+        let err = do_create(&versions, vault, files.clone(), output_file.clone())
+            .expect_err("Updated the same version twice");
+        assert_eq!(err.error_type, "old_version");
+        assert!(!output_file.exists());
+
+        fs::remove_dir_all(files).unwrap();
+        fs::remove_file(backup).unwrap();
+        fs::remove_file(first.path).unwrap();
+        fs::remove_file(second.path).unwrap();
+        let _ = fs::remove_file(versions);
     }
 }
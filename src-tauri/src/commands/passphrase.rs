@@ -0,0 +1,72 @@
+use crate::crypto::KeyPiece;
+use crate::error::AppResult;
+use crate::piece;
+
+/// Protects a key piece with a passphrase chosen by its recipient, so a piece that is found can't be used on its own.
+/// The protected piece can be copied, saved or armored like any other.
+#[tauri::command]
+pub fn protect_piece(key: KeyPiece, passphrase: String) -> AppResult<KeyPiece> {
+    return piece::protect_piece(&key, &passphrase);
+}
+
+/// Removes the passphrase from a key piece before it is used to unlock a vault.
+/// A wrong passphrase gives a wrong_passphrase error, so the user can be asked again.
+#[tauri::command]
+pub fn unprotect_piece(key: KeyPiece, passphrase: String) -> AppResult<KeyPiece> {
+    return piece::unprotect_piece(&key, &passphrase, 1);
+}
+
+/// Whether a key piece needs a passphrase before it can be used.
+#[tauri::command]
+pub fn is_protected_piece(key: KeyPiece) -> bool {
+    return piece::is_protected_piece(&key);
+}
+
+#[cfg(test)]
+mod test {
+    use crate::armor::{armor, ArmorKind};
+    use crate::commands::passphrase::{is_protected_piece, protect_piece, unprotect_piece};
+    use crate::crypto::tests::{get_basic_combo, get_public_info};
+    use crate::crypto::{generate_circle_keys, generate_key};
+    use crate::passphrase::protect_with;
+    use crate::passphrase::tests::TEST_KDF_PARAMS;
+    use crate::piece::{unwrap_piece, PROTECTED_PIECE_MAGIC};
+
+    #[test]
+    fn protected_pieces() {
+        let (circles, _commitments, _coefficients) =
+            generate_circle_keys(&generate_key(), &[], get_basic_combo(), 3)
+                .expect("Failed to make keys");
+        let piece = circles[0].keys.as_ref().unwrap()[0].clone();
+
+        let protected = protect_with(PROTECTED_PIECE_MAGIC, &piece, "for Alice", TEST_KDF_PARAMS)
+            .expect("Failed to protect");
+        assert!(is_protected_piece(protected.clone()));
+        assert!(!is_protected_piece(piece.clone()));
+        let err = unwrap_piece(&protected, 2).expect_err("Used a protected piece");
+        assert_eq!(err.error_type, "piece_protected");
+        assert!(err.message.starts_with("Key piece 2 "));
+
+        assert_eq!(
+            unprotect_piece(protected.clone(), "for Alice".to_string()).expect("Failed to unlock"),
+            piece
+        );
+        let err = unprotect_piece(protected.clone(), "for Bob".to_string())
+            .expect_err("Unlocked with the wrong passphrase");
+        assert_eq!(err.error_type, "wrong_passphrase");
+
+        // Protected pieces can be saved as armored text.
+        let info = get_public_info(circles, 3);
+        let armored = armor(ArmorKind::KeyPiece, &protected, &info, None, false).into_bytes();
+        assert!(is_protected_piece(armored.clone()));
+        assert_eq!(
+            unprotect_piece(armored, "for Alice".to_string()).expect("Failed to unlock"),
+            piece
+        );
+
+        // Broken pieces are refused before they are protected.
+        let err = protect_piece(piece[..20].to_vec(), "for Alice".to_string())
+            .expect_err("Protected a broken piece");
+        assert_eq!(err.error_type, "piece_checksum");
+    }
+}
@@ -0,0 +1,297 @@
+use std::fs::remove_dir_all;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::commands::create::{do_create, CreateResponse};
+use crate::commands::open::do_open;
+use crate::constants::OPENED_VAULT_FOLDER;
+use crate::error::{app_error, make_error, AppResult};
+use crate::util::{get_random_file_path, get_versions_path};
+use crate::vault::{Circle, ShareConfiguration};
+
+/// A member of a circle, by their position in the vault's share configuration.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct MemberPosition {
+    pub(crate) circle: usize,
+    pub(crate) member: usize,
+}
+
+/// Who holds a key piece, for showing to the owner.
+#[derive(Serialize, Debug, PartialEq)]
+pub struct PieceHolder {
+    pub(crate) circle: String,
+    pub(crate) member: String,
+}
+
+#[derive(Serialize, Debug)]
+pub struct RekeyResponse {
+    // The new vault, with its new main key and key pieces.
+    pub(crate) vault: CreateResponse,
+    // Everyone who keeps access. Their old pieces no longer work, so each must be given their new one.
+    pub(crate) reissued: Vec<PieceHolder>,
+    // The members whose pieces were revoked. Nothing new is made for them.
+    pub(crate) revoked: Vec<PieceHolder>,
+}
+
+/// Rebuilds a vault under a fresh main key, leaving out the members given, so their key pieces are revoked.
+/// Takes the path to the vault file and the current main key (and its passphrase, if it has one), as for open.
+/// The contents are encrypted again, and new pieces are made only for the remaining members. Every old piece stops
+/// working, so the owner must hand out the new pieces and the new main key. The new vault is the next version of the
+/// old one, which is kept as a backup.
+/// A cloud vault gets new cloud credentials, so the copy on the server should be deleted with the old owner token.
+#[tauri::command]
+pub async fn rekey(
+    app_handle: tauri::AppHandle,
+    file_path: String,
+    key: Vec<u8>,
+    passphrase: Option<String>,
+    revoke: Vec<MemberPosition>,
+) -> AppResult<RekeyResponse> {
+    let mut opened_path = app_handle
+        .path_resolver()
+        .app_data_dir()
+        .ok_or(app_error("Could not get data directory".to_string()))?;
+    opened_path.push(OPENED_VAULT_FOLDER);
+    let versions_path = get_versions_path(&app_handle)?;
+    let output_file_path = get_random_file_path(app_handle)?;
+
+    return do_rekey(
+        &versions_path,
+        opened_path,
+        file_path,
+        key,
+        passphrase.as_deref(),
+        &revoke,
+        output_file_path,
+    );
+}
+
+// Does the actual command actions.
+// Seperated to allow for testing.
+pub fn do_rekey(
+    versions_path: &Path,
+    opened_path: PathBuf,
+    file_path: String,
+    key: Vec<u8>,
+    passphrase: Option<&str>,
+    revoke: &[MemberPosition],
+    output_file_path: PathBuf,
+) -> AppResult<RekeyResponse> {
+    let mut vault = do_open(opened_path, file_path, key, passphrase)?;
+    let files_dir = PathBuf::from(&vault.vault_folder);
+
+    let (share_config, reissued, revoked) = match remove_members(&vault.share_config, revoke) {
+        Ok(removed) => removed,
+        Err(e) => {
+            // Don't leave the opened vault behind.
+            let _ = remove_dir_all(&files_dir);
+            return Err(e);
+        }
+    };
+
+    // Without keys, create makes a fresh main key and pieces, as for a new vault.
+    vault.share_config = share_config;
+    vault.keys = None;
+    vault.cloud_creds = None;
+    let created = do_create(versions_path, vault, files_dir.clone(), output_file_path);
+    if created.is_err() {
+        let _ = remove_dir_all(&files_dir);
+    }
+
+    return Ok(RekeyResponse {
+        vault: created?,
+        reissued,
+        revoked,
+    });
+}
+
+// The share configuration without the revoked members, along with who keeps access and who was revoked.
+fn remove_members(
+    config: &ShareConfiguration,
+    revoke: &[MemberPosition],
+) -> AppResult<(ShareConfiguration, Vec<PieceHolder>, Vec<PieceHolder>)> {
+    for position in revoke.iter() {
+        let exists = config
+            .circles
+            .get(position.circle)
+            .is_some_and(|circle| position.member < circle.key_comments.len());
+        if !exists {
+            return Err(make_error(
+                "bad_member",
+                "A member to revoke is not in this vault. Load the vault again, and choose the members again.",
+            ));
+        }
+    }
+
+    let mut reissued: Vec<PieceHolder> = Vec::new();
+    let mut revoked: Vec<PieceHolder> = Vec::new();
+    let mut circles: Vec<Circle> = Vec::new();
+    for (circle_index, circle) in config.circles.iter().enumerate() {
+        let mut kept = Circle {
+            key_comments: Vec::new(),
+            keys: None,
+            weights: Vec::new(),
+            recipients: Vec::new(),
+            sealed_keys: None,
+            ..circle.clone()
+        };
+        for (member, comment) in circle.key_comments.iter().enumerate() {
+            let holder = PieceHolder {
+                circle: circle.name.clone(),
+                member: comment.clone(),
+            };
+            let position = MemberPosition {
+                circle: circle_index,
+                member,
+            };
+            if revoke.contains(&position) {
+                revoked.push(holder);
+                continue;
+            }
+            kept.key_comments.push(comment.clone());
+            kept.weights.push(circle.weight(member));
+            kept.recipients
+                .push(circle.recipient(member).unwrap_or_default().to_string());
+            reissued.push(holder);
+        }
+        circles.push(kept);
+    }
+
+    let config = ShareConfiguration {
+        required: config.required,
+        circles,
+    };
+    return Ok((config, reissued, revoked));
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+    use std::{env, fs};
+
+    use crate::commands::create::do_create;
+    use crate::commands::create::test::get_vault;
+    use crate::commands::rekey::{do_rekey, MemberPosition, PieceHolder};
+    use crate::commands::unlock::do_unlock;
+    use crate::crypto::KeyPiece;
+    use crate::lineage::test::temp_versions_path;
+    use crate::util::get_random_file_name;
+    use crate::vault::KeyCollection;
+
+    fn pieces(keys: &KeyCollection) -> Vec<KeyPiece> {
+        return keys
+            .share_keys
+            .iter()
+            .flat_map(|circle| circle.keys.clone().unwrap())
+            .collect();
+    }
+
+    #[test]
+    fn revoked_members() {
+        let versions = temp_versions_path();
+        let mut files = env::temp_dir();
+        files.push(get_random_file_name().unwrap() + "files");
+        fs::create_dir_all(&files).unwrap();
+        fs::write(files.join("letter.txt"), "To my family").unwrap();
+        let mut output_file = env::temp_dir();
+        output_file.push(get_random_file_name().unwrap());
+
+        let vault = get_vault();
+        let old = do_create(&versions, vault, files, output_file).expect("Failed to create");
+        let old_pieces = pieces(&old.keys);
+
+        let mut opened = env::temp_dir();
+        opened.push(get_random_file_name().unwrap() + "opened");
+        let mut new_file = env::temp_dir();
+        new_file.push(get_random_file_name().unwrap());
+        let revoke = [MemberPosition {
+            circle: 0,
+            member: 1,
+        }];
+        let rekeyed = do_rekey(
+            &versions,
+            opened.clone(),
+            old.path.clone(),
+            old.keys.main.to_vec(),
+            None,
+            &revoke,
+            new_file,
+        )
+        .expect("Failed to rekey");
+
+        assert_eq!(
+            rekeyed.revoked,
+            Vec::from([PieceHolder {
+                circle: "Friends".to_string(),
+                member: "b".to_string(),
+            }])
+        );
+        let reissued: Vec<&str> = rekeyed.reissued.iter().map(|h| h.member.as_str()).collect();
+        assert_eq!(reissued, Vec::from(["a", "c", "d", "e"]));
+        let new_keys = &rekeyed.vault.keys;
+        assert_ne!(new_keys.main, old.keys.main);
+        assert_eq!(
+            new_keys.share_keys[0].key_comments,
+            Vec::from(["a", "c", "d", "e"])
+        );
+
+        // The new pieces unlock the new vault, with the contents carried over.
+        let unlocked = do_unlock(
+            &versions,
+            rekeyed.vault.path.clone(),
+            pieces(new_keys)[..3].to_vec(),
+            Some(".".to_string()),
+            None,
+        )
+        .expect("Failed to unlock");
+        let letter = fs::read_to_string(PathBuf::from(&unlocked.path).join("letter.txt"));
+        assert_eq!(letter.unwrap(), "To my family");
+        fs::remove_dir_all(unlocked.path).unwrap();
+
+        // Old pieces, including the revoked one, don't.
+        let err = do_unlock(
+            &versions,
+            rekeyed.vault.path.clone(),
+            old_pieces[..3].to_vec(),
+            Some(".".to_string()),
+            None,
+        )
+        .expect_err("Unlocked with old pieces");
+        assert_eq!(err.error_type, "piece_foreign");
+
+        // Nor do they unlock the old vault, as it is out of date.
+        let err = do_unlock(
+            &versions,
+            old.path.clone(),
+            old_pieces[..3].to_vec(),
+            Some(".".to_string()),
+            None,
+        )
+        .expect_err("Unlocked the old version");
+        assert_eq!(err.error_type, "old_version");
+
+        // A member who isn't in the vault.
+        let mut other_file = env::temp_dir();
+        other_file.push(get_random_file_name().unwrap());
+        let missing = [MemberPosition {
+            circle: 0,
+            member: 9,
+        }];
+        let err = do_rekey(
+            &versions,
+            opened,
+            rekeyed.vault.path.clone(),
+            new_keys.main.to_vec(),
+            None,
+            &missing,
+            other_file,
+        )
+        .expect_err("Revoked a missing member");
+        assert_eq!(err.error_type, "bad_member");
+
+        fs::remove_file(old.path).unwrap();
+        fs::remove_file(rekeyed.vault.path).unwrap();
+        let _ = fs::remove_file(versions);
+    }
+}
@@ -0,0 +1,132 @@
+use std::fs;
+use std::path::PathBuf;
+
+use crate::crypto::KeyPiece;
+use crate::error::{make_error, AppResult};
+use crate::piece::{is_protected_piece, qr_payload_to_piece, unwrap_piece};
+use crate::sheet::render_sheet;
+use crate::vault::{Circle, PublicInfo};
+
+/// Writes a printable recovery sheet for every key piece into a folder, and returns the paths of the sheets.
+/// Takes the public info from load_meta and the circles with their keys, as made by create.
+#[tauri::command]
+pub fn recovery_sheets(
+    info: PublicInfo,
+    circles: Vec<Circle>,
+    folder: String,
+) -> AppResult<Vec<String>> {
+    let folder = PathBuf::from(folder);
+    if !folder.is_dir() {
+        return Err(make_error(
+            "fs",
+            "The folder for the recovery sheets does not exist.",
+        ));
+    }
+
+    let mut paths: Vec<String> = Vec::new();
+    // Pieces are numbered from 1 across all circles, as when they are shared.
+    let mut number = 1;
+    for circle in circles.iter() {
+        for (member, piece) in circle.keys.iter().flatten().enumerate() {
+            let sheet = render_sheet(&info, circle, member, number, piece)?;
+            let comment = circle.key_comments.get(member).cloned().unwrap_or_default();
+            let mut path = folder.clone();
+            path.push(sheet_file_name(number, &comment));
+            fs::write(&path, sheet)?;
+
+            paths.push(path.to_string_lossy().to_string());
+            number += 1;
+        }
+    }
+    return Ok(paths);
+}
+
+/// Reads the key piece from a QR code scanned off a recovery sheet, checking it is intact.
+/// Pieces protected by a passphrase can only be checked once it is entered.
+#[tauri::command]
+pub fn read_qr_piece(payload: String) -> AppResult<KeyPiece> {
+    let piece = qr_payload_to_piece(&payload, 1)?;
+    if !is_protected_piece(&piece) {
+        unwrap_piece(&piece, 1)?;
+    }
+    return Ok(piece);
+}
+
+// Comments can contain anything, so only keep the characters which are safe in a file name.
+fn sheet_file_name(number: usize, comment: &str) -> String {
+    let safe: String = comment
+        .chars()
+        .map(|c| if c.is_alphanumeric() { c } else { '-' })
+        .take(40)
+        .collect();
+    let safe = safe.trim_matches('-');
+    if safe.is_empty() {
+        return format!("key-piece-{}.html", number);
+    }
+    return format!("key-piece-{}-{}.html", number, safe);
+}
+
+#[cfg(test)]
+mod test {
+    use std::{env, fs};
+
+    use crate::commands::sheets::{read_qr_piece, recovery_sheets, sheet_file_name};
+    use crate::crypto::tests::{get_basic_combo, get_public_info};
+    use crate::crypto::{generate_circle_keys, generate_key};
+    use crate::piece::piece_to_qr_payload;
+    use crate::util::get_random_file_name;
+    use crate::vault::PublicInfo;
+
+    #[test]
+    fn sheets_written() {
+        let (circles, commitments, _coefficients) =
+            generate_circle_keys(&generate_key(), &[], get_basic_combo(), 3)
+                .expect("Failed to make keys");
+        let info = PublicInfo {
+            commitments,
+            ..get_public_info(circles.clone(), 3)
+        };
+
+        let mut folder = env::temp_dir();
+        folder.push(get_random_file_name().unwrap() + "sheets");
+        fs::create_dir_all(&folder).unwrap();
+        let paths = recovery_sheets(
+            info.clone(),
+            circles.clone(),
+            folder.to_string_lossy().to_string(),
+        )
+        .expect("Failed to write sheets");
+
+        let pieces: Vec<_> = circles
+            .iter()
+            .flat_map(|c| c.keys.clone().unwrap())
+            .collect();
+        assert_eq!(paths.len(), pieces.len());
+        assert!(paths[0].ends_with("key-piece-1-a.html"));
+        for path in paths.iter() {
+            let sheet = fs::read_to_string(path).unwrap();
+            assert!(sheet.contains("<svg"));
+        }
+
+        // What a scanner reads back off each sheet.
+        for piece in pieces.iter() {
+            let payload = piece_to_qr_payload(piece);
+            assert_eq!(&read_qr_piece(payload).expect("Failed to read code"), piece);
+        }
+        let err =
+            read_qr_piece(piece_to_qr_payload(&pieces[0][..20])).expect_err("Read a cut code");
+        assert_eq!(err.error_type, "piece_checksum");
+
+        let err = recovery_sheets(info, circles, "/does/not/exist".to_string())
+            .expect_err("Wrote to a missing folder");
+        assert_eq!(err.error_type, "fs");
+        fs::remove_dir_all(folder).unwrap();
+    }
+
+    #[test]
+    fn file_names() {
+        assert_eq!(sheet_file_name(3, "Aunt May"), "key-piece-3-Aunt-May.html");
+        assert_eq!(sheet_file_name(1, "../../etc"), "key-piece-1-etc.html");
+        assert_eq!(sheet_file_name(2, ""), "key-piece-2.html");
+    }
+}
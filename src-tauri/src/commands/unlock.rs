@@ -1,45 +1,90 @@
-use std::fs::{create_dir_all, File};
-use std::io::{BufReader, Cursor, Read};
+use std::fs::{create_dir_all, remove_dir_all, File};
+use std::io::{BufReader, Read};
 use std::path::{Path, PathBuf};
 
+use serde::Serialize;
 use tar::Archive;
 use tauri::api::path::download_dir;
 
-use crate::crypto::{combine_keys, decrypt_file, EncryptedFile, KeyPiece};
-use crate::error::{app_error, make_error, AppResult};
-use crate::meta::decode_meta;
-use crate::util::read_meta_file;
+use crate::crypto::{
+    combine_keys, combine_pieces, derive_key_id, derive_lookup_token, legacy_lookup_token,
+    DecryptReader, KeyPiece,
+};
+use crate::error::{app_error, make_error, AppError, AppResult};
+use crate::lineage::{check_signed, check_version};
+use crate::meta::skip_owner_state;
+use crate::piece::check_piece_owners;
+use crate::recipient::open_sealed_pieces;
+use crate::signature::{verify_vault_start, HashReader, VerifiedVault};
+use crate::util::{get_versions_path, read_meta_file};
+
+/// An unlocked vault.
+#[derive(Serialize, Debug)]
+pub struct UnlockResult {
+    pub(crate) path: String,
+    // Pieces which were left out because they were damaged or did not fit with the others.
+    // The vault was still unlocked, but their owners should check them.
+    pub(crate) bad_pieces: Vec<AppError>,
+    // The fingerprint of the owner who signed the vault. Empty for vaults made before vaults were signed.
+    pub(crate) signer: Vec<u8>,
+}
 
 /// Unlock a vault using keypieces
-/// Takes the path to a vault file and a vector of key pieces
-/// Returns the path to the unpacked data
+/// Takes the path to a vault file and a vector of key pieces, and the recipients' identity files for any sealed pieces
+/// Returns the path to the unpacked data, and any key pieces which had to be left out
 #[tauri::command]
 pub fn unlock(
+    app_handle: tauri::AppHandle,
     file_path: String,
     keys: Vec<KeyPiece>,
     save_path: Option<String>,
-) -> AppResult<String> {
-    // Combine keys
-
-    let secret_key = combine_keys(keys)?;
-    // Load file
-    let mut reader = BufReader::new(File::open(&file_path)?);
-
-    // Decrypt
-    let (raw_meta, meta) = decode_meta(&mut reader)?;
+    identities: Option<String>,
+) -> AppResult<UnlockResult> {
+    let versions_path = get_versions_path(&app_handle)?;
+    return do_unlock(&versions_path, file_path, keys, save_path, identities);
+}
 
-    let mut buff = Vec::new();
-    reader.read_to_end(&mut buff)?;
+/// Does all the actual command functionality, given the versions file (see lineage.rs).
+/// Seperated so that app_handle is not used, so it is really easy to test.
+pub(crate) fn do_unlock(
+    versions_path: &Path,
+    file_path: String,
+    keys: Vec<KeyPiece>,
+    save_path: Option<String>,
+    identities: Option<String>,
+) -> AppResult<UnlockResult> {
+    // Check who signed the vault before any key pieces are combined or anything is decrypted.
+    let mut file = BufReader::new(File::open(&file_path)?);
+    let verified = verify_vault_start(&mut file)?;
+    let header = &verified.header;
+    let signer = verified.signer.clone().unwrap_or_default();
 
-    let file = EncryptedFile {
-        ciphertext: buff,
-        nonce: meta.nonce,
-        aad: raw_meta,
-    };
+    // Combine keys, leaving out any which are not for this vault or don't fit with the rest.
+    let keys = open_sealed_pieces(&keys, identities.as_deref())?;
+    // Pieces which name their owner are only used for a vault that owner signed.
+    check_piece_owners(&keys, &header.info, &signer)?;
+    let combined = combine_pieces(&keys, Some(&header.info))?;
+    let content_key = header.content_key(&combined.key);
+    header.check_content_key(&content_key)?;
+    // A copy of a vault seen signed before must still be signed.
+    check_signed(
+        versions_path,
+        &derive_key_id(&combined.key),
+        !signer.is_empty(),
+    )?;
+    // An old copy of the vault is refused, as when it is opened.
+    check_version(versions_path, &header.info.lineage)?;
 
-    let decrypted = decrypt_file(&secret_key, file).map_err(|_e| make_error("crypto", "Decryption failed. Did you provide all of the required keys, and are they definitely for this vault? The combined key was not correct."))?;
+    // Decrypt
+    // The vault is read again from the file which was checked, and must not have changed since.
+    let mut reader = verified.reread(file)?;
+    // The owner state is sealed under the main key, so it is of no use here.
+    if header.has_owner_state() {
+        skip_owner_state(&mut reader)?;
+    }
 
-    let mut tar = Archive::new(Cursor::new(decrypted));
+    let decryptor = header.decryptor(&content_key, reader)?;
+    let tar = Archive::new(decryptor);
 
     // Either save to downloads or specified folder
     let mut data_dir: PathBuf = if save_path.is_some() {
@@ -51,61 +96,99 @@ pub fn unlock(
     let current_path = Path::new(&file_path);
     data_dir.push(current_path.file_stem().unwrap());
     // make sure data dir it exists
+    // A folder made here is removed again if unlocking fails, so a half-unpacked vault is never left behind.
+    // A folder which was already there is left alone, as it may hold other files.
+    let created = !data_dir.exists();
     create_dir_all(&data_dir)?;
 
-    tar.unpack(&data_dir)?;
-
-    // Read meta file w/ full vault info and delete it
-    let _meta = read_meta_file(&data_dir)?;
+    let unpacked = unpack(tar, &data_dir, &verified);
+    if unpacked.is_err() && created {
+        let _ = remove_dir_all(&data_dir);
+    }
+    unpacked?;
 
     // Return vault info (incl. path)
 
-    return Ok(String::from(data_dir.to_str().unwrap()));
+    return Ok(UnlockResult {
+        path: String::from(data_dir.to_str().unwrap()),
+        bad_pieces: combined.bad_pieces,
+        signer,
+    });
+}
+
+// Unpacks the vault into the folder, checking the whole stream was authentic and was the file which was checked.
+fn unpack<R: Read>(
+    mut tar: Archive<DecryptReader<HashReader<R>>>,
+    data_dir: &Path,
+    verified: &VerifiedVault,
+) -> AppResult<()> {
+    let unpacked = tar.unpack(data_dir);
+    // Always check the whole stream was authentic, as tar may not read up to the final chunk.
+    let reader = tar.into_inner().finish().map_err(|_e| make_error("crypto", "Decryption failed. Did you provide all of the required keys, and are they definitely for this vault? The combined key was not correct."))?;
+    verified.check_unchanged(reader)?;
+    unpacked?;
+
+    // Read meta file w/ full vault info and delete it
+    let _meta = read_meta_file(&data_dir.to_path_buf())?;
+    return Ok(());
+}
+
+/// Token to find a cloud vault with.
+#[derive(Serialize, Debug)]
+pub struct CloudTokens {
+    pub(crate) token: Vec<u8>,
 }
 
 #[tauri::command]
-pub fn unlock_cloud(keys: Vec<KeyPiece>) -> AppResult<[u8; 8]> {
+pub fn unlock_cloud(
+    keys: Vec<KeyPiece>,
+    identities: Option<String>,
+    legacy: bool,
+) -> AppResult<CloudTokens> {
     // Combine key-shares
-    // Return ID,Token,key. (CloudKey Data)
-    // Frontend then requests it using id + token, then passes to open using vault file and key.
-    let secret_key = combine_keys(keys)?;
+    // Return the lookup token derived from the combined secret.
+    // Frontend then requests it using the token, then passes to unlock using vault file and key pieces.
+    let secret_key = combine_keys(open_sealed_pieces(&keys, identities.as_deref())?)?;
 
-    return Ok(secret_key[0..8].try_into()?);
+    // Vaults uploaded before format version 2 are known to the server by the legacy token instead.
+    // It contains bytes of the recipient secret, so it is only given out once the server has said
+    // it has no vault for the normal token.
+    let token = if legacy {
+        legacy_lookup_token(&secret_key)
+    } else {
+        derive_lookup_token(&secret_key)
+    };
+    return Ok(CloudTokens { token });
 }
 
 #[cfg(test)]
 mod test {
+    use std::fs::File;
+    use std::io::BufReader;
+    use std::path::Path;
     use std::{env, fs};
 
+    use tar::Archive;
+
+    use crate::commands::armor::armor_piece;
     use crate::commands::create::do_create;
-    use crate::commands::unlock::unlock;
-    use crate::crypto::tests::get_basic_combo;
+    use crate::commands::create::test::get_vault;
+    use crate::commands::loadmeta::read_meta;
+    use crate::commands::unlock::{do_unlock, unlock_cloud};
+    use crate::constants::META_FILE_NAME;
+    use crate::crypto::{combine_keys, DecryptReader, KeyPiece};
+    use crate::lineage::test::temp_versions_path;
+    use crate::meta::{decode_meta, skip_owner_state};
+    use crate::piece::unwrap_piece;
     use crate::util::get_random_file_name;
-    use crate::vault::{PersonalInfo, ShareConfiguration, Vault, VaultType};
+    use crate::vault::{Vault, VaultType};
 
     // Very simple. Empty vault!
     #[test]
     fn simple_unlock() {
+        let versions = temp_versions_path();
         // CREATE
-        let vault_info_in = Vault {
-            vault_type: VaultType::Offline,
-            personal_info: PersonalInfo {
-                name: "Test".to_string(),
-                email_address: "test@example.com".to_string(),
-                full_legal_name: None,
-                phone_number: None,
-                guidance_doc: None,
-                address: None,
-            },
-            share_config: ShareConfiguration {
-                required: 3,
-                circles: get_basic_combo(),
-            },
-            vault_folder: "/foo/bar".to_string(),
-            alert_duration: 0,
-            reminder_period: 0,
-            keys: None,
-        };
+        let vault_info_in = get_vault();
 
         let mut empty_folder = env::temp_dir();
         empty_folder.push(get_random_file_name().unwrap() + "empty");
@@ -114,7 +197,7 @@ mod test {
         let mut output_file = env::temp_dir();
         output_file.push(get_random_file_name().expect("Failed to get output file"));
 
-        let sample_vault_result = do_create(vault_info_in, empty_folder, output_file);
+        let sample_vault_result = do_create(&versions, vault_info_in, empty_folder, output_file);
 
         let res = sample_vault_result.expect("Could not create vault to test unlock");
 
@@ -129,10 +212,221 @@ mod test {
         }
 
         // Actual test - open it using the main key.
-        let open_result =
-            unlock(res.path, keys_only, Some(".".to_string())).expect("Failed to open");
-        assert_ne!(open_result, path);
+        let open_result = do_unlock(
+            &versions,
+            res.path.clone(),
+            keys_only.clone(),
+            Some(".".to_string()),
+            None,
+        )
+        .expect("Failed to open");
+        assert_ne!(open_result.path, path);
+        assert!(open_result.bad_pieces.is_empty());
+        // The pieces name the owner who signed the vault.
+        let (piece_info, _raw) = unwrap_piece(&keys_only[0], 1).expect("Failed to unwrap");
+        assert_eq!(piece_info.unwrap().owner_fingerprint, open_result.signer);
+        fs::remove_dir_all(open_result.path).unwrap();
+
+        // Pieces saved as armored text.
+        let info = read_meta(path.clone()).expect("Failed to load meta");
+        let armored: Vec<Vec<u8>> = keys_only
+            .iter()
+            .map(|key| {
+                armor_piece(info.clone(), key.clone(), false)
+                    .expect("Failed to armor")
+                    .into_bytes()
+            })
+            .collect();
+        let open_result = do_unlock(
+            &versions,
+            res.path.clone(),
+            armored.clone(),
+            Some(".".to_string()),
+            None,
+        )
+        .expect("Failed to open with armored pieces");
+        assert!(open_result.bad_pieces.is_empty());
+        fs::remove_dir_all(open_result.path).unwrap();
+        assert!(unlock_cloud(armored, None, false).is_ok());
+
+        // A mistyped piece is left out, as there are enough without it.
+        let last = keys_only[1].len() - 1;
+        keys_only[1][last] ^= 1;
+        let open_result = do_unlock(&versions, res.path, keys_only, Some(".".to_string()), None)
+            .expect("Failed to open");
+        assert_eq!(open_result.bad_pieces.len(), 1);
+        assert_eq!(open_result.bad_pieces[0].error_type, "piece_checksum");
+        assert!(open_result.bad_pieces[0]
+            .message
+            .starts_with("Key piece 2 "));
+
+        fs::remove_dir_all(open_result.path).unwrap();
+        let _ = fs::remove_file(versions);
+    }
+
+    // Recipients must not be able to see anybody else's key pieces.
+    #[test]
+    fn archive_has_no_keys() {
+        let versions = temp_versions_path();
+        let vault_info_in = Vault {
+            vault_type: VaultType::Cloud,
+            ..get_vault()
+        };
+
+        let mut empty_folder = env::temp_dir();
+        empty_folder.push(get_random_file_name().unwrap() + "empty");
+        fs::create_dir_all(&empty_folder).expect("Failed to create empty folder for testing");
+
+        let mut output_file = env::temp_dir();
+        output_file.push(get_random_file_name().expect("Failed to get output file"));
+
+        let res = do_create(&versions, vault_info_in, empty_folder, output_file)
+            .expect("Failed to create");
+
+        let mut keys_only: Vec<Vec<u8>> = Vec::new();
+        for c in res.keys.share_keys {
+            if let Some(keys) = c.keys {
+                keys_only.extend(keys);
+            }
+        }
+        let secret_key = combine_keys(keys_only).expect("Failed to combine keys");
+
+        // Decrypt the archive as a recipient would, and check the meta file inside it.
+        let mut reader = BufReader::new(File::open(&res.path).unwrap());
+        let header = decode_meta(&mut reader).expect("Failed to read header");
+        skip_owner_state(&mut reader).expect("Failed to skip owner state");
+        let decryptor = DecryptReader::new(
+            header.cipher,
+            &header.content_key(&secret_key),
+            &header.info.nonce,
+            &header.raw,
+            reader,
+        )
+        .unwrap();
+        let mut tar = Archive::new(decryptor);
+
+        let mut meta: Option<Vault> = None;
+        for entry in tar.entries().unwrap() {
+            let entry = entry.unwrap();
+            if entry.path().unwrap().ends_with(META_FILE_NAME) {
+                meta = Some(rmp_serde::from_read(entry).expect("Failed to read meta"));
+            }
+        }
+        let meta = meta.expect("Meta file missing from archive");
+        assert!(meta.keys.is_none(), "Key pieces were put in the archive");
+        assert!(
+            meta.cloud_creds.is_none(),
+            "Cloud credentials were put in the archive"
+        );
+        tar.into_inner()
+            .finish()
+            .expect("Archive was not authentic");
+
+        fs::remove_file(res.path).unwrap();
+        let _ = fs::remove_file(versions);
+    }
+
+    // Recipients find a cloud vault with the same token that was uploaded with it.
+    #[test]
+    fn cloud_tokens_match() {
+        let versions = temp_versions_path();
+        let vault_info_in = Vault {
+            vault_type: VaultType::Cloud,
+            ..get_vault()
+        };
+
+        let mut empty_folder = env::temp_dir();
+        empty_folder.push(get_random_file_name().unwrap() + "empty");
+        fs::create_dir_all(&empty_folder).expect("Failed to create empty folder for testing");
+
+        let mut output_file = env::temp_dir();
+        output_file.push(get_random_file_name().expect("Failed to get output file"));
+
+        let res = do_create(&versions, vault_info_in, empty_folder, output_file)
+            .expect("Failed to create");
+        let creds = res.cloud_keys.expect("Expected cloud credentials");
+
+        let mut keys_only: Vec<Vec<u8>> = Vec::new();
+        for c in res.keys.share_keys {
+            if let Some(keys) = c.keys {
+                keys_only.extend(keys);
+            }
+        }
+        let tokens = unlock_cloud(keys_only.clone(), None, false).expect("Failed to get tokens");
+        assert_eq!(tokens.token, creds.share_token);
+        let legacy = unlock_cloud(keys_only, None, true).expect("Failed to get legacy token");
+        assert_ne!(legacy.token, creds.share_token);
+
+        fs::remove_file(res.path).unwrap();
+        let _ = fs::remove_file(versions);
+    }
+
+    // Vaults made by older versions of the app still unlock with their pieces.
+    #[test]
+    fn unlocks_older_vaults() {
+        let versions = temp_versions_path();
+        let fixtures = Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures");
+        let pieces: Vec<KeyPiece> =
+            serde_json::from_slice(&fs::read(fixtures.join("baseline_pieces.json")).unwrap())
+                .unwrap();
+        let mut save = env::temp_dir();
+        save.push(get_random_file_name().unwrap() + "baseline");
+
+        let unlocked = do_unlock(
+            &versions,
+            fixtures
+                .join("baseline.vault")
+                .to_string_lossy()
+                .to_string(),
+            Vec::from([pieces[0].clone(), pieces[2].clone(), pieces[4].clone()]),
+            Some(save.to_string_lossy().to_string()),
+            None,
+        )
+        .expect("Failed to unlock");
+        let letter = fs::read_to_string(Path::new(&unlocked.path).join("letter.txt"));
+        assert_eq!(letter.unwrap(), "To my family");
+        assert!(unlocked.signer.is_empty());
+
+        // Version 1 vaults have no owner state to skip.
+        let version1: Vec<KeyPiece> =
+            serde_json::from_slice(&fs::read(fixtures.join("version1_pieces.json")).unwrap())
+                .unwrap();
+        let unlocked = do_unlock(
+            &versions,
+            fixtures
+                .join("version1.vault")
+                .to_string_lossy()
+                .to_string(),
+            Vec::from([
+                version1[1].clone(),
+                version1[3].clone(),
+                version1[4].clone(),
+            ]),
+            Some(save.to_string_lossy().to_string()),
+            None,
+        )
+        .expect("Failed to unlock version 1");
+        let letter = fs::read_to_string(Path::new(&unlocked.path).join("letter.txt"));
+        assert_eq!(letter.unwrap(), "To my family");
+
+        // A vault which can't be decrypted leaves nothing behind.
+        let mut truncated = env::temp_dir();
+        truncated.push(get_random_file_name().unwrap());
+        let file = fs::read(fixtures.join("baseline.vault")).unwrap();
+        fs::write(&truncated, &file[..file.len() - 10]).unwrap();
+        let err = do_unlock(
+            &versions,
+            truncated.to_string_lossy().to_string(),
+            Vec::from([pieces[0].clone(), pieces[2].clone(), pieces[4].clone()]),
+            Some(save.to_string_lossy().to_string()),
+            None,
+        )
+        .expect_err("Unlocked a truncated vault");
+        assert_eq!(err.error_type, "crypto");
+        assert!(!save.join(truncated.file_name().unwrap()).exists());
 
-        fs::remove_dir_all(open_result).unwrap();
+        fs::remove_file(truncated).unwrap();
+        fs::remove_dir_all(save).unwrap();
+        let _ = fs::remove_file(versions);
     }
 }
@@ -0,0 +1,139 @@
+use serde::Serialize;
+
+use crate::crypto::KeyPiece;
+use crate::error::{make_error, AppResult};
+use crate::piece::check_piece;
+use crate::vault::PublicInfo;
+
+/// Who a checked key piece belongs to.
+#[derive(Serialize, Debug)]
+pub struct VerifiedPiece {
+    pub(crate) circle: String,
+    pub(crate) member: String,
+}
+
+/// Checks a single key piece against the share commitments in the public info from load_meta.
+/// No other pieces are needed, so a damaged or swapped piece can be caught when it is handed out, not years later
+/// when the vault is unlocked.
+#[tauri::command]
+pub fn verify_piece(info: PublicInfo, key: KeyPiece) -> AppResult<VerifiedPiece> {
+    if info.commitments.individual.is_empty() {
+        return Err(make_error(
+            "piece_unverifiable",
+            "This vault was made before key pieces could be checked. Updating the vault will make new key pieces which can be.",
+        ));
+    }
+
+    let (piece_info, _raw) = check_piece(&key, 1, &info)?;
+    let piece_info = piece_info.ok_or(make_error(
+        "piece_unverifiable",
+        "This key piece does not say which circle it is for, so it can't be checked.",
+    ))?;
+
+    let circle = &info.share_config.circles[usize::from(piece_info.circle)];
+    return Ok(VerifiedPiece {
+        circle: circle.name.clone(),
+        member: circle.key_comments[usize::from(piece_info.member)].clone(),
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use crate::commands::verify::verify_piece;
+    use crate::crypto::tests::get_public_info;
+    use crate::crypto::{generate_circle_keys, generate_key, KeyPiece, DERIVED_SHARE_LEN};
+    use crate::piece::{unwrap_piece, wrap_piece};
+    use crate::vault::{Circle, PublicInfo, ShareCommitments};
+
+    // 2 of 3 siblings, and any 3 keys in total.
+    fn get_info() -> PublicInfo {
+        let circles = Vec::from([
+            Circle {
+                required: true,
+                key_comments: Vec::from(["a".to_string(), "b".to_string(), "c".to_string()]),
+                name: "Siblings".to_string(),
+                keys: None,
+                threshold: 2,
+                weights: Vec::new(),
+                recipients: Vec::new(),
+                sealed_keys: None,
+            },
+            Circle {
+                required: false,
+                key_comments: Vec::from(["d".to_string(), "e".to_string()]),
+                name: "Friends".to_string(),
+                keys: None,
+                threshold: 1,
+                weights: Vec::from([2]),
+                recipients: Vec::new(),
+                sealed_keys: None,
+            },
+        ]);
+        return get_public_info(circles, 3);
+    }
+
+    fn get_keys(info: &mut PublicInfo) -> Vec<Vec<KeyPiece>> {
+        let (circles, commitments, _coefficients) = generate_circle_keys(
+            &generate_key(),
+            &[],
+            info.share_config.circles.clone(),
+            info.share_config.required,
+        )
+        .expect("Did not receive circles with keys.");
+        let keys: Vec<Vec<KeyPiece>> = circles
+            .into_iter()
+            .map(|c| c.keys.expect("Keys not set"))
+            .collect();
+        info.key_id = unwrap_piece(&keys[0][0], 1).unwrap().0.unwrap().key_id;
+        info.commitments = commitments;
+        return keys;
+    }
+
+    #[test]
+    fn good_pieces() {
+        let mut info = get_info();
+        let keys = get_keys(&mut info);
+
+        let verified = verify_piece(info.clone(), keys[0][2].clone()).expect("Rejected a piece");
+        assert_eq!(verified.circle, "Siblings");
+        assert_eq!(verified.member, "c");
+        let verified = verify_piece(info, keys[1][0].clone()).expect("Rejected a piece");
+        assert_eq!(verified.circle, "Friends");
+        assert_eq!(verified.member, "d");
+    }
+
+    #[test]
+    fn bad_pieces() {
+        let mut info = get_info();
+        let keys = get_keys(&mut info);
+        let mut other_info = get_info();
+        let other_keys = get_keys(&mut other_info);
+
+        // A piece from another vault with the same key id, as if someone had made their own pieces.
+        let mut forged_info = info.clone();
+        forged_info.commitments = other_info.commitments.clone();
+        let err =
+            verify_piece(forged_info, keys[0][0].clone()).expect_err("Accepted a forged piece");
+        assert_eq!(err.error_type, "piece_invalid");
+
+        let err = verify_piece(info.clone(), other_keys[0][0].clone())
+            .expect_err("Accepted a piece for another vault");
+        assert_eq!(err.error_type, "piece_foreign");
+
+        // A sibling's piece with the group share taken off.
+        let (piece_info, raw) = unwrap_piece(&keys[0][1], 1).unwrap();
+        let cut = wrap_piece(
+            &piece_info.unwrap(),
+            &raw[..raw.len() - 1 - DERIVED_SHARE_LEN],
+        );
+        let err = verify_piece(info.clone(), cut).expect_err("Accepted a cut piece");
+        assert_eq!(err.error_type, "piece_invalid");
+
+        // Vaults made before pieces could be checked.
+        let mut old_info = info.clone();
+        old_info.commitments = ShareCommitments::default();
+        let err =
+            verify_piece(old_info, keys[0][0].clone()).expect_err("Checked without commitments");
+        assert_eq!(err.error_type, "piece_unverifiable");
+    }
+}
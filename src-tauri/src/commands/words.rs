@@ -0,0 +1,9 @@
+use crate::crypto::{piece_to_mnemonic, KeyPiece};
+use crate::error::AppResult;
+
+/// Writes a key piece as words, for recipients who would rather write their piece on paper.
+/// The words can be entered in place of the piece when unlocking.
+#[tauri::command]
+pub fn piece_words(key: KeyPiece) -> AppResult<String> {
+    return piece_to_mnemonic(&key);
+}
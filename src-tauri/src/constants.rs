@@ -2,3 +2,9 @@
 pub const META_FILE_NAME: &str = "META_DATA";
 pub const PRIVACY_FILE_NAME: &str = "privacy_notice.txt";
 pub const OPENED_VAULT_FOLDER: &str = "open";
+// Previous versions of updated vaults are kept here, next to the new vault files.
+pub const BACKUP_FOLDER: &str = "backups";
+// The latest version of each vault seen on this device, and which vaults have been seen signed.
+pub const VERSIONS_FILE_NAME: &str = "versions";
+// Added to the name of a file while it is being written. It only gets its real name once it is complete.
+pub const PARTIAL_FILE_SUFFIX: &str = ".partial";
@@ -2,24 +2,63 @@
    crypto.rs
    This file provides encryption functionality, including Shamir's secret sharing and authenticated encryption.
    This is done using two libraries: shamirsecretsharing and chacha20poly1305.
+   Key pieces are split with Feldman verifiable secret sharing (see vss.rs), so each piece can be checked against
+   commitments in the vault header. Pieces made before this, with shamirsecretsharing, can still be combined.
+   The main key is first split into parts which are all needed, one per required circle and one for everyone (Z).
+   This was done with shamirsecretsharing, which limited vaults to 255 key shares. Each part is now derived from a
+   random scalar shared with vss.rs, which allows up to 65535 shares and thresholds. The commitments are public, and
+   only hide whole scalars, so the parts are never shared themselves. A mask in every piece XORs with the parts to
+   give the main key. Pieces made before this shared the parts directly, and can still be combined.
+   The coefficients of the polynomials are kept with the owner's keys, so members can be added to circles later
+   without changing anyone else's piece.
 
    This is so that we get some useful security properties, namely confidentiality and message integrity.
 
+   Vault contents are encrypted as a STREAM (https://eprint.iacr.org/2015/189.pdf) of fixed size chunks, so that
+   vaults larger than memory can be created and opened. Each chunk has its own nonce made from a random prefix,
+   a counter and a final chunk flag, so reordered, dropped or truncated chunks cause decryption to fail.
+   The cipher is recorded in the vault header. New vaults use XChaCha20Poly1305, whose longer nonce prefix stays safe
+   however many times a vault is updated with the same main key. AES-256-GCM-SIV can be chosen instead, which also
+   stays safe if a nonce is ever repeated. ChaCha20Poly1305 is still used for small sealed messages, and is read for
+   vaults made before the cipher could be chosen.
+
 */
 extern crate chacha20poly1305;
 extern crate shamirsecretsharing;
 
+use aes_gcm_siv::Aes256GcmSiv;
 use chacha20poly1305::{
-    aead::{generic_array::GenericArray, Aead, AeadCore, KeyInit, OsRng, Payload},
-    ChaCha20Poly1305, Error, Nonce,
+    aead::{
+        generic_array::GenericArray,
+        rand_core::RngCore,
+        stream::{DecryptorBE32, EncryptorBE32},
+        Aead, KeyInit, OsRng, Payload,
+    },
+    ChaCha20Poly1305, Error, XChaCha20Poly1305,
 };
+use hkdf::Hkdf;
 use serde::{Deserialize, Serialize};
-use shamirsecretsharing::hazmat::{combine_keyshares, create_keyshares, KEYSHARE_SIZE, KEY_SIZE};
-use shamirsecretsharing::{combine_shares, create_shares, DATA_SIZE, SHARE_SIZE};
-use std::collections::HashSet;
-
-use crate::error::{app_error, make_error, AppResult};
-use crate::vault::{Circle, CloudKeyData};
+use sha2::Sha256;
+use shamirsecretsharing::hazmat::{combine_keyshares, KEYSHARE_SIZE, KEY_SIZE};
+use shamirsecretsharing::{combine_shares, DATA_SIZE, SHARE_SIZE};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::{self, Read, Write};
+
+use crate::error::{app_error, make_error, AppError, AppResult};
+use crate::mnemonic::{decode_words, encode_words};
+use crate::piece::{
+    check_piece, envelope_layout, piece_error, read_member_index, unwrap_piece, wrap_piece,
+    PieceInfo, KEY_ID_LEN,
+};
+use crate::recipient::{parse_recipient, seal_piece};
+use crate::signature::owner_fingerprint;
+use crate::vault::{
+    Circle, CloudKeyData, KeyCollection, PublicInfo, ShareCoefficients, ShareCommitments,
+};
+use crate::vss::{
+    combine_random, combine_secret, make_shares, next_combination, random_share_len, share_len,
+    split_random, Coefficients, Combined, Commitments, ShareFormat,
+};
 
 // Values
 // The length of our encryption key - the one that is actually used to encrypt the file.
@@ -29,88 +68,588 @@ pub const ENCRYPTION_KEY_LEN: usize = 32;
 pub const KEYSHARE_LEN: usize = KEYSHARE_SIZE;
 // The length of a full secret KEY_LEN + padding.
 const FULL_SECRET_LEN: usize = DATA_SIZE;
-// The length of a 'full share', used for individual keys.
+// The length of a 'full share', used for individual keys in pieces made before verifiable sharing.
 const RE_SHARE_LEN: usize = SHARE_SIZE;
+// Legacy key pieces are one or more individual shares, then the group part for members of required circles.
+// Where any one member is enough, the group part is the group keyshare.
+const GROUP_KEY_PART_LEN: usize = KEYSHARE_LEN;
+// Where a circle has a threshold, it is the circle's index among the required circles, then a share of the group key.
+const CIRCLE_SHARE_PART_LEN: usize = 1 + RE_SHARE_LEN;
+
+// Verifiable key pieces start with a 0, which can't be the start of a legacy piece (share indexes start from 1).
+// verifiable marker (0) | share count (u8) | individual shares | circle index (u8) | group key share
+// The circle index and group key share are only there for members of required circles.
+const VERIFIABLE_MARKER: u8 = 0;
+// The length of a verifiable share of a keyshare.
+const VERIFIABLE_SHARE_LEN: usize = share_len(KEYSHARE_LEN, ShareFormat::Narrow);
+const VERIFIABLE_GROUP_PART_LEN: usize = 1 + VERIFIABLE_SHARE_LEN;
+// Wide key pieces are the same, but have a share count of 0 followed by the real share count (u16), and wide shares
+// of parts of the main key which XOR to it, rather than of keyshares.
+// verifiable marker (0) | 0 | share count (u16) | individual shares | circle index (u8) | group key share
+const WIDE_SHARE_LEN: usize = share_len(ENCRYPTION_KEY_LEN, ShareFormat::Wide);
+const WIDE_GROUP_PART_LEN: usize = 1 + WIDE_SHARE_LEN;
+// Derived key pieces are wide pieces with a share count of 0, followed by the real share count (u16) and the mask.
+// Their shares are of random scalars, from which the parts of the main key are derived (see derive_key_part).
+// verifiable marker (0) | 0 | 0 | 0 | share count (u16) | mask | individual shares | circle index (u8) | group share
+pub const DERIVED_SHARE_LEN: usize = random_share_len(ShareFormat::Wide);
+const DERIVED_GROUP_PART_LEN: usize = 1 + DERIVED_SHARE_LEN;
+const MASK_LEN: usize = ENCRYPTION_KEY_LEN;
+// The most key shares a vault can have.
+const MAX_SHARES: usize = u16::MAX as usize;
+
+// The amount of plaintext sealed in each chunk of a vault stream. This bounds memory use when encrypting/decrypting.
+pub const STREAM_CHUNK_LEN: usize = 64 * 1024;
+// Length of the Poly1305 tag added to every chunk.
+const TAG_LEN: usize = 16;
+// The length of a sealed chunk (the final chunk may be shorter).
+const STREAM_SEALED_CHUNK_LEN: usize = STREAM_CHUNK_LEN + TAG_LEN;
+// The random nonce prefix for a ChaCha20Poly1305 or AES-256-GCM-SIV stream. The other 5 bytes of the nonce are the
+// chunk counter and final chunk flag.
+pub const STREAM_NONCE_LEN: usize = 7;
+// The nonce of vaults made before the contents were streamed, which are one ChaCha20Poly1305 message.
+pub const LEGACY_NONCE_LEN: usize = 12;
+// The random nonce prefix for an XChaCha20Poly1305 stream, which has a 24 byte nonce.
+const EXTENDED_STREAM_NONCE_LEN: usize = 19;
+
+// Labels for keys derived from a secret. Each use of a secret gets its own label so that the derived keys are
+// independent: Knowing one (e.g. the cloud lookup token, which the server sees) reveals nothing about the others.
+// Derived from the main key. The recipient secret label predates the others, and is kept so existing key pieces work.
+const RECIPIENT_SECRET_LABEL: &[u8] = b"digital-legacies vault key";
+const OWNER_STATE_KEY_LABEL: &[u8] = b"digital-legacies owner state key";
+const OWNER_TOKEN_LABEL: &[u8] = b"digital-legacies cloud owner token";
+const SIGNING_SEED_LABEL: &[u8] = b"digital-legacies owner signing key";
+// Derived from the recipient secret.
+const CONTENT_KEY_LABEL: &[u8] = b"digital-legacies content key";
+const LOOKUP_TOKEN_LABEL: &[u8] = b"digital-legacies cloud lookup token";
+const KEY_ID_LABEL: &[u8] = b"digital-legacies key id";
+// Derived from the content key.
+const KEY_COMMITMENT_LABEL: &[u8] = b"digital-legacies key commitment";
+// Derived from the random scalars shared in key pieces.
+const KEY_PART_LABEL: &[u8] = b"digital-legacies key part";
 
 // 8 bytes
 const READ_ONLY_CLOUD_KEY_LEN: usize = 8;
 // 16 bytes
 const OWNER_CLOUD_KEY_LEN: usize = 16;
 
-pub type KeyPiece = Vec<u8>;
-pub(crate) type Key = [u8; ENCRYPTION_KEY_LEN];
+/// The cipher used to encrypt a vault's contents. Its id is stored in the vault header.
+/// Each is used as a STREAM with 64 KiB chunks.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Default)]
+pub enum Cipher {
+    ChaCha20Poly1305,
+    #[default]
+    XChaCha20Poly1305,
+    Aes256GcmSiv,
+}
+
+impl Cipher {
+    pub fn id(&self) -> u8 {
+        return match self {
+            Cipher::ChaCha20Poly1305 => 1,
+            Cipher::XChaCha20Poly1305 => 2,
+            Cipher::Aes256GcmSiv => 3,
+        };
+    }
+
+    pub fn from_id(id: u8) -> Option<Cipher> {
+        return match id {
+            1 => Some(Cipher::ChaCha20Poly1305),
+            2 => Some(Cipher::XChaCha20Poly1305),
+            3 => Some(Cipher::Aes256GcmSiv),
+            _ => None,
+        };
+    }
 
-/// Stores an encrypted message with a message authentication tag
-#[derive(Debug, Serialize, Deserialize)]
-pub struct EncryptedFile {
-    // Encrypted contents of the file itself
-    pub ciphertext: Vec<u8>,
-    pub nonce: Vec<u8>,
-    pub aad: Vec<u8>,
+    /// The length of the random nonce prefix for a stream.
+    pub fn nonce_len(&self) -> usize {
+        return match self {
+            Cipher::ChaCha20Poly1305 | Cipher::Aes256GcmSiv => STREAM_NONCE_LEN,
+            Cipher::XChaCha20Poly1305 => EXTENDED_STREAM_NONCE_LEN,
+        };
+    }
 }
 
+pub type KeyPiece = Vec<u8>;
+pub(crate) type Key = [u8; ENCRYPTION_KEY_LEN];
+
 // Generation method abstract over the use of osrng for other functions.
 /// Generates a key. Type is an abstract over a slice of bytes
 pub fn generate_key() -> Key {
     return ChaCha20Poly1305::generate_key(&mut OsRng).into();
 }
 
-/// Generates a nonce.
-pub fn generate_nonce() -> Nonce {
-    return ChaCha20Poly1305::generate_nonce(&mut OsRng);
+// Fills the output from a secret using HKDF-SHA256, with a label for domain separation.
+fn derive_bytes(secret: &Key, label: &[u8], output: &mut [u8]) {
+    Hkdf::<Sha256>::new(None, secret)
+        .expand(label, output)
+        .expect("HKDF output is a valid length");
 }
 
-/// Encrypts a file. Takes a nonce, file_contents to encrypt and meta data.
-/// The meta_data is signed as AAD, and must be provided at decryption time.
-pub fn encrypt_file(
-    key: &Key,
-    file_contents: &Vec<u8>,
-    meta_data: &[u8],
-    nonce: Nonce,
-) -> AppResult<EncryptedFile> {
-    let cipher = ChaCha20Poly1305::new(key.into());
-
-    let payload = Payload {
-        aad: meta_data,
-        msg: file_contents.as_slice(),
-    };
-    let ciphertext = cipher.encrypt(&nonce, payload)?;
+fn derive_key(secret: &Key, label: &[u8]) -> Key {
+    let mut derived: Key = [0; ENCRYPTION_KEY_LEN];
+    derive_bytes(secret, label, &mut derived);
+    return derived;
+}
 
-    return Ok(EncryptedFile {
-        ciphertext,
-        nonce: Vec::from(nonce.as_slice()),
-        aad: Vec::from(meta_data),
-    });
+/// The recipient secret is split into key pieces, so recipients rebuild it but never learn the main key.
+/// The content key and cloud lookup token are derived from it.
+pub fn derive_recipient_secret(main: &Key) -> Key {
+    return derive_key(main, RECIPIENT_SECRET_LABEL);
 }
 
-/// Decrypt a file using the provided key.
-/// The meta data (AAD) must match the AAD provided at encryption time.
-pub fn decrypt_file(key: &[u8], file: EncryptedFile) -> Result<Vec<u8>, Error> {
-    let cipher = ChaCha20Poly1305::new(key.into());
+/// The content key encrypts the vault contents (format version 2 onwards).
+pub fn derive_content_key(recipient_secret: &Key) -> Key {
+    return derive_key(recipient_secret, CONTENT_KEY_LABEL);
+}
 
-    let payload = Payload {
-        aad: &file.aad,
-        msg: &file.ciphertext,
-    };
+/// The cloud lookup token lets recipients request and download a cloud vault.
+/// It is sent to the server, so it must never contain bytes of a key.
+pub fn derive_lookup_token(recipient_secret: &Key) -> Vec<u8> {
+    let mut token = vec![0u8; READ_ONLY_CLOUD_KEY_LEN];
+    derive_bytes(recipient_secret, LOOKUP_TOKEN_LABEL, &mut token);
+    return token;
+}
+
+/// The lookup token used by format version 1 vaults, which is the first 8 bytes of the recipient secret.
+/// Only sent to the server for vaults that were uploaded with it.
+pub fn legacy_lookup_token(recipient_secret: &Key) -> Vec<u8> {
+    return recipient_secret[0..READ_ONLY_CLOUD_KEY_LEN].to_vec();
+}
+
+/// The key id is put in every key piece and in the public vault info, so pieces can be matched to their vault
+/// without needing the secret.
+pub fn derive_key_id(recipient_secret: &Key) -> Vec<u8> {
+    let mut key_id = vec![0u8; KEY_ID_LEN];
+    derive_bytes(recipient_secret, KEY_ID_LABEL, &mut key_id);
+    return key_id;
+}
+
+/// The key commitment is put in the public vault info, so a wrong content key is caught before anything is
+/// decrypted. ChaCha20Poly1305 does not commit to its key, so this also stops a vault being made which decrypts to
+/// different contents under different keys.
+pub fn derive_key_commitment(content_key: &Key) -> Vec<u8> {
+    return derive_key(content_key, KEY_COMMITMENT_LABEL).to_vec();
+}
+
+/// The cloud owner token lets the owner download, update and delete a cloud vault. Only the main key can derive it.
+pub fn derive_owner_token(main: &Key) -> Vec<u8> {
+    let mut token = vec![0u8; OWNER_CLOUD_KEY_LEN];
+    derive_bytes(main, OWNER_TOKEN_LABEL, &mut token);
+    return token;
+}
+
+/// The owner state key seals the owner-only editing state (see vault::OwnerState). Only the main key can derive it.
+pub fn derive_owner_state_key(main: &Key) -> Key {
+    return derive_key(main, OWNER_STATE_KEY_LABEL);
+}
+
+/// The seed of the owner's Ed25519 signing key for vaults signed before the seed was kept in the owner state
+/// (see signature.rs). Only the main key can derive it.
+pub fn derive_signing_seed(main: &Key) -> Key {
+    return derive_key(main, SIGNING_SEED_LABEL);
+}
+
+/// Derives a part of the main key from a random scalar shared in derived key pieces (see vss::split_random).
+fn derive_key_part(scalar: &[u8]) -> AppResult<Key> {
+    let scalar: Key = scalar.try_into()?;
+    return Ok(derive_key(&scalar, KEY_PART_LABEL));
+}
+
+/// Seals a small message with a fresh nonce. The result is the nonce followed by the ciphertext.
+/// The meta_data is signed as AAD, and must be provided to open_sealed.
+pub fn seal(key: &Key, message: &[u8], meta_data: &[u8]) -> AppResult<Vec<u8>> {
+    let nonce = generate_stream_nonce(Cipher::ChaCha20Poly1305);
+    // The inner writer starts with the nonce, so the ciphertext is written after it.
+    let mut writer = EncryptWriter::new(
+        Cipher::ChaCha20Poly1305,
+        key,
+        &nonce,
+        meta_data,
+        nonce.clone(),
+    )?;
+    writer.write_all(message)?;
+    return writer.finish();
+}
+
+/// Opens a message made by seal.
+pub fn open_sealed(key: &Key, sealed: &[u8], meta_data: &[u8]) -> AppResult<Vec<u8>> {
+    if sealed.len() < STREAM_NONCE_LEN {
+        return Err(make_error("crypto", "Sealed data is too short."));
+    }
+    let (nonce, ciphertext) = sealed.split_at(STREAM_NONCE_LEN);
+    let mut reader =
+        DecryptReader::new(Cipher::ChaCha20Poly1305, key, nonce, meta_data, ciphertext)?;
+
+    let mut message = Vec::new();
+    let result = reader.read_to_end(&mut message);
+    reader.finish()?;
+    result?;
+
+    return Ok(message);
+}
+
+/// Generates a random nonce prefix for a vault stream.
+pub fn generate_stream_nonce(cipher: Cipher) -> Vec<u8> {
+    let mut nonce = vec![0u8; cipher.nonce_len()];
+    OsRng.fill_bytes(&mut nonce);
+    return nonce;
+}
+
+// Sets up a cipher, checking the key is the right size rather than panicking.
+fn new_cipher<A: KeyInit>(key: &[u8]) -> AppResult<A> {
+    return A::new_from_slice(key)
+        .map_err(|_e| make_error("key_length", "Bad key: The key is not the correct length."));
+}
+
+// Checks the nonce is the right size for the cipher, rather than panicking.
+fn check_nonce(cipher: Cipher, nonce: &[u8]) -> AppResult<()> {
+    if nonce.len() != cipher.nonce_len() {
+        return Err(make_error(
+            "crypto",
+            "Bad nonce: The vault nonce is not the correct length.",
+        ));
+    }
+    return Ok(());
+}
+
+// The STREAM encryptor for each cipher.
+enum StreamEncryptor {
+    ChaCha20Poly1305(EncryptorBE32<ChaCha20Poly1305>),
+    XChaCha20Poly1305(EncryptorBE32<XChaCha20Poly1305>),
+    // Boxed, as AES keeps its expanded key schedule, which is much larger than the others.
+    Aes256GcmSiv(Box<EncryptorBE32<Aes256GcmSiv>>),
+}
+
+impl StreamEncryptor {
+    fn new(cipher: Cipher, key: &[u8], nonce: &[u8]) -> AppResult<Self> {
+        check_nonce(cipher, nonce)?;
+        return Ok(match cipher {
+            Cipher::ChaCha20Poly1305 => StreamEncryptor::ChaCha20Poly1305(
+                EncryptorBE32::from_aead(new_cipher(key)?, GenericArray::from_slice(nonce)),
+            ),
+            Cipher::XChaCha20Poly1305 => StreamEncryptor::XChaCha20Poly1305(
+                EncryptorBE32::from_aead(new_cipher(key)?, GenericArray::from_slice(nonce)),
+            ),
+            Cipher::Aes256GcmSiv => StreamEncryptor::Aes256GcmSiv(Box::new(
+                EncryptorBE32::from_aead(new_cipher(key)?, GenericArray::from_slice(nonce)),
+            )),
+        });
+    }
+
+    fn encrypt_next(&mut self, payload: Payload) -> Result<Vec<u8>, Error> {
+        return match self {
+            StreamEncryptor::ChaCha20Poly1305(encryptor) => encryptor.encrypt_next(payload),
+            StreamEncryptor::XChaCha20Poly1305(encryptor) => encryptor.encrypt_next(payload),
+            StreamEncryptor::Aes256GcmSiv(encryptor) => encryptor.encrypt_next(payload),
+        };
+    }
+
+    fn encrypt_last(self, payload: Payload) -> Result<Vec<u8>, Error> {
+        return match self {
+            StreamEncryptor::ChaCha20Poly1305(encryptor) => encryptor.encrypt_last(payload),
+            StreamEncryptor::XChaCha20Poly1305(encryptor) => encryptor.encrypt_last(payload),
+            StreamEncryptor::Aes256GcmSiv(encryptor) => encryptor.encrypt_last(payload),
+        };
+    }
+}
+
+// The STREAM decryptor for each cipher.
+enum StreamDecryptor {
+    ChaCha20Poly1305(DecryptorBE32<ChaCha20Poly1305>),
+    XChaCha20Poly1305(DecryptorBE32<XChaCha20Poly1305>),
+    // Boxed, as for StreamEncryptor.
+    Aes256GcmSiv(Box<DecryptorBE32<Aes256GcmSiv>>),
+    // Not a stream: The whole message, with its nonce.
+    Whole(ChaCha20Poly1305, Vec<u8>),
+}
+
+impl StreamDecryptor {
+    fn new(cipher: Cipher, key: &[u8], nonce: &[u8]) -> AppResult<Self> {
+        check_nonce(cipher, nonce)?;
+        return Ok(match cipher {
+            Cipher::ChaCha20Poly1305 => StreamDecryptor::ChaCha20Poly1305(
+                DecryptorBE32::from_aead(new_cipher(key)?, GenericArray::from_slice(nonce)),
+            ),
+            Cipher::XChaCha20Poly1305 => StreamDecryptor::XChaCha20Poly1305(
+                DecryptorBE32::from_aead(new_cipher(key)?, GenericArray::from_slice(nonce)),
+            ),
+            Cipher::Aes256GcmSiv => StreamDecryptor::Aes256GcmSiv(Box::new(
+                DecryptorBE32::from_aead(new_cipher(key)?, GenericArray::from_slice(nonce)),
+            )),
+        });
+    }
+
+    fn decrypt_next(&mut self, payload: Payload) -> Result<Vec<u8>, Error> {
+        return match self {
+            StreamDecryptor::ChaCha20Poly1305(decryptor) => decryptor.decrypt_next(payload),
+            StreamDecryptor::XChaCha20Poly1305(decryptor) => decryptor.decrypt_next(payload),
+            StreamDecryptor::Aes256GcmSiv(decryptor) => decryptor.decrypt_next(payload),
+            StreamDecryptor::Whole(_cipher, _nonce) => Err(Error),
+        };
+    }
+
+    fn decrypt_last(self, payload: Payload) -> Result<Vec<u8>, Error> {
+        return match self {
+            StreamDecryptor::ChaCha20Poly1305(decryptor) => decryptor.decrypt_last(payload),
+            StreamDecryptor::XChaCha20Poly1305(decryptor) => decryptor.decrypt_last(payload),
+            StreamDecryptor::Aes256GcmSiv(decryptor) => decryptor.decrypt_last(payload),
+            StreamDecryptor::Whole(cipher, nonce) => {
+                cipher.decrypt(GenericArray::from_slice(&nonce), payload)
+            }
+        };
+    }
+}
+
+/// Encrypts everything written to it and writes the sealed chunks to the inner writer.
+/// Plaintext is buffered until a full chunk is available, so at most one chunk is held in memory.
+/// finish() must be called once everything has been written, as it seals the final chunk.
+pub struct EncryptWriter<W: Write> {
+    inner: W,
+    encryptor: StreamEncryptor,
+    aad: Vec<u8>,
+    buffer: Vec<u8>,
+}
+
+impl<W: Write> EncryptWriter<W> {
+    /// The meta_data is signed as AAD on every chunk, and must be provided at decryption time.
+    pub fn new(
+        cipher: Cipher,
+        key: &Key,
+        nonce: &[u8],
+        meta_data: &[u8],
+        inner: W,
+    ) -> AppResult<Self> {
+        return Ok(EncryptWriter {
+            inner,
+            encryptor: StreamEncryptor::new(cipher, key, nonce)?,
+            aad: Vec::from(meta_data),
+            buffer: Vec::with_capacity(STREAM_CHUNK_LEN),
+        });
+    }
+
+    /// Seals the remaining plaintext as the final chunk and returns the inner writer.
+    pub fn finish(mut self) -> AppResult<W> {
+        let payload = Payload {
+            aad: &self.aad,
+            msg: &self.buffer,
+        };
+        let sealed = self.encryptor.encrypt_last(payload)?;
+
+        self.inner.write_all(&sealed)?;
+        self.inner.flush()?;
+        return Ok(self.inner);
+    }
+
+    // Seals the buffered (full) chunk. It is only called once more data arrives, so the final chunk is
+    // always left for finish().
+    fn seal_chunk(&mut self) -> io::Result<()> {
+        let payload = Payload {
+            aad: &self.aad,
+            msg: &self.buffer,
+        };
+        let sealed = self
+            .encryptor
+            .encrypt_next(payload)
+            .map_err(|_e| io::Error::other("Failed to encrypt chunk"))?;
+
+        self.inner.write_all(&sealed)?;
+        self.buffer.clear();
+        return Ok(());
+    }
+}
+
+impl<W: Write> Write for EncryptWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut written = 0;
+        while written < buf.len() {
+            if self.buffer.len() == STREAM_CHUNK_LEN {
+                self.seal_chunk()?;
+            }
+            let to_take = (STREAM_CHUNK_LEN - self.buffer.len()).min(buf.len() - written);
+            self.buffer
+                .extend_from_slice(&buf[written..written + to_take]);
+            written += to_take;
+        }
+        return Ok(written);
+    }
 
-    return cipher.decrypt(&GenericArray::from_slice(&file.nonce), payload);
+    fn flush(&mut self) -> io::Result<()> {
+        return self.inner.flush();
+    }
+}
+
+/// Decrypts a vault stream as it is read. Each chunk is authenticated before any of its plaintext is returned.
+/// It reads one byte ahead of the current chunk so that it knows whether the chunk should be the final one.
+pub struct DecryptReader<R: Read> {
+    inner: R,
+    // Taken when the final chunk is opened.
+    decryptor: Option<StreamDecryptor>,
+    aad: Vec<u8>,
+    // Ciphertext which has been read but not yet decrypted.
+    pending: Vec<u8>,
+    // Plaintext of the current chunk, and how much of it has been returned.
+    plaintext: Vec<u8>,
+    position: usize,
+    // Set if a chunk fails authentication.
+    failed: bool,
+    // Set if the inner reader is one message rather than a stream (see new_whole).
+    whole: bool,
+}
+
+impl<R: Read> DecryptReader<R> {
+    /// The meta data (AAD) must match the AAD provided at encryption time.
+    pub fn new(
+        cipher: Cipher,
+        key: &[u8],
+        nonce: &[u8],
+        meta_data: &[u8],
+        inner: R,
+    ) -> AppResult<Self> {
+        return Ok(DecryptReader {
+            inner,
+            decryptor: Some(StreamDecryptor::new(cipher, key, nonce)?),
+            aad: Vec::from(meta_data),
+            pending: Vec::with_capacity(STREAM_SEALED_CHUNK_LEN + 1),
+            plaintext: Vec::new(),
+            position: 0,
+            failed: false,
+            whole: false,
+        });
+    }
+
+    /// Decrypts vaults made before the contents were streamed, which are one ChaCha20Poly1305 message.
+    /// These are read in full before anything is returned, so the whole vault is held in memory.
+    pub fn new_whole(key: &[u8], nonce: &[u8], meta_data: &[u8], inner: R) -> AppResult<Self> {
+        if nonce.len() != LEGACY_NONCE_LEN {
+            return Err(make_error(
+                "crypto",
+                "Bad nonce: The vault nonce is not the correct length.",
+            ));
+        }
+        return Ok(DecryptReader {
+            inner,
+            decryptor: Some(StreamDecryptor::Whole(new_cipher(key)?, Vec::from(nonce))),
+            aad: Vec::from(meta_data),
+            pending: Vec::new(),
+            plaintext: Vec::new(),
+            position: 0,
+            failed: false,
+            whole: true,
+        });
+    }
+
+    /// Reads (and authenticates) the rest of the stream, then returns the inner reader.
+    /// This must be called after reading, as the consumer (i.e. tar) may stop before the final chunk.
+    /// Gives a "crypto" error if any chunk failed authentication or the stream was truncated.
+    pub fn finish(mut self) -> AppResult<R> {
+        let result = io::copy(&mut self, &mut io::sink());
+
+        if self.failed {
+            return Err(make_error(
+                "crypto",
+                "Decryption failed: The vault is corrupt, incomplete or the key is not correct.",
+            ));
+        }
+        result?;
+        return Ok(self.inner);
+    }
+
+    // Read until there is a full sealed chunk and one more byte, or the inner reader runs out.
+    fn fill_pending(&mut self) -> io::Result<()> {
+        while self.pending.len() <= STREAM_SEALED_CHUNK_LEN {
+            let start = self.pending.len();
+            self.pending.resize(STREAM_SEALED_CHUNK_LEN + 1, 0);
+
+            match self.inner.read(&mut self.pending[start..]) {
+                Ok(0) => {
+                    self.pending.truncate(start);
+                    break;
+                }
+                Ok(read) => self.pending.truncate(start + read),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => self.pending.truncate(start),
+                Err(e) => {
+                    self.pending.truncate(start);
+                    return Err(e);
+                }
+            }
+        }
+        return Ok(());
+    }
+
+    // Decrypt the next chunk into the plaintext buffer. If there is nothing after it, it is opened as the final chunk.
+    fn open_chunk(&mut self) -> io::Result<()> {
+        if self.whole {
+            self.inner.read_to_end(&mut self.pending)?;
+        } else {
+            self.fill_pending()?;
+        }
+
+        let result = if !self.whole && self.pending.len() > STREAM_SEALED_CHUNK_LEN {
+            let chunk: Vec<u8> = self.pending.drain(..STREAM_SEALED_CHUNK_LEN).collect();
+            let payload = Payload {
+                aad: &self.aad,
+                msg: &chunk,
+            };
+            match self.decryptor.as_mut() {
+                Some(decryptor) => decryptor.decrypt_next(payload),
+                None => Err(Error),
+            }
+        } else {
+            let chunk = std::mem::take(&mut self.pending);
+            let payload = Payload {
+                aad: &self.aad,
+                msg: &chunk,
+            };
+            match self.decryptor.take() {
+                Some(decryptor) => decryptor.decrypt_last(payload),
+                None => Err(Error),
+            }
+        };
+
+        match result {
+            Ok(plaintext) => self.plaintext = plaintext,
+            Err(_e) => {
+                self.failed = true;
+                self.decryptor = None;
+                return Err(chunk_auth_error());
+            }
+        }
+        self.position = 0;
+        return Ok(());
+    }
 }
 
-/// A padded key is the length first, then the secret, then 0s.
-/// The secret sharing function requires secrets of 64 bytes, and the key is 33 bytes.
-/// This function fills out the rest with 0s.
-fn pad_key(key: Vec<u8>) -> [u8; FULL_SECRET_LEN] {
-    let mut result = vec![0u8; FULL_SECRET_LEN];
-    result[0] = u8::try_from(key.len()).expect("Key is too large");
+fn chunk_auth_error() -> io::Error {
+    return io::Error::new(
+        io::ErrorKind::InvalidData,
+        "Decryption failed: A chunk of the vault could not be authenticated.",
+    );
+}
+
+impl<R: Read> Read for DecryptReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.position == self.plaintext.len() {
+            if self.failed {
+                return Err(chunk_auth_error());
+            }
+            if self.decryptor.is_none() {
+                // Final chunk has been opened: End of stream.
+                return Ok(0);
+            }
+            self.open_chunk()?;
+        }
+
+        let available = &self.plaintext[self.position..];
+        let to_copy = available.len().min(buf.len());
+        buf[..to_copy].copy_from_slice(&available[..to_copy]);
+        self.position += to_copy;
 
-    let copied_key = key.clone();
-    for counter in 0..key.len() {
-        result[counter + 1] = copied_key[counter];
+        return Ok(to_copy);
     }
-    return result.as_slice().try_into().unwrap();
 }
 
-/// Removes padding from a 64-byte secret to produce the key.
+/// Removes padding from a 64-byte secret to produce the key. Legacy pieces padded keys to share them, as
+/// shamirsecretsharing requires secrets of 64 bytes: The length first, then the secret, then 0s.
 /// Uses the first value as the length of the secret, and returns the secret.
 fn unpad_key(key: &Vec<u8>) -> AppResult<Vec<u8>> {
     if key.len() != FULL_SECRET_LEN {
@@ -135,116 +674,673 @@ fn unpad_key(key: &Vec<u8>) -> AppResult<Vec<u8>> {
     return Ok(return_key);
 }
 
-/// Generates a key collection from a given configuration.
-/// Takes the circles and returns a new Vector of circles with key information specified. It does not
-/// modify the original vector.
-/// The returned circles will include keys, each of which will either be of length 32 (length of Key type) or 64.
-/// Where a key is 64 bytes, the first 32 will be the individual key and the second the group key.
-pub fn generate_circle_keys(
-    key: &Key,
-    circles: Vec<Circle>,
-    required_keys: u8,
-) -> AppResult<Vec<Circle>> {
-    // Count totals
-    let mut required_groups: u8 = 0;
-    let mut total_keys = 0;
+/// Joins parts of the main key with XOR. Every part (and the mask, for derived pieces) is needed to rebuild it.
+fn join_main_key(parts: &[Vec<u8>]) -> AppResult<Key> {
+    let mut key: Key = [0; ENCRYPTION_KEY_LEN];
+    for part in parts.iter() {
+        if part.len() != ENCRYPTION_KEY_LEN {
+            return Err(app_error("Bad secret: Incorrect length".to_string()));
+        }
+        for (byte, part_byte) in key.iter_mut().zip(part.iter()) {
+            *byte ^= part_byte;
+        }
+    }
+    return Ok(key);
+}
+
+/// Checks circles can be given key pieces, and returns the number of shares of Z their members hold.
+fn check_circles(circles: &[Circle]) -> AppResult<usize> {
+    if circles.len() > usize::from(u8::MAX) + 1 {
+        return Err(make_error(
+            "too_many_circles",
+            "There are too many circles. A vault can have up to 256 circles.",
+        ));
+    }
+    let mut total_keys: usize = 0;
 
     for c in circles.iter() {
-        if c.required {
-            required_groups += 1;
+        if c.weights.len() > c.key_comments.len() || c.weights.contains(&0) {
+            return Err(make_error(
+                "bad_weight",
+                &format!(
+                    "The circle '{}' has a bad weight. Each member must have a weight of at least 1.",
+                    c.name
+                ),
+            ));
+        }
+        for member in 0..c.key_comments.len() {
+            if let Some(recipient) = c.recipient(member) {
+                parse_recipient(recipient).map_err(|_e| {
+                    make_error(
+                        "bad_recipient",
+                        &format!(
+                            "The public key for '{}' in '{}' is not valid. Public keys start with age1, and are made by age-keygen.",
+                            c.key_comments[member], c.name
+                        ),
+                    )
+                })?;
+            }
         }
-        total_keys += c.key_comments.len();
+        if c.required && (c.threshold == 0 || usize::from(c.threshold) > c.key_comments.len()) {
+            return Err(make_error(
+                "circle_threshold",
+                &format!(
+                    "The circle '{}' needs {} keys to unlock, but it has {}.",
+                    c.name,
+                    c.threshold,
+                    c.key_comments.len()
+                ),
+            ));
+        }
+        total_keys += c.total_weight();
     }
+    if total_keys > MAX_SHARES {
+        return Err(make_error(
+            "too_many_keys",
+            "There are too many keys. The weights of all members must add up to 65535 or less.",
+        ));
+    }
+    return Ok(total_keys);
+}
 
-    let key_splits = required_groups + 1;
+/// Generates a key collection from a given configuration.
+/// Takes the circles and returns a new Vector of circles with key information specified, the commitments each key
+/// can be checked against, and the coefficients the shares were made from. It does not modify the original vector.
+/// Every key starts with the mask, then one verifiable share of the scalar Z is derived from per unit of the member's
+/// weight. Keys for required circles then include the circle's index and a verifiable share of the scalar the circle's
+/// part of the main key is derived from, of which the circle's threshold are needed. Where any one member is enough,
+/// the share is the same for every member.
+/// Each key is then put in an envelope identifying the vault, circle and member, and the owner by the fingerprint
+/// given (see piece.rs).
+pub fn generate_circle_keys(
+    key: &Key,
+    owner_fingerprint: &[u8],
+    circles: Vec<Circle>,
+    required_keys: u16,
+) -> AppResult<(Vec<Circle>, ShareCommitments, ShareCoefficients)> {
+    let total_keys: u16 = check_circles(&circles)?.try_into()?;
 
     let mut new_circles: Vec<Circle> = vec![];
 
-    // 1   Linear split S with M+1 of M=1 needed (linear/all keys needed)
-    let group_keys = create_keyshares(key, key_splits, key_splits)?;
-    //         Assign each L_n (n 0..M-1) to a group. The final share L_M is Z, the individual share.
-    let z = group_keys[usize::from(required_groups)].clone();
-    // 2   Threshold split Z using M of N into z_n (0..N-1)
-    // The key must be passed with zero values as create_shares will only accept a slice with 64 bytes.
-    let padded_z = pad_key(z);
-    // Use the other, higher level keyshare. Returns keys with length 113.
-    let individual_shares = create_shares(&padded_z, total_keys.try_into()?, required_keys)?;
+    // 1   Share a random scalar using M of N into z_n (0..N-1), committing to the shares so each can be checked.
+    //     Z, the individual part of the main key, is derived from it.
+    //     The coefficients are kept, so that members added later can be given more shares of Z.
+    let (z_scalar, (individual_shares, individual_commitments, individual_coefficients)) =
+        split_random(total_keys, required_keys)?;
+    let mut parts: Vec<Vec<u8>> = Vec::from([key.to_vec(), derive_key_part(&z_scalar)?.to_vec()]);
+    let mut commitments = ShareCommitments {
+        individual: individual_commitments,
+        circles: Vec::new(),
+    };
+    let mut coefficients = ShareCoefficients {
+        individual: individual_coefficients,
+        circles: Vec::new(),
+        mask: Vec::new(),
+    };
+
+    // 2   Share another between the members of each required group, of which the group's threshold are needed.
+    //     The group's part L_n of the main key is derived from it.
+    let mut group_shares: Vec<Option<Vec<Vec<u8>>>> = Vec::with_capacity(circles.len());
+    for circle in circles.iter() {
+        if circle.required {
+            let (scalar, (shares, circle_commitments, circle_coefficients)) =
+                split_random(circle.key_comments.len().try_into()?, circle.threshold)?;
+            parts.push(derive_key_part(&scalar)?.to_vec());
+            commitments.circles.push(circle_commitments);
+            coefficients.circles.push(circle_coefficients);
+            group_shares.push(Some(shares));
+        } else {
+            commitments.circles.push(Commitments::default());
+            coefficients.circles.push(Coefficients::default());
+            group_shares.push(None);
+        }
+    }
+    // The mask XORs with Z and every L_n to give the main key.
+    let mask = join_main_key(&parts)?;
+    coefficients.mask = mask.to_vec();
 
     // This is the index of the next key to give.
     let mut z_counter = 0;
-    let mut g_counter = 0;
+    let key_id = derive_key_id(key);
 
     // key issuing
-    for existing_circle in circles {
+    for (circle_index, (existing_circle, group_shares)) in
+        circles.into_iter().zip(group_shares).enumerate()
+    {
         let group_key_count = existing_circle.key_comments.len();
-        let mut key_vector: Vec<KeyPiece> = vec![Vec::new(); group_key_count];
+        let mut key_vector: Vec<KeyPiece> = Vec::with_capacity(group_key_count);
+        let circle_index: u8 = circle_index.try_into()?;
 
         // For each circle 'member'
         for key_index in 0..group_key_count {
-            if existing_circle.required {
-                // 3   Issue keys: Each member of a required group gets their group key L_M and one share of Z z_n.
-                let mut group_share = group_keys[g_counter].clone();
-                let mut full_share = individual_shares[z_counter].clone();
-                full_share.append(&mut group_share);
-                key_vector[key_index] = full_share;
-            } else {
-                // 4   Each non-required gets a share of Z z_n only.
-                key_vector[key_index] = individual_shares[z_counter].clone();
-            }
-            z_counter += 1;
+            // A member gets one share of Z for each unit of weight, all in the one key.
+            let weight = usize::from(existing_circle.weight(key_index));
+            let group_share = group_shares
+                .as_ref()
+                .map(|shares| shares[key_index].as_slice());
+            key_vector.push(make_piece(
+                &key_id,
+                owner_fingerprint,
+                circle_index,
+                key_index,
+                &mask,
+                &individual_shares[z_counter..z_counter + weight],
+                group_share,
+            )?);
+            z_counter += weight;
         }
+
+        let sealed_keys = seal_pieces(&existing_circle, &key_vector, &[])?;
         // Add the circle with keys to the result.
         new_circles.push(Circle {
             required: existing_circle.required,
             key_comments: existing_circle.key_comments.clone(),
             name: existing_circle.name.clone(),
             keys: Some(key_vector),
+            threshold: existing_circle.threshold,
+            weights: existing_circle.weights.clone(),
+            recipients: existing_circle.recipients.clone(),
+            sealed_keys,
         });
+    }
+    return Ok((new_circles, commitments, coefficients));
+}
 
-        if existing_circle.required {
-            g_counter += 1;
+/// Gives key pieces to members added to the end of circles since the vault's pieces were made, without changing
+/// anyone else's piece. Takes the recipient secret, the vault's keys, and the circles and number of keys needed as
+/// they are now. Returns the circles with keys, as generate_circle_keys does.
+/// The new shares are made from the coefficients kept when the pieces were made, so they fit with the existing pieces
+/// and check against the same commitments. New shares of Z follow on from those already given. Nothing else about
+/// the circles can change, and existing members keep their weight and public key.
+pub fn add_circle_members(
+    key: &Key,
+    keys: &KeyCollection,
+    circles: Vec<Circle>,
+    required_keys: u16,
+) -> AppResult<Vec<Circle>> {
+    let changed = || {
+        make_error(
+            "circles_changed",
+            "Only new members can be added to the circles of an existing vault. To change anything else, rekey the vault or make a new one.",
+        )
+    };
+    let existing = &keys.share_keys;
+    check_circles(&circles)?;
+    if circles.len() != existing.len() {
+        return Err(changed());
+    }
+    // The commitments have one per share needed. They are empty for vaults made before pieces could be checked.
+    if let Some(needed) = keys.commitments.individual.chunks.first() {
+        if needed.len() != usize::from(required_keys) {
+            return Err(changed());
+        }
+    }
+
+    let mut added = false;
+    for (old, new) in existing.iter().zip(circles.iter()) {
+        let members = old.key_comments.len();
+        let same_members = (0..members)
+            .all(|m| old.weight(m) == new.weight(m) && old.recipient(m) == new.recipient(m));
+        if old.required != new.required
+            || (old.required && old.threshold != new.threshold)
+            || new.key_comments.len() < members
+            || !same_members
+        {
+            return Err(changed());
         }
+        added = added || new.key_comments.len() > members;
+    }
+    if !added {
+        return Ok(existing.clone());
+    }
+
+    let coefficients = keys.coefficients.as_ref().ok_or_else(|| {
+        make_error(
+            "members_fixed",
+            "This vault was made before members could be added to it. Rekey the vault first, which gives everyone a new piece, and then add the members.",
+        )
+    })?;
+    // Pieces from before the parts of the main key were derived are not made any more.
+    let mask: Key = coefficients.mask.as_slice().try_into().map_err(|_e| {
+        make_error(
+            "members_fixed",
+            "This vault's key pieces were made by an older version of the app, so members can't be added to it. Rekey the vault first, which gives everyone a new piece, and then add the members.",
+        )
+    })?;
+    let key_id = derive_key_id(key);
+    let fingerprint = owner_fingerprint(keys);
+    let mut next_z: usize = existing.iter().map(|c| c.total_weight()).sum::<usize>() + 1;
+
+    let mut new_circles: Vec<Circle> = Vec::new();
+    for (circle_index, (old, circle)) in existing.iter().zip(circles).enumerate() {
+        let circle_index: u8 = circle_index.try_into()?;
+        let mut pieces: Vec<KeyPiece> = old
+            .keys
+            .clone()
+            .ok_or_else(|| app_error("The vault's key pieces are missing.".to_string()))?;
+        let members = pieces.len();
+
+        for member in members..circle.key_comments.len() {
+            let last = next_z + usize::from(circle.weight(member)) - 1;
+            let individual_shares = make_shares(
+                &coefficients.individual,
+                next_z.try_into()?..=last.try_into()?,
+            )?;
+            next_z = last + 1;
+
+            // Members of a required circle also get the next share of its group key.
+            let group_share = if circle.required {
+                let circle_coefficients = coefficients
+                    .circles
+                    .get(usize::from(circle_index))
+                    .ok_or_else(changed)?;
+                let index: u16 = (member + 1).try_into()?;
+                Some(make_shares(circle_coefficients, index..=index)?.remove(0))
+            } else {
+                None
+            };
+            pieces.push(make_piece(
+                &key_id,
+                &fingerprint,
+                circle_index,
+                member,
+                &mask,
+                &individual_shares,
+                group_share.as_deref(),
+            )?);
+        }
+
+        let already_sealed = old
+            .sealed_keys
+            .clone()
+            .unwrap_or_else(|| vec![String::new(); members]);
+        let sealed_keys = seal_pieces(&circle, &pieces, &already_sealed)?;
+        new_circles.push(Circle {
+            keys: Some(pieces),
+            sealed_keys,
+            ..circle
+        });
     }
     return Ok(new_circles);
 }
 
-/// Combine a set of keys. This function excepts multi-level keys of either length 113 or length 113 + 33 (146).
+/// Makes a member's derived key piece from the mask, their shares of Z and, for members of required circles, their
+/// share of the circle's group key. The piece is put in an envelope identifying the vault, circle, member and owner.
+fn make_piece(
+    key_id: &[u8],
+    owner_fingerprint: &[u8],
+    circle: u8,
+    member: usize,
+    mask: &Key,
+    individual_shares: &[Vec<u8>],
+    group_share: Option<&[u8]>,
+) -> AppResult<KeyPiece> {
+    let count: u16 = individual_shares.len().try_into()?;
+    let mut full_share = vec![VERIFIABLE_MARKER, 0, 0, 0];
+    full_share.extend_from_slice(&count.to_be_bytes());
+    full_share.extend_from_slice(mask);
+    full_share.extend_from_slice(&individual_shares.concat());
+
+    // 3   Issue keys: Each member of a required group gets a share of their group key L_M and their shares of Z z_n.
+    // 4   Each non-required gets shares of Z z_n only.
+    if let Some(share) = group_share {
+        full_share.push(circle);
+        full_share.extend_from_slice(share);
+    }
+    let info = PieceInfo {
+        key_id: key_id.to_vec(),
+        circle,
+        member: member.try_into()?,
+        owner_fingerprint: owner_fingerprint.to_vec(),
+    };
+    return Ok(wrap_piece(&info, &full_share));
+}
+
+/// Seals each member's piece to their public key, if they gave one. The first members keep the sealed pieces given.
+/// None if nobody in the circle gave a public key.
+fn seal_pieces(
+    circle: &Circle,
+    pieces: &[KeyPiece],
+    already_sealed: &[String],
+) -> AppResult<Option<Vec<String>>> {
+    if !(0..pieces.len()).any(|m| circle.recipient(m).is_some()) {
+        return Ok(None);
+    }
+    let mut sealed = already_sealed.to_vec();
+    for (member, piece) in pieces.iter().enumerate().skip(already_sealed.len()) {
+        sealed.push(match circle.recipient(member) {
+            Some(recipient) => seal_piece(piece, recipient)?,
+            None => String::new(),
+        });
+    }
+    return Ok(Some(sealed));
+}
+
+/// The group part of a key piece, for members of required circles.
+pub enum GroupPart {
+    None,
+    // The circle's group keyshare (legacy pieces only).
+    GroupKey(Vec<u8>),
+    // The circle's index and a threshold share of its group keyshare. Legacy pieces count the index among the
+    // required circles only.
+    CircleShare(u8, Vec<u8>),
+}
+
+/// How the shares in a key piece were made.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PieceFormat {
+    // Shares from shamirsecretsharing, which can't be checked.
+    Legacy,
+    // Narrow verifiable shares (see vss.rs) of keyshares from shamirsecretsharing.
+    Verifiable,
+    // Wide verifiable shares of parts of the main key.
+    Wide,
+    // Wide verifiable shares of random scalars, which the parts of the main key are derived from.
+    Derived,
+}
+
+impl PieceFormat {
+    /// The format of the piece's verifiable shares, if it has them.
+    pub fn share_format(&self) -> Option<ShareFormat> {
+        return match self {
+            PieceFormat::Legacy => None,
+            PieceFormat::Verifiable => Some(ShareFormat::Narrow),
+            PieceFormat::Wide | PieceFormat::Derived => Some(ShareFormat::Wide),
+        };
+    }
+
+    // The length of the secrets the piece's verifiable shares are of.
+    fn secret_len(&self) -> usize {
+        return match self {
+            PieceFormat::Wide | PieceFormat::Derived => ENCRYPTION_KEY_LEN,
+            _ => KEYSHARE_LEN,
+        };
+    }
+}
+
+/// The parts of a raw key piece (without its envelope).
+pub struct PieceParts {
+    pub format: PieceFormat,
+    pub shares: Vec<Vec<u8>>,
+    pub group: GroupPart,
+    // The mask the parts of the main key are joined with. Empty for all but derived pieces.
+    pub mask: Vec<u8>,
+}
+
+/// Splits a raw key piece (without its envelope) into its individual shares and group part.
+pub fn split_piece(key: &[u8]) -> AppResult<PieceParts> {
+    let bad_length = || {
+        make_error(
+            "combine",
+            "Incorrect key length: One or more of the keys are not the correct size.",
+        )
+    };
+
+    if key.first() == Some(&VERIFIABLE_MARKER) {
+        if key.get(1) == Some(&0) {
+            return split_wide_piece(key).ok_or_else(bad_length);
+        }
+        return split_verifiable_piece(key).ok_or_else(bad_length);
+    }
+
+    // The group part is identified by what is left over after the individual shares.
+    let group_part_len = match key.len() % RE_SHARE_LEN {
+        0 => 0,
+        GROUP_KEY_PART_LEN => GROUP_KEY_PART_LEN,
+        // The circle share part is one byte longer than an individual share.
+        1 => CIRCLE_SHARE_PART_LEN,
+        _ => return Err(bad_length()),
+    };
+    if key.len() < RE_SHARE_LEN + group_part_len {
+        return Err(bad_length());
+    }
+    let (individual_part, group_part) = key.split_at(key.len() - group_part_len);
+
+    let individual_shares = individual_part
+        .chunks(RE_SHARE_LEN)
+        .map(|share| share.to_vec())
+        .collect();
+    let group = match group_part.len() {
+        GROUP_KEY_PART_LEN => GroupPart::GroupKey(group_part.to_vec()),
+        CIRCLE_SHARE_PART_LEN => GroupPart::CircleShare(group_part[0], group_part[1..].to_vec()),
+        _ => GroupPart::None,
+    };
+    return Ok(PieceParts {
+        format: PieceFormat::Legacy,
+        shares: individual_shares,
+        group,
+        mask: Vec::new(),
+    });
+}
+
+fn split_verifiable_piece(key: &[u8]) -> Option<PieceParts> {
+    let count = usize::from(*key.get(1)?);
+    let shares_end = 2 + count * VERIFIABLE_SHARE_LEN;
+    if count == 0 || key.len() < shares_end {
+        return None;
+    }
+
+    let group = match key.len() - shares_end {
+        0 => GroupPart::None,
+        VERIFIABLE_GROUP_PART_LEN => {
+            GroupPart::CircleShare(key[shares_end], key[shares_end + 1..].to_vec())
+        }
+        _ => return None,
+    };
+    let shares = key[2..shares_end]
+        .chunks(VERIFIABLE_SHARE_LEN)
+        .map(|share| share.to_vec())
+        .collect();
+    return Some(PieceParts {
+        format: PieceFormat::Verifiable,
+        shares,
+        group,
+        mask: Vec::new(),
+    });
+}
+
+fn split_wide_piece(key: &[u8]) -> Option<PieceParts> {
+    let count = usize::from(u16::from_be_bytes([*key.get(2)?, *key.get(3)?]));
+    if count == 0 {
+        return split_derived_piece(key);
+    }
+    let shares_end = 4 + count * WIDE_SHARE_LEN;
+    if key.len() < shares_end {
+        return None;
+    }
+
+    let group = match key.len() - shares_end {
+        0 => GroupPart::None,
+        WIDE_GROUP_PART_LEN => {
+            GroupPart::CircleShare(key[shares_end], key[shares_end + 1..].to_vec())
+        }
+        _ => return None,
+    };
+    let shares = key[4..shares_end]
+        .chunks(WIDE_SHARE_LEN)
+        .map(|share| share.to_vec())
+        .collect();
+    return Some(PieceParts {
+        format: PieceFormat::Wide,
+        shares,
+        group,
+        mask: Vec::new(),
+    });
+}
+
+fn split_derived_piece(key: &[u8]) -> Option<PieceParts> {
+    let count = usize::from(u16::from_be_bytes([*key.get(4)?, *key.get(5)?]));
+    let shares_start = 6 + MASK_LEN;
+    let shares_end = shares_start + count * DERIVED_SHARE_LEN;
+    if count == 0 || key.len() < shares_end {
+        return None;
+    }
+
+    let group = match key.len() - shares_end {
+        0 => GroupPart::None,
+        DERIVED_GROUP_PART_LEN => {
+            GroupPart::CircleShare(key[shares_end], key[shares_end + 1..].to_vec())
+        }
+        _ => return None,
+    };
+    let shares = key[shares_start..shares_end]
+        .chunks(DERIVED_SHARE_LEN)
+        .map(|share| share.to_vec())
+        .collect();
+    return Some(PieceParts {
+        format: PieceFormat::Derived,
+        shares,
+        group,
+        mask: key[6..shares_start].to_vec(),
+    });
+}
+
+/// A main key combined from key pieces.
+#[derive(Debug)]
+pub struct CombinedKey {
+    pub key: Key,
+    // An error naming each piece which was left out because it was damaged or did not fit with the others.
+    pub bad_pieces: Vec<AppError>,
+}
+
+// The most legacy pieces left out at once when looking for a set which combines.
+const MAX_LEGACY_LEFT_OUT: usize = 2;
+// The most combinations of the parts of the main key tried against the key id, when derived pieces don't show
+// which of their shares are wrong.
+const MAX_DERIVED_TRIES: usize = 10_000;
+
+// Shares along with the index of the piece they came from.
+type IndexedShares = Vec<(usize, Vec<u8>)>;
+
+/// Combine a set of keys. This function excepts multi-level keys in any of the layouts made by generate_circle_keys.
 /// It will give reasonably descriptive errors to indicate where the issue occurred.
 pub fn combine_keys(keys: Vec<Vec<u8>>) -> AppResult<Key> {
-    // Identify key types (First 113 is individual key)
-    // Use sets so the values are unique. Saves having to check for duplicates manually.
-    let mut group_keys: HashSet<Vec<u8>> = HashSet::new();
-    let mut individual_keys: HashSet<Vec<u8>> = HashSet::new();
+    return Ok(combine_pieces(&keys, None)?.key);
+}
 
-    for key in keys.iter() {
-        if key.len() > RE_SHARE_LEN + KEYSHARE_LEN {
-            return Err(make_error(
-                "combine",
-                "Incorrect key length: One or more of the keys are not the correct size.",
-            ));
+/// Combines key pieces, leaving out any which are damaged or don't fit with the others, as long as enough good
+/// pieces remain. Where the public info is given, each piece is first checked against it (see piece::check_piece).
+/// If the key can't be rebuilt, the error for the first bad piece is returned, as that piece is the likely cause.
+pub fn combine_pieces(keys: &[KeyPiece], info: Option<&PublicInfo>) -> AppResult<CombinedKey> {
+    // Errors for pieces which were left out, by index.
+    let mut bad_pieces: BTreeMap<usize, AppError> = BTreeMap::new();
+    let mut pieces: Vec<(usize, PieceParts)> = Vec::new();
+
+    let mut unwrapped_pieces: Vec<(usize, Option<PieceInfo>, Vec<u8>)> = Vec::new();
+    for (index, wrapped) in keys.iter().enumerate() {
+        let unwrapped = match info {
+            Some(info) => check_piece(wrapped, index + 1, info),
+            None => unwrap_piece(wrapped, index + 1),
+        };
+        match unwrapped {
+            Ok((piece_info, key)) => unwrapped_pieces.push((index, piece_info, key)),
+            Err(e) => {
+                bad_pieces.insert(index, e);
+            }
         }
-        let individual_key = &key[0..RE_SHARE_LEN];
-        individual_keys.insert(individual_key.to_vec());
-        if key.len() > RE_SHARE_LEN {
-            // It also has a group key
-            let group_key = &key[RE_SHARE_LEN..key.len()];
-            group_keys.insert(group_key.to_vec());
+    }
+
+    // Pieces with an envelope name their vault. Without the vault's info to go by, go with whichever vault most of
+    // them are for, and leave out the rest. Ties go to the vault of the first piece.
+    let mut key_id_counts: Vec<(Vec<u8>, usize)> = Vec::new();
+    for piece_info in unwrapped_pieces
+        .iter()
+        .filter_map(|(_index, info, _key)| info.as_ref())
+    {
+        match key_id_counts
+            .iter_mut()
+            .find(|(key_id, _count)| *key_id == piece_info.key_id)
+        {
+            Some((_key_id, count)) => *count += 1,
+            None => key_id_counts.push((piece_info.key_id.clone(), 1)),
         }
     }
+    let key_id = key_id_counts
+        .iter()
+        .rev()
+        .max_by_key(|(_key_id, count)| *count)
+        .map(|(key_id, _count)| key_id.clone());
+
+    for (index, piece_info, key) in unwrapped_pieces {
+        let foreign =
+            piece_info.is_some_and(|piece_info| Some(&piece_info.key_id) != key_id.as_ref());
+        if foreign {
+            bad_pieces.insert(
+                index,
+                piece_error(
+                    "piece_foreign",
+                    index + 1,
+                    "is for a different vault to most of the other key pieces.",
+                ),
+            );
+            continue;
+        }
 
-    // Try combine indiv. keys
-    let vec_keys = Vec::from_iter(individual_keys);
-    let padded_z = combine_shares(&vec_keys)?.ok_or(make_error(
-        "indiv_combine",
-        "You have not provided enough keys. Please provide more.",
-    ))?;
-    let z = unpad_key(&padded_z)?;
+        match split_piece(&key) {
+            Ok(parts) => pieces.push((index, parts)),
+            Err(_) => {
+                bad_pieces.insert(
+                    index,
+                    piece_error(
+                        "combine",
+                        index + 1,
+                        "is not the correct size. Check that it was copied in full.",
+                    ),
+                );
+            }
+        }
+    }
 
-    // Now try to combine the circle/group keys
-    let mut vec_circle_keys = Vec::from_iter(group_keys);
-    vec_circle_keys.push(z);
+    // Pieces of different formats can't be for the same vault, so go with whichever most of the pieces are.
+    // Ties go to the newest format.
+    let format = [
+        PieceFormat::Legacy,
+        PieceFormat::Verifiable,
+        PieceFormat::Wide,
+        PieceFormat::Derived,
+    ]
+    .into_iter()
+    .max_by_key(|format| {
+        pieces
+            .iter()
+            .filter(|(_index, parts)| parts.format == *format)
+            .count()
+    })
+    .unwrap_or(PieceFormat::Derived);
+    pieces.retain(|(index, parts)| {
+        if parts.format != format {
+            bad_pieces.insert(
+                *index,
+                piece_error(
+                    "indiv_combine",
+                    index + 1,
+                    "was made by a different version of the app to the other key pieces, so they can't be for the same vault.",
+                ),
+            );
+        }
+        return parts.format == format;
+    });
+
+    let keyshares = match format {
+        PieceFormat::Legacy => combine_legacy_pieces(&pieces, &mut bad_pieces),
+        PieceFormat::Derived => check_required_circles(&pieces, format, info)
+            .and_then(|_| combine_derived_pieces(&pieces, key_id.as_deref(), &mut bad_pieces)),
+        _ => check_required_circles(&pieces, format, info)
+            .and_then(|_| combine_verifiable_pieces(&pieces, format, &mut bad_pieces)),
+    };
+    // A bad piece is the most likely reason for not having enough, so its error is more useful.
+    let keyshares = match keyshares {
+        Ok(keyshares) => keyshares,
+        Err(e) => return Err(bad_pieces.into_values().next().unwrap_or(e)),
+    };
 
-    let secret = combine_keyshares(&vec_circle_keys).map_err(|e| {
+    if format == PieceFormat::Wide || format == PieceFormat::Derived {
+        return Ok(CombinedKey {
+            key: join_main_key(&keyshares)?,
+            bad_pieces: bad_pieces.into_values().collect(),
+        });
+    }
+    let secret = combine_keyshares(&keyshares).map_err(|e| {
         let error_str = format!(
             "Insufficient circle data: Have you got all of the required participants? ({})",
             e
@@ -255,24 +1351,361 @@ pub fn combine_keys(keys: Vec<Vec<u8>>) -> AppResult<Key> {
 
     // Unpad it and turn it into a Key/Slice.
 
-    let result_slice: Key = secret[0..KEY_SIZE].try_into()?;
-    return Ok(result_slice);
+    let key: Key = secret[0..KEY_SIZE].try_into()?;
+    return Ok(CombinedKey {
+        key,
+        bad_pieces: bad_pieces.into_values().collect(),
+    });
 }
 
-/// Generates an encryption key and owner cloud secret.
-/// The share_token is the first 8 bytes of the key.
-pub fn generate_cloud_creds() -> (Key, CloudKeyData) {
-    let key = generate_key();
-    let tokens = generate_key();
+// Wide and derived pieces rebuild parts of the main key which are joined with XOR, so without a required circle's
+// part they still join, just to the wrong key. Where the vault's info is given, check every required circle has
+// pieces first. These pieces give a circle's index among all circles.
+fn check_required_circles(
+    pieces: &[(usize, PieceParts)],
+    format: PieceFormat,
+    info: Option<&PublicInfo>,
+) -> AppResult<()> {
+    let info = match info {
+        Some(info) if format == PieceFormat::Wide || format == PieceFormat::Derived => info,
+        _ => return Ok(()),
+    };
+    let present: HashSet<u8> = pieces
+        .iter()
+        .filter_map(|(_index, parts)| match &parts.group {
+            GroupPart::CircleShare(circle, _share) => Some(*circle),
+            _ => None,
+        })
+        .collect();
+    for (index, circle) in info.share_config.circles.iter().enumerate() {
+        if circle.required && !u8::try_from(index).is_ok_and(|index| present.contains(&index)) {
+            return Err(make_error(
+                "circle_missing",
+                &format!(
+                    "Insufficient circle data: No key pieces were given for the required circle \"{}\". At least one of its members must provide their key.",
+                    circle.name
+                ),
+            ));
+        }
+    }
+    return Ok(());
+}
+
+// Rebuilds Z and the group keys of any circles from verifiable pieces, marking pieces with shares which don't fit.
+fn combine_verifiable_pieces(
+    pieces: &[(usize, PieceParts)],
+    format: PieceFormat,
+    bad_pieces: &mut BTreeMap<usize, AppError>,
+) -> AppResult<Vec<Vec<u8>>> {
+    let (individual_shares, circle_shares) = collect_shares(pieces.iter());
+    let z =
+        combine_verifiable_shares(&individual_shares, format, bad_pieces)?.ok_or(make_error(
+            "indiv_combine",
+            "You have not provided enough keys. Please provide more.",
+        ))?;
+
+    let mut keyshares = Vec::from([z]);
+    for shares in circle_shares.into_values() {
+        keyshares.push(combine_verifiable_shares(&shares, format, bad_pieces)?.ok_or(make_error(
+            "circle_combine",
+            "Insufficient circle data: Not enough members of a required circle have provided their keys.",
+        ))?);
+    }
+    return Ok(keyshares);
+}
+
+fn combine_verifiable_shares(
+    shares: &[(usize, Vec<u8>)],
+    format: PieceFormat,
+    bad_pieces: &mut BTreeMap<usize, AppError>,
+) -> AppResult<Option<Vec<u8>>> {
+    let share_format = format.share_format().ok_or(app_error(
+        "Legacy pieces can't be combined as verifiable pieces".to_string(),
+    ))?;
+    let combined = match combine_secret(&just_shares(shares), format.secret_len(), share_format)? {
+        Some(combined) => combined,
+        None => return Ok(None),
+    };
+    mark_bad_shares(shares, &combined.bad_shares, bad_pieces);
+    return Ok(Some(combined.secret));
+}
+
+// Rebuilds the mask and the parts of the main key from derived pieces, marking pieces which don't fit. Any set of
+// shares gives some scalar, so where there are no spare shares to show which are wrong, the scalars each part could
+// come from are tried together until the main key matches the pieces' key id.
+fn combine_derived_pieces(
+    pieces: &[(usize, PieceParts)],
+    key_id: Option<&[u8]>,
+    bad_pieces: &mut BTreeMap<usize, AppError>,
+) -> AppResult<Vec<Vec<u8>>> {
+    // Every piece has the same mask, so go with the most common one.
+    let mut mask_counts: BTreeMap<&[u8], usize> = BTreeMap::new();
+    for (_index, parts) in pieces.iter() {
+        *mask_counts.entry(&parts.mask).or_default() += 1;
+    }
+    let mask = mask_counts
+        .into_iter()
+        .max_by_key(|(_mask, count)| *count)
+        .map(|(mask, _count)| mask.to_vec())
+        .unwrap_or_default();
+    for (index, _parts) in pieces.iter().filter(|(_index, parts)| parts.mask != mask) {
+        bad_pieces
+            .entry(*index)
+            .or_insert_with(|| inconsistent_piece(*index));
+    }
+
+    let (individual_shares, circle_shares) =
+        collect_shares(pieces.iter().filter(|(_index, parts)| parts.mask == mask));
+    let mut parts: Vec<(IndexedShares, Vec<Combined>)> = Vec::new();
+    let z = combine_random(&just_shares(&individual_shares), ShareFormat::Wide)?;
+    if z.is_empty() {
+        return Err(make_error(
+            "indiv_combine",
+            "You have not provided enough keys. Please provide more.",
+        ));
+    }
+    parts.push((individual_shares, z));
+    for shares in circle_shares.into_values() {
+        let group_key = combine_random(&just_shares(&shares), ShareFormat::Wide)?;
+        if group_key.is_empty() {
+            return Err(make_error(
+                "circle_combine",
+                "Insufficient circle data: Not enough members of a required circle have provided their keys.",
+            ));
+        }
+        parts.push((shares, group_key));
+    }
+
+    // Which of the scalars for each part is being tried, the ones fitting the most shares first.
+    let mut choice: Vec<usize> = vec![0; parts.len()];
+    for _ in 0..MAX_DERIVED_TRIES {
+        let mut keyshares = Vec::from([mask.clone()]);
+        for ((_shares, scalars), chosen) in parts.iter().zip(choice.iter()) {
+            keyshares.push(derive_key_part(&scalars[*chosen].secret)?.to_vec());
+        }
+        let key = join_main_key(&keyshares)?;
+        if key_id.is_none_or(|key_id| derive_key_id(&key) == key_id) {
+            for ((shares, scalars), chosen) in parts.iter().zip(choice.iter()) {
+                mark_bad_shares(shares, &scalars[*chosen].bad_shares, bad_pieces);
+            }
+            return Ok(keyshares);
+        }
+
+        // Move on to the next combination, like counting.
+        let mut carried = true;
+        for ((_shares, scalars), chosen) in parts.iter().zip(choice.iter_mut()) {
+            *chosen += 1;
+            if *chosen < scalars.len() {
+                carried = false;
+                break;
+            }
+            *chosen = 0;
+        }
+        if carried {
+            break;
+        }
+    }
+    return Err(make_error(
+        "piece_inconsistent",
+        "The key pieces do not fit together. One or more of them may have been mistyped or changed, so check them with their owners.",
+    ));
+}
+
+// The individual shares, and the shares of each circle's group key by circle index, of verifiable pieces.
+fn collect_shares<'a>(
+    pieces: impl Iterator<Item = &'a (usize, PieceParts)>,
+) -> (IndexedShares, BTreeMap<u8, IndexedShares>) {
+    let mut individual_shares: IndexedShares = Vec::new();
+    let mut circle_shares: BTreeMap<u8, IndexedShares> = BTreeMap::new();
+    for (index, parts) in pieces {
+        for share in parts.shares.iter() {
+            individual_shares.push((*index, share.clone()));
+        }
+        if let GroupPart::CircleShare(circle, share) = &parts.group {
+            circle_shares
+                .entry(*circle)
+                .or_default()
+                .push((*index, share.clone()));
+        }
+    }
+    return (individual_shares, circle_shares);
+}
 
-    let mut share_token: [u8; READ_ONLY_CLOUD_KEY_LEN] = [0; READ_ONLY_CLOUD_KEY_LEN];
-    share_token.copy_from_slice(&key[0..READ_ONLY_CLOUD_KEY_LEN]);
+fn just_shares(shares: &[(usize, Vec<u8>)]) -> Vec<Vec<u8>> {
+    return shares.iter().map(|(_index, share)| share.clone()).collect();
+}
+
+// Marks the pieces which gave the shares at the positions given, as not fitting with the others.
+fn mark_bad_shares(
+    shares: &[(usize, Vec<u8>)],
+    bad_shares: &[usize],
+    bad_pieces: &mut BTreeMap<usize, AppError>,
+) {
+    for position in bad_shares.iter() {
+        let index = shares[*position].0;
+        bad_pieces
+            .entry(index)
+            .or_insert_with(|| inconsistent_piece(index));
+    }
+}
+
+fn inconsistent_piece(index: usize) -> AppError {
+    return piece_error(
+        "piece_inconsistent",
+        index + 1,
+        "does not fit with the other key pieces. It may have been mistyped or changed, so check it with its owner.",
+    );
+}
+
+// Rebuilds Z and the group keys from legacy pieces. These can't be checked one by one, so if the pieces don't
+// combine, sets of them are left out in turn until the rest do.
+fn combine_legacy_pieces(
+    pieces: &[(usize, PieceParts)],
+    bad_pieces: &mut BTreeMap<usize, AppError>,
+) -> AppResult<Vec<Vec<u8>>> {
+    let mut first_error: Option<AppError> = None;
+    for left_out_count in 0..=MAX_LEGACY_LEFT_OUT.min(pieces.len()) {
+        let mut left_out: Vec<usize> = (0..left_out_count).collect();
+        loop {
+            let kept: Vec<&PieceParts> = pieces
+                .iter()
+                .enumerate()
+                .filter(|(i, _piece)| !left_out.contains(i))
+                .map(|(_i, (_index, parts))| parts)
+                .collect();
+            match combine_legacy_parts(&kept) {
+                Ok(keyshares) => {
+                    for i in left_out {
+                        let index = pieces[i].0;
+                        bad_pieces.insert(index, inconsistent_piece(index));
+                    }
+                    return Ok(keyshares);
+                }
+                Err(e) => {
+                    first_error.get_or_insert(e);
+                }
+            }
+            if !next_combination(&mut left_out, pieces.len()) {
+                break;
+            }
+        }
+    }
+    return Err(first_error.unwrap_or(make_error(
+        "indiv_combine",
+        "You have not provided enough keys. Please provide more.",
+    )));
+}
 
-    let owner_token = &tokens[0..OWNER_CLOUD_KEY_LEN];
+fn combine_legacy_parts(pieces: &[&PieceParts]) -> AppResult<Vec<Vec<u8>>> {
+    // Use sets so the values are unique. Saves having to check for duplicates manually.
+    let mut group_keys: HashSet<Vec<u8>> = HashSet::new();
+    let mut individual_keys: HashSet<Vec<u8>> = HashSet::new();
+    // Shares of group keys, by circle index.
+    let mut circle_shares: HashMap<u8, HashSet<Vec<u8>>> = HashMap::new();
+    for parts in pieces.iter() {
+        match &parts.group {
+            GroupPart::GroupKey(group_key) => {
+                group_keys.insert(group_key.clone());
+            }
+            GroupPart::CircleShare(circle, share) => {
+                circle_shares
+                    .entry(*circle)
+                    .or_default()
+                    .insert(share.clone());
+            }
+            GroupPart::None => {}
+        }
+        individual_keys.extend(parts.shares.iter().cloned());
+    }
+
+    // Try combine indiv. keys
+    let vec_keys = Vec::from_iter(individual_keys);
+    let z = combine_shares(&vec_keys)?
+        .map(|padded_z| unpad_key(&padded_z))
+        .transpose()?
+        .ok_or(make_error(
+            "indiv_combine",
+            "You have not provided enough keys. Please provide more.",
+        ))?;
+
+    // Rebuild the group keys of circles with a threshold.
+    for shares in circle_shares.into_values() {
+        let shares = Vec::from_iter(shares);
+        let group_key = combine_shares(&shares)?
+            .map(|padded_group_key| unpad_key(&padded_group_key))
+            .transpose()?;
+        group_keys.insert(group_key.ok_or(make_error(
+            "circle_combine",
+            "Insufficient circle data: Not enough members of a required circle have provided their keys.",
+        ))?);
+    }
+
+    let mut keyshares = Vec::from_iter(group_keys);
+    keyshares.push(z);
+    return Ok(keyshares);
+}
+
+/// Writes a key piece as words (see mnemonic.rs), for writing on paper or reading out.
+/// The words carry the vault's key id, the piece's circle and member and the owner fingerprint, like the envelope, so
+/// they can be given to combine_keys as they are. Pieces from before pieces had envelopes can't be written as words.
+pub fn piece_to_mnemonic(piece: &[u8]) -> AppResult<String> {
+    let (info, raw) = unwrap_piece(piece, 1)?;
+    let info = info.ok_or(make_error(
+        "piece_words",
+        "This key piece was made by an older version of the app, so it can't be written as words.",
+    ))?;
+
+    // The envelope version comes first. It is never 0, as the words can't start with a 0 byte.
+    let mut data = Vec::from([info.version()]);
+    data.extend_from_slice(&info.key_id);
+    data.push(info.circle);
+    data.extend_from_slice(&info.member.to_be_bytes());
+    data.extend_from_slice(&info.owner_fingerprint);
+    data.extend_from_slice(&raw);
+    return Ok(encode_words(&data).join(" "));
+}
+
+/// Reads a key piece written by piece_to_mnemonic, and puts it back in its envelope.
+/// The position is the piece's place in the list the user gave, starting from 1, and is used in errors.
+pub fn mnemonic_to_piece(mnemonic: &str, position: usize) -> AppResult<KeyPiece> {
+    let data = decode_words(mnemonic, position)?;
+    let too_short = || {
+        return piece_error(
+            "piece_words",
+            position,
+            "is too short. Check that no words are missing.",
+        );
+    };
+    let (member_len, fingerprint_len) = envelope_layout(*data.first().ok_or_else(too_short)?)
+        .ok_or(piece_error(
+            "piece_version",
+            position,
+            "was made by a newer version of the app. Please update the app.",
+        ))?;
+    let member_start = 1 + KEY_ID_LEN + 1;
+    let fingerprint_start = member_start + member_len;
+    let raw_start = fingerprint_start + fingerprint_len;
+    if data.len() <= raw_start {
+        return Err(too_short());
+    }
+
+    let info = PieceInfo {
+        key_id: data[1..1 + KEY_ID_LEN].to_vec(),
+        circle: data[1 + KEY_ID_LEN],
+        member: read_member_index(&data[member_start..fingerprint_start]),
+        owner_fingerprint: data[fingerprint_start..raw_start].to_vec(),
+    };
+    return Ok(wrap_piece(&info, &data[raw_start..]));
+}
+
+/// Generates a main key and its cloud credentials.
+/// Both tokens are derived, so no bytes of any key are ever sent to the server.
+pub fn generate_cloud_creds() -> (Key, CloudKeyData) {
+    let key = generate_key();
 
     let creds = CloudKeyData {
-        owner_token: owner_token.to_vec(),
-        share_token: share_token.to_vec(),
+        owner_token: derive_owner_token(&key),
+        share_token: derive_lookup_token(&derive_recipient_secret(&key)),
     };
 
     return (key, creds);
@@ -283,12 +1716,21 @@ pub fn generate_cloud_creds() -> (Key, CloudKeyData) {
 #[cfg(test)]
 pub(crate) mod tests {
     use crate::crypto::{
-        combine_keys, decrypt_file, encrypt_file, generate_circle_keys, generate_key,
-        generate_nonce, Key, KeyPiece,
+        add_circle_members, combine_keys, combine_pieces, derive_content_key, derive_key_id,
+        derive_lookup_token, derive_owner_state_key, derive_owner_token, derive_recipient_secret,
+        generate_circle_keys, generate_cloud_creds, generate_key, generate_stream_nonce,
+        legacy_lookup_token, mnemonic_to_piece, open_sealed, piece_to_mnemonic, seal, split_piece,
+        Cipher, DecryptReader, EncryptWriter, GroupPart, Key, KeyPiece, PieceFormat,
+        DERIVED_SHARE_LEN, MASK_LEN, STREAM_CHUNK_LEN, STREAM_SEALED_CHUNK_LEN,
     };
     use crate::error::AppError;
-    use crate::vault::Circle;
+    use crate::piece::{unwrap_piece, wrap_piece};
+    use crate::vault::{
+        Circle, KeyCollection, Lineage, PublicInfo, ShareCommitments, ShareConfiguration,
+    };
+    use crate::vss::{verify_share, ShareFormat};
     use std::collections::HashSet;
+    use std::io::{Read, Write};
 
     pub fn get_basic_combo() -> Vec<Circle> {
         return Vec::from([Circle {
@@ -302,9 +1744,28 @@ pub(crate) mod tests {
             ]),
             name: "Friends".to_string(),
             keys: None,
+            threshold: 1,
+            weights: Vec::new(),
+            recipients: Vec::new(),
+            sealed_keys: None,
         }]);
     }
 
+    pub fn get_public_info(circles: Vec<Circle>, required: u16) -> PublicInfo {
+        return PublicInfo {
+            share_config: ShareConfiguration { required, circles },
+            name: "Test".to_string(),
+            email_address: "test@example.com".to_string(),
+            nonce: vec![],
+            path: "/foo/bar".to_string(),
+            key_id: vec![],
+            commitments: ShareCommitments::default(),
+            owner_fingerprint: vec![],
+            key_commitment: vec![],
+            lineage: Lineage::default(),
+        };
+    }
+
     #[test]
     fn basic_key_combination() {
         let circles: Vec<Circle> = get_basic_combo();
@@ -326,12 +1787,20 @@ pub(crate) mod tests {
                 ]),
                 name: "Friends".to_string(),
                 keys: None,
+                threshold: 1,
+                weights: Vec::new(),
+                recipients: Vec::new(),
+                sealed_keys: None,
             },
             Circle {
                 required: true,
                 key_comments: Vec::from(["a".to_string()]),
                 name: "Family".to_string(),
                 keys: None,
+                threshold: 1,
+                weights: Vec::new(),
+                recipients: Vec::new(),
+                sealed_keys: None,
             },
         ]);
 
@@ -352,18 +1821,72 @@ pub(crate) mod tests {
                 ]),
                 name: "Friends".to_string(),
                 keys: None,
+                threshold: 1,
+                weights: Vec::new(),
+                recipients: Vec::new(),
+                sealed_keys: None,
             },
             Circle {
                 required: true,
                 key_comments: Vec::from(["a".to_string(), "b".to_string()]),
                 name: "Family".to_string(),
                 keys: None,
+                threshold: 1,
+                weights: Vec::new(),
+                recipients: Vec::new(),
+                sealed_keys: None,
             },
         ]);
 
         test_circle_combination(circles, 3).expect("Failed to do circle test");
     }
 
+    // Without a required circle the parts of the main key would still join, to the wrong key, so the missing circle
+    // is named instead.
+    #[test]
+    fn required_circle_missing() {
+        let circles: Vec<Circle> = Vec::from([
+            Circle {
+                required: false,
+                key_comments: Vec::from(["a".to_string(), "b".to_string(), "c".to_string()]),
+                name: "Friends".to_string(),
+                keys: None,
+                threshold: 1,
+                weights: Vec::new(),
+                recipients: Vec::new(),
+                sealed_keys: None,
+            },
+            Circle {
+                required: true,
+                key_comments: Vec::from(["d".to_string(), "e".to_string()]),
+                name: "Family".to_string(),
+                keys: None,
+                threshold: 1,
+                weights: Vec::new(),
+                recipients: Vec::new(),
+                sealed_keys: None,
+            },
+        ]);
+        let key = generate_key();
+        let (circles, commitments, _coefficients) =
+            generate_circle_keys(&key, &[], circles, 2).expect("Failed to generate keys");
+        let info = PublicInfo {
+            key_id: derive_key_id(&key),
+            commitments,
+            ..get_public_info(circles.clone(), 2)
+        };
+        let friends = circles[0].keys.clone().unwrap();
+        let family = circles[1].keys.clone().unwrap();
+
+        let err = combine_pieces(&friends, Some(&info)).expect_err("Combined without Family");
+        assert_eq!(err.error_type, "circle_missing");
+        assert!(err.message.contains("\"Family\""));
+
+        let combined = combine_pieces(&[friends[0].clone(), family[1].clone()], Some(&info))
+            .expect("Failed to combine");
+        assert_eq!(combined.key, key);
+    }
+
     #[test]
     fn complex() {
         let circles: Vec<Circle> = Vec::from([
@@ -378,30 +1901,260 @@ pub(crate) mod tests {
                 ]),
                 name: "One".to_string(),
                 keys: None,
+                threshold: 1,
+                weights: Vec::new(),
+                recipients: Vec::new(),
+                sealed_keys: None,
             },
             Circle {
                 required: true,
                 key_comments: Vec::from(["a".to_string(), "b".to_string()]),
                 name: "Two".to_string(),
                 keys: None,
+                threshold: 1,
+                weights: Vec::new(),
+                recipients: Vec::new(),
+                sealed_keys: None,
             },
             Circle {
                 required: false,
                 key_comments: Vec::from(["a".to_string(), "b".to_string(), "e".to_string()]),
                 name: "Three".to_string(),
                 keys: None,
+                threshold: 1,
+                weights: Vec::new(),
+                recipients: Vec::new(),
+                sealed_keys: None,
             },
             Circle {
                 required: false,
                 key_comments: Vec::from(["a".to_string()]),
                 name: "Four".to_string(),
                 keys: None,
+                threshold: 1,
+                weights: Vec::new(),
+                recipients: Vec::new(),
+                sealed_keys: None,
             },
         ]);
 
         test_circle_combination(circles, 8).expect("Failed to do circle test");
     }
 
+    // 2 of 3 siblings AND any 3 of 6 friends, plus 7 keys in total.
+    fn get_threshold_combo() -> Vec<Circle> {
+        return Vec::from([
+            Circle {
+                required: true,
+                key_comments: Vec::from(["a".to_string(), "b".to_string(), "c".to_string()]),
+                name: "Siblings".to_string(),
+                keys: None,
+                threshold: 2,
+                weights: Vec::new(),
+                recipients: Vec::new(),
+                sealed_keys: None,
+            },
+            Circle {
+                required: true,
+                key_comments: Vec::from([
+                    "a".to_string(),
+                    "b".to_string(),
+                    "c".to_string(),
+                    "d".to_string(),
+                    "e".to_string(),
+                    "f".to_string(),
+                ]),
+                name: "Friends".to_string(),
+                keys: None,
+                threshold: 3,
+                weights: Vec::new(),
+                recipients: Vec::new(),
+                sealed_keys: None,
+            },
+            Circle {
+                required: true,
+                key_comments: Vec::from(["a".to_string(), "b".to_string()]),
+                name: "Parents".to_string(),
+                keys: None,
+                threshold: 1,
+                weights: Vec::new(),
+                recipients: Vec::new(),
+                sealed_keys: None,
+            },
+            Circle {
+                required: false,
+                key_comments: Vec::from(["a".to_string(), "b".to_string()]),
+                name: "Others".to_string(),
+                keys: None,
+                threshold: 1,
+                weights: Vec::new(),
+                recipients: Vec::new(),
+                sealed_keys: None,
+            },
+        ]);
+    }
+
+    #[test]
+    fn circle_thresholds() {
+        test_circle_combination(get_threshold_combo(), 7).expect("Failed to do circle test");
+        test_circle_combination(get_threshold_combo(), 13).expect("Failed to do circle test");
+    }
+
+    #[test]
+    fn circle_thresholds_minimum() {
+        let key = generate_key();
+        let (circles, _commitments, _coefficients) =
+            generate_circle_keys(&key, &[], get_threshold_combo(), 7)
+                .expect("Did not receive circles with keys.");
+        let keys: Vec<Vec<KeyPiece>> = circles
+            .into_iter()
+            .map(|c| c.keys.expect("Keys not set"))
+            .collect();
+
+        // 2 siblings, 3 friends, 1 parent and 1 other.
+        let mut just_keys: Vec<KeyPiece> = Vec::new();
+        just_keys.extend_from_slice(&keys[0][1..3]);
+        just_keys.extend_from_slice(&keys[1][2..5]);
+        just_keys.push(keys[2][1].clone());
+        just_keys.push(keys[3][0].clone());
+        assert_eq!(
+            combine_keys(just_keys.clone()).expect("Failed to combine"),
+            key
+        );
+
+        // Enough keys in total, but only one sibling.
+        let mut one_sibling = just_keys.clone();
+        one_sibling.remove(0);
+        one_sibling.push(keys[1][0].clone());
+        assert_eq!(
+            combine_keys(one_sibling)
+                .expect_err("Combined without enough siblings")
+                .error_type,
+            "circle_combine"
+        );
+
+        // Enough keys in total, but only two friends.
+        let mut two_friends = just_keys.clone();
+        two_friends.remove(2);
+        two_friends.push(keys[3][1].clone());
+        assert_eq!(
+            combine_keys(two_friends)
+                .expect_err("Combined without enough friends")
+                .error_type,
+            "circle_combine"
+        );
+    }
+
+    #[test]
+    fn bad_circle_threshold() {
+        let mut circles = get_threshold_combo();
+        circles[0].threshold = 4;
+        let result = generate_circle_keys(&generate_key(), &[], circles, 7);
+        assert_eq!(
+            result.expect_err("Expected an error").error_type,
+            "circle_threshold"
+        );
+
+        let mut circles = get_threshold_combo();
+        circles[1].threshold = 0;
+        let result = generate_circle_keys(&generate_key(), &[], circles, 7);
+        assert_eq!(
+            result.expect_err("Expected an error").error_type,
+            "circle_threshold"
+        );
+
+        // Thresholds of circles which are not required are ignored.
+        let mut circles = get_threshold_combo();
+        circles[3].threshold = 0;
+        test_circle_combination(circles, 7).expect("Failed to do circle test");
+    }
+
+    // An executor and a spouse who count for more than friends.
+    fn get_weighted_combo() -> Vec<Circle> {
+        return Vec::from([
+            Circle {
+                required: true,
+                key_comments: Vec::from(["Executor".to_string(), "Spouse".to_string()]),
+                name: "Family".to_string(),
+                keys: None,
+                threshold: 1,
+                weights: Vec::from([3, 2]),
+                recipients: Vec::new(),
+                sealed_keys: None,
+            },
+            Circle {
+                required: false,
+                key_comments: Vec::from(["a".to_string(), "b".to_string(), "c".to_string()]),
+                name: "Friends".to_string(),
+                keys: None,
+                threshold: 1,
+                weights: Vec::new(),
+                recipients: Vec::new(),
+                sealed_keys: None,
+            },
+        ]);
+    }
+
+    #[test]
+    fn weighted_keys() {
+        test_circle_combination(get_weighted_combo(), 4).expect("Failed to do circle test");
+        test_circle_combination(get_weighted_combo(), 8).expect("Failed to do circle test");
+
+        let key = generate_key();
+        let (circles, _commitments, _coefficients) =
+            generate_circle_keys(&key, &[], get_weighted_combo(), 4)
+                .expect("Did not receive circles with keys.");
+        let family = circles[0].keys.clone().expect("Keys not set");
+        let friends = circles[1].keys.clone().expect("Keys not set");
+        let (_info, executor_piece) = unwrap_piece(&family[0], 1).expect("Failed to unwrap");
+        assert_eq!(
+            executor_piece.len(),
+            6 + MASK_LEN + 3 * DERIVED_SHARE_LEN + 1 + DERIVED_SHARE_LEN
+        );
+        let (_info, friend_piece) = unwrap_piece(&friends[0], 1).expect("Failed to unwrap");
+        assert_eq!(friend_piece.len(), 6 + MASK_LEN + DERIVED_SHARE_LEN);
+
+        // The executor and one friend make 4.
+        let result = combine_keys(Vec::from([family[0].clone(), friends[0].clone()]));
+        assert_eq!(result.expect("Failed to combine"), key);
+
+        // The spouse and one friend only make 3.
+        let result = combine_keys(Vec::from([family[1].clone(), friends[0].clone()]));
+        assert_eq!(
+            result.expect_err("Combined with too few shares").error_type,
+            "indiv_combine"
+        );
+
+        // Giving the same piece twice does not count twice.
+        let result = combine_keys(Vec::from([family[1].clone(), family[1].clone()]));
+        assert_eq!(
+            result
+                .expect_err("Combined with duplicate pieces")
+                .error_type,
+            "indiv_combine"
+        );
+    }
+
+    #[test]
+    fn bad_weights() {
+        let mut circles = get_weighted_combo();
+        circles[1].weights = Vec::from([1, 0, 1]);
+        let result = generate_circle_keys(&generate_key(), &[], circles, 3);
+        assert_eq!(
+            result.expect_err("Expected an error").error_type,
+            "bad_weight"
+        );
+
+        let mut circles = get_weighted_combo();
+        circles[1].key_comments = vec!["Member".to_string(); 260];
+        circles[1].weights = vec![255; 260];
+        let result = generate_circle_keys(&generate_key(), &[], circles, 3);
+        assert_eq!(
+            result.expect_err("Expected an error").error_type,
+            "too_many_keys"
+        );
+    }
+
     #[test]
     fn one() {
         let circles: Vec<Circle> = Vec::from([Circle {
@@ -409,6 +2162,10 @@ pub(crate) mod tests {
             key_comments: Vec::from(["a".to_string()]),
             name: "One".to_string(),
             keys: None,
+            threshold: 1,
+            weights: Vec::new(),
+            recipients: Vec::new(),
+            sealed_keys: None,
         }]);
 
         test_circle_combination(circles, 1).expect("Failed to do circle test");
@@ -421,6 +2178,10 @@ pub(crate) mod tests {
             key_comments: Vec::from(["a".to_string(), "b".to_string()]),
             name: "One".to_string(),
             keys: None,
+            threshold: 1,
+            weights: Vec::new(),
+            recipients: Vec::new(),
+            sealed_keys: None,
         }]);
         test_circle_combination(circles.clone(), 1).expect("Failed to do circle test");
         test_circle_combination(circles, 2).expect("Failed to do circle test");
@@ -439,6 +2200,10 @@ pub(crate) mod tests {
             key_comments,
             name: "One".to_string(),
             keys: None,
+            threshold: 1,
+            weights: Vec::new(),
+            recipients: Vec::new(),
+            sealed_keys: None,
         }]);
         test_circle_combination(all_in_one_circle.clone(), 3).expect("Failed to do circle test");
         test_circle_combination(all_in_one_circle.clone(), 200).expect("Failed to do circle test");
@@ -463,6 +2228,10 @@ pub(crate) mod tests {
                 key_comments: keys,
                 name: format!("Circle {}", c),
                 keys: None,
+                threshold: 1,
+                weights: Vec::new(),
+                recipients: Vec::new(),
+                sealed_keys: None,
             };
             circles.push(new_circle);
         }
@@ -474,7 +2243,7 @@ pub(crate) mod tests {
             .into_iter()
             .filter(|circle| !circle.required)
             .collect();
-        test_circle_combination(required_only.clone(), required_only.len() as u8)
+        test_circle_combination(required_only.clone(), required_only.len() as u16)
             .expect("Failed to do circle test");
     }
 
@@ -485,12 +2254,17 @@ pub(crate) mod tests {
             key_comments: Vec::from(["a".to_string(), "b".to_string(), "a".to_string()]),
             name: "One".to_string(),
             keys: None,
+            threshold: 1,
+            weights: Vec::new(),
+            recipients: Vec::new(),
+            sealed_keys: None,
         }]);
-        let threshold: u8 = 2;
+        let threshold: u16 = 2;
 
         let key = generate_key();
-        let circles_with_keys = generate_circle_keys(&key, circles, threshold)
-            .expect("Did not receive circles with keys.");
+        let (circles_with_keys, _commitments, _coefficients) =
+            generate_circle_keys(&key, &[], circles, threshold)
+                .expect("Did not receive circles with keys.");
         let mut just_keys: Vec<KeyPiece> = circles_to_keys(circles_with_keys);
         just_keys.remove(0);
 
@@ -511,12 +2285,17 @@ pub(crate) mod tests {
             key_comments: Vec::from(["a".to_string(), "b".to_string(), "a".to_string()]),
             name: "One".to_string(),
             keys: None,
+            threshold: 1,
+            weights: Vec::new(),
+            recipients: Vec::new(),
+            sealed_keys: None,
         }]);
-        let threshold: u8 = 2;
+        let threshold: u16 = 2;
 
         let key = generate_key();
-        let circles_with_keys = generate_circle_keys(&key, circles, threshold)
-            .expect("Did not receive circles with keys.");
+        let (circles_with_keys, _commitments, _coefficients) =
+            generate_circle_keys(&key, &[], circles, threshold)
+                .expect("Did not receive circles with keys.");
         let mut just_keys: Vec<KeyPiece> = circles_to_keys(circles_with_keys);
         // Remove two keys - result is one key, not enough!
         just_keys.remove(0);
@@ -539,14 +2318,19 @@ pub(crate) mod tests {
             key_comments: Vec::from(["a".to_string(), "b".to_string(), "a".to_string()]),
             name: "One".to_string(),
             keys: None,
+            threshold: 1,
+            weights: Vec::new(),
+            recipients: Vec::new(),
+            sealed_keys: None,
         }]);
-        let threshold: u8 = 2;
+        let threshold: u16 = 2;
 
         let key = generate_key();
-        let circles_with_keys = generate_circle_keys(&key, circles, threshold)
-            .expect("Did not receive circles with keys.");
+        let (circles_with_keys, _commitments, _coefficients) =
+            generate_circle_keys(&key, &[], circles, threshold)
+                .expect("Did not receive circles with keys.");
         let mut just_keys: Vec<KeyPiece> = circles_to_keys(circles_with_keys);
-        // Remove two keys - result is one key, not enough!
+        // Remove one key - the two left are just enough.
         just_keys.remove(0);
         // Add an incorrect key.
         just_keys.push(Vec::from([
@@ -559,8 +2343,16 @@ pub(crate) mod tests {
             144,
         ]));
 
+        // The incorrect key is left out and named, as the others are enough.
+        let combined = combine_pieces(&just_keys, None).expect("Failed to combine");
+        assert_eq!(combined.key, key);
+        assert_eq!(combined.bad_pieces.len(), 1);
+        assert_eq!(combined.bad_pieces[0].error_type, "indiv_combine");
+        assert!(combined.bad_pieces[0].message.starts_with("Key piece 3 "));
+
+        // Without enough good keys, the incorrect key is blamed.
+        just_keys.remove(0);
         let result = combine_keys(just_keys);
-        assert!(result.is_err());
         assert_eq!(
             result
                 .expect_err("Expected error value, did not get one.")
@@ -569,10 +2361,431 @@ pub(crate) mod tests {
         );
     }
 
-    fn test_circle_combination(circles: Vec<Circle>, threshold: u8) -> Result<(), AppError> {
+    // Pieces which are well formed but changed are found by not fitting with the others.
+    #[test]
+    fn inconsistent_pieces_left_out() {
+        let circles: Vec<Circle> = Vec::from([
+            Circle {
+                required: true,
+                key_comments: Vec::from(["a".to_string(), "b".to_string(), "c".to_string()]),
+                name: "Family".to_string(),
+                keys: None,
+                threshold: 2,
+                weights: Vec::new(),
+                recipients: Vec::new(),
+                sealed_keys: None,
+            },
+            Circle {
+                required: false,
+                key_comments: Vec::from(["d".to_string(), "e".to_string()]),
+                name: "Friends".to_string(),
+                keys: None,
+                threshold: 1,
+                weights: Vec::new(),
+                recipients: Vec::new(),
+                sealed_keys: None,
+            },
+        ]);
+        let key = generate_key();
+        let (circles_with_keys, _commitments, _coefficients) =
+            generate_circle_keys(&key, &[], circles, 3)
+                .expect("Did not receive circles with keys.");
+        let mut just_keys: Vec<KeyPiece> = circles_to_keys(circles_with_keys);
+
+        // Change a share inside the second family member's piece, and give it a fresh envelope.
+        let (piece_info, mut raw) = unwrap_piece(&just_keys[1], 2).unwrap();
+        raw[6 + MASK_LEN + 10] ^= 0x01;
+        just_keys[1] = wrap_piece(&piece_info.unwrap(), &raw);
+        let combined = combine_pieces(&just_keys, None).expect("Failed to combine");
+        assert_eq!(combined.key, key);
+        let error_types: Vec<&str> = combined
+            .bad_pieces
+            .iter()
+            .map(|e| e.error_type.as_str())
+            .collect();
+        assert_eq!(error_types, Vec::from(["piece_inconsistent"]));
+        assert!(combined.bad_pieces[0].message.starts_with("Key piece 2 "));
+
+        // The same, but in the third member's group share. The second member's group share is still good.
+        let change_group_share = |piece: &KeyPiece, position: usize| {
+            let (piece_info, mut raw) = unwrap_piece(piece, position).unwrap();
+            let value = raw.len() - DERIVED_SHARE_LEN + 4;
+            raw[value] ^= 0x01;
+            return wrap_piece(&piece_info.unwrap(), &raw);
+        };
+        just_keys[2] = change_group_share(&just_keys[2], 3);
+        let combined = combine_pieces(&just_keys, None).expect("Failed to combine");
+        assert_eq!(combined.key, key);
+        assert_eq!(combined.bad_pieces.len(), 2);
+        assert!(combined.bad_pieces[1].message.starts_with("Key piece 3 "));
+
+        // Only one good group share is left.
+        just_keys[0] = change_group_share(&just_keys[0], 1);
+        let err = combine_pieces(&just_keys, None).expect_err("Combined with changed pieces");
+        assert_eq!(err.error_type, "piece_inconsistent");
+    }
+
+    #[test]
+    fn pieces_from_other_vaults() {
+        let circles = get_basic_combo();
+        let key = generate_key();
+        let just_keys = circles_to_keys(
+            generate_circle_keys(&key, &[], circles.clone(), 3)
+                .expect("No keys")
+                .0,
+        );
+        let other_keys = circles_to_keys(
+            generate_circle_keys(&generate_key(), &[], circles, 3)
+                .expect("No keys")
+                .0,
+        );
+
+        // The mixed in piece is left out and named, and the rest still combine.
+        let mut mixed = just_keys[0..2].to_vec();
+        mixed.push(other_keys[0].clone());
+        mixed.push(just_keys[2].clone());
+        let combined = combine_pieces(&mixed, None).expect("Failed to combine");
+        assert_eq!(combined.key, key);
+        assert_eq!(combined.bad_pieces.len(), 1);
+        assert_eq!(combined.bad_pieces[0].error_type, "piece_foreign");
+        assert!(combined.bad_pieces[0].message.starts_with("Key piece 3 "));
+
+        // Without enough pieces for any one vault, the foreign piece is named.
+        let mut mixed = just_keys[0..2].to_vec();
+        mixed.push(other_keys[0].clone());
+        let err = combine_keys(mixed).expect_err("Combined a foreign piece");
+        assert_eq!(err.error_type, "piece_foreign");
+        assert!(err.message.starts_with("Key piece 3 "));
+
+        // A damaged piece names the piece.
+        let mut damaged = just_keys[0..3].to_vec();
+        let last = damaged[1].len() - 1;
+        damaged[1][last] ^= 1;
+        let err = combine_keys(damaged).expect_err("Combined a damaged piece");
+        assert_eq!(err.error_type, "piece_checksum");
+        assert!(err.message.starts_with("Key piece 2 "));
+
+        // Pieces without an envelope still combine, including alongside ones with an envelope.
+        let mut legacy: Vec<KeyPiece> = Vec::new();
+        for (index, piece) in just_keys[0..2].iter().enumerate() {
+            legacy.push(unwrap_piece(piece, index + 1).unwrap().1);
+        }
+        legacy.push(just_keys[2].clone());
+        assert_eq!(combine_keys(legacy).expect("Failed to combine"), key);
+    }
+
+    // Pieces made before verifiable sharing (one required member and two friends, two needed) must still combine.
+    #[test]
+    fn pieces_as_words() {
+        let key = generate_key();
+        let just_keys = circles_to_keys(
+            generate_circle_keys(&key, &[], get_basic_combo(), 3)
+                .expect("No keys")
+                .0,
+        );
+
+        let words: Vec<String> = just_keys
+            .iter()
+            .map(|piece| piece_to_mnemonic(piece).expect("Failed to write as words"))
+            .collect();
+        for (piece, mnemonic) in just_keys.iter().zip(words.iter()) {
+            assert_eq!(
+                &mnemonic_to_piece(mnemonic, 1).expect("Failed to read words"),
+                piece
+            );
+        }
+
+        // Words can be mixed with pieces as bytes.
+        let mixed = Vec::from([
+            words[0].clone().into_bytes(),
+            just_keys[1].clone(),
+            words[4].to_uppercase().into_bytes(),
+        ]);
+        assert_eq!(combine_keys(mixed.clone()).expect("Failed to combine"), key);
+
+        // A wrong word is caught, and the piece left out if there are enough without it.
+        let mut wrong: Vec<&str> = words[2].split(' ').collect();
+        wrong[5] = if wrong[5] == "academic" {
+            "acid"
+        } else {
+            "academic"
+        };
+        let mut with_wrong = mixed.clone();
+        with_wrong.push(wrong.join(" ").into_bytes());
+        let combined = combine_pieces(&with_wrong, None).expect("Failed to combine");
+        assert_eq!(combined.key, key);
+        assert_eq!(combined.bad_pieces[0].error_type, "piece_checksum");
+        assert!(combined.bad_pieces[0].message.starts_with("Key piece 4 "));
+
+        let err = piece_to_mnemonic(&[9u8; 113]).expect_err("Wrote a legacy piece as words");
+        assert_eq!(err.error_type, "piece_words");
+    }
+
+    #[test]
+    fn legacy_pieces_combine() {
+        let key = from_hex("ce1b0e1f72c50223751c9b0b39f976240c3175447ac71b2cde0fe3050705be45");
+        let family = from_hex(
+            "444c4b50018d85b27b6ae6165d00000106f2f94a7acf5eea79edeb4583baea2188526bf37c6c5f3a9a4230badb768318\
+            358b9fb13b9ee56abccd94376770d2dcfceddab649fcfa3f328bca23136b7d3f9f3bb7aaa6aa61845c47f371cf131005\
+            f3d874d563ff04a7da414bc4626d7f0fd84dfa17808ede5d59ba2c525b3f1b900148e23c90f16de0a9dce91712eda736\
+            49ae82b5abb2771a6711f48f39073a07e03081c2c2",
+        );
+        let friends = [
+            from_hex(
+                "444c4b50018d85b27b6ae6165d010002b61c003632c096e109943997ae55b618a2952ad2183bad1eabfb92a7f08cf86b\
+                358b9fb13b9ee56abccd94376770d2dcfceddab649fcfa3f328bca23136b7d3f9f3bb7aaa6aa61845c47f371cf131005\
+                f3d874d563ff04a7da414bc4626d7f0fd84dfa17808ede5d59ba2c525b3f1b90b81e4013",
+            ),
+            from_hex(
+                "444c4b50018d85b27b6ae6165d0101032f4657eb0ac52711d04a77d9b5f9820f4d2115cdcdff0a024d6505ace9dad1b3\
+                358b9fb13b9ee56abccd94376770d2dcfceddab649fcfa3f328bca23136b7d3f9f3bb7aaa6aa61845c47f371cf131005\
+                f3d874d563ff04a7da414bc4626d7f0fd84dfa17808ede5d59ba2c525b3f1b90cf5ef633",
+            ),
+        ];
+
+        let result = combine_keys(Vec::from([family.clone(), friends[1].clone()]));
+        assert_eq!(result.expect("Failed to combine").to_vec(), key);
+        let result = combine_keys(Vec::from([friends[0].clone(), family.clone()]));
+        assert_eq!(result.expect("Failed to combine").to_vec(), key);
+
+        // Legacy pieces can't be mixed with verifiable ones, even without an envelope to tell them apart.
+        let circles = get_basic_combo();
+        let mut mixed = circles_to_keys(
+            generate_circle_keys(&generate_key(), &[], circles, 2)
+                .expect("No keys")
+                .0,
+        );
+        mixed.truncate(1);
+        mixed.push(unwrap_piece(&friends[0], 1).unwrap().1);
+        assert_eq!(
+            combine_keys(mixed)
+                .expect_err("Combined mixed pieces")
+                .error_type,
+            "indiv_combine"
+        );
+    }
+
+    // Pieces made with narrow verifiable shares, before vaults could have more than 255 shares, must still combine.
+    #[test]
+    fn verifiable_pieces_combine() {
+        let key = from_hex("f63a9aef633796f27d67325bc54582545ad28a34045c89d5a362af63ca1c95b8");
+        let family = from_hex(
+            "444c4b5001e6c4b3436395fd9a0000000102019645e76c75b88bbf03a6a49ee3339342bdec9b406dec1e20f2d2682672\
+            b58d0e6c4f064b4d44efec2dc9841e18e3c9e5c5223319312ec5a65696a2e45652d6070001010125d0b3c805897030e2\
+            2d9a3453da55a9619b76572ba60161bda3d8394e5700a5eb000000000000000000000000000000000000000000000000\
+            000000000000ad3983da",
+        );
+        let friend = from_hex(
+            "444c4b5001e6c4b3436395fd9a0100000102023d6ce3b32e5eb4e5c3565e20637be638c5acf717e87d8abd3d06d60a0d\
+            91900ce37f0c969a88ded95b92093d30c693cb8b456632625c8a4dad2c45c9ada4ac0fd4296dc0",
+        );
+
+        let result = combine_keys(Vec::from([family.clone(), friend.clone()]));
+        assert_eq!(result.expect("Failed to combine").to_vec(), key);
+        let (_info, raw) = unwrap_piece(&friend, 1).expect("Failed to unwrap");
+        assert_eq!(split_piece(&raw).unwrap().format, PieceFormat::Verifiable);
+
+        // They can't be mixed with derived pieces, even without an envelope to tell them apart.
+        let mut mixed = circles_to_keys(
+            generate_circle_keys(&generate_key(), &[], get_basic_combo(), 2)
+                .expect("No keys")
+                .0,
+        );
+        mixed.truncate(1);
+        mixed.push(unwrap_piece(&family, 1).unwrap().1);
+        assert_eq!(
+            combine_keys(mixed)
+                .expect_err("Combined mixed pieces")
+                .error_type,
+            "indiv_combine"
+        );
+    }
+
+    // Pieces made with wide shares of the parts of the main key, before the parts were derived, must still combine.
+    #[test]
+    fn wide_pieces_combine() {
+        let key = from_hex("7784401958b7bfa258710b7066353d399ecc6650b8a288fcd58af841dfcb2040");
+        let family = from_hex(
+            "444c4b5002ba651ad6ac58b64a000000000000010002000186d0c7b3c111f43ce86b9d71a4516688b66f1f6e9b2caf93\
+            e22082eba0550203974bfd6ec011bf2dd135a0d1bd0196d9190709ad60a8f1b54841e57320b34f0500000100010a4647\
+            8eece4466c6b5e4d2699b8821f3318effb52c640bd9e0a092b6a54e30089000000000000000000000000000000000000\
+            00000000000000000000000000b71ceb78",
+        );
+        let friend = from_hex(
+            "444c4b5002ba651ad6ac58b64a01000000000001000200028fde87d0cecfeeaa9ca8f48c49150deabf0ab5304cf495e5\
+            79c1126c8c0b41056596fadd80237e5ba26b40a37b032cb3330e125ac150e36b9182cae740669f0a4b7dab22",
+        );
+
+        let result = combine_keys(Vec::from([family.clone(), friend.clone()]));
+        assert_eq!(result.expect("Failed to combine").to_vec(), key);
+        let (_info, raw) = unwrap_piece(&friend, 1).expect("Failed to unwrap");
+        assert_eq!(split_piece(&raw).unwrap().format, PieceFormat::Wide);
+
+        // They can't be mixed with derived pieces.
+        let mut mixed = circles_to_keys(
+            generate_circle_keys(&generate_key(), &[], get_basic_combo(), 2)
+                .expect("No keys")
+                .0,
+        );
+        mixed.truncate(1);
+        mixed.push(unwrap_piece(&friend, 1).unwrap().1);
+        assert_eq!(
+            combine_keys(mixed)
+                .expect_err("Combined mixed pieces")
+                .error_type,
+            "indiv_combine"
+        );
+    }
+
+    #[test]
+    fn many_members() {
+        // More members than fit in a byte, with a threshold which doesn't either.
+        let members: Vec<String> = (0..300).map(|i| format!("Member {}", i)).collect();
+        let circles = Vec::from([Circle {
+            required: true,
+            key_comments: members,
+            name: "Community".to_string(),
+            keys: None,
+            threshold: 2,
+            weights: Vec::new(),
+            recipients: Vec::new(),
+            sealed_keys: None,
+        }]);
+        let key = generate_key();
+        let (circles, commitments, _coefficients) =
+            generate_circle_keys(&key, &[], circles, 280).expect("Failed to make keys");
+        assert_eq!(commitments.individual.chunks[0].len(), 280);
+        let pieces = circles_to_keys(circles);
+
+        let (info, _raw) = unwrap_piece(&pieces[299], 1).expect("Failed to unwrap");
+        assert_eq!(info.expect("No envelope").member, 299);
+        let words = piece_to_mnemonic(&pieces[299]).expect("Failed to write as words");
+        assert_eq!(mnemonic_to_piece(&words, 1).unwrap(), pieces[299]);
+
+        assert_eq!(
+            combine_keys(pieces[20..].to_vec()).expect("Failed to combine"),
+            key
+        );
+        assert_eq!(
+            combine_keys(pieces[21..].to_vec())
+                .expect_err("Combined with too few")
+                .error_type,
+            "indiv_combine"
+        );
+    }
+
+    #[test]
+    fn added_members() {
+        let key = generate_key();
+        let (circles, commitments, coefficients) =
+            generate_circle_keys(&key, &[], get_weighted_combo(), 4)
+                .expect("Did not receive circles with keys.");
+        let keys = KeyCollection {
+            share_keys: circles.clone(),
+            main: key,
+            commitments: commitments.clone(),
+            coefficients: Some(coefficients),
+            pieces_rebuild_main: false,
+            signing_seed: None,
+        };
+
+        // A grandchild joins the family, and a neighbour who counts as 2 joins the friends.
+        let mut grown = get_weighted_combo();
+        grown[0].key_comments.push("Grandchild".to_string());
+        grown[0].weights.push(1);
+        grown[1].key_comments.push("Neighbour".to_string());
+        grown[1].weights = Vec::from([1, 1, 1, 2]);
+        let added =
+            add_circle_members(&key, &keys, grown.clone(), 4).expect("Failed to add members");
+        let family = added[0].keys.clone().expect("Keys not set");
+        let friends = added[1].keys.clone().expect("Keys not set");
+        assert_eq!(family.len(), 3);
+        assert_eq!(friends.len(), 4);
+
+        // Everyone else keeps the piece they have.
+        assert_eq!(family[..2], circles[0].keys.clone().unwrap());
+        assert_eq!(friends[..3], circles[1].keys.clone().unwrap());
+
+        // The new shares follow on from the 8 already given, and check against the same commitments.
+        let (info, raw) = unwrap_piece(&family[2], 1).expect("Failed to unwrap");
+        assert_eq!(info.expect("No envelope").member, 2);
+        let parts = split_piece(&raw).expect("Failed to split");
+        assert_eq!(&parts.shares[0][..4], &[0, 4, 0, 9]);
+        assert!(verify_share(
+            &parts.shares[0],
+            &commitments.individual,
+            ShareFormat::Wide
+        ));
+        match parts.group {
+            GroupPart::CircleShare(0, share) => {
+                assert_eq!(&share[..4], &[0, 1, 0, 3]);
+                assert!(verify_share(
+                    &share,
+                    &commitments.circles[0],
+                    ShareFormat::Wide
+                ));
+            }
+            _ => panic!("Expected a share of the family's group key"),
+        }
+        let (_info, raw) = unwrap_piece(&friends[3], 1).expect("Failed to unwrap");
+        let parts = split_piece(&raw).expect("Failed to split");
+        assert_eq!(&parts.shares[1][..4], &[0, 4, 0, 11]);
+
+        // The new members unlock along with the old: 1 for the grandchild, 2 for the neighbour and 1 for a friend.
+        let result = combine_keys(Vec::from([
+            family[2].clone(),
+            friends[3].clone(),
+            friends[0].clone(),
+        ]));
+        assert_eq!(result.expect("Failed to combine"), key);
+
+        // With nobody new, the pieces are as they were.
+        let same = add_circle_members(&key, &keys, get_weighted_combo(), 4).unwrap();
+        assert_eq!(same[1].keys, circles[1].keys);
+
+        // Nothing else can change.
+        let mut changed = grown.clone();
+        changed[0].weights[1] = 1;
+        let result = add_circle_members(&key, &keys, changed, 4);
+        assert_eq!(result.unwrap_err().error_type, "circles_changed");
+        let mut changed = grown.clone();
+        changed[0].threshold = 2;
+        let result = add_circle_members(&key, &keys, changed, 4);
+        assert_eq!(result.unwrap_err().error_type, "circles_changed");
+        let result = add_circle_members(&key, &keys, grown.clone(), 5);
+        assert_eq!(result.unwrap_err().error_type, "circles_changed");
+
+        // Nor can vaults whose pieces were made before the parts of the main key were derived.
+        let mut wide_coefficients = keys.coefficients.clone().unwrap();
+        wide_coefficients.mask = Vec::new();
+        let wide_keys = KeyCollection {
+            coefficients: Some(wide_coefficients),
+            ..keys.clone()
+        };
+        let result = add_circle_members(&key, &wide_keys, grown.clone(), 4);
+        assert_eq!(result.unwrap_err().error_type, "members_fixed");
+
+        // Vaults made before the coefficients were kept can't have members added.
+        let old_keys = KeyCollection {
+            coefficients: None,
+            ..keys
+        };
+        let result = add_circle_members(&key, &old_keys, grown, 4);
+        assert_eq!(result.unwrap_err().error_type, "members_fixed");
+    }
+
+    fn from_hex(hex: &str) -> Vec<u8> {
+        return (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+            .collect();
+    }
+
+    fn test_circle_combination(circles: Vec<Circle>, threshold: u16) -> Result<(), AppError> {
         let key = generate_key();
-        let circles_with_keys = generate_circle_keys(&key, circles, threshold)
-            .expect("Did not receive circles with keys.");
+        let (circles_with_keys, _commitments, _coefficients) =
+            generate_circle_keys(&key, &[], circles, threshold)
+                .expect("Did not receive circles with keys.");
 
         let just_keys: Vec<KeyPiece> = circles_to_keys(circles_with_keys);
 
@@ -612,17 +2825,14 @@ pub(crate) mod tests {
     #[test]
     fn simple_encryption_decrypt() {
         let key = generate_key();
-        let nonce = generate_nonce();
+        let nonce = generate_stream_nonce(Cipher::ChaCha20Poly1305);
 
         let test_data = get_test_data();
 
-        let encrypted =
-            encrypt_file(&key, &test_data, &[], nonce).expect("Failed to encrypt sample file");
-
-        assert_eq!(encrypted.nonce, Vec::from(nonce.as_slice()));
-        assert!(encrypted.ciphertext.len() > 0);
+        let encrypted = seal_stream(&key, &nonce, &test_data);
+        assert!(encrypted.len() > 0);
 
-        let decrypted = decrypt_file(&key, encrypted).expect("Failed to decrypt");
+        let decrypted = open_stream(&key, &nonce, encrypted).expect("Failed to decrypt");
 
         assert_eq!(
             decrypted, test_data,
@@ -633,20 +2843,32 @@ pub(crate) mod tests {
     #[test]
     fn encrypt_with_aad() {
         let key = generate_key();
-        let nonce = generate_nonce();
+        let nonce = generate_stream_nonce(Cipher::ChaCha20Poly1305);
 
         let aad = "Lorem ipsum dolor sit amet, consectetur adipiscing elit. Proin hendrerit, nibh non volutpat laoreet, eros lacus placerat lacus, non scelerisque leo eros nec est. Mauris cursus diam hendrerit, finibus felis at, pellentesque sem. ".as_bytes();
 
         let test_data = get_test_data();
 
-        let encrypted =
-            encrypt_file(&key, &test_data, aad, nonce).expect("Failed to encrypt sample file");
-
-        assert_eq!(encrypted.nonce, Vec::from(nonce.as_slice()));
-        assert!(encrypted.ciphertext.len() > 0);
-        assert_eq!(encrypted.aad, aad);
-
-        let decrypted = decrypt_file(&key, encrypted).expect("Failed to decrypt");
+        let mut writer =
+            EncryptWriter::new(Cipher::ChaCha20Poly1305, &key, &nonce, aad, Vec::new())
+                .expect("Failed to make writer");
+        writer.write_all(&test_data).expect("Failed to write");
+        let encrypted = writer.finish().expect("Failed to encrypt sample file");
+        assert!(encrypted.len() > 0);
+
+        let mut reader = DecryptReader::new(
+            Cipher::ChaCha20Poly1305,
+            &key,
+            &nonce,
+            aad,
+            encrypted.as_slice(),
+        )
+        .expect("Failed to make reader");
+        let mut decrypted = Vec::new();
+        reader
+            .read_to_end(&mut decrypted)
+            .expect("Failed to decrypt");
+        reader.finish().expect("Failed to decrypt");
 
         assert_eq!(
             decrypted, test_data,
@@ -654,24 +2876,245 @@ pub(crate) mod tests {
         );
     }
 
+    #[test]
+    fn every_cipher() {
+        let key = generate_key();
+        let test_data = get_test_data();
+        let mut encrypted_with = Vec::new();
+
+        for cipher in [
+            Cipher::ChaCha20Poly1305,
+            Cipher::XChaCha20Poly1305,
+            Cipher::Aes256GcmSiv,
+        ] {
+            assert_eq!(Cipher::from_id(cipher.id()), Some(cipher));
+            let nonce = generate_stream_nonce(cipher);
+            assert_eq!(nonce.len(), cipher.nonce_len());
+
+            let mut writer = EncryptWriter::new(cipher, &key, &nonce, b"aad", Vec::new())
+                .expect("Failed to make writer");
+            writer.write_all(&test_data).expect("Failed to write");
+            let encrypted = writer.finish().expect("Failed to encrypt");
+
+            let mut reader = DecryptReader::new(cipher, &key, &nonce, b"aad", encrypted.as_slice())
+                .expect("Failed to make reader");
+            let mut decrypted = Vec::new();
+            reader
+                .read_to_end(&mut decrypted)
+                .expect("Failed to decrypt");
+            reader.finish().expect("Failed to decrypt");
+            assert_eq!(decrypted, test_data);
+
+            // A nonce for another cipher is refused rather than used.
+            let wrong_nonce = vec![0u8; cipher.nonce_len() + 1];
+            assert!(EncryptWriter::new(cipher, &key, &wrong_nonce, b"aad", Vec::new()).is_err());
+            encrypted_with.push((cipher, nonce, encrypted));
+        }
+
+        // Data can't be read as if it were encrypted with another cipher.
+        let (_cipher, nonce, encrypted) = &encrypted_with[0];
+        let mut reader = DecryptReader::new(
+            Cipher::Aes256GcmSiv,
+            &key,
+            nonce,
+            b"aad",
+            encrypted.as_slice(),
+        )
+        .expect("Failed to make reader");
+        assert!(reader.read_to_end(&mut Vec::new()).is_err());
+        assert_eq!(Cipher::from_id(0), None);
+        assert_eq!(Cipher::default(), Cipher::XChaCha20Poly1305);
+    }
+
     #[test]
     fn wrong_key_gives_error() {
         let key = generate_key();
         let other_key = generate_key();
-        let nonce = generate_nonce();
+        let nonce = generate_stream_nonce(Cipher::ChaCha20Poly1305);
 
         let test_data = get_test_data();
 
-        let encrypted = encrypt_file(&key, &test_data, &[], nonce).expect("failed to encrypt");
+        let encrypted = seal_stream(&key, &nonce, &test_data);
 
-        let decrypted = decrypt_file(&other_key, encrypted);
+        let decrypted = open_stream(&other_key, &nonce, encrypted);
         assert!(decrypted.is_err());
     }
 
+    // Streaming tests
+    #[test]
+    fn stream_round_trip() {
+        // Empty, less than one chunk, exactly one chunk, just over and several chunks.
+        let sizes = [
+            0,
+            1,
+            STREAM_CHUNK_LEN - 1,
+            STREAM_CHUNK_LEN,
+            STREAM_CHUNK_LEN + 1,
+            STREAM_CHUNK_LEN * 3 + 17,
+        ];
+        for size in sizes {
+            let key = generate_key();
+            let nonce = generate_stream_nonce(Cipher::ChaCha20Poly1305);
+            let data = get_stream_data(size);
+
+            let sealed = seal_stream(&key, &nonce, &data);
+            assert_eq!(
+                sealed.len(),
+                size + size.div_ceil(STREAM_CHUNK_LEN).max(1) * 16,
+                "Unexpected ciphertext length"
+            );
+
+            let opened = open_stream(&key, &nonce, sealed).expect("Failed to decrypt stream");
+            assert_eq!(opened, data, "Decrypted stream does not match for {}", size);
+        }
+    }
+
+    #[test]
+    fn stream_wrong_key_or_aad() {
+        let key = generate_key();
+        let nonce = generate_stream_nonce(Cipher::ChaCha20Poly1305);
+        let data = get_stream_data(STREAM_CHUNK_LEN * 2);
+        let sealed = seal_stream(&key, &nonce, &data);
+
+        assert!(open_stream(&generate_key(), &nonce, sealed.clone()).is_err());
+
+        let mut reader = DecryptReader::new(
+            Cipher::ChaCha20Poly1305,
+            &key,
+            &nonce,
+            b"other meta",
+            sealed.as_slice(),
+        )
+        .expect("Failed to make reader");
+        let mut out = Vec::new();
+        assert!(reader.read_to_end(&mut out).is_err());
+        assert!(reader.finish().is_err());
+    }
+
+    #[test]
+    fn stream_truncation_detected() {
+        let key = generate_key();
+        let nonce = generate_stream_nonce(Cipher::ChaCha20Poly1305);
+        let data = get_stream_data(STREAM_CHUNK_LEN * 3);
+        let sealed = seal_stream(&key, &nonce, &data);
+
+        // Drop the final chunk: The last remaining chunk was not sealed as the final one.
+        let truncated = sealed[..STREAM_SEALED_CHUNK_LEN * 2].to_vec();
+        let result = open_stream(&key, &nonce, truncated);
+        assert_eq!(result.expect_err("Expected error").error_type, "crypto");
+
+        // Cut part way through a chunk
+        let truncated = sealed[..sealed.len() - 5].to_vec();
+        assert!(open_stream(&key, &nonce, truncated).is_err());
+    }
+
+    #[test]
+    fn stream_reordering_detected() {
+        let key = generate_key();
+        let nonce = generate_stream_nonce(Cipher::ChaCha20Poly1305);
+        let data = get_stream_data(STREAM_CHUNK_LEN * 3);
+        let sealed = seal_stream(&key, &nonce, &data);
+
+        // Swap the first two chunks
+        let mut reordered = Vec::new();
+        reordered.extend_from_slice(&sealed[STREAM_SEALED_CHUNK_LEN..STREAM_SEALED_CHUNK_LEN * 2]);
+        reordered.extend_from_slice(&sealed[..STREAM_SEALED_CHUNK_LEN]);
+        reordered.extend_from_slice(&sealed[STREAM_SEALED_CHUNK_LEN * 2..]);
+
+        let result = open_stream(&key, &nonce, reordered);
+        assert_eq!(result.expect_err("Expected error").error_type, "crypto");
+    }
+
+    #[test]
+    fn derived_keys_are_independent() {
+        let main = generate_key();
+        let recipient_secret = derive_recipient_secret(&main);
+        let owner_key = derive_owner_state_key(&main);
+        let content_key = derive_content_key(&recipient_secret);
+
+        assert_ne!(recipient_secret, main);
+        assert_ne!(owner_key, main);
+        assert_ne!(recipient_secret, owner_key);
+        assert_ne!(content_key, recipient_secret);
+        assert_ne!(content_key, owner_key);
+        // Derivation must be stable, or old vaults could not be opened.
+        assert_eq!(recipient_secret, derive_recipient_secret(&main));
+    }
+
+    // The server must never see any bytes of a key.
+    #[test]
+    fn cloud_tokens_hide_keys() {
+        let (main, creds) = generate_cloud_creds();
+        let recipient_secret = derive_recipient_secret(&main);
+        assert_eq!(creds.share_token.len(), 8);
+        assert_eq!(creds.owner_token.len(), 16);
+        assert_eq!(creds.share_token, derive_lookup_token(&recipient_secret));
+        assert_eq!(creds.owner_token, derive_owner_token(&main));
+
+        let keys = [
+            main,
+            recipient_secret,
+            derive_content_key(&recipient_secret),
+            derive_owner_state_key(&main),
+        ];
+        for key in keys.iter() {
+            for token in [&creds.share_token, &creds.owner_token] {
+                assert!(
+                    !key.windows(4).any(|w| token.windows(4).any(|t| t == w)),
+                    "Token contains key bytes"
+                );
+            }
+        }
+        assert_ne!(creds.share_token, legacy_lookup_token(&recipient_secret));
+    }
+
+    #[test]
+    fn seal_round_trip() {
+        let key = generate_key();
+        let sealed = seal(&key, b"owner only", b"meta").expect("Failed to seal");
+
+        let opened = open_sealed(&key, &sealed, b"meta").expect("Failed to open");
+        assert_eq!(opened, b"owner only");
+
+        assert!(open_sealed(&generate_key(), &sealed, b"meta").is_err());
+        assert!(open_sealed(&key, &sealed, b"other meta").is_err());
+        assert!(open_sealed(&key, &sealed[..3], b"meta").is_err());
+    }
+
+    fn seal_stream(key: &Key, nonce: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut writer =
+            EncryptWriter::new(Cipher::ChaCha20Poly1305, key, nonce, b"meta", Vec::new())
+                .expect("Failed to make writer");
+        // Write in uneven pieces to check buffering across chunk boundaries.
+        for part in data.chunks(1000) {
+            writer.write_all(part).expect("Failed to write");
+        }
+        return writer.finish().expect("Failed to finish stream");
+    }
+
+    fn open_stream(key: &Key, nonce: &[u8], sealed: Vec<u8>) -> Result<Vec<u8>, AppError> {
+        let mut reader = DecryptReader::new(
+            Cipher::ChaCha20Poly1305,
+            key,
+            nonce,
+            b"meta",
+            sealed.as_slice(),
+        )?;
+        let mut out = Vec::new();
+        let read = reader.read_to_end(&mut out);
+        reader.finish()?;
+        read?;
+        return Ok(out);
+    }
+
+    fn get_stream_data(size: usize) -> Vec<u8> {
+        return (0..size).map(|i| (i % 251) as u8).collect();
+    }
+
     fn get_test_data() -> Vec<u8> {
         let rand1 = generate_key();
         let rand2 = generate_key();
-        let rand3 = generate_nonce();
+        let rand3 = generate_stream_nonce(Cipher::ChaCha20Poly1305);
         let mut buff: Vec<u8> = Vec::new();
         buff.extend_from_slice(rand1.as_slice());
         buff.extend_from_slice(rand2.as_slice());
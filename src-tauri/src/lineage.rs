@@ -0,0 +1,327 @@
+/*
+   lineage.rs
+   Vault versions, so that an old copy of a vault can't be passed off as the latest one.
+
+   Every vault has a random id, and a version which starts at 1 and goes up by one each time the vault is updated.
+   An update also records the SHA-256 of the vault file it replaced, so the versions can be followed back. These are
+   in the public info, so they are covered by the owner's signature (see signature.rs), and can't be changed.
+   The file an update replaced is kept as a backup.
+
+   The app remembers the latest version of each vault it has seen. An older version of a vault is refused, as somebody
+   may be presenting an old copy (with old contents, or pieces which were since replaced) as the current one.
+   It also remembers which vaults it has seen signed, by their key id, so a copy with the signature stripped off is
+   refused. The key id is checked against the one the key pieces rebuild, so a copy can't claim to be another vault.
+*/
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{self, File};
+use std::io::{self, BufReader};
+use std::path::{Path, PathBuf};
+
+use rmp_serde::Serializer;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::error::{make_error, AppError, AppResult};
+use crate::signature::{verify_vault_file, HashWriter};
+use crate::util::{get_partial_path, persist_file, write_file_atomic};
+use crate::vault::{Lineage, PreviousVersion};
+
+// What the app remembers about the vaults it has seen, kept in the versions file.
+#[derive(Deserialize, Serialize, Default)]
+struct SeenVaults {
+    // The latest version of each vault, by id.
+    versions: BTreeMap<Uuid, u64>,
+    // The key ids (see crypto::derive_key_id) of vaults which have been seen signed.
+    signed: BTreeSet<Vec<u8>>,
+}
+
+/// The lineage of a new vault.
+pub fn first_version() -> Lineage {
+    return Lineage {
+        vault_id: Uuid::new_v4(),
+        version: 1,
+        previous_hash: Vec::new(),
+    };
+}
+
+/// The SHA-256 of a whole vault file.
+pub fn hash_file(path: &Path) -> AppResult<Vec<u8>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut hasher = HashWriter::new(io::sink());
+    io::copy(&mut reader, &mut hasher)?;
+    let (_sink, digest) = hasher.finish();
+    return Ok(digest);
+}
+
+/// The lineage of a vault updated from the previous version given.
+/// The previous file must be the one the vault was opened from, so an older copy can't be swapped in while the vault
+/// is being updated. Vaults made before versions get an id when they are first updated.
+pub fn next_version(previous: &PreviousVersion) -> AppResult<Lineage> {
+    let hash = hash_file(Path::new(&previous.path)).map_err(|_e| {
+        make_error(
+            "previous_version",
+            "The vault being updated could not be read. Open the vault again to update it.",
+        )
+    })?;
+    if hash != previous.hash {
+        return Err(make_error(
+            "previous_version",
+            "The vault file changed after it was opened. Open the vault again to update it.",
+        ));
+    }
+
+    let lineage = verify_vault_file(&previous.path)?.info.lineage;
+    let vault_id = if lineage.vault_id.is_nil() {
+        Uuid::new_v4()
+    } else {
+        lineage.vault_id
+    };
+    let version = lineage
+        .version
+        .checked_add(1)
+        .ok_or_else(|| make_error("previous_version", "The vault has too many versions."))?;
+    return Ok(Lineage {
+        vault_id,
+        version,
+        previous_hash: hash,
+    });
+}
+
+/// Copies the version of a vault an update replaced into the backup folder, named by the vault's id and the version.
+/// Returns the path to the backup.
+pub fn back_up(
+    previous: &PreviousVersion,
+    lineage: &Lineage,
+    backup_folder: &Path,
+) -> AppResult<PathBuf> {
+    fs::create_dir_all(backup_folder)?;
+    let mut backup_path = backup_folder.to_path_buf();
+    backup_path.push(format!(
+        "{}-v{}",
+        lineage.vault_id,
+        lineage.version.saturating_sub(1)
+    ));
+    // Copied in full before it gets its name, so a backup is never part of a file.
+    let partial = get_partial_path(&backup_path);
+    let copied = fs::copy(&previous.path, &partial)
+        .and_then(|_| File::open(&partial)?.sync_all())
+        .map_err(AppError::from)
+        .and_then(|_| persist_file(&partial, &backup_path));
+    if copied.is_err() {
+        let _ = fs::remove_file(&partial);
+    }
+    copied?;
+    return Ok(backup_path);
+}
+
+/// Checks a vault is not older than the latest version of it seen before, and remembers its version if it is newer.
+/// The versions seen are kept in the file given. Vaults made before versions can't be checked, and always pass.
+pub fn check_version(versions_path: &Path, lineage: &Lineage) -> AppResult<()> {
+    if lineage.vault_id.is_nil() {
+        return Ok(());
+    }
+    let mut seen = read_seen(versions_path)?;
+
+    if let Some(latest) = seen.versions.get(&lineage.vault_id).copied() {
+        if latest > lineage.version {
+            return Err(make_error(
+                "old_version",
+                &format!(
+                    "This is version {} of the vault, but version {} has been seen on this device. This copy is out of date, and somebody may be passing it off as the latest one. Use the latest copy of the vault.",
+                    lineage.version, latest
+                ),
+            ));
+        }
+        if latest == lineage.version {
+            return Ok(());
+        }
+    }
+
+    seen.versions.insert(lineage.vault_id, lineage.version);
+    return write_seen(versions_path, &seen);
+}
+
+/// Remembers a new version of a vault before it is written, so that a version which can't be remembered is never
+/// written. Refuses a version which is not newer than every version seen, as that would fork the vault.
+/// Returns the latest version seen before, to put back with release_version if the new version is not kept.
+pub fn reserve_version(versions_path: &Path, lineage: &Lineage) -> AppResult<Option<u64>> {
+    if lineage.vault_id.is_nil() {
+        return Ok(None);
+    }
+    let mut seen = read_seen(versions_path)?;
+    let latest = seen.versions.get(&lineage.vault_id).copied();
+    if let Some(latest) = latest.filter(|latest| *latest >= lineage.version) {
+        return Err(make_error(
+            "old_version",
+            &format!(
+                "Version {} of this vault has been seen on this device, so this update would not be the latest version. Open the latest copy of the vault to update it.",
+                latest
+            ),
+        ));
+    }
+
+    seen.versions.insert(lineage.vault_id, lineage.version);
+    write_seen(versions_path, &seen)?;
+    return Ok(latest);
+}
+
+/// Puts back the version remembered before reserve_version, once the new version is not going to be kept.
+pub fn release_version(
+    versions_path: &Path,
+    lineage: &Lineage,
+    latest: Option<u64>,
+) -> AppResult<()> {
+    let mut seen = read_seen(versions_path)?;
+    // Anything seen since is left alone.
+    if seen.versions.get(&lineage.vault_id) != Some(&lineage.version) {
+        return Ok(());
+    }
+    match latest {
+        Some(latest) => seen.versions.insert(lineage.vault_id, latest),
+        None => seen.versions.remove(&lineage.vault_id),
+    };
+    return write_seen(versions_path, &seen);
+}
+
+/// Checks a vault is signed if it has been seen signed before, and remembers it if it is signed.
+/// Takes the key id of the key the pieces rebuild, rather than the one in the vault file, which can be changed.
+pub fn check_signed(versions_path: &Path, key_id: &[u8], signed: bool) -> AppResult<()> {
+    let mut seen = read_seen(versions_path)?;
+    if signed {
+        if seen.signed.insert(key_id.to_vec()) {
+            write_seen(versions_path, &seen)?;
+        }
+        return Ok(());
+    }
+    if seen.signed.contains(key_id) {
+        return Err(make_error(
+            "unsigned_vault",
+            "This vault has been signed by its owner before, but this copy is not signed. It may not have come from them, so do not trust it.",
+        ));
+    }
+    return Ok(());
+}
+
+fn read_seen(versions_path: &Path) -> AppResult<SeenVaults> {
+    let bytes = match fs::read(versions_path) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(SeenVaults::default()),
+        Err(e) => return Err(e.into()),
+    };
+    // Versions files from before signed vaults were remembered only have the versions.
+    if let Ok(versions) = rmp_serde::from_slice::<BTreeMap<Uuid, u64>>(&bytes) {
+        return Ok(SeenVaults {
+            versions,
+            signed: BTreeSet::new(),
+        });
+    }
+    return Ok(rmp_serde::from_slice(&bytes)?);
+}
+
+fn write_seen(versions_path: &Path, seen: &SeenVaults) -> AppResult<()> {
+    let mut buffer = Vec::new();
+    seen.serialize(&mut Serializer::new(&mut buffer))?;
+    return write_file_atomic(versions_path, &buffer);
+}
+
+#[cfg(test)]
+pub mod test {
+    use std::collections::BTreeMap;
+    use std::path::PathBuf;
+    use std::{env, fs};
+
+    use rmp_serde::Serializer;
+    use serde::Serialize;
+    use uuid::Uuid;
+
+    use crate::lineage::{
+        check_signed, check_version, first_version, release_version, reserve_version,
+    };
+    use crate::util::get_random_file_name;
+    use crate::vault::Lineage;
+
+    /// A versions file of its own for a test.
+    pub fn temp_versions_path() -> PathBuf {
+        let mut versions = env::temp_dir();
+        versions.push(get_random_file_name().unwrap() + "versions");
+        return versions;
+    }
+
+    #[test]
+    fn old_versions_refused() {
+        let versions = temp_versions_path();
+
+        let first = first_version();
+        let second = Lineage {
+            version: 2,
+            previous_hash: vec![1; 32],
+            ..first.clone()
+        };
+        check_version(&versions, &first).expect("Refused the first version");
+        check_version(&versions, &second).expect("Refused a newer version");
+        check_version(&versions, &second).expect("Refused the same version again");
+        let err = check_version(&versions, &first).expect_err("Accepted an older version");
+        assert_eq!(err.error_type, "old_version");
+
+        // Other vaults, and vaults from before versions, are not affected.
+        check_version(&versions, &first_version()).expect("Refused another vault");
+        check_version(&versions, &Lineage::default()).expect("Refused an old vault");
+        fs::remove_file(versions).unwrap();
+    }
+
+    #[test]
+    fn stripped_signatures_refused() {
+        let versions = temp_versions_path();
+
+        check_signed(&versions, &[1; 8], false).expect("Refused an unsigned vault");
+        check_signed(&versions, &[1; 8], true).expect("Refused a signed vault");
+        let err = check_signed(&versions, &[1; 8], false).expect_err("Accepted a stripped copy");
+        assert_eq!(err.error_type, "unsigned_vault");
+        check_signed(&versions, &[2; 8], false).expect("Refused another vault");
+        fs::remove_file(&versions).unwrap();
+
+        // Versions files from before signed vaults were remembered are still read.
+        let first = first_version();
+        let old: BTreeMap<Uuid, u64> = BTreeMap::from([(first.vault_id, 2)]);
+        let mut buffer = Vec::new();
+        old.serialize(&mut Serializer::new(&mut buffer)).unwrap();
+        fs::write(&versions, buffer).unwrap();
+        let err = check_version(&versions, &first).expect_err("Forgot the versions seen");
+        assert_eq!(err.error_type, "old_version");
+        check_signed(&versions, &[1; 8], true).expect("Refused a signed vault");
+        let err = check_version(&versions, &first).expect_err("Forgot the versions seen");
+        assert_eq!(err.error_type, "old_version");
+        fs::remove_file(versions).unwrap();
+    }
+
+    #[test]
+    fn versions_reserved() {
+        let versions = temp_versions_path();
+        let first = first_version();
+        let second = Lineage {
+            version: 2,
+            previous_hash: vec![1; 32],
+            ..first.clone()
+        };
+
+        // A new vault, which is not written after all.
+        let latest = reserve_version(&versions, &first).expect("Failed to reserve");
+        assert_eq!(latest, None);
+        release_version(&versions, &first, latest).expect("Failed to release");
+        check_version(&versions, &second).expect("Refused a newer version");
+
+        // An update can't be made twice from the same version, but can once the first is released.
+        let err = reserve_version(&versions, &second).expect_err("Forked the vault");
+        assert_eq!(err.error_type, "old_version");
+        let third = Lineage {
+            version: 3,
+            ..second.clone()
+        };
+        let latest = reserve_version(&versions, &third).expect("Failed to reserve");
+        assert_eq!(latest, Some(2));
+        check_version(&versions, &second).expect_err("Accepted an older version");
+        release_version(&versions, &third, latest).expect("Failed to release");
+        check_version(&versions, &second).expect("Refused the latest version");
+        fs::remove_file(versions).unwrap();
+    }
+}
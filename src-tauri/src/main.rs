@@ -5,25 +5,48 @@
 
 use tauri::{Manager, Window};
 // Import commands
+use crate::commands::armor::{armor_main_key, armor_piece, read_main_key};
+use crate::commands::assess::assess_unlock;
 use crate::commands::create::create;
 use crate::commands::loadmeta::{get_file_path, load_meta};
 use crate::commands::open::open;
+use crate::commands::passphrase::{is_protected_piece, protect_piece, unprotect_piece};
+use crate::commands::rekey::rekey;
+use crate::commands::sheets::{read_qr_piece, recovery_sheets};
 use crate::commands::unlock::{unlock, unlock_cloud};
+use crate::commands::verify::verify_piece;
+use crate::commands::words::piece_words;
 
 // Import all other files
+mod armor;
 mod constants;
 mod crypto;
 mod error;
+mod lineage;
 mod meta;
+mod mnemonic;
+mod passphrase;
+mod piece;
+mod recipient;
+mod sheet;
+mod signature;
 mod util;
 mod vault;
+mod vss;
 
 // Define module structure - allows command files to be imported.
 mod commands {
+    pub mod armor;
+    pub mod assess;
     pub mod create;
     pub mod loadmeta;
     pub mod open;
+    pub mod passphrase;
+    pub mod rekey;
+    pub mod sheets;
     pub mod unlock;
+    pub mod verify;
+    pub mod words;
 }
 
 // Removes the splashscreen when the program finishes loading
@@ -55,7 +78,19 @@ fn main() {
             open,
             close_splashscreen,
             unlock_cloud,
-            get_file_path
+            get_file_path,
+            assess_unlock,
+            verify_piece,
+            piece_words,
+            armor_piece,
+            armor_main_key,
+            read_main_key,
+            recovery_sheets,
+            read_qr_piece,
+            protect_piece,
+            unprotect_piece,
+            is_protected_piece,
+            rekey
         ])
         .run(tauri::generate_context!())
         .expect("error while running tauri application");
@@ -1,69 +1,371 @@
 use rmp_serde::Serializer;
 use serde::Serialize;
-use std::io::{Read, Write};
+use std::io::{self, Read, Seek, SeekFrom, Write};
 
-use crate::error::AppResult;
+use crate::crypto::{
+    derive_content_key, derive_key_commitment, derive_recipient_secret, Cipher, DecryptReader, Key,
+    LEGACY_NONCE_LEN,
+};
+use crate::error::{make_error, AppError, AppResult};
 use crate::vault::PublicInfo;
 
 /*
-pub const KEYSHARE_COMMENT: &str = "\
-\
-";
-// Then include: Comment, M of N scheme, Name of owner.
+   Vault file layout (versions 1 to 3)
+   magic (8 bytes) | format version (u16) | cipher id (u8) | length (u32) | MessagePack PublicInfo
+   | signature block (version 3 onwards) | owner state length (u32) | sealed owner state (version 2 onwards)
+   | ciphertext
 
-pub const VAULT_COMMENT: &str = "This is a digital vault. It contains photos, documents and the last will and testament of the named individual below.\
-Access is gained by combining key pieces - a number of these have been generated and given to trusted contacts. You can also find the number of keys required below.";
+   All integers are big endian. Everything before the owner state is the header, which is authenticated as AAD.
+   The magic and version come first and must never move, so that any version of the app can identify the file.
+   The owner state can only be opened with the main key. Recipients skip over it.
 
-pub const SCHEME_COMMENT: &str = "This is a {M] of {N} key sharing scheme. {M} keys exist, and {N} keys must be combined to unlock the data.";
-*/
-/*
-   Use this for a comment at a later date.
-       // Make meta data
-   let mut meta_str: String = VAULT_COMMENT.to_owned();
-   meta_str.push('\n');
-   meta_str.push_str(&*format!("Name: {}, Email address: {}. This is a {} of {} key sharing scheme.",
-                               vault.personal_info.name,
-                               vault.personal_info.email_address,
-       vault.share_config.shares, vault.share_config.required
-   ));
+   Version 1 has no owner state: The keys are kept in the archive, and the key pieces rebuild the main key, which
+   encrypts the contents and whose first bytes are the cloud lookup token. Version 2 adds the owner state, and the
+   pieces rebuild a recipient secret derived from the main key, from which the content key and lookup token are
+   derived separately. Version 3 adds the owner's signature over the rest of the file (see signature.rs). It is not
+   part of the header, as it is written once everything else is.
+
+   Vaults made before the magic (read as version 0) are just length (u32) | MessagePack PublicInfo | ciphertext.
+   The contents are one ChaCha20Poly1305 message under the key the pieces rebuild, with only the MessagePack as AAD,
+   and the keys are kept in the archive. A file can't be both: The magic read as a length is far over MAX_HEADER_LEN.
 */
 
+// The first bytes of every vault file.
+pub const VAULT_MAGIC: &[u8; 8] = b"DLVAULT\0";
+// The version written by this build. Bump it (and add a decoder) whenever the layout or meaning of a vault changes.
+pub const FORMAT_VERSION: u16 = 3;
+// The largest MessagePack header we will read. Files claiming more than this are rejected before anything is allocated.
+pub const MAX_HEADER_LEN: u32 = 1024 * 1024;
+// The largest sealed owner state we will read. It holds every key piece, so it is allowed to be larger than the header.
+pub const MAX_OWNER_STATE_LEN: u32 = 64 * 1024 * 1024;
+// The length before the MessagePack in version 0 vaults, which have no magic.
+const LEGACY_LEN_LEN: usize = 4;
+// The owner's Ed25519 public key (32 bytes) then the signature (64 bytes).
+pub const SIGNATURE_BLOCK_LEN: usize = 32 + 64;
+
+/// A decoded vault header.
+#[derive(Debug)]
+pub struct VaultHeader {
+    pub version: u16,
+    pub cipher: Cipher,
+    // The raw header bytes as they appear in the file. These must be provided as AAD at decryption time.
+    pub raw: Vec<u8>,
+    pub info: PublicInfo,
+    // The signature block, for vaults from version 3 onwards.
+    pub signature: Option<Vec<u8>>,
+}
+
+impl VaultHeader {
+    /// The key that the contents of this vault are encrypted with, given the secret rebuilt from key pieces.
+    pub fn content_key(&self, recipient_secret: &Key) -> Key {
+        return if self.version <= 1 {
+            *recipient_secret
+        } else {
+            derive_content_key(recipient_secret)
+        };
+    }
+
+    /// The secret the key pieces rebuild, given the main key. Pieces of vaults before version 2 rebuild the main key
+    /// itself.
+    pub fn recipient_secret(&self, main: &Key) -> Key {
+        return if self.version <= 1 {
+            *main
+        } else {
+            derive_recipient_secret(main)
+        };
+    }
+
+    /// Whether the sealed owner state follows the header. Vaults before version 2 kept the keys in the archive instead.
+    pub fn has_owner_state(&self) -> bool {
+        return self.version >= 2;
+    }
+
+    /// Decrypts the contents of the vault as they are read from the reader, which must be positioned after the
+    /// header (and owner state).
+    pub fn decryptor<R: Read>(&self, content_key: &Key, reader: R) -> AppResult<DecryptReader<R>> {
+        if self.version == 0 {
+            return DecryptReader::new_whole(content_key, &self.info.nonce, &self.raw, reader);
+        }
+        return DecryptReader::new(
+            self.cipher,
+            content_key,
+            &self.info.nonce,
+            &self.raw,
+            reader,
+        );
+    }
+
+    /// Checks a content key against the key commitment in the header, so a wrong key is caught straight away rather
+    /// than after reading the whole vault. Vaults before version 3 may have been made before key commitments, and
+    /// pass without one. From version 3 a missing commitment means the header was tampered with.
+    pub fn check_content_key(&self, content_key: &Key) -> AppResult<()> {
+        let unchecked = self.version < 3 && self.info.key_commitment.is_empty();
+        if unchecked || derive_key_commitment(content_key) == self.info.key_commitment {
+            return Ok(());
+        }
+        return Err(make_error(
+            "wrong_key",
+            "The combined key is not the key for this vault. Are the key pieces definitely for this vault, and were they copied correctly?",
+        ));
+    }
+}
+
 // meta data encoding
-pub fn encode_meta(writer: &mut impl Write, info: PublicInfo) -> Vec<u8> {
+// Writes the header for the current format version, and returns the raw header bytes.
+// Space is left after the header for the signature block, which is filled in by write_signature.
+pub fn encode_meta(
+    writer: &mut impl Write,
+    info: PublicInfo,
+    cipher: Cipher,
+) -> AppResult<Vec<u8>> {
     // Serialize
     let mut meta_buffer = Vec::new();
-    info.serialize(&mut Serializer::new(&mut meta_buffer))
-        .expect("Failed to encode");
+    info.serialize(&mut Serializer::new(&mut meta_buffer))?;
+
+    let len = u32::try_from(meta_buffer.len())?;
+    if len > MAX_HEADER_LEN {
+        return Err(header_too_large());
+    }
 
-    let len = u32::try_from(meta_buffer.len()).unwrap();
+    let mut header = Vec::from(VAULT_MAGIC.as_slice());
+    header.extend_from_slice(&FORMAT_VERSION.to_be_bytes());
+    header.push(cipher.id());
+    // Length of message pack
+    header.extend_from_slice(&len.to_be_bytes());
+    header.extend_from_slice(&meta_buffer);
 
-    // First two bytes - Length of message pack
-    writer.write(len.to_be_bytes().as_ref()).unwrap();
-    writer.write(&meta_buffer).unwrap();
+    writer.write_all(&header)?;
+    writer.write_all(&[0u8; SIGNATURE_BLOCK_LEN])?;
 
-    meta_buffer
+    return Ok(header);
+}
+
+/// Fills in the signature block left by encode_meta, once the rest of the vault has been written.
+pub fn write_signature(
+    writer: &mut (impl Write + Seek),
+    header: &[u8],
+    signature: &[u8],
+) -> AppResult<()> {
+    if signature.len() != SIGNATURE_BLOCK_LEN {
+        return Err(make_error(
+            "crypto",
+            "The vault signature is the wrong length.",
+        ));
+    }
+    writer.seek(SeekFrom::Start(u64::try_from(header.len())?))?;
+    writer.write_all(signature)?;
+    writer.flush()?;
+    return Ok(());
 }
 
 // All we actually need to read in is the nonce and the start point of the file.
-pub fn decode_meta(reader: &mut impl Read) -> AppResult<(Vec<u8>, PublicInfo)> {
-    let mut meta_len_buff: [u8; 4] = [0; 4];
+// Identifies the file and dispatches on the format version.
+// The file is untrusted: Every malformed input gives an error, and nothing is allocated based on the file until
+// it has been checked against MAX_HEADER_LEN.
+pub fn decode_meta(reader: &mut impl Read) -> AppResult<VaultHeader> {
+    // Vaults from before the magic start with the header length instead.
+    let mut magic = [0u8; VAULT_MAGIC.len()];
+    let (start, rest) = magic.split_at_mut(LEGACY_LEN_LEN);
+    read_magic_bytes(reader, start)?;
+    if start != &VAULT_MAGIC[..LEGACY_LEN_LEN] {
+        return decode_v0(reader, start);
+    }
+    read_magic_bytes(reader, rest)?;
+    if &magic != VAULT_MAGIC {
+        return Err(not_a_vault());
+    }
+
+    let mut version_buff = [0u8; 2];
+    read_header_bytes(reader, &mut version_buff)?;
+    let version = u16::from_be_bytes(version_buff);
+
+    let mut raw = Vec::from(magic.as_slice());
+    raw.extend_from_slice(&version_buff);
+
+    return match version {
+        // Version 2 only added the owner state after the header, so the header itself is the same.
+        1 | 2 => decode_v1(reader, raw, version),
+        3 => {
+            let mut header = decode_v1(reader, raw, version)?;
+            let mut signature = vec![0u8; SIGNATURE_BLOCK_LEN];
+            read_header_bytes(reader, &mut signature)?;
+            header.signature = Some(signature);
+            Ok(header)
+        }
+        _ => Err(make_error(
+            "unsupported_version",
+            &format!(
+                "This vault uses format version {}, which this version of the app does not support. Please update the app.",
+                version
+            ),
+        )),
+    };
+}
+
+// Version 0: length, then the MessagePack PublicInfo. The length has already been read.
+// Without a magic anything could be a version 0 vault, so anything which doesn't read as one is not a vault.
+fn decode_v0(reader: &mut impl Read, len_buff: &[u8]) -> AppResult<VaultHeader> {
+    let meta_len = u32::from_be_bytes(len_buff.try_into()?);
+    if meta_len > MAX_HEADER_LEN {
+        return Err(not_a_vault());
+    }
+    let mut buff: Vec<u8> = vec![0u8; usize::try_from(meta_len)?];
+    read_header_bytes(reader, &mut buff)?;
+
+    let info: PublicInfo = rmp_serde::from_slice(&buff).map_err(|_e| not_a_vault())?;
+    if info.nonce.len() != LEGACY_NONCE_LEN {
+        return Err(not_a_vault());
+    }
+
+    // Only the MessagePack was used as AAD.
+    return Ok(VaultHeader {
+        version: 0,
+        cipher: Cipher::ChaCha20Poly1305,
+        raw: buff,
+        info,
+        signature: None,
+    });
+}
 
-    reader.read(&mut meta_len_buff)?;
+// Version 1: cipher id, length, then the MessagePack PublicInfo.
+fn decode_v1(reader: &mut impl Read, mut raw: Vec<u8>, version: u16) -> AppResult<VaultHeader> {
+    let mut cipher_buff = [0u8; 1];
+    read_header_bytes(reader, &mut cipher_buff)?;
+    let cipher = Cipher::from_id(cipher_buff[0]).ok_or(make_error(
+        "unsupported_cipher",
+        "This vault was encrypted with a cipher this version of the app does not support.",
+    ))?;
+
+    let mut meta_len_buff: [u8; 4] = [0; 4];
+    read_header_bytes(reader, &mut meta_len_buff)?;
 
     let meta_len: u32 = u32::from_be_bytes(meta_len_buff);
-    let mut buff: Vec<u8> = vec![0u8; meta_len.try_into().unwrap()];
+    if meta_len > MAX_HEADER_LEN {
+        return Err(header_too_large());
+    }
+    let mut buff: Vec<u8> = vec![0u8; usize::try_from(meta_len)?];
+    read_header_bytes(reader, &mut buff)?;
+
+    let info: PublicInfo = rmp_serde::from_slice(&buff).map_err(|e| {
+        make_error(
+            "bad_header",
+            &format!("The vault information could not be read: {}", e),
+        )
+    })?;
+
+    raw.extend_from_slice(&cipher_buff);
+    raw.extend_from_slice(&meta_len_buff);
+    raw.extend_from_slice(&buff);
+
+    return Ok(VaultHeader {
+        version,
+        cipher,
+        raw,
+        info,
+        signature: None,
+    });
+}
+
+/// Writes the sealed owner state. This must directly follow the header.
+pub fn encode_owner_state(writer: &mut impl Write, sealed: &[u8]) -> AppResult<()> {
+    let len = u32::try_from(sealed.len())?;
+    if len > MAX_OWNER_STATE_LEN {
+        return Err(owner_state_too_large());
+    }
+    writer.write_all(&len.to_be_bytes())?;
+    writer.write_all(sealed)?;
+    return Ok(());
+}
+
+/// Reads the sealed owner state following the header.
+pub fn decode_owner_state(reader: &mut impl Read) -> AppResult<Vec<u8>> {
+    let len = read_owner_state_len(reader)?;
+    let mut sealed = vec![0u8; usize::try_from(len)?];
+    read_header_bytes(reader, &mut sealed)?;
+    return Ok(sealed);
+}
+
+/// Skips over the owner state following the header, without keeping it in memory.
+pub fn skip_owner_state(reader: &mut impl Read) -> AppResult<()> {
+    let len = read_owner_state_len(reader)?;
+    let skipped = io::copy(&mut reader.take(u64::from(len)), &mut io::sink())?;
+    if skipped != u64::from(len) {
+        return Err(truncated());
+    }
+    return Ok(());
+}
+
+fn read_owner_state_len(reader: &mut impl Read) -> AppResult<u32> {
+    let mut len_buff = [0u8; 4];
+    read_header_bytes(reader, &mut len_buff)?;
+    let len = u32::from_be_bytes(len_buff);
+    if len > MAX_OWNER_STATE_LEN {
+        return Err(owner_state_too_large());
+    }
+    return Ok(len);
+}
+
+// Fill the buffer from the start of the file. A file too short to hold the magic is not a vault.
+fn read_magic_bytes(reader: &mut impl Read, buff: &mut [u8]) -> AppResult<()> {
+    return reader.read_exact(buff).map_err(|e| {
+        if e.kind() == io::ErrorKind::UnexpectedEof {
+            not_a_vault()
+        } else {
+            e.into()
+        }
+    });
+}
 
-    reader.read_exact(&mut buff)?;
+// Fill the buffer from the header, giving a typed error if the file ends early.
+fn read_header_bytes(reader: &mut impl Read, buff: &mut [u8]) -> AppResult<()> {
+    return reader.read_exact(buff).map_err(|e| {
+        if e.kind() == io::ErrorKind::UnexpectedEof {
+            truncated()
+        } else {
+            e.into()
+        }
+    });
+}
 
-    let res = rmp_serde::from_slice(&buff)?;
+fn truncated() -> AppError {
+    return make_error(
+        "truncated",
+        "The vault file is incomplete. It may not have been copied or downloaded fully.",
+    );
+}
+
+fn not_a_vault() -> AppError {
+    return make_error(
+        "not_a_vault",
+        "This file is not a digital vault, or was made by a very old version of the app.",
+    );
+}
 
-    return Ok((buff, res));
+fn owner_state_too_large() -> AppError {
+    return make_error(
+        "header_too_large",
+        "The vault's owner information is larger than this app allows. The file may be corrupt.",
+    );
+}
+
+fn header_too_large() -> AppError {
+    return make_error(
+        "header_too_large",
+        "The vault information is larger than this app allows. The file may be corrupt.",
+    );
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::meta::{decode_meta, encode_meta};
-    use crate::vault::{PublicInfo, ShareConfiguration};
+    use crate::crypto::tests::get_public_info;
+    use crate::crypto::{
+        derive_content_key, derive_key_commitment, derive_recipient_secret, generate_key, Cipher,
+    };
+    use crate::meta::{
+        decode_meta, decode_owner_state, encode_meta, encode_owner_state, skip_owner_state,
+        FORMAT_VERSION, MAX_HEADER_LEN, MAX_OWNER_STATE_LEN, SIGNATURE_BLOCK_LEN, VAULT_MAGIC,
+    };
+    use crate::vault::{Lineage, PublicInfo, ShareCommitments, ShareConfiguration};
     use std::env::temp_dir;
     use std::fs;
     use std::fs::{File, OpenOptions};
@@ -80,9 +382,14 @@ mod tests {
             email_address: "foo@bar.com".to_string(),
             nonce: vec![0, 1, 2, 3, 4, 5],
             path: "/foo/bar".to_string(),
+            key_id: vec![],
+            commitments: ShareCommitments::default(),
+            owner_fingerprint: vec![],
+            key_commitment: vec![],
+            lineage: Lineage::default(),
         };
 
-        let res = encode_meta(&mut f, info);
+        let res = encode_meta(&mut f, info, Cipher::ChaCha20Poly1305).expect("Failed to encode");
         assert!(res.len() > 0);
     }
 
@@ -104,6 +411,11 @@ mod tests {
             email_address: "foo@bar.com".to_string(),
             nonce: vec![0, 1, 2, 3, 4, 5],
             path: "/foo/bar".to_string(),
+            key_id: vec![],
+            commitments: ShareCommitments::default(),
+            owner_fingerprint: vec![],
+            key_commitment: vec![],
+            lineage: Lineage::default(),
         };
 
         // Inner closure allows for drops
@@ -112,7 +424,7 @@ mod tests {
 
             let copied = info.clone();
 
-            encode_meta(&mut writable, copied)
+            encode_meta(&mut writable, copied, Cipher::ChaCha20Poly1305).expect("Failed to encode")
         };
         assert!(res.len() > 0);
 
@@ -120,8 +432,10 @@ mod tests {
         let result = decode_meta(&mut readable);
         assert!(result.is_ok(), "Failed to decode");
 
-        let (raw, meta) = result.unwrap();
+        let header = result.unwrap();
+        let (raw, meta) = (header.raw, header.info);
         assert_eq!(raw, res);
+        assert_eq!(header.cipher, Cipher::ChaCha20Poly1305);
         assert_eq!(info.name, meta.name, "Returned meta is incorrect");
         assert_eq!(
             info.email_address, meta.email_address,
@@ -131,6 +445,278 @@ mod tests {
         assert_eq!(info.path, meta.path, "Returned meta is incorrect");
     }
 
+    // Vaults made before the header had a magic are still read, as version 0.
+    #[test]
+    fn reads_version_0() {
+        let file = fs::read(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/fixtures/baseline.vault"
+        ))
+        .expect("Missing fixture");
+        let header = decode_meta(&mut file.as_slice()).expect("Failed to decode version 0");
+        assert_eq!(header.version, 0);
+        assert_eq!(header.cipher, Cipher::ChaCha20Poly1305);
+        assert_eq!(header.info.name, "Test");
+        assert_eq!(header.info.share_config.circles.len(), 2);
+        assert_eq!(header.raw, file[4..4 + header.raw.len()]);
+        assert!(header.signature.is_none());
+        assert!(!header.has_owner_state());
+        let main = generate_key();
+        assert_eq!(header.content_key(&header.recipient_secret(&main)), main);
+
+        // Only a small header length can be version 0, and it must hold the vault information.
+        let mut data: &[u8] = b"DLVA and then not a vault";
+        assert_eq!(
+            decode_meta(&mut data).unwrap_err().error_type,
+            "not_a_vault"
+        );
+        let mut data: &[u8] = &[0, 0, 0, 3, 1, 2, 3, 4];
+        assert_eq!(
+            decode_meta(&mut data).unwrap_err().error_type,
+            "not_a_vault"
+        );
+        let mut data: &[u8] = &file[..50];
+        assert_eq!(decode_meta(&mut data).unwrap_err().error_type, "truncated");
+    }
+
+    #[test]
+    fn rejects_non_vault() {
+        let mut data: &[u8] = b"Not a vault file, just some text that happens to be long enough.";
+        let result = decode_meta(&mut data);
+        assert_eq!(
+            result.expect_err("Expected an error").error_type,
+            "not_a_vault"
+        );
+    }
+
+    // Version 1 vaults are still read. They have no owner state, and their pieces rebuild the key of the contents.
+    #[test]
+    fn reads_version_1() {
+        let mut header = encode_meta(&mut Vec::new(), get_info(), Cipher::ChaCha20Poly1305)
+            .expect("Failed to encode");
+        let secret = generate_key();
+        let mut file = header.clone();
+        file.extend_from_slice(&[0u8; SIGNATURE_BLOCK_LEN]);
+        let current = decode_meta(&mut file.as_slice()).expect("Failed to decode");
+        assert_eq!(current.version, FORMAT_VERSION);
+        assert_eq!(current.content_key(&secret), derive_content_key(&secret));
+        assert!(current.signature.is_some());
+        assert!(current.has_owner_state());
+        assert_eq!(
+            current.recipient_secret(&secret),
+            derive_recipient_secret(&secret)
+        );
+
+        // Versions before 3 have no signature block.
+        header[VAULT_MAGIC.len()..VAULT_MAGIC.len() + 2].copy_from_slice(&1u16.to_be_bytes());
+        let legacy = decode_meta(&mut header.as_slice()).expect("Failed to decode version 1");
+        assert_eq!(legacy.version, 1);
+        assert_eq!(legacy.raw, header);
+        assert_eq!(legacy.content_key(&secret), secret);
+        assert!(legacy.signature.is_none());
+        assert!(!legacy.has_owner_state());
+        assert_eq!(legacy.recipient_secret(&secret), secret);
+    }
+
+    // A wrong key is caught by the key commitment, before anything is decrypted.
+    #[test]
+    fn key_commitment() {
+        let content_key = generate_key();
+        let mut info = get_info();
+        info.key_commitment = derive_key_commitment(&content_key);
+        let mut file = Vec::new();
+        encode_meta(&mut file, info, Cipher::ChaCha20Poly1305).expect("Failed to encode");
+        let header = decode_meta(&mut file.as_slice()).expect("Failed to decode");
+
+        assert!(header.check_content_key(&content_key).is_ok());
+        let err = header
+            .check_content_key(&generate_key())
+            .expect_err("Accepted the wrong key");
+        assert_eq!(err.error_type, "wrong_key");
+    }
+
+    // Version 3 vaults always have a key commitment, so a header without one is rejected.
+    #[test]
+    fn empty_key_commitment() {
+        let content_key = generate_key();
+        let mut file = encode_meta(&mut Vec::new(), get_info(), Cipher::ChaCha20Poly1305)
+            .expect("Failed to encode");
+        file.extend_from_slice(&[0u8; SIGNATURE_BLOCK_LEN]);
+        let header = decode_meta(&mut file.as_slice()).expect("Failed to decode");
+        assert_eq!(header.version, 3);
+        let err = header
+            .check_content_key(&content_key)
+            .expect_err("Accepted a version 3 header without a commitment");
+        assert_eq!(err.error_type, "wrong_key");
+
+        // Older vaults may be from before key commitments, and can't be checked.
+        file[VAULT_MAGIC.len()..VAULT_MAGIC.len() + 2].copy_from_slice(&2u16.to_be_bytes());
+        let header = decode_meta(&mut file.as_slice()).expect("Failed to decode");
+        assert!(header.check_content_key(&generate_key()).is_ok());
+    }
+
+    #[test]
+    fn rejects_unknown_version() {
+        let mut data = Vec::from(VAULT_MAGIC.as_slice());
+        data.extend_from_slice(&(FORMAT_VERSION + 1).to_be_bytes());
+        data.extend_from_slice(&[1, 0, 0, 0, 0]);
+
+        let result = decode_meta(&mut data.as_slice());
+        assert_eq!(
+            result.expect_err("Expected an error").error_type,
+            "unsupported_version"
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_cipher() {
+        let mut header = encode_meta(&mut Vec::new(), get_info(), Cipher::ChaCha20Poly1305)
+            .expect("Failed to encode");
+        // The cipher id follows the magic and version
+        header[VAULT_MAGIC.len() + 2] = 200;
+
+        let result = decode_meta(&mut header.as_slice());
+        assert_eq!(
+            result.expect_err("Expected an error").error_type,
+            "unsupported_cipher"
+        );
+    }
+
+    // Malformed input tests. None of these should panic or allocate more than MAX_HEADER_LEN.
+    #[test]
+    fn truncated_header() {
+        // The signature block is read with the header.
+        let mut header = Vec::new();
+        encode_meta(&mut header, get_info(), Cipher::ChaCha20Poly1305).expect("Failed to encode");
+
+        for len in 0..header.len() {
+            let result = decode_meta(&mut &header[..len]);
+            let expected = if len < VAULT_MAGIC.len() {
+                "not_a_vault"
+            } else {
+                "truncated"
+            };
+            assert_eq!(
+                result.expect_err("Expected an error").error_type,
+                expected,
+                "Wrong error for header cut to {} bytes",
+                len
+            );
+        }
+        assert!(decode_meta(&mut header.as_slice()).is_ok());
+    }
+
+    #[test]
+    fn oversized_header() {
+        let mut data = Vec::from(VAULT_MAGIC.as_slice());
+        data.extend_from_slice(&FORMAT_VERSION.to_be_bytes());
+        data.push(Cipher::ChaCha20Poly1305.id());
+        data.extend_from_slice(&u32::MAX.to_be_bytes());
+
+        let result = decode_meta(&mut data.as_slice());
+        assert_eq!(
+            result.expect_err("Expected an error").error_type,
+            "header_too_large"
+        );
+
+        // Exactly at the limit is allowed, but this file doesn't have that much data.
+        data.truncate(data.len() - 4);
+        data.extend_from_slice(&MAX_HEADER_LEN.to_be_bytes());
+        let result = decode_meta(&mut data.as_slice());
+        assert_eq!(
+            result.expect_err("Expected an error").error_type,
+            "truncated"
+        );
+    }
+
+    #[test]
+    fn owner_state_round_trip() {
+        let mut data = Vec::new();
+        encode_owner_state(&mut data, b"sealed state").expect("Failed to encode");
+        data.extend_from_slice(b"ciphertext");
+
+        let mut reader = data.as_slice();
+        assert_eq!(decode_owner_state(&mut reader).unwrap(), b"sealed state");
+        assert_eq!(reader, b"ciphertext");
+
+        let mut reader = data.as_slice();
+        skip_owner_state(&mut reader).expect("Failed to skip");
+        assert_eq!(reader, b"ciphertext");
+
+        // Ends part way through the owner state
+        let truncated = &data[..8];
+        assert_eq!(
+            decode_owner_state(&mut &truncated[..])
+                .unwrap_err()
+                .error_type,
+            "truncated"
+        );
+        assert_eq!(
+            skip_owner_state(&mut &truncated[..])
+                .unwrap_err()
+                .error_type,
+            "truncated"
+        );
+
+        let oversized = (MAX_OWNER_STATE_LEN + 1).to_be_bytes();
+        assert_eq!(
+            decode_owner_state(&mut oversized.as_slice())
+                .unwrap_err()
+                .error_type,
+            "header_too_large"
+        );
+    }
+
+    #[test]
+    fn corrupted_header() {
+        let header = encode_meta(&mut Vec::new(), get_info(), Cipher::ChaCha20Poly1305)
+            .expect("Failed to encode");
+
+        // Flip every bit of the header in turn. It must never panic.
+        for index in 0..header.len() {
+            for bit in 0..8 {
+                let mut corrupted = header.clone();
+                corrupted[index] ^= 1 << bit;
+                let _ = decode_meta(&mut corrupted.as_slice());
+            }
+        }
+    }
+
+    // Replays the fuzzing corpus (see fuzz/), so known awkward inputs are checked on every test run.
+    // There is a valid header seed for every supported version, named v<version>_header.
+    #[test]
+    fn fuzz_corpus() {
+        let corpus = concat!(env!("CARGO_MANIFEST_DIR"), "/fuzz/corpus/decode_meta");
+        let entries = fs::read_dir(corpus).expect("Missing fuzz corpus");
+
+        let mut versions = Vec::new();
+        for entry in entries {
+            let path = entry.expect("Bad corpus entry").path();
+            let data = fs::read(&path).unwrap();
+            let decoded = decode_meta(&mut data.as_slice());
+            let name = path.file_name().unwrap().to_string_lossy().to_string();
+            if let Some(version) = name
+                .strip_prefix('v')
+                .and_then(|n| n.strip_suffix("_header"))
+            {
+                let header = decoded.expect("Failed to decode a valid seed");
+                assert_eq!(header.version.to_string(), version);
+                versions.push(header.version);
+            }
+        }
+        versions.sort();
+        assert_eq!(versions, (0..=FORMAT_VERSION).collect::<Vec<u16>>());
+    }
+
+    fn get_info() -> PublicInfo {
+        return PublicInfo {
+            name: "Test_encode".to_string(),
+            email_address: "foo@bar.com".to_string(),
+            nonce: vec![0, 1, 2, 3, 4, 5],
+            ..get_public_info(vec![], 4)
+        };
+    }
+
     fn get_test_file() -> File {
         let mut temp_dir = temp_dir();
         temp_dir.push("test_meta");
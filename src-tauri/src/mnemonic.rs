@@ -0,0 +1,243 @@
+use crate::error::AppResult;
+use crate::piece::piece_error;
+
+/*
+   Key piece words
+   Key pieces can be written as words in the style of SLIP-39 (https://github.com/satoshilabs/slips/blob/master/slip-0039.md),
+   so they can be written on paper or read out. Each word stands for 10 bits, using the SLIP-39 word list, in which
+   every word is known by its first four letters. The data is padded with zero bits at the start to a whole number of
+   words, and followed by three RS1024 checksum words. These catch any mistake in up to three words.
+   Sometimes the padding is a whole byte, so data written as words must not start with a zero byte.
+   The checksum is customised differently to SLIP-39, so a SLIP-39 wallet backup is not mistaken for a key piece.
+*/
+
+// The SLIP-39 word list, in alphabetical order.
+const WORD_LIST: &str = include_str!("wordlist.txt");
+const WORD_BITS: usize = 10;
+const WORD_COUNT: usize = 1 << WORD_BITS;
+// Words can be shortened to this many letters.
+const PREFIX_LEN: usize = 4;
+pub const CHECKSUM_WORDS: usize = 3;
+const CUSTOMIZATION: &[u8] = b"digital-legacies";
+
+/// Writes data as words. The data must not start with a zero byte.
+pub fn encode_words(data: &[u8]) -> Vec<&'static str> {
+    let words = word_list();
+    let word_count = (data.len() * 8).div_ceil(WORD_BITS);
+
+    // The padding goes at the start, so reading it back is the same as reading a number.
+    let mut values: Vec<u32> = Vec::with_capacity(word_count + CHECKSUM_WORDS);
+    let mut accumulator: u32 = 0;
+    let mut bits = word_count * WORD_BITS - data.len() * 8;
+    for byte in data.iter() {
+        accumulator = (accumulator << 8) | u32::from(*byte);
+        bits += 8;
+        if bits >= WORD_BITS {
+            bits -= WORD_BITS;
+            values.push((accumulator >> bits) & word_mask());
+        }
+    }
+    values.extend(checksum(&values));
+
+    return values
+        .into_iter()
+        .map(|value| words[value as usize])
+        .collect();
+}
+
+/// Reads data written as words, checking the checksum.
+/// Words may be in any case, shortened to their first four letters, and separated by any whitespace.
+/// The position is the piece's place in the list the user gave, starting from 1, and is used in errors.
+pub fn decode_words(text: &str, position: usize) -> AppResult<Vec<u8>> {
+    let words = word_list();
+    let mut values: Vec<u32> = Vec::new();
+    for word in text.split_whitespace() {
+        let word = word.to_lowercase();
+        let prefix: String = word.chars().take(PREFIX_LEN).collect();
+        let value = words
+            .iter()
+            .position(|w| {
+                word.len() >= PREFIX_LEN
+                    && w.starts_with(&prefix)
+                    && (word.len() == PREFIX_LEN || **w == word)
+            })
+            .ok_or_else(|| {
+                piece_error(
+                    "piece_words",
+                    position,
+                    &format!(
+                        "has a word which is not in the word list ({}). Check that it was copied correctly.",
+                        word
+                    ),
+                )
+            })?;
+        values.push(value as u32);
+    }
+
+    if values.len() <= CHECKSUM_WORDS {
+        return Err(piece_error(
+            "piece_words",
+            position,
+            "is too short. Check that no words are missing.",
+        ));
+    }
+    if polymod(&values) != 1 {
+        return Err(piece_error(
+            "piece_checksum",
+            position,
+            "has a word which is wrong, missing or in the wrong place. Check that it was copied correctly.",
+        ));
+    }
+
+    let data_values = &values[..values.len() - CHECKSUM_WORDS];
+    let total_bits = data_values.len() * WORD_BITS;
+    let mut data: Vec<u8> = Vec::with_capacity(total_bits / 8);
+    let mut accumulator: u32 = 0;
+    // Skip the padding, which must be zero.
+    let mut bits = 0;
+    let mut padding = total_bits % 8;
+    for value in data_values.iter() {
+        accumulator = (accumulator << WORD_BITS) | value;
+        bits += WORD_BITS;
+        if padding > 0 {
+            bits -= padding;
+            if accumulator >> bits != 0 {
+                return Err(piece_error(
+                    "piece_words",
+                    position,
+                    "does not start with a valid word. Check that it was copied correctly.",
+                ));
+            }
+            padding = 0;
+        }
+        while bits >= 8 {
+            bits -= 8;
+            data.push((accumulator >> bits) as u8);
+        }
+        accumulator &= (1 << bits) - 1;
+    }
+    // A whole byte of padding.
+    if data.first() == Some(&0) {
+        data.remove(0);
+    }
+    return Ok(data);
+}
+
+/// Whether a key piece looks like it was given as words rather than bytes.
+pub fn is_words(piece: &[u8]) -> bool {
+    return piece.iter().any(|b| b.is_ascii_whitespace())
+        && piece.iter().any(|b| b.is_ascii_alphabetic())
+        && piece
+            .iter()
+            .all(|b| b.is_ascii_alphabetic() || b.is_ascii_whitespace());
+}
+
+fn word_list() -> Vec<&'static str> {
+    return WORD_LIST.lines().collect();
+}
+
+fn word_mask() -> u32 {
+    return (WORD_COUNT - 1) as u32;
+}
+
+// The three checksum words for the data words.
+fn checksum(values: &[u32]) -> Vec<u32> {
+    let mut with_space = values.to_vec();
+    with_space.extend([0; CHECKSUM_WORDS]);
+    let remainder = polymod(&with_space) ^ 1;
+    return (0..CHECKSUM_WORDS)
+        .map(|i| (remainder >> (WORD_BITS * (CHECKSUM_WORDS - 1 - i))) & word_mask())
+        .collect();
+}
+
+// The RS1024 checksum from SLIP-39, over the customisation string and the words.
+fn polymod(values: &[u32]) -> u32 {
+    const GENERATOR: [u32; 10] = [
+        0xE0E040, 0x1C1C080, 0x3838100, 0x7070200, 0xE0E0009, 0x1C0C2412, 0x38086C24, 0x3090FC48,
+        0x21B1F890, 0x3F3F120,
+    ];
+    let mut check: u32 = 1;
+    let customization = CUSTOMIZATION.iter().map(|b| u32::from(*b));
+    for value in customization.chain(values.iter().copied()) {
+        let top = check >> 20;
+        check = ((check & 0xFFFFF) << WORD_BITS) ^ value;
+        for (i, generator) in GENERATOR.iter().enumerate() {
+            if (top >> i) & 1 == 1 {
+                check ^= generator;
+            }
+        }
+    }
+    return check;
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::mnemonic::{
+        decode_words, encode_words, is_words, word_list, CHECKSUM_WORDS, PREFIX_LEN, WORD_COUNT,
+    };
+    use std::collections::HashSet;
+
+    #[test]
+    fn word_list_usable() {
+        let words = word_list();
+        assert_eq!(words.len(), WORD_COUNT);
+        let prefixes: HashSet<&str> = words.iter().map(|w| &w[..PREFIX_LEN]).collect();
+        assert_eq!(prefixes.len(), WORD_COUNT);
+        assert!(words.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+
+    #[test]
+    fn round_trip() {
+        // Lengths with every amount of padding, including a whole byte.
+        for len in 1..20 {
+            let data: Vec<u8> = (0..len).map(|i| (i * 37 + 5) as u8).collect();
+            let text = encode_words(&data).join(" ");
+            assert!(is_words(text.as_bytes()));
+            assert_eq!(decode_words(&text, 1).expect("Failed to decode"), data);
+        }
+
+        // Shortened, in capitals and split over lines.
+        let data = vec![1, 2, 3, 250, 251, 252];
+        let words = encode_words(&data);
+        let text = words
+            .iter()
+            .map(|w| w[..PREFIX_LEN].to_uppercase())
+            .collect::<Vec<String>>()
+            .join("\n  ");
+        assert_eq!(decode_words(&text, 1).expect("Failed to decode"), data);
+    }
+
+    #[test]
+    fn mistakes_caught() {
+        let data: Vec<u8> = (0..40).collect();
+        let words = encode_words(&data);
+        let list = word_list();
+
+        // Any three words changed.
+        for (a, b, c) in [(0, 1, 2), (3, 17, 30), (10, 20, words.len() - 1)] {
+            let mut changed = words.clone();
+            for i in [a, b, c] {
+                let index = list.iter().position(|w| *w == changed[i]).unwrap();
+                changed[i] = list[(index + 1 + i) % list.len()];
+            }
+            let err = decode_words(&changed.join(" "), 2).expect_err("Accepted changed words");
+            assert_eq!(err.error_type, "piece_checksum");
+            assert!(err.message.starts_with("Key piece 2 "));
+        }
+
+        // Swapped, missing and unknown words.
+        let mut swapped = words.clone();
+        swapped.swap(4, 5);
+        assert!(decode_words(&swapped.join(" "), 1).is_err());
+        assert!(decode_words(&words[1..].join(" "), 1).is_err());
+        let mut unknown = words.clone();
+        unknown[3] = "bitcoin";
+        let err = decode_words(&unknown.join(" "), 1).expect_err("Accepted an unknown word");
+        assert_eq!(err.error_type, "piece_words");
+        assert!(decode_words(&words[..CHECKSUM_WORDS].join(" "), 1).is_err());
+
+        assert!(!is_words(&[1, 2, 3]));
+        assert!(!is_words(&[9, 9, 9]));
+        assert!(!is_words(b"academic"));
+    }
+}
@@ -0,0 +1,241 @@
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::aead::{rand_core::RngCore, OsRng};
+
+use crate::armor::{dearmor, is_armored, ArmorKind};
+use crate::crypto::{open_sealed, seal, Key, ENCRYPTION_KEY_LEN};
+use crate::error::{make_error, AppError, AppResult};
+
+/*
+   Passphrase protection
+   magic (4 bytes) | version (u8) | memory in KiB (u32) | iterations (u32) | parallelism (u32) | salt (16 bytes)
+   | sealed data
+
+   The key is derived from the passphrase with Argon2id, using the salt and parameters in the header, so the header is
+   all that is needed to unwrap it, and the parameters can be raised later without breaking older files.
+   The data is sealed with crypto::seal under that key, with the header as AAD, so a changed header is caught too.
+   Integers are big endian. The magic says what the data is, so the same format is used for key pieces and main keys.
+   A protected main key is saved as armored text like any other, so the owner keeps a key file and a passphrase rather
+   than the raw key.
+*/
+
+// The first bytes of a main key protected with a passphrase. Key pieces have their own, in piece.rs.
+pub const PROTECTED_MAIN_KEY_MAGIC: &[u8; 4] = b"DLMK";
+
+// The version written by this build.
+const PROTECTED_VERSION: u8 = 1;
+const SALT_LEN: usize = 16;
+const HEADER_LEN: usize = 4 + 1 + 4 + 4 + 4 + SALT_LEN;
+const SALT_START: usize = HEADER_LEN - SALT_LEN;
+// The largest parameters we will use, so a doctored header can't make us use all of the memory or run forever.
+const MAX_MEMORY_KIB: u32 = 1024 * 1024;
+const MAX_ITERATIONS: u32 = 64;
+const MAX_PARALLELISM: u32 = 16;
+
+/// The Argon2id parameters for deriving a key from a passphrase.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct KdfParams {
+    pub memory_kib: u32,
+    pub iterations: u32,
+    pub parallelism: u32,
+}
+
+// The OWASP recommendation for Argon2id (64 MiB, 3 iterations), which takes around a second on a typical laptop.
+pub const DEFAULT_KDF_PARAMS: KdfParams = KdfParams {
+    memory_kib: 64 * 1024,
+    iterations: 3,
+    parallelism: 1,
+};
+
+/// Wraps data with a passphrase, using the default parameters.
+pub fn protect(magic: &[u8; 4], data: &[u8], passphrase: &str) -> AppResult<Vec<u8>> {
+    return protect_with(magic, data, passphrase, DEFAULT_KDF_PARAMS);
+}
+
+/// Wraps data with a passphrase, using the given parameters.
+pub fn protect_with(
+    magic: &[u8; 4],
+    data: &[u8],
+    passphrase: &str,
+    params: KdfParams,
+) -> AppResult<Vec<u8>> {
+    if passphrase.is_empty() {
+        return Err(make_error("passphrase", "The passphrase can't be empty."));
+    }
+    let mut salt = [0u8; SALT_LEN];
+    OsRng.fill_bytes(&mut salt);
+
+    let mut protected = Vec::from(magic.as_slice());
+    protected.push(PROTECTED_VERSION);
+    protected.extend_from_slice(&params.memory_kib.to_be_bytes());
+    protected.extend_from_slice(&params.iterations.to_be_bytes());
+    protected.extend_from_slice(&params.parallelism.to_be_bytes());
+    protected.extend_from_slice(&salt);
+
+    let key = derive_passphrase_key(passphrase, &salt, params)?;
+    let sealed = seal(&key, data, &protected)?;
+    protected.extend_from_slice(&sealed);
+    return Ok(protected);
+}
+
+/// Unwraps data made by protect. A wrong passphrase gives a wrong_passphrase error.
+pub fn unprotect(magic: &[u8; 4], protected: &[u8], passphrase: &str) -> AppResult<Vec<u8>> {
+    if !is_protected(magic, protected) || protected.len() <= HEADER_LEN {
+        return Err(damaged());
+    }
+    if protected[4] != PROTECTED_VERSION {
+        return Err(make_error(
+            "passphrase_version",
+            "This was protected by a newer version of the app. Please update the app.",
+        ));
+    }
+
+    let read_u32 = |at: usize| {
+        return u32::from_be_bytes(
+            protected[at..at + 4]
+                .try_into()
+                .expect("Header length was checked"),
+        );
+    };
+    let params = KdfParams {
+        memory_kib: read_u32(5),
+        iterations: read_u32(9),
+        parallelism: read_u32(13),
+    };
+    if params.memory_kib > MAX_MEMORY_KIB
+        || params.iterations > MAX_ITERATIONS
+        || params.parallelism > MAX_PARALLELISM
+    {
+        return Err(damaged());
+    }
+
+    let (header, sealed) = protected.split_at(HEADER_LEN);
+    let key = derive_passphrase_key(passphrase, &header[SALT_START..], params)?;
+    return open_sealed(&key, sealed, header).map_err(|_e| {
+        make_error(
+            "wrong_passphrase",
+            "The passphrase is not correct. Passphrases are case sensitive.",
+        )
+    });
+}
+
+/// Whether data is protected by a passphrase, and is of the kind given by the magic.
+pub fn is_protected(magic: &[u8; 4], data: &[u8]) -> bool {
+    return data.starts_with(magic);
+}
+
+/// Reads a main key as the owner gave it, which may be armored and may be protected by a passphrase.
+/// For cloud vaults, the owner token is left after the key.
+pub fn unwrap_main_key(key: &[u8], passphrase: Option<&str>) -> AppResult<Vec<u8>> {
+    let key = if is_armored(key) {
+        dearmor(&String::from_utf8_lossy(key), ArmorKind::MainKey)?
+    } else {
+        key.to_vec()
+    };
+    if !is_protected(PROTECTED_MAIN_KEY_MAGIC, &key) {
+        return Ok(key);
+    }
+    return match passphrase {
+        Some(passphrase) if !passphrase.is_empty() => {
+            unprotect(PROTECTED_MAIN_KEY_MAGIC, &key, passphrase)
+        }
+        _ => Err(make_error(
+            "passphrase_needed",
+            "This main key is protected by a passphrase. Enter it to open the vault.",
+        )),
+    };
+}
+
+fn derive_passphrase_key(passphrase: &str, salt: &[u8], params: KdfParams) -> AppResult<Key> {
+    let argon_params = Params::new(
+        params.memory_kib,
+        params.iterations,
+        params.parallelism,
+        Some(ENCRYPTION_KEY_LEN),
+    )
+    .map_err(|_e| damaged())?;
+    let mut key: Key = [0; ENCRYPTION_KEY_LEN];
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, argon_params)
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|_e| damaged())?;
+    return Ok(key);
+}
+
+fn damaged() -> AppError {
+    return make_error(
+        "passphrase_format",
+        "The passphrase protected data is damaged or incomplete. Check that it was copied in full.",
+    );
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use crate::passphrase::{
+        protect_with, unprotect, unwrap_main_key, KdfParams, HEADER_LEN, PROTECTED_MAIN_KEY_MAGIC,
+    };
+
+    // Small parameters, so tests don't take long. Real files use DEFAULT_KDF_PARAMS.
+    pub const TEST_KDF_PARAMS: KdfParams = KdfParams {
+        memory_kib: 64,
+        iterations: 1,
+        parallelism: 1,
+    };
+
+    #[test]
+    fn round_trip() {
+        let data = vec![5u8; 60];
+        let protected = protect_with(b"TEST", &data, "correct horse", TEST_KDF_PARAMS)
+            .expect("Failed to protect");
+        assert!(protected.starts_with(b"TEST"));
+        assert_eq!(
+            unprotect(b"TEST", &protected, "correct horse").expect("Failed to unprotect"),
+            data
+        );
+
+        let err = unprotect(b"TEST", &protected, "Correct horse").expect_err("Wrong passphrase");
+        assert_eq!(err.error_type, "wrong_passphrase");
+        let err = unprotect(b"OTHR", &protected, "correct horse").expect_err("Wrong kind");
+        assert_eq!(err.error_type, "passphrase_format");
+        assert!(protect_with(b"TEST", &data, "", TEST_KDF_PARAMS).is_err());
+    }
+
+    #[test]
+    fn changes_caught() {
+        let protected = protect_with(b"TEST", &[1, 2, 3], "passphrase", TEST_KDF_PARAMS).unwrap();
+
+        // The parameters and salt are authenticated, as is the data.
+        for i in [8, 12, HEADER_LEN - 1, protected.len() - 1] {
+            let mut changed = protected.clone();
+            changed[i] ^= 1;
+            assert!(unprotect(b"TEST", &changed, "passphrase").is_err());
+        }
+
+        // Parameters too large to be used.
+        let mut greedy = protected.clone();
+        greedy[5..9].copy_from_slice(&u32::MAX.to_be_bytes());
+        let err = unprotect(b"TEST", &greedy, "passphrase").expect_err("Used huge parameters");
+        assert_eq!(err.error_type, "passphrase_format");
+
+        let mut newer = protected.clone();
+        newer[4] += 1;
+        let err = unprotect(b"TEST", &newer, "passphrase").expect_err("Read a newer version");
+        assert_eq!(err.error_type, "passphrase_version");
+        assert!(unprotect(b"TEST", &protected[..HEADER_LEN], "passphrase").is_err());
+    }
+
+    #[test]
+    fn main_keys() {
+        let key = vec![9u8; 40];
+        let protected =
+            protect_with(PROTECTED_MAIN_KEY_MAGIC, &key, "owner", TEST_KDF_PARAMS).unwrap();
+        assert_eq!(unwrap_main_key(&protected, Some("owner")).unwrap(), key);
+        assert_eq!(unwrap_main_key(&key, None).unwrap(), key);
+
+        let err = unwrap_main_key(&protected, None).expect_err("Opened without the passphrase");
+        assert_eq!(err.error_type, "passphrase_needed");
+        let err = unwrap_main_key(&protected, Some("")).expect_err("Opened without the passphrase");
+        assert_eq!(err.error_type, "passphrase_needed");
+        let err = unwrap_main_key(&protected, Some("other"))
+            .expect_err("Opened with the wrong passphrase");
+        assert_eq!(err.error_type, "wrong_passphrase");
+    }
+}
@@ -0,0 +1,582 @@
+use sha2::{Digest, Sha256};
+
+use crate::armor::{dearmor, is_armored, ArmorKind};
+use crate::crypto::{mnemonic_to_piece, split_piece, GroupPart, KeyPiece};
+use crate::error::{make_error, AppError, AppResult};
+use crate::mnemonic::is_words;
+use crate::passphrase::{is_protected, protect, unprotect};
+use crate::recipient::is_sealed;
+use crate::signature::FINGERPRINT_LEN;
+use crate::vault::PublicInfo;
+use crate::vss::verify_share;
+
+/*
+   Key piece envelope (version 3)
+   magic (4 bytes) | envelope version (u8) | key id (8 bytes) | circle index (u8) | member index (u16)
+   | owner fingerprint (16 bytes) | piece | checksum (4 bytes)
+
+   The piece is the raw key piece made by crypto::generate_circle_keys. The key id identifies the vault (see
+   crypto::derive_key_id), and the circle and member index are positions in the vault's share configuration.
+   The owner fingerprint is the fingerprint of the key the owner signs the vault with (see signature.rs), so a piece
+   is only used to unlock a vault signed by the owner it was made by, whatever the vault's header claims.
+   The checksum is the start of the SHA-256 of everything before it, so a mistyped piece is caught before combining.
+   Pieces from before the envelope existed do not start with the magic, and are accepted as they are.
+   Version 1 envelopes, from before circles could have more than 255 members, have a one byte member index.
+   Version 2 envelopes, from before pieces carried the owner fingerprint, have none. Pieces with no fingerprint are
+   still written as version 2, as when a version 2 piece written as words is read.
+   Pieces written as words (see mnemonic.rs) hold everything but the magic and checksum, and are put back in an
+   envelope when read. Pieces saved as armored text (see armor.rs) hold the whole envelope, as do the QR codes on
+   recovery sheets (see sheet.rs), which are the QR prefix followed by the envelope in upper case hex. Upper case hex
+   fits in the QR alphanumeric mode, which is smaller than bytes and survives scanner apps that only return text.
+   Pieces can also be protected with a passphrase (see passphrase.rs), with their own magic. They must be unprotected
+   before they can be unwrapped.
+*/
+
+// The first bytes of every key piece envelope.
+pub const PIECE_MAGIC: &[u8; 4] = b"DLKP";
+// The envelope version written by this build.
+pub const PIECE_VERSION: u8 = 3;
+// The envelope version for pieces without an owner fingerprint.
+const UNSIGNED_PIECE_VERSION: u8 = 2;
+pub const KEY_ID_LEN: usize = 8;
+const CHECKSUM_LEN: usize = 4;
+// The first bytes of a key piece protected with a passphrase.
+pub const PROTECTED_PIECE_MAGIC: &[u8; 4] = b"DLPP";
+// The start of every QR code on a recovery sheet.
+pub const QR_PREFIX: &str = "DLKP:";
+// Everything before the member index.
+const ENVELOPE_HEADER_LEN: usize = PIECE_MAGIC.len() + 1 + KEY_ID_LEN + 1;
+
+/// Where a key piece came from.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PieceInfo {
+    pub key_id: Vec<u8>,
+    pub circle: u8,
+    pub member: u16,
+    // The fingerprint of the owner's signing key. Empty for pieces made before pieces carried it.
+    pub owner_fingerprint: Vec<u8>,
+}
+
+impl PieceInfo {
+    /// The envelope version the piece is written in.
+    pub fn version(&self) -> u8 {
+        return if self.owner_fingerprint.is_empty() {
+            UNSIGNED_PIECE_VERSION
+        } else {
+            PIECE_VERSION
+        };
+    }
+}
+
+/// Puts a raw key piece in an envelope.
+pub fn wrap_piece(info: &PieceInfo, piece: &[u8]) -> KeyPiece {
+    let mut wrapped = Vec::from(PIECE_MAGIC.as_slice());
+    wrapped.push(info.version());
+    wrapped.extend_from_slice(&info.key_id);
+    wrapped.push(info.circle);
+    wrapped.extend_from_slice(&info.member.to_be_bytes());
+    wrapped.extend_from_slice(&info.owner_fingerprint);
+    wrapped.extend_from_slice(piece);
+
+    let checksum = checksum(&wrapped);
+    wrapped.extend_from_slice(&checksum);
+    return wrapped;
+}
+
+/// Takes a key piece out of its envelope, checking it is intact.
+/// Returns the envelope information (None for pieces without an envelope) and the raw piece.
+/// Pieces written as words (see crypto::piece_to_mnemonic), as armored text or as a scanned QR code are accepted
+/// as text.
+/// The position is the piece's place in the list the user gave, starting from 1, and is used in errors.
+pub fn unwrap_piece(piece: &[u8], position: usize) -> AppResult<(Option<PieceInfo>, Vec<u8>)> {
+    if is_armored(piece) {
+        return unwrap_piece(&dearmor_if_needed(piece, position)?, position);
+    }
+    // Sealed pieces are opened before combining (see recipient::open_sealed_pieces), so this one could not be.
+    if is_sealed(piece) {
+        return Err(piece_error(
+            "piece_sealed",
+            position,
+            "is sealed to its recipient's key, and could not be opened. Give the identity file for that key.",
+        ));
+    }
+    if is_protected(PROTECTED_PIECE_MAGIC, piece) {
+        return Err(piece_error(
+            "piece_protected",
+            position,
+            "is protected by a passphrase. Enter the passphrase for it when adding it.",
+        ));
+    }
+    if is_qr_payload(piece) {
+        let payload = String::from_utf8_lossy(piece);
+        return unwrap_piece(&qr_payload_to_piece(&payload, position)?, position);
+    }
+    if is_words(piece) {
+        let words = String::from_utf8_lossy(piece);
+        return unwrap_piece(&mnemonic_to_piece(&words, position)?, position);
+    }
+    if !piece.starts_with(PIECE_MAGIC) {
+        return Ok((None, piece.to_vec()));
+    }
+    let incomplete = || {
+        return piece_error(
+            "piece_checksum",
+            position,
+            "is incomplete. Check that it was copied in full.",
+        );
+    };
+    let version = *piece.get(PIECE_MAGIC.len()).ok_or_else(incomplete)?;
+    let (member_len, fingerprint_len) = envelope_layout(version).ok_or(piece_error(
+        "piece_version",
+        position,
+        "was made by a newer version of the app. Please update the app.",
+    ))?;
+    let header_len = ENVELOPE_HEADER_LEN + member_len + fingerprint_len;
+    if piece.len() <= header_len + CHECKSUM_LEN {
+        return Err(incomplete());
+    }
+
+    let (body, stored_checksum) = piece.split_at(piece.len() - CHECKSUM_LEN);
+    if checksum(body) != stored_checksum {
+        return Err(piece_error(
+            "piece_checksum",
+            position,
+            "is damaged or has been mistyped. Check that it was copied correctly.",
+        ));
+    }
+
+    let id_start = PIECE_MAGIC.len() + 1;
+    let fingerprint_start = ENVELOPE_HEADER_LEN + member_len;
+    let info = PieceInfo {
+        key_id: body[id_start..id_start + KEY_ID_LEN].to_vec(),
+        circle: body[id_start + KEY_ID_LEN],
+        member: read_member_index(&body[ENVELOPE_HEADER_LEN..fingerprint_start]),
+        owner_fingerprint: body[fingerprint_start..header_len].to_vec(),
+    };
+    return Ok((Some(info), body[header_len..].to_vec()));
+}
+
+/// The lengths of the member index and the owner fingerprint in an envelope of the given version, or None for a
+/// version we can't read.
+pub fn envelope_layout(version: u8) -> Option<(usize, usize)> {
+    return match version {
+        1 => Some((1, 0)),
+        UNSIGNED_PIECE_VERSION => Some((2, 0)),
+        PIECE_VERSION => Some((2, FINGERPRINT_LEN)),
+        _ => None,
+    };
+}
+
+/// Reads a member index of the length given by envelope_layout.
+pub fn read_member_index(bytes: &[u8]) -> u16 {
+    return match bytes {
+        [member] => u16::from(*member),
+        [high, low] => u16::from_be_bytes([*high, *low]),
+        _ => 0,
+    };
+}
+
+/// Protects a key piece with a passphrase chosen by its recipient.
+pub fn protect_piece(piece: &[u8], passphrase: &str) -> AppResult<KeyPiece> {
+    unwrap_piece(piece, 1)?;
+    return protect(PROTECTED_PIECE_MAGIC, piece, passphrase);
+}
+
+/// Removes the passphrase from a key piece, which may be given in any of the forms unwrap_piece accepts.
+/// The position is the piece's place in the list the user gave, starting from 1, and is used in errors.
+pub fn unprotect_piece(piece: &[u8], passphrase: &str, position: usize) -> AppResult<KeyPiece> {
+    let protected = dearmor_if_needed(piece, position)?;
+    let unprotected = unprotect(PROTECTED_PIECE_MAGIC, &protected, passphrase).map_err(|e| {
+        return piece_error(
+            &e.error_type,
+            position,
+            &format!("could not be unlocked. {}", e.message),
+        );
+    })?;
+    unwrap_piece(&unprotected, position)?;
+    return Ok(unprotected);
+}
+
+/// Whether a key piece, in any of the forms unwrap_piece accepts, is protected by a passphrase.
+pub fn is_protected_piece(piece: &[u8]) -> bool {
+    return dearmor_if_needed(piece, 1)
+        .map(|piece| is_protected(PROTECTED_PIECE_MAGIC, &piece))
+        .unwrap_or(false);
+}
+
+// Protected pieces can be saved as armored text like any other.
+fn dearmor_if_needed(piece: &[u8], position: usize) -> AppResult<Vec<u8>> {
+    if !is_armored(piece) {
+        return Ok(piece.to_vec());
+    }
+    let text = String::from_utf8_lossy(piece);
+    return dearmor(&text, ArmorKind::KeyPiece).map_err(|e| {
+        piece_error(
+            &e.error_type,
+            position,
+            &format!("could not be read. {}", e.message),
+        )
+    });
+}
+
+/// The text of the QR code for a key piece on a recovery sheet.
+pub fn piece_to_qr_payload(piece: &[u8]) -> String {
+    let hex: String = piece.iter().map(|b| format!("{:02X}", b)).collect();
+    return format!("{}{}", QR_PREFIX, hex);
+}
+
+/// Reads the key piece from a scanned QR code. The piece is not checked, so use unwrap_piece on it.
+/// The position is the piece's place in the list the user gave, starting from 1, and is used in errors.
+pub fn qr_payload_to_piece(payload: &str, position: usize) -> AppResult<KeyPiece> {
+    let unreadable = || {
+        return piece_error(
+            "piece_qr",
+            position,
+            "could not be read from its QR code. Try scanning it again, or type the words printed under it.",
+        );
+    };
+    let hex = payload
+        .trim()
+        .strip_prefix(QR_PREFIX)
+        .ok_or_else(unreadable)?
+        .as_bytes();
+    if hex.is_empty() || hex.len() % 2 != 0 {
+        return Err(unreadable());
+    }
+    return hex
+        .chunks(2)
+        .map(|pair| {
+            let pair = std::str::from_utf8(pair).map_err(|_e| unreadable())?;
+            return u8::from_str_radix(pair, 16).map_err(|_e| unreadable());
+        })
+        .collect();
+}
+
+/// Whether a key piece looks like a scanned QR code from a recovery sheet.
+pub fn is_qr_payload(piece: &[u8]) -> bool {
+    return piece.trim_ascii_start().starts_with(QR_PREFIX.as_bytes());
+}
+
+/// Takes a key piece out of its envelope, checking it is intact and belongs to the vault described by the public
+/// info. Where the vault has share commitments, every share in the piece is checked against them.
+/// Pieces without an envelope can't be matched to a circle, so only their shares are checked.
+pub fn check_piece(
+    piece: &[u8],
+    position: usize,
+    info: &PublicInfo,
+) -> AppResult<(Option<PieceInfo>, Vec<u8>)> {
+    let (piece_info, raw) = unwrap_piece(piece, position)?;
+
+    let mut circle_required: Option<bool> = None;
+    if let Some(piece_info) = &piece_info {
+        // Vaults from before pieces had envelopes have no key id to check against.
+        if !info.key_id.is_empty() && piece_info.key_id != info.key_id {
+            return Err(piece_error(
+                "piece_foreign",
+                position,
+                "is for a different vault.",
+            ));
+        }
+
+        let circle = info
+            .share_config
+            .circles
+            .get(usize::from(piece_info.circle));
+        let comment = circle.and_then(|c| c.key_comments.get(usize::from(piece_info.member)));
+        if circle.is_none() || comment.is_none() {
+            return Err(piece_error(
+                "piece_foreign",
+                position,
+                "does not match any key in this vault.",
+            ));
+        }
+        circle_required = circle.map(|c| c.required);
+
+        if !piece_info.owner_fingerprint.is_empty()
+            && piece_info.owner_fingerprint != info.owner_fingerprint
+        {
+            return Err(piece_error(
+                "piece_foreign",
+                position,
+                "was made by a different owner than this vault's.",
+            ));
+        }
+    }
+
+    // Vaults from before pieces could be checked have no commitments.
+    if !info.commitments.individual.is_empty() {
+        let parts = split_piece(&raw)?;
+        // Legacy pieces have no verifiable shares, so never match.
+        let format = parts.format.share_format();
+        let shares_valid = format.is_some_and(|format| {
+            parts
+                .shares
+                .iter()
+                .all(|share| verify_share(share, &info.commitments.individual, format))
+        });
+        let group_valid = match &parts.group {
+            GroupPart::None => circle_required != Some(true),
+            GroupPart::CircleShare(circle, share) => format.is_some_and(|format| {
+                info.commitments
+                    .circles
+                    .get(usize::from(*circle))
+                    .is_some_and(|commitments| verify_share(share, commitments, format))
+            }),
+            GroupPart::GroupKey(_) => false,
+        };
+        if !shares_valid || !group_valid {
+            return Err(piece_error(
+                "piece_invalid",
+                position,
+                "does not match this vault's commitments. It has been changed, or is not a real key piece.",
+            ));
+        }
+    }
+    return Ok((piece_info, raw));
+}
+
+/// Checks every key piece for the vault which carries an owner fingerprint was made for a vault signed by that owner.
+/// The signer is the fingerprint of whoever signed the vault, or empty if it is not signed. The fingerprint in the
+/// vault's public info can't be used, as whoever made the vault chose it.
+/// Pieces for other vaults are left to check_piece, which reports them as foreign.
+pub fn check_piece_owners(pieces: &[KeyPiece], info: &PublicInfo, signer: &[u8]) -> AppResult<()> {
+    for (index, piece) in pieces.iter().enumerate() {
+        // Pieces which can't be read are left out when combining, and reported then.
+        let piece_info = match unwrap_piece(piece, index + 1) {
+            Ok((Some(piece_info), _raw)) => piece_info,
+            _ => continue,
+        };
+        if piece_info.owner_fingerprint.is_empty() || piece_info.key_id != info.key_id {
+            continue;
+        }
+        if piece_info.owner_fingerprint != signer {
+            return Err(piece_error(
+                "bad_signature",
+                index + 1,
+                "was made for a vault signed by its owner, but this vault is not signed by them. It may not have \
+                come from them, so do not trust it.",
+            ));
+        }
+    }
+    return Ok(());
+}
+
+fn checksum(data: &[u8]) -> [u8; CHECKSUM_LEN] {
+    let digest = Sha256::digest(data);
+    let mut checksum = [0u8; CHECKSUM_LEN];
+    checksum.copy_from_slice(&digest[0..CHECKSUM_LEN]);
+    return checksum;
+}
+
+pub(crate) fn piece_error(error_type: &str, position: usize, problem: &str) -> AppError {
+    return make_error(error_type, &format!("Key piece {} {}", position, problem));
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::crypto::tests::{get_basic_combo, get_public_info};
+    use crate::crypto::KeyPiece;
+    use crate::error::AppResult;
+    use crate::piece::{
+        check_piece, check_piece_owners, piece_to_qr_payload, qr_payload_to_piece, unwrap_piece,
+        wrap_piece, PieceInfo, PIECE_VERSION,
+    };
+    use crate::signature::FINGERPRINT_LEN;
+    use crate::vault::PublicInfo;
+
+    fn get_info() -> PieceInfo {
+        return PieceInfo {
+            key_id: vec![1, 2, 3, 4, 5, 6, 7, 8],
+            circle: 2,
+            member: 5,
+            owner_fingerprint: vec![7; FINGERPRINT_LEN],
+        };
+    }
+
+    #[test]
+    fn round_trip() {
+        let raw = vec![9u8; 113];
+        let wrapped = wrap_piece(&get_info(), &raw);
+
+        let (info, unwrapped) = unwrap_piece(&wrapped, 1).expect("Failed to unwrap");
+        assert_eq!(info, Some(get_info()));
+        assert_eq!(unwrapped, raw);
+        assert_eq!(wrapped[4], PIECE_VERSION);
+
+        // Pieces without an owner fingerprint are written as before pieces carried it.
+        let unsigned = PieceInfo {
+            owner_fingerprint: vec![],
+            ..get_info()
+        };
+        let wrapped = wrap_piece(&unsigned, &raw);
+        assert_eq!(wrapped[4], 2);
+        let (info, unwrapped) = unwrap_piece(&wrapped, 1).expect("Failed to unwrap");
+        assert_eq!(info, Some(unsigned));
+        assert_eq!(unwrapped, raw);
+    }
+
+    #[test]
+    fn qr_round_trip() {
+        let raw = vec![9u8; 113];
+        let wrapped = wrap_piece(&get_info(), &raw);
+        let payload = piece_to_qr_payload(&wrapped);
+        assert!(payload
+            .chars()
+            .all(|c| c.is_ascii_digit() || c.is_ascii_uppercase() || c == ':'));
+        assert_eq!(qr_payload_to_piece(&payload, 1).unwrap(), wrapped);
+
+        // Scanned codes can be given in place of the piece.
+        let (info, unwrapped) = unwrap_piece(format!("{}\n", payload).as_bytes(), 1)
+            .expect("Failed to unwrap a scanned code");
+        assert_eq!(info, Some(get_info()));
+        assert_eq!(unwrapped, raw);
+
+        // Cut short, damaged and not from a sheet.
+        let err = unwrap_piece(&payload.as_bytes()[..payload.len() - 1], 2)
+            .expect_err("Accepted a cut code");
+        assert_eq!(err.error_type, "piece_qr");
+        assert!(err.message.starts_with("Key piece 2 "));
+        let damaged = payload.replacen("09", "19", 1);
+        let err = unwrap_piece(damaged.as_bytes(), 1).expect_err("Accepted a damaged code");
+        assert_eq!(err.error_type, "piece_checksum");
+        assert!(qr_payload_to_piece("https://example.com", 1).is_err());
+        assert!(qr_payload_to_piece("DLKP:XY", 1).is_err());
+    }
+
+    #[test]
+    fn legacy_pieces_accepted() {
+        let raw = vec![3u8; 146];
+        let (info, unwrapped) = unwrap_piece(&raw, 1).expect("Failed to unwrap");
+        assert!(info.is_none());
+        assert_eq!(unwrapped, raw);
+    }
+
+    #[test]
+    fn damaged_pieces_rejected() {
+        let wrapped = wrap_piece(&get_info(), &[9u8; 113]);
+
+        // Every single byte change after the magic is caught.
+        for i in 4..wrapped.len() {
+            let mut damaged = wrapped.clone();
+            damaged[i] ^= 0x10;
+            let err = unwrap_piece(&damaged, 3).expect_err("Accepted a damaged piece");
+            assert!(err.message.starts_with("Key piece 3 "));
+        }
+
+        let err = unwrap_piece(&wrapped[..wrapped.len() - 1], 1).expect_err("Accepted a cut piece");
+        assert_eq!(err.error_type, "piece_checksum");
+        let err = unwrap_piece(&wrapped[..10], 1).expect_err("Accepted a cut piece");
+        assert_eq!(err.error_type, "piece_checksum");
+
+        let mut newer = wrapped.clone();
+        newer[4] = PIECE_VERSION + 1;
+        let err = unwrap_piece(&newer, 1).expect_err("Accepted a newer piece");
+        assert_eq!(err.error_type, "piece_version");
+    }
+
+    fn check_pieces(pieces: &[KeyPiece], info: &PublicInfo) -> AppResult<()> {
+        for (index, piece) in pieces.iter().enumerate() {
+            check_piece(piece, index + 1, info)?;
+        }
+        return Ok(());
+    }
+
+    #[test]
+    fn foreign_pieces_rejected() {
+        let public_info = PublicInfo {
+            key_id: vec![1, 2, 3, 4, 5, 6, 7, 8],
+            ..get_public_info(get_basic_combo(), 3)
+        };
+        let piece = |key_id: Vec<u8>, circle: u8, member: u16| {
+            let info = PieceInfo {
+                key_id,
+                circle,
+                member,
+                owner_fingerprint: vec![],
+            };
+            return wrap_piece(&info, &[9u8; 113]);
+        };
+
+        let good = vec![
+            piece(public_info.key_id.clone(), 0, 0),
+            piece(public_info.key_id.clone(), 0, 4),
+            vec![9u8; 113],
+        ];
+        check_pieces(&good, &public_info).expect("Rejected good pieces");
+
+        let mut other_vault = good.clone();
+        other_vault.push(piece(vec![0; 8], 0, 1));
+        let err = check_pieces(&other_vault, &public_info).expect_err("Accepted a foreign piece");
+        assert_eq!(err.error_type, "piece_foreign");
+        assert!(err.message.starts_with("Key piece 4 "));
+
+        for (circle, member) in [(1, 0), (0, 5)] {
+            let mut no_such_key = good.clone();
+            no_such_key.insert(0, piece(public_info.key_id.clone(), circle, member));
+            let err =
+                check_pieces(&no_such_key, &public_info).expect_err("Accepted a foreign piece");
+            assert_eq!(err.error_type, "piece_foreign");
+            assert!(err.message.starts_with("Key piece 1 "));
+        }
+
+        // Pieces made by another owner, or for a vault whose info names no owner.
+        let signed = PublicInfo {
+            owner_fingerprint: vec![7; FINGERPRINT_LEN],
+            ..public_info.clone()
+        };
+        let owned = |owner_fingerprint: Vec<u8>| {
+            let info = PieceInfo {
+                key_id: public_info.key_id.clone(),
+                circle: 0,
+                member: 1,
+                owner_fingerprint,
+            };
+            return wrap_piece(&info, &[9u8; 113]);
+        };
+        check_pieces(&[owned(vec![7; FINGERPRINT_LEN])], &signed).expect("Rejected a good piece");
+        let err = check_pieces(&[owned(vec![8; FINGERPRINT_LEN])], &signed)
+            .expect_err("Accepted another owner's piece");
+        assert_eq!(err.error_type, "piece_foreign");
+        let err = check_pieces(&[owned(vec![7; FINGERPRINT_LEN])], &public_info)
+            .expect_err("Accepted a piece for an unsigned vault");
+        assert_eq!(err.error_type, "piece_foreign");
+    }
+
+    // A piece naming its owner is only used for a vault that owner signed.
+    #[test]
+    fn piece_owners() {
+        let public_info = PublicInfo {
+            key_id: get_info().key_id,
+            ..get_public_info(get_basic_combo(), 3)
+        };
+        let owned = wrap_piece(&get_info(), &[9u8; 113]);
+        let unsigned = wrap_piece(
+            &PieceInfo {
+                owner_fingerprint: vec![],
+                ..get_info()
+            },
+            &[9u8; 113],
+        );
+        let pieces = vec![unsigned.clone(), owned, vec![9u8; 113]];
+
+        check_piece_owners(&pieces, &public_info, &[7; FINGERPRINT_LEN])
+            .expect("Rejected the owner's vault");
+        let err = check_piece_owners(&pieces, &public_info, &[8; FINGERPRINT_LEN])
+            .expect_err("Accepted another signer");
+        assert_eq!(err.error_type, "bad_signature");
+        assert!(err.message.starts_with("Key piece 2 "));
+        let err =
+            check_piece_owners(&pieces, &public_info, &[]).expect_err("Accepted an unsigned vault");
+        assert_eq!(err.error_type, "bad_signature");
+
+        // Pieces which don't name their owner can be used for any vault, and pieces for other vaults are left to
+        // check_piece.
+        check_piece_owners(&[unsigned, vec![9u8; 113]], &public_info, &[])
+            .expect("Rejected older pieces");
+        let other_vault = PublicInfo {
+            key_id: vec![0; 8],
+            ..public_info
+        };
+        check_piece_owners(&pieces, &other_vault, &[]).expect("Checked a foreign piece");
+    }
+}
@@ -0,0 +1,148 @@
+use std::io::{BufReader, Read};
+use std::str::FromStr;
+
+use age::armor::ArmoredReader;
+use age::x25519;
+use age::{DecryptError, Decryptor, Identity, IdentityFile};
+
+use crate::crypto::KeyPiece;
+use crate::error::{make_error, AppResult};
+
+/*
+   Sealed key pieces
+   Recipients can give the owner an age (https://age-encryption.org) X25519 public key, such as one made by age-keygen.
+   Their key piece is then also sealed to that key, so it can be sent by email or over other channels which are not
+   private. Only the matching identity file can open it.
+   Sealed pieces are armored age files, so a recipient can also open one with age itself (age -d -i key.txt piece.age).
+*/
+
+const ARMOR_BEGIN: &str = "-----BEGIN AGE ENCRYPTED FILE-----";
+// The start of an age file which is not armored.
+const BINARY_BEGIN: &str = "age-encryption.org/";
+
+/// Reads a recipient's public key.
+pub fn parse_recipient(recipient: &str) -> AppResult<x25519::Recipient> {
+    return x25519::Recipient::from_str(recipient.trim()).map_err(|_e| {
+        make_error(
+            "bad_recipient",
+            "This is not a valid public key. Public keys start with age1, and are made by age-keygen.",
+        )
+    });
+}
+
+/// Seals a key piece to a recipient's public key, as armored age text.
+pub fn seal_piece(piece: &[u8], recipient: &str) -> AppResult<String> {
+    let recipient = parse_recipient(recipient)?;
+    return age::encrypt_and_armor(&recipient, piece)
+        .map_err(|_e| make_error("crypto", "The key piece could not be sealed."));
+}
+
+/// Whether a key piece is sealed to a recipient.
+pub fn is_sealed(piece: &[u8]) -> bool {
+    let piece = piece.trim_ascii_start();
+    return piece.starts_with(ARMOR_BEGIN.as_bytes()) || piece.starts_with(BINARY_BEGIN.as_bytes());
+}
+
+/// Opens any sealed key pieces using the given identity files, one after another.
+/// Pieces which are not sealed are left as they are. So are sealed pieces which none of the identities can open, so
+/// they can be reported with the other bad pieces.
+pub fn open_sealed_pieces(
+    pieces: &[KeyPiece],
+    identities: Option<&str>,
+) -> AppResult<Vec<KeyPiece>> {
+    if !pieces.iter().any(|piece| is_sealed(piece)) {
+        return Ok(pieces.to_vec());
+    }
+    let identities = match identities {
+        Some(identities) => parse_identities(identities)?,
+        None => return Ok(pieces.to_vec()),
+    };
+
+    return Ok(pieces
+        .iter()
+        .map(|piece| {
+            if !is_sealed(piece) {
+                return piece.clone();
+            }
+            return open_piece(piece, &identities).unwrap_or_else(|_e| piece.clone());
+        })
+        .collect());
+}
+
+fn parse_identities(identities: &str) -> AppResult<Vec<Box<dyn Identity>>> {
+    let unreadable = || {
+        return make_error(
+            "identity",
+            "The identity file could not be read. It should contain a key starting with AGE-SECRET-KEY-1.",
+        );
+    };
+    return IdentityFile::from_buffer(BufReader::new(identities.as_bytes()))
+        .map_err(|_e| unreadable())?
+        .into_identities()
+        .map_err(|_e| unreadable());
+}
+
+fn open_piece(piece: &[u8], identities: &[Box<dyn Identity>]) -> Result<KeyPiece, DecryptError> {
+    let decryptor = Decryptor::new_buffered(ArmoredReader::new(piece.trim_ascii()))?;
+    let mut reader = decryptor.decrypt(identities.iter().map(|i| i.as_ref() as &dyn Identity))?;
+    let mut opened = Vec::new();
+    reader.read_to_end(&mut opened)?;
+    return Ok(opened);
+}
+
+#[cfg(test)]
+mod tests {
+    use age::secrecy::ExposeSecret;
+    use age::x25519;
+
+    use crate::piece::unwrap_piece;
+    use crate::recipient::{is_sealed, open_sealed_pieces, parse_recipient, seal_piece};
+
+    #[test]
+    fn seal_and_open() {
+        let identity = x25519::Identity::generate();
+        let recipient = identity.to_public().to_string();
+        let identity_file = format!(
+            "# created: today\n# public key: {}\n{}\n",
+            recipient,
+            identity.to_string().expose_secret()
+        );
+        let piece = vec![7u8; 90];
+
+        let sealed = seal_piece(&piece, &recipient).expect("Failed to seal");
+        assert!(sealed.starts_with("-----BEGIN AGE ENCRYPTED FILE-----"));
+        assert!(is_sealed(sealed.as_bytes()));
+        assert!(!is_sealed(&piece));
+
+        // Only sealed pieces are opened, and any which can't be are left for combining to report.
+        let other = x25519::Identity::generate();
+        let other_sealed = seal_piece(&piece, &other.to_public().to_string()).unwrap();
+        let pieces = Vec::from([
+            sealed.clone().into_bytes(),
+            vec![1, 2, 3],
+            other_sealed.clone().into_bytes(),
+        ]);
+        let opened = open_sealed_pieces(&pieces, Some(&identity_file)).expect("Failed to open");
+        assert_eq!(opened[0], piece);
+        assert_eq!(opened[1], vec![1, 2, 3]);
+        assert_eq!(opened[2], other_sealed.as_bytes());
+        assert_eq!(open_sealed_pieces(&pieces, None).unwrap(), pieces);
+
+        let err = unwrap_piece(&opened[2], 3).expect_err("Unwrapped a sealed piece");
+        assert_eq!(err.error_type, "piece_sealed");
+        assert!(err.message.starts_with("Key piece 3 "));
+        let err = open_sealed_pieces(&pieces, Some("not an identity"))
+            .expect_err("Read a bad identity file");
+        assert_eq!(err.error_type, "identity");
+    }
+
+    #[test]
+    fn bad_recipients() {
+        assert!(parse_recipient("age1notakey").is_err());
+        assert!(parse_recipient("").is_err());
+        let recipient = x25519::Identity::generate().to_public().to_string();
+        assert!(parse_recipient(&format!(" {}\n", recipient)).is_ok());
+        let err = seal_piece(&[1, 2, 3], "ssh-rsa AAAA").expect_err("Sealed to a bad key");
+        assert_eq!(err.error_type, "bad_recipient");
+    }
+}
@@ -0,0 +1,202 @@
+use qrcode::render::svg;
+use qrcode::{EcLevel, QrCode};
+
+use crate::armor::scheme_comment;
+use crate::crypto::piece_to_mnemonic;
+use crate::error::{make_error, AppResult};
+use crate::piece::{is_protected_piece, piece_to_qr_payload};
+use crate::signature::format_fingerprint;
+use crate::vault::{Circle, PublicInfo};
+
+/*
+   Recovery sheets
+   A printable page for each key piece, for recipients who would rather keep their piece on paper.
+   Each sheet is a standalone HTML file, with the QR code drawn as inline SVG so it prints without the app or an
+   internet connection. Under the QR code is the piece as words (see mnemonic.rs), which can be typed in if the code
+   can't be scanned. Pieces from before pieces had envelopes can't be written as words, so their bytes are shown instead.
+*/
+
+// The size of the printed QR code, in CSS pixels.
+const QR_SIZE: u32 = 320;
+// Words are numbered and shown in columns, so they are easier to copy out and check.
+const STYLE: &str = "body { font-family: sans-serif; max-width: 48em; margin: 2em auto; color: #000; }
+h1 { font-size: 1.6em; margin-bottom: 0.2em; }
+.qr { text-align: center; margin: 1.5em 0; }
+.words { display: grid; grid-template-columns: repeat(4, 1fr); gap: 0.3em 1.5em; font-family: monospace; font-size: 1.1em; }
+.bytes { font-family: monospace; word-break: break-all; }
+.note { border: 1px solid #000; padding: 0.5em 1em; }
+@media print { body { margin: 0; } }";
+
+/// Renders the recovery sheet for one member of a circle.
+/// The number is the piece's number across all circles, starting from 1, as shown when the pieces were shared.
+pub fn render_sheet(
+    info: &PublicInfo,
+    circle: &Circle,
+    member: usize,
+    number: usize,
+    piece: &[u8],
+) -> AppResult<String> {
+    let code = QrCode::with_error_correction_level(piece_to_qr_payload(piece), EcLevel::M)
+        .map_err(|_e| make_error("sheet", "This key piece is too large to fit in a QR code."))?;
+    let image = code
+        .render::<svg::Color>()
+        .min_dimensions(QR_SIZE, QR_SIZE)
+        .build();
+    // The XML declaration is not needed inside HTML.
+    let image = &image[image.find("<svg").unwrap_or(0)..];
+
+    let fallback = match piece_to_mnemonic(piece) {
+        Ok(words) => {
+            let items: Vec<String> = words
+                .split(' ')
+                .enumerate()
+                .map(|(index, word)| format!("<span>{}. {}</span>", index + 1, word))
+                .collect();
+            format!(
+                "<p>If the code can't be scanned, type these {} words in order instead:</p>\n<div class=\"words\">{}</div>",
+                items.len(),
+                items.join("")
+            )
+        }
+        Err(_e) => {
+            let hex: Vec<String> = piece.iter().map(|b| format!("{:x}", b)).collect();
+            format!(
+                "<p>If the code can't be scanned, type this key in instead:</p>\n<p class=\"bytes\">{}</p>",
+                hex.join(":")
+            )
+        }
+    };
+
+    let holder = circle
+        .key_comments
+        .get(member)
+        .map(|comment| escape(comment))
+        .unwrap_or_default();
+    let mut circle_text = format!(
+        "This piece belongs to {} in the <strong>{}</strong> circle.",
+        holder,
+        escape(&circle.name)
+    );
+    if circle.required {
+        circle_text.push_str(&format!(
+            " At least {} of the {} members of this circle must take part.",
+            circle.threshold.max(1),
+            circle.key_comments.len()
+        ));
+    }
+    let weight = circle.weight(member);
+    if weight > 1 {
+        circle_text.push_str(&format!(" This piece counts as {} key pieces.", weight));
+    }
+
+    if is_protected_piece(piece) {
+        circle_text.push_str(
+            " It is protected by a passphrase which you chose. You will be asked for it when the piece is used.",
+        );
+    }
+
+    let fingerprint = if info.owner_fingerprint.is_empty() {
+        String::new()
+    } else {
+        format!(
+            "<p>The vault is signed by {}'s key, with the fingerprint <code>{}</code>. The app shows this \
+fingerprint when the vault is unlocked. If it is different, the vault did not come from them.</p>",
+            escape(&info.name),
+            format_fingerprint(&info.owner_fingerprint)
+        )
+    };
+
+    let owner = escape(&info.name);
+    return Ok(format!(
+        "<!DOCTYPE html>
+<html lang=\"en\">
+<head>
+<meta charset=\"utf-8\">
+<title>Key piece {number} for {owner}'s digital vault</title>
+<style>
+{STYLE}
+</style>
+</head>
+<body>
+<h1>Key piece {number} for {owner}'s digital vault</h1>
+<p>This is a key piece from a digital vault, which contains {owner}'s personal data. It is used to access that data \
+if they die or in other emergency circumstances.</p>
+<p>{circle_text}</p>
+<p>{scheme}</p>
+{fingerprint}
+<div class=\"qr\">{image}</div>
+{fallback}
+<h2>How to use this key piece</h2>
+<ol>
+<li>Keep this sheet somewhere safe, such as with your important documents. Do not share it or make copies unless {owner} asks you to.</li>
+<li>When the time comes, contact the other people who hold key pieces. Enough of you must come together to unlock the vault.</li>
+<li>Install the Digital Legacies application and choose to unlock a vault. You will also need the vault file, or an internet connection if the vault was stored online.</li>
+<li>Scan the QR code above, or type in the words under it, when you are asked for your key piece.</li>
+</ol>
+<p class=\"note\">If this sheet is lost or damaged, let {owner} know so they can make you a new key piece.</p>
+</body>
+</html>
+",
+        scheme = escape(&scheme_comment(info)),
+    ));
+}
+
+// Names and comments are entered by the owner, so they must not be able to change the page.
+fn escape(text: &str) -> String {
+    return text
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;");
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::crypto::tests::{get_basic_combo, get_public_info};
+    use crate::crypto::{generate_circle_keys, generate_key};
+    use crate::passphrase::protect_with;
+    use crate::passphrase::tests::TEST_KDF_PARAMS;
+    use crate::piece::{piece_to_qr_payload, PROTECTED_PIECE_MAGIC};
+    use crate::sheet::{escape, render_sheet};
+    use crate::vault::PublicInfo;
+
+    #[test]
+    fn sheet_contents() {
+        let mut circles = get_basic_combo();
+        circles[0].key_comments[1] = "<b>Bob</b>".to_string();
+        let (circles, _commitments, _coefficients) =
+            generate_circle_keys(&generate_key(), &[], circles, 3).expect("Failed to make keys");
+        let info = PublicInfo {
+            name: "Test & Co".to_string(),
+            owner_fingerprint: vec![0xAB, 0xCD, 0x01, 0x02],
+            ..get_public_info(circles.clone(), 3)
+        };
+
+        let circle = &circles[0];
+        let piece = &circle.keys.as_ref().unwrap()[1];
+        let sheet = render_sheet(&info, circle, 1, 6, piece).expect("Failed to render");
+
+        assert!(sheet.starts_with("<!DOCTYPE html>"));
+        assert!(sheet.contains("Key piece 6 for Test &amp; Co's digital vault"));
+        assert!(sheet.contains("&lt;b&gt;Bob&lt;/b&gt;"));
+        assert!(!sheet.contains("<b>Bob"));
+        assert!(sheet.contains(&escape(&circle.name)));
+        assert!(sheet.contains("This is a 3 of"));
+        assert!(sheet.contains("<svg"));
+        assert!(!sheet.contains("<?xml"));
+        assert!(sheet.contains("<span>1. "));
+        assert!(sheet.contains("<code>ABCD 0102</code>"));
+
+        // Pieces from before envelopes are shown as bytes.
+        let legacy = vec![3u8; 40];
+        let sheet = render_sheet(&info, circle, 1, 6, &legacy).expect("Failed to render");
+        assert!(sheet.contains("3:3:3:3"));
+        assert!(!piece_to_qr_payload(&legacy).is_empty());
+
+        let protected =
+            protect_with(PROTECTED_PIECE_MAGIC, piece, "passphrase", TEST_KDF_PARAMS).unwrap();
+        let sheet = render_sheet(&info, circle, 1, 6, &protected).expect("Failed to render");
+        assert!(sheet.contains("protected by a passphrase"));
+    }
+}
@@ -0,0 +1,389 @@
+use std::fs::File;
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey, PUBLIC_KEY_LENGTH};
+use sha2::{Digest, Sha256};
+
+use crate::crypto::derive_signing_seed;
+use crate::error::{make_error, AppError, AppResult};
+use crate::meta::{decode_meta, VaultHeader};
+use crate::vault::KeyCollection;
+
+/*
+   Owner signatures
+   Every vault is signed by its owner, so recipients can tell that a vault file really came from the owner and was
+   not swapped for one built by someone else, such as a person who intercepted the key pieces.
+
+   The signing key is a random Ed25519 key, kept in the sealed owner state (see vault::KeyCollection), so it stays the
+   same each time the vault is updated. Vaults signed before it was kept there use a key derived from the main key.
+   Pieces of vaults from before format version 2 rebuild the main key, which opens the owner state, so their holders
+   could sign as the owner. Those vaults are never signed: They must be rekeyed before they can be updated.
+   The public info carries a fingerprint of the public key, which is also written on armored key pieces and recovery
+   sheets so recipients can compare them. Key pieces carry it too (see piece.rs), and are only used to unlock a vault
+   signed with the key it is the fingerprint of.
+
+   The signature block follows the header (see meta.rs): public key (32 bytes) | signature (64 bytes).
+   The signature is over a label, the raw header and the SHA-256 of everything after the signature block (the owner
+   state and the ciphertext). The block is written last, once the ciphertext is known, so the file is still written
+   in one pass. Vaults made before signatures (format versions 1 and 2) have no block, and are reported as unsigned.
+   Vaults have had versions (see lineage.rs) since they were signed, so an unsigned vault with a version has had its
+   signature stripped. Older vaults which have been seen signed are remembered, so stripped copies are refused too.
+   The block only shows the vault was signed with the key in it. Whoever has the main key checks that it is their
+   key, and recipients compare the fingerprint with the one on their key piece or recovery sheet.
+   A vault is decrypted from the same open file its signature was checked in, and its hash is checked again as it is
+   decrypted, so it can't be swapped for another file once it has been checked.
+*/
+
+const SIGNATURE_LABEL: &[u8] = b"digital-legacies vault signature";
+// Fingerprints are the first bytes of the SHA-256 of the public key.
+pub const FINGERPRINT_LEN: usize = 16;
+
+/// The fingerprint of the owner's signing key, which is put in the public info.
+pub fn owner_fingerprint(keys: &KeyCollection) -> Vec<u8> {
+    return fingerprint(&signing_key(keys).verifying_key());
+}
+
+/// Checks the owner can sign with their keys, which is only when no one else can rebuild the main key.
+pub fn check_can_sign(keys: &KeyCollection) -> AppResult<()> {
+    if keys.pieces_rebuild_main {
+        return Err(make_error(
+            "rekey_required",
+            "This vault was made by an older version of the app, whose key pieces can rebuild the main key. Anyone \
+            holding enough of them could change the vault as if they were you, so it must be rekeyed with new key \
+            pieces before it can be updated.",
+        ));
+    }
+    return Ok(());
+}
+
+/// Shows a fingerprint as groups of hex digits, so it is easy to read out and compare.
+pub fn format_fingerprint(fingerprint: &[u8]) -> String {
+    let groups: Vec<String> = fingerprint
+        .chunks(2)
+        .map(|group| group.iter().map(|b| format!("{:02X}", b)).collect())
+        .collect();
+    return groups.join(" ");
+}
+
+/// Signs a vault, given its raw header and the hash of everything written after the signature block.
+/// Returns the signature block. The keys must have been checked with check_can_sign.
+pub fn sign_vault(keys: &KeyCollection, header: &[u8], digest: &[u8]) -> Vec<u8> {
+    let key = signing_key(keys);
+    let signature = key.sign(&signed_message(header, digest));
+
+    let mut block = Vec::from(key.verifying_key().to_bytes());
+    block.extend_from_slice(&signature.to_bytes());
+    return block;
+}
+
+/// Checks the owner's signature over a vault, reading everything after the signature block from the reader.
+/// Returns the fingerprint of the signer, or None for a vault made before vaults were signed.
+pub fn verify_vault(header: &VaultHeader, rest: &mut impl Read) -> AppResult<Option<Vec<u8>>> {
+    let block = match &header.signature {
+        Some(block) => block,
+        None => {
+            // A vault claiming an owner or a version must prove it.
+            if !header.info.owner_fingerprint.is_empty() || !header.info.lineage.vault_id.is_nil() {
+                return Err(bad_signature());
+            }
+            return Ok(None);
+        }
+    };
+
+    let mut hasher = HashWriter::new(io::sink());
+    io::copy(rest, &mut hasher)?;
+    let (_sink, digest) = hasher.finish();
+
+    let (public, signature) = block.split_at(PUBLIC_KEY_LENGTH);
+    let public: [u8; PUBLIC_KEY_LENGTH] = public.try_into().map_err(|_e| bad_signature())?;
+    let public = VerifyingKey::from_bytes(&public).map_err(|_e| bad_signature())?;
+    let signature = Signature::from_slice(signature).map_err(|_e| bad_signature())?;
+
+    if fingerprint(&public) != header.info.owner_fingerprint {
+        return Err(bad_signature());
+    }
+    public
+        .verify(&signed_message(&header.raw, &digest), &signature)
+        .map_err(|_e| bad_signature())?;
+    return Ok(Some(fingerprint(&public)));
+}
+
+/// Reads a vault's header and checks its signature, reading the whole file. Nothing is decrypted.
+pub fn verify_vault_file(file_path: &str) -> AppResult<VaultHeader> {
+    let mut reader = BufReader::new(File::open(file_path)?);
+    let header = decode_meta(&mut reader)?;
+    verify_vault(&header, &mut reader)?;
+    return Ok(header);
+}
+
+/// A vault file whose signature has been checked.
+pub struct VerifiedVault {
+    pub header: VaultHeader,
+    // The fingerprint of the signer, or None for a vault made before vaults were signed.
+    pub signer: Option<Vec<u8>>,
+    // The SHA-256 of the whole file, as it was when it was checked.
+    pub file_hash: Vec<u8>,
+}
+
+impl VerifiedVault {
+    /// Reads the header again from the start of the file which was checked, checking it has not changed.
+    /// Everything read through the reader returned is hashed, so check_unchanged can check the rest once it is read.
+    pub fn reread<R: Read>(&self, file: R) -> AppResult<HashReader<R>> {
+        let mut reader = HashReader::new(file);
+        let header = decode_meta(&mut reader)?;
+        if header.raw != self.header.raw || header.signature != self.header.signature {
+            return Err(vault_changed());
+        }
+        return Ok(reader);
+    }
+
+    /// Checks everything read through the reader was the file which was checked, reading whatever is left.
+    pub fn check_unchanged<R: Read>(&self, reader: HashReader<R>) -> AppResult<()> {
+        if reader.finish()? != self.file_hash {
+            return Err(vault_changed());
+        }
+        return Ok(());
+    }
+}
+
+/// Checks a vault's signature, reading the whole file, then goes back to the start so that the vault can be read
+/// from the same file (see VerifiedVault::reread). Otherwise the file could be swapped after it was checked.
+pub fn verify_vault_start<R: Read + Seek>(file: &mut R) -> AppResult<VerifiedVault> {
+    let mut reader = HashReader::new(&mut *file);
+    let header = decode_meta(&mut reader)?;
+    let signer = verify_vault(&header, &mut reader)?;
+    let file_hash = reader.finish()?;
+    file.seek(SeekFrom::Start(0))?;
+    return Ok(VerifiedVault {
+        header,
+        signer,
+        file_hash,
+    });
+}
+
+/// Hashes everything read through it.
+pub struct HashReader<R: Read> {
+    inner: R,
+    hasher: Sha256,
+}
+
+impl<R: Read> HashReader<R> {
+    pub fn new(inner: R) -> Self {
+        return HashReader {
+            inner,
+            hasher: Sha256::new(),
+        };
+    }
+
+    /// Reads whatever is left, and returns the hash of everything read.
+    pub fn finish(mut self) -> io::Result<Vec<u8>> {
+        io::copy(&mut self, &mut io::sink())?;
+        return Ok(self.hasher.finalize().to_vec());
+    }
+}
+
+impl<R: Read> Read for HashReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.hasher.update(&buf[..read]);
+        return Ok(read);
+    }
+}
+
+/// Hashes everything written through it, so a vault can be signed as it is written.
+pub struct HashWriter<W: Write> {
+    inner: W,
+    hasher: Sha256,
+}
+
+impl<W: Write> HashWriter<W> {
+    pub fn new(inner: W) -> Self {
+        return HashWriter {
+            inner,
+            hasher: Sha256::new(),
+        };
+    }
+
+    /// Returns the inner writer and the hash of everything written.
+    pub fn finish(self) -> (W, Vec<u8>) {
+        return (self.inner, self.hasher.finalize().to_vec());
+    }
+}
+
+impl<W: Write> Write for HashWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        return Ok(written);
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        return self.inner.flush();
+    }
+}
+
+fn signing_key(keys: &KeyCollection) -> SigningKey {
+    return match &keys.signing_seed {
+        Some(seed) => SigningKey::from_bytes(seed),
+        None => SigningKey::from_bytes(&derive_signing_seed(&keys.main)),
+    };
+}
+
+fn fingerprint(public: &VerifyingKey) -> Vec<u8> {
+    return Sha256::digest(public.as_bytes())[..FINGERPRINT_LEN].to_vec();
+}
+
+fn signed_message(header: &[u8], digest: &[u8]) -> Vec<u8> {
+    let mut message = Vec::from(SIGNATURE_LABEL);
+    message.extend_from_slice(header);
+    message.extend_from_slice(digest);
+    return message;
+}
+
+fn vault_changed() -> AppError {
+    return make_error(
+        "vault_changed",
+        "The vault file changed while it was being read. Please try again.",
+    );
+}
+
+fn bad_signature() -> AppError {
+    return make_error(
+        "bad_signature",
+        "This vault is not signed by its owner, or has been changed since it was signed. It may not have come from \
+        them, so do not trust it.",
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs::{self, OpenOptions};
+    use std::io::{self, Cursor, Seek, SeekFrom, Write};
+
+    use crate::commands::create::do_create;
+    use crate::commands::create::test::get_vault;
+    use crate::crypto::generate_key;
+    use crate::lineage::test::temp_versions_path;
+    use crate::meta::decode_meta;
+    use crate::signature::{
+        check_can_sign, format_fingerprint, owner_fingerprint, verify_vault, verify_vault_file,
+        verify_vault_start, FINGERPRINT_LEN,
+    };
+    use crate::util::get_random_file_name;
+    use crate::vault::{KeyCollection, Lineage, ShareCommitments};
+
+    #[test]
+    fn fingerprints() {
+        let keys = KeyCollection {
+            share_keys: vec![],
+            main: generate_key(),
+            commitments: ShareCommitments::default(),
+            coefficients: None,
+            pieces_rebuild_main: false,
+            signing_seed: Some(generate_key()),
+        };
+        assert_eq!(owner_fingerprint(&keys).len(), FINGERPRINT_LEN);
+        assert_eq!(owner_fingerprint(&keys), owner_fingerprint(&keys));
+        assert!(check_can_sign(&keys).is_ok());
+
+        // The signing key is independent of the main key.
+        let same_main = KeyCollection {
+            signing_seed: Some(generate_key()),
+            ..keys.clone()
+        };
+        assert_ne!(owner_fingerprint(&keys), owner_fingerprint(&same_main));
+        // Vaults signed before the seed was kept derive it from the main key.
+        let derived = KeyCollection {
+            signing_seed: None,
+            ..keys.clone()
+        };
+        assert_ne!(owner_fingerprint(&keys), owner_fingerprint(&derived));
+        assert_eq!(owner_fingerprint(&derived), owner_fingerprint(&derived));
+
+        // Holders of pieces that rebuild the main key could sign as the owner.
+        let legacy = KeyCollection {
+            pieces_rebuild_main: true,
+            ..keys
+        };
+        let err = check_can_sign(&legacy).expect_err("Signed with a shared key");
+        assert_eq!(err.error_type, "rekey_required");
+
+        assert_eq!(
+            format_fingerprint(&[0xAB, 0xCD, 0x01, 0x02, 0xFF]),
+            "ABCD 0102 FF"
+        );
+    }
+
+    #[test]
+    fn signed_vaults() {
+        let versions = temp_versions_path();
+        let mut empty_folder = env::temp_dir();
+        empty_folder.push(get_random_file_name().unwrap() + "empty");
+        fs::create_dir_all(&empty_folder).expect("Failed to create empty folder for testing");
+        let mut output_file = env::temp_dir();
+        output_file.push(get_random_file_name().expect("Failed to get output file"));
+
+        let vault = get_vault();
+        let res = do_create(&versions, vault, empty_folder, output_file).expect("Failed to create");
+
+        let header = verify_vault_file(&res.path).expect("Signature was not valid");
+        assert_eq!(header.info.owner_fingerprint, owner_fingerprint(&res.keys));
+        let bytes = fs::read(&res.path).unwrap();
+
+        // Any change to the header, signature, owner state or ciphertext is caught.
+        for index in [
+            20,
+            header.raw.len() + 5,
+            header.raw.len() + 40,
+            bytes.len() - 1,
+        ] {
+            let mut changed = bytes.clone();
+            changed[index] ^= 1;
+            let mut reader = changed.as_slice();
+            let result = decode_meta(&mut reader).and_then(|h| verify_vault(&h, &mut reader));
+            assert!(result.is_err(), "Change at {} was not caught", index);
+        }
+
+        // The file is read again after it is checked, and must not have changed in between.
+        let mut file = Cursor::new(bytes.clone());
+        let verified = verify_vault_start(&mut file).expect("Signature was not valid");
+        let reader = verified.reread(&mut file).expect("Failed to read again");
+        verified
+            .check_unchanged(reader)
+            .expect("Unchanged file was refused");
+        file.get_mut()[bytes.len() - 1] ^= 1;
+        file.set_position(0);
+        let reader = verified.reread(&mut file).expect("Failed to read again");
+        let err = verified
+            .check_unchanged(reader)
+            .expect_err("Changed file was accepted");
+        assert_eq!(err.error_type, "vault_changed");
+        file.get_mut()[20] ^= 1;
+        file.set_position(0);
+        let err = verified
+            .reread(&mut file)
+            .err()
+            .expect("Changed header was accepted");
+        assert_eq!(err.error_type, "vault_changed");
+
+        // A vault with a version but no signature has had it stripped.
+        let mut stripped = decode_meta(&mut bytes.as_slice()).unwrap();
+        stripped.info.owner_fingerprint = vec![];
+        stripped.signature = None;
+        let err = verify_vault(&stripped, &mut io::empty()).expect_err("Trusted a stripped vault");
+        assert_eq!(err.error_type, "bad_signature");
+        stripped.info.lineage = Lineage::default();
+        assert_eq!(verify_vault(&stripped, &mut io::empty()).unwrap(), None);
+
+        // A header claiming an owner but with no signature, as a version 2 vault.
+        let mut file = OpenOptions::new().write(true).open(&res.path).unwrap();
+        file.seek(SeekFrom::Start(8)).unwrap();
+        file.write_all(&2u16.to_be_bytes()).unwrap();
+        let err = verify_vault_file(&res.path).expect_err("Trusted an unsigned vault");
+        assert_eq!(err.error_type, "bad_signature");
+
+        fs::remove_file(res.path).unwrap();
+        let _ = fs::remove_file(versions);
+    }
+}
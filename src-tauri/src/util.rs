@@ -1,11 +1,12 @@
-use crate::constants::META_FILE_NAME;
-use crate::error::AppResult;
+use crate::constants::{META_FILE_NAME, PARTIAL_FILE_SUFFIX, VERSIONS_FILE_NAME};
+use crate::error::{app_error, AppError, AppResult};
 use crate::vault::Vault;
 use chacha20poly1305::aead::rand_core::RngCore;
 use chacha20poly1305::aead::OsRng;
 use std::fs;
 use std::fs::File;
-use std::path::PathBuf;
+use std::io::Write;
+use std::path::{Path, PathBuf};
 use std::time::SystemTime;
 
 pub fn get_random_file_path(handle: tauri::AppHandle) -> AppResult<PathBuf> {
@@ -18,6 +19,17 @@ pub fn get_random_file_path(handle: tauri::AppHandle) -> AppResult<PathBuf> {
     return Ok(base_folder);
 }
 
+// The file recording the latest version of each vault seen (see lineage.rs).
+pub fn get_versions_path(handle: &tauri::AppHandle) -> AppResult<PathBuf> {
+    let mut path = handle
+        .path_resolver()
+        .app_data_dir()
+        .ok_or(app_error("Could not get data directory".to_string()))?;
+    fs::create_dir_all(&path)?;
+    path.push(VERSIONS_FILE_NAME);
+    return Ok(path);
+}
+
 // Originally this just used the ms time, which was not very good for obvious reasons (very very frequent collisions during testing...)
 // Also appends a random number to make the chance of that happening very low
 pub fn get_random_file_name() -> AppResult<String> {
@@ -29,6 +41,42 @@ pub fn get_random_file_name() -> AppResult<String> {
     return Ok(rand_str + "-" + &file_name_str);
 }
 
+// Where a file is written before it is complete.
+pub fn get_partial_path(path: &Path) -> PathBuf {
+    let mut partial = path.as_os_str().to_owned();
+    partial.push(PARTIAL_FILE_SUFFIX);
+    return PathBuf::from(partial);
+}
+
+// Gives a complete file its real name. The file must already be synced.
+// A crash leaves either the old file or the whole new one, never part of one.
+pub fn persist_file(partial: &Path, path: &Path) -> AppResult<()> {
+    fs::rename(partial, path)?;
+    // The rename itself is only safe on disk once the folder is synced. Windows can't open folders to do this.
+    if cfg!(unix) {
+        if let Some(folder) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            File::open(folder)?.sync_all()?;
+        }
+    }
+    return Ok(());
+}
+
+// Replaces a file with the contents given, so that it is never left half written.
+pub fn write_file_atomic(path: &Path, contents: &[u8]) -> AppResult<()> {
+    let partial = get_partial_path(path);
+    let written = File::create(&partial)
+        .and_then(|mut file| {
+            file.write_all(contents)?;
+            return file.sync_all();
+        })
+        .map_err(AppError::from)
+        .and_then(|_| persist_file(&partial, path));
+    if written.is_err() {
+        let _ = fs::remove_file(&partial);
+    }
+    return written;
+}
+
 pub fn read_meta_file(folder_path: &PathBuf) -> AppResult<Vault> {
     let mut meta_file_path = folder_path.clone();
     meta_file_path.push(META_FILE_NAME);
@@ -42,7 +90,9 @@ pub fn read_meta_file(folder_path: &PathBuf) -> AppResult<Vault> {
 
 #[cfg(test)]
 mod test {
-    use crate::util::get_random_file_name;
+    use std::{env, fs};
+
+    use crate::util::{get_partial_path, get_random_file_name, write_file_atomic};
 
     #[test]
     fn random_paths() {
@@ -55,4 +105,15 @@ mod test {
         assert_ne!(name1, name3);
         assert_ne!(name2, name3);
     }
+
+    #[test]
+    fn atomic_writes() {
+        let mut path = env::temp_dir();
+        path.push(get_random_file_name().unwrap());
+        write_file_atomic(&path, b"first").expect("Failed to write");
+        write_file_atomic(&path, b"second").expect("Failed to replace");
+        assert_eq!(fs::read(&path).unwrap(), b"second");
+        assert!(!get_partial_path(&path).exists());
+        fs::remove_file(path).unwrap();
+    }
 }
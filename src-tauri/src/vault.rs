@@ -1,7 +1,9 @@
 // Vault typings - rust version. VaultInfo.tsx contains TypeScript typings.
-use crate::crypto::KeyPiece;
+use crate::crypto::{derive_recipient_secret, Cipher, Key, KeyPiece};
+use crate::vss::{Coefficients, Commitments};
 use serde::{Deserialize, Serialize};
 use std::fmt;
+use uuid::Uuid;
 
 #[derive(Deserialize, Serialize, Clone, PartialEq, Debug)]
 pub enum VaultType {
@@ -21,7 +23,7 @@ pub struct PersonalInfo {
 
 #[derive(Deserialize, Serialize, Clone, Debug)]
 pub struct ShareConfiguration {
-    pub(crate) required: u8,
+    pub(crate) required: u16,
     pub(crate) circles: Vec<Circle>,
 }
 
@@ -31,6 +33,48 @@ pub struct Circle {
     pub key_comments: Vec<String>,
     pub name: String,
     pub keys: Option<Vec<KeyPiece>>,
+    // How many members of a required circle are needed to unlock. Ignored for circles which are not required.
+    #[serde(default = "default_threshold")]
+    pub threshold: u16,
+    // The weight of each member, in the same order as key_comments. A member with weight 3 counts as 3 keys.
+    // Members without a weight count as 1.
+    #[serde(default)]
+    pub weights: Vec<u8>,
+    // The age public key (age1...) of each member, in the same order as key_comments. Their key piece is also sealed
+    // to it (see recipient.rs). Members without a key have an empty string, or no entry.
+    #[serde(default)]
+    pub recipients: Vec<String>,
+    // Each member's key piece sealed to their public key, as armored age text. Empty for members without a key.
+    #[serde(default)]
+    pub sealed_keys: Option<Vec<String>>,
+}
+
+impl Circle {
+    /// The number of shares the member at this index holds.
+    pub fn weight(&self, member: usize) -> u8 {
+        return self.weights.get(member).copied().unwrap_or(1);
+    }
+
+    /// The number of shares all of the circle's members hold.
+    pub fn total_weight(&self) -> usize {
+        return (0..self.key_comments.len())
+            .map(|member| usize::from(self.weight(member)))
+            .sum();
+    }
+
+    /// The public key the member at this index gave, if any.
+    pub fn recipient(&self, member: usize) -> Option<&str> {
+        return self
+            .recipients
+            .get(member)
+            .map(|recipient| recipient.trim())
+            .filter(|recipient| !recipient.is_empty());
+    }
+}
+
+// Circles from before thresholds existed need any one member.
+fn default_threshold() -> u16 {
+    return 1;
 }
 
 #[derive(Deserialize, Serialize, Clone)]
@@ -44,6 +88,16 @@ pub struct Vault {
     // The presence of these indicates it is an existing vault being updated
     // Only difference in behaviour is that it will use these keys instead of making new ones.
     pub keys: Option<KeyCollection>,
+    // Cloud credentials of an existing cloud vault. Like keys, these are owner-only and are never put in the archive.
+    #[serde(default)]
+    pub cloud_creds: Option<CloudKeyData>,
+    // The cipher for the vault's contents. Vaults from older versions of the app get the default when updated.
+    #[serde(default)]
+    pub cipher: Cipher,
+    // The file an existing vault was opened from, so the update can follow on from it. Like keys, this is only set
+    // when updating, and is never put in the archive.
+    #[serde(default)]
+    pub previous: Option<PreviousVersion>,
 }
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct CloudKeyData {
@@ -56,7 +110,85 @@ pub struct CloudKeyData {
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct KeyCollection {
     pub share_keys: Vec<Circle>,
-    pub main: crate::crypto::Key,
+    pub main: Key,
+    // Kept so the commitments can be published again when the vault is updated.
+    #[serde(default)]
+    pub commitments: ShareCommitments,
+    // Kept so members can be added to circles without changing anyone else's piece (see crypto::add_circle_members).
+    // None for vaults made before members could be added.
+    #[serde(default)]
+    pub coefficients: Option<ShareCoefficients>,
+    // Whether the key pieces rebuild the main key itself, as they did before format version 2. Set when an older vault
+    // is opened. Their holders can rebuild the main key, as they always could, and so could sign as the owner, so
+    // such a vault can't be updated until it is rekeyed with new pieces.
+    #[serde(default)]
+    pub pieces_rebuild_main: bool,
+    // The seed of the owner's signing key (see signature.rs), which nothing else is derived from.
+    // None for vaults signed before it was kept, whose signing key is derived from the main key.
+    #[serde(default)]
+    pub signing_seed: Option<Key>,
+}
+
+impl KeyCollection {
+    /// The secret the key pieces rebuild.
+    pub fn recipient_secret(&self) -> Key {
+        return if self.pieces_rebuild_main {
+            self.main
+        } else {
+            derive_recipient_secret(&self.main)
+        };
+    }
+}
+
+/// Commitments to the shares in a vault's key pieces (see vss.rs), so each piece can be checked on its own.
+/// Empty for vaults made before key pieces could be checked.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct ShareCommitments {
+    // Commitments to the individual shares (of Z).
+    pub individual: Commitments,
+    // Commitments to the shares of each required circle's group key, by circle index. Empty for other circles.
+    pub circles: Vec<Commitments>,
+}
+
+/// The polynomials the shares in a vault's key pieces were made from (see vss.rs), laid out as ShareCommitments.
+/// These can make the shares of any member, so they are only ever kept in the owner state.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct ShareCoefficients {
+    pub individual: Coefficients,
+    pub circles: Vec<Coefficients>,
+    // The mask in every derived key piece (see crypto.rs). Empty for pieces made before the parts of the main key
+    // were derived, which new members can't be given pieces to fit with.
+    #[serde(default)]
+    pub mask: Vec<u8>,
+}
+
+/// Owner-only editing state. This is sealed under a key derived from the main key and stored in the vault file
+/// separately from the archive, so recipients who unlock the vault never see anybody else's key pieces.
+/// Opening the vault with the main key restores it so the owner can keep updating the vault.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct OwnerState {
+    pub keys: KeyCollection,
+    pub cloud_creds: Option<CloudKeyData>,
+}
+
+/// Where a vault file is in the history of its vault (see lineage.rs).
+#[derive(Deserialize, Serialize, Clone, Debug, Default, PartialEq)]
+pub struct Lineage {
+    // The same for every version of a vault. Nil for vaults made before vaults had versions.
+    pub vault_id: Uuid,
+    // 1 for a new vault, and one more for each update. 0 for vaults made before vaults had versions.
+    pub version: u64,
+    // The SHA-256 of the vault file this version replaced. Empty for the first version.
+    pub previous_hash: Vec<u8>,
+}
+
+/// The vault file an opened vault was read from.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct PreviousVersion {
+    pub path: String,
+    // The SHA-256 of the file when it was opened, so it can't be swapped for another before the update is made.
+    pub hash: Vec<u8>,
+    pub lineage: Lineage,
 }
 
 #[derive(Deserialize, Serialize, Clone, Debug)]
@@ -67,6 +199,20 @@ pub struct PublicInfo {
     pub nonce: Vec<u8>,
     // Internal application path
     pub path: String,
+    // Identifies the vault's key pieces (see crypto::derive_key_id). Empty for vaults made before pieces had one.
+    #[serde(default)]
+    pub key_id: Vec<u8>,
+    #[serde(default)]
+    pub commitments: ShareCommitments,
+    // The fingerprint of the owner's signing key (see signature.rs). Empty for vaults made before vaults were signed.
+    #[serde(default)]
+    pub owner_fingerprint: Vec<u8>,
+    // Commits to the content key (see crypto::derive_key_commitment). Empty for vaults made before key commitments.
+    #[serde(default)]
+    pub key_commitment: Vec<u8>,
+    // The vault's id and version, and the hash of the version before. Default for vaults made before versions.
+    #[serde(default)]
+    pub lineage: Lineage,
 }
 
 // Vault toString converter (Read more: https://doc.rust-lang.org/rust-by-example/conversion/string.html)
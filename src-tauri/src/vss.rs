@@ -0,0 +1,814 @@
+/*
+   vss.rs
+   Feldman verifiable secret sharing over the Ristretto group (curve25519-dalek).
+
+   The secret is a uniformly random scalar, shared with a random polynomial whose constant term is the secret. A share
+   is the polynomial evaluated at the share's index.
+   The dealer publishes a commitment to each coefficient of the polynomial (the coefficient times the base point).
+   Anyone with the commitments can then check a share on its own. The commitment to the constant term is the secret
+   times the base point, which only hides the secret because it is a whole random scalar. Secrets which are chosen,
+   such as keys, are derived from it rather than shared themselves.
+   Shares made before this were of chosen secrets, split into chunks of 31 bytes then whatever was left, each shared
+   with its own polynomial. A short chunk can be found from its commitment by trying values, so these shares are only
+   read, so that old pieces can still be combined.
+
+   Share layout
+   threshold (u16) | index (u16) | the secret's scalar (32 bytes), or one scalar per chunk for old shares
+
+   Indexes are scalars, so there can be up to 65535 shares. Shares made before this had a one byte threshold and
+   index, and so at most 255 shares. They can still be checked and combined, given as ShareFormat::Narrow.
+
+   The dealer can keep the coefficients of the polynomials, to make more shares of the same secret later. New shares
+   check against the same commitments and combine with the old ones. The coefficients reveal the secret, so they must
+   be kept as secret as it is.
+*/
+extern crate curve25519_dalek;
+
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use std::ops::RangeInclusive;
+
+use chacha20poly1305::aead::{rand_core::RngCore, OsRng};
+use curve25519_dalek::ristretto::CompressedRistretto;
+use curve25519_dalek::{RistrettoPoint, Scalar};
+use serde::{Deserialize, Serialize};
+
+use crate::error::{make_error, AppResult};
+
+// The most bytes of an old, chosen secret in each chunk. A 32 byte scalar with a zero top byte is always below the
+// group order.
+const CHUNK_LEN: usize = 31;
+const SCALAR_LEN: usize = 32;
+
+/// How a share's threshold and index are written.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ShareFormat {
+    // One byte each, for shares made before more than 255 shares were allowed.
+    Narrow,
+    // Two bytes each, big endian.
+    Wide,
+}
+
+impl ShareFormat {
+    // The length of the threshold and index.
+    const fn header_len(&self) -> usize {
+        return match self {
+            ShareFormat::Narrow => 2,
+            ShareFormat::Wide => 4,
+        };
+    }
+}
+
+/// Public commitments to the polynomials used to split a secret.
+#[derive(Deserialize, Serialize, Clone, Debug, Default, PartialEq)]
+pub struct Commitments {
+    // For each chunk of the secret, the compressed commitment to each coefficient, starting from the constant term.
+    pub chunks: Vec<Vec<Vec<u8>>>,
+}
+
+impl Commitments {
+    pub fn is_empty(&self) -> bool {
+        return self.chunks.is_empty();
+    }
+}
+
+/// The coefficients of the polynomials used to split a secret, so more shares of it can be made later.
+#[derive(Deserialize, Serialize, Clone, Debug, Default, PartialEq)]
+pub struct Coefficients {
+    // For each chunk of the secret, each coefficient of its polynomial, starting from the constant term.
+    pub chunks: Vec<Vec<Vec<u8>>>,
+}
+
+/// The length of each share of an old, chosen secret with the given length.
+pub const fn share_len(secret_len: usize, format: ShareFormat) -> usize {
+    return format.header_len() + secret_len.div_ceil(CHUNK_LEN) * SCALAR_LEN;
+}
+
+/// The shares of a split secret, the commitments to check them against, and the coefficients to make more shares with.
+pub type Split = (Vec<Vec<u8>>, Commitments, Coefficients);
+
+/// The length of each share of a random secret (see split_random).
+pub const fn random_share_len(format: ShareFormat) -> usize {
+    return format.header_len() + SCALAR_LEN;
+}
+
+/// Splits a new, uniformly random secret into count shares, of which threshold are needed to rebuild it.
+/// The secret is a whole scalar, so unlike a chunk of a chosen secret, it can't be found from its commitment by trying
+/// values. Derive what is needed from it (e.g. with HKDF). Returns the secret (32 bytes) and its split, with shares
+/// at indexes 1 to count in the wide format.
+pub fn split_random(count: u16, threshold: u16) -> AppResult<(Vec<u8>, Split)> {
+    let secret = random_scalar();
+    let split = split_scalars(&[secret], count, threshold)?;
+    return Ok((secret.to_bytes().to_vec(), split));
+}
+
+// Shares each scalar with its own random polynomial, whose constant term is the scalar. The shares have indexes 1 to
+// count, in the wide format.
+fn split_scalars(constants: &[Scalar], count: u16, threshold: u16) -> AppResult<Split> {
+    if threshold == 0 || threshold > count {
+        return Err(make_error(
+            "SSS",
+            "Bad threshold: The threshold must be between 1 and the number of shares.",
+        ));
+    }
+
+    let mut polynomials: Vec<Vec<Scalar>> = Vec::new();
+    for constant in constants.iter() {
+        let mut coefficients = vec![*constant];
+        for _ in 1..threshold {
+            coefficients.push(random_scalar());
+        }
+        polynomials.push(coefficients);
+    }
+
+    let commitments = Commitments {
+        chunks: polynomials
+            .iter()
+            .map(|coefficients| {
+                coefficients
+                    .iter()
+                    .map(|c| RistrettoPoint::mul_base(c).compress().to_bytes().to_vec())
+                    .collect()
+            })
+            .collect(),
+    };
+    let coefficients = Coefficients {
+        chunks: polynomials
+            .iter()
+            .map(|coefficients| coefficients.iter().map(|c| c.to_bytes().to_vec()).collect())
+            .collect(),
+    };
+
+    let shares = make_shares(&coefficients, 1..=count)?;
+    return Ok((shares, commitments, coefficients));
+}
+
+/// Makes the shares (in the wide format) at the given indexes, from the coefficients of the polynomials a secret was
+/// split with. The same index always gives the same share.
+pub fn make_shares(
+    coefficients: &Coefficients,
+    indexes: RangeInclusive<u16>,
+) -> AppResult<Vec<Vec<u8>>> {
+    let bad_coefficients = || {
+        make_error(
+            "SSS",
+            "Bad coefficients: These are not the coefficients of a split secret.",
+        )
+    };
+    if *indexes.start() == 0 {
+        // The share at 0 would be the secret itself.
+        return Err(make_error("SSS", "Bad index: Share indexes start at 1."));
+    }
+
+    let mut polynomials: Vec<Vec<Scalar>> = Vec::new();
+    for chunk in coefficients.chunks.iter() {
+        let mut polynomial: Vec<Scalar> = Vec::new();
+        for bytes in chunk.iter() {
+            let bytes: [u8; SCALAR_LEN] = bytes
+                .as_slice()
+                .try_into()
+                .map_err(|_e| bad_coefficients())?;
+            let scalar = Option::from(Scalar::from_canonical_bytes(bytes));
+            polynomial.push(scalar.ok_or_else(bad_coefficients)?);
+        }
+        polynomials.push(polynomial);
+    }
+    // Every chunk's polynomial has one coefficient for each share needed.
+    let threshold = polynomials.first().map_or(0, |p| p.len());
+    if threshold == 0 || polynomials.iter().any(|p| p.len() != threshold) {
+        return Err(bad_coefficients());
+    }
+    let threshold: u16 = threshold.try_into().map_err(|_e| bad_coefficients())?;
+
+    let mut shares: Vec<Vec<u8>> = Vec::new();
+    for index in indexes {
+        let x = Scalar::from(index);
+        let mut share = Vec::from(threshold.to_be_bytes());
+        share.extend_from_slice(&index.to_be_bytes());
+        for polynomial in polynomials.iter() {
+            share.extend_from_slice(evaluate(polynomial, &x).as_bytes());
+        }
+        shares.push(share);
+    }
+    return Ok(shares);
+}
+
+/// Checks a share against the commitments made when the secret was split.
+pub fn verify_share(share: &[u8], commitments: &Commitments, format: ShareFormat) -> bool {
+    let (threshold, index, values) = match parse_share(share, format) {
+        Some(parsed) => parsed,
+        None => return false,
+    };
+    if values.len() != commitments.chunks.len() {
+        return false;
+    }
+
+    let x = Scalar::from(index);
+    for (value, chunk_commitments) in values.iter().zip(commitments.chunks.iter()) {
+        if chunk_commitments.len() != usize::from(threshold) {
+            return false;
+        }
+        // The share is valid if value * G is the committed polynomial evaluated at x.
+        let mut expected = RistrettoPoint::default();
+        let mut power = Scalar::ONE;
+        for commitment in chunk_commitments.iter() {
+            let point = match decompress(commitment) {
+                Some(point) => point,
+                None => return false,
+            };
+            expected += point * power;
+            power *= x;
+        }
+        if RistrettoPoint::mul_base(value) != expected {
+            return false;
+        }
+    }
+    return true;
+}
+
+/// A secret rebuilt from its shares.
+#[derive(Debug, PartialEq)]
+pub struct Combined {
+    pub secret: Vec<u8>,
+    // Positions (in the shares given) of shares which do not fit with the rebuilt secret.
+    pub bad_shares: Vec<usize>,
+}
+
+/// Rebuilds an old, chosen secret of the given length from its shares, tolerating shares which are wrong.
+/// Subsets of the threshold size are tried until the polynomial through one fits more shares than it was made from.
+/// Every chunk of the secret has zero top bytes, which a wrong share almost always breaks, so subsets giving anything
+/// else are skipped. Shares which don't fit the polynomial chosen are reported as bad.
+/// Returns None if there are not enough distinct shares, or no subset of them fits together.
+pub fn combine_secret(
+    shares: &[Vec<u8>],
+    secret_len: usize,
+    format: ShareFormat,
+) -> AppResult<Option<Combined>> {
+    let fits = fit_polynomials(
+        shares,
+        share_len(secret_len, format),
+        format,
+        |polynomial| {
+            return polynomial.secret(&full_chunk_lens(secret_len));
+        },
+    )?;
+
+    // With no more to go on, the top bytes can be fooled by small changes to a share, so go with the first which fits
+    // the most.
+    let mut best: Option<(Combined, usize)> = None;
+    for (combined, fitting) in fits {
+        if best
+            .as_ref()
+            .is_none_or(|(_best, best_fitting)| fitting > *best_fitting)
+        {
+            best = Some((combined, fitting));
+        }
+    }
+    return Ok(best.map(|(combined, _fitting)| combined));
+}
+
+/// Rebuilds a secret made by split_random from its shares, tolerating shares which are wrong.
+/// Any subset of the threshold size gives a secret, so a wrong share only shows by not fitting the polynomial through
+/// a subset which fits more shares than it was made from. Where none does, every secret the subsets give is returned,
+/// those fitting the most shares first, to be checked some other way (e.g. against a key id).
+/// Returns no secrets if there are not enough distinct shares.
+pub fn combine_random(shares: &[Vec<u8>], format: ShareFormat) -> AppResult<Vec<Combined>> {
+    let mut fits = fit_polynomials(shares, random_share_len(format), format, |polynomial| {
+        return Some(polynomial.constant());
+    })?;
+    fits.sort_by_key(|(_combined, fitting)| Reverse(*fitting));
+
+    let mut secrets: Vec<Combined> = Vec::new();
+    for (combined, _fitting) in fits {
+        if !secrets.iter().any(|s| s.secret == combined.secret) {
+            secrets.push(combined);
+        }
+    }
+    return Ok(secrets);
+}
+
+// Tries subsets of the threshold size of the shares, and returns each secret given by the polynomial through one,
+// along with how many of the shares it fits. A wrong polynomial only fits the shares it was made from, so one which
+// fits any more (or every share) is right, and is returned on its own.
+fn fit_polynomials(
+    shares: &[Vec<u8>],
+    share_len: usize,
+    format: ShareFormat,
+    secret: impl Fn(&Polynomial) -> Option<Vec<u8>>,
+) -> AppResult<Vec<(Combined, usize)>> {
+    let bad_share = || {
+        make_error(
+            "combine",
+            "Incorrect key length: One or more of the keys are not the correct size.",
+        )
+    };
+
+    let mut parsed: Vec<ParsedShare> = Vec::new();
+    for (position, share) in shares.iter().enumerate() {
+        if share.len() != share_len {
+            return Err(bad_share());
+        }
+        let (threshold, index, values) = parse_share(share, format).ok_or_else(bad_share)?;
+        parsed.push(ParsedShare {
+            position,
+            threshold,
+            x: Scalar::from(index),
+            values,
+        });
+    }
+
+    // A damaged share may have the wrong threshold, so go with the most common one.
+    let threshold = match most_common_threshold(&parsed) {
+        Some(threshold) => usize::from(threshold),
+        None => return Ok(Vec::new()),
+    };
+    let mut candidates: Vec<&ParsedShare> = Vec::new();
+    for share in parsed
+        .iter()
+        .filter(|s| usize::from(s.threshold) == threshold)
+    {
+        // The same share given twice only counts once.
+        if !candidates
+            .iter()
+            .any(|c| c.x == share.x && c.values == share.values)
+        {
+            candidates.push(share);
+        }
+    }
+
+    let mut fits: Vec<(Combined, usize)> = Vec::new();
+    let mut tried = 0;
+    let mut subset: Vec<usize> = (0..threshold).collect();
+    while subset.len() <= candidates.len() && tried < MAX_SUBSETS {
+        tried += 1;
+        let points: Vec<&ParsedShare> = subset.iter().map(|i| candidates[*i]).collect();
+        if let Some(polynomial) = Polynomial::through(points) {
+            if let Some(secret) = secret(&polynomial) {
+                // Everything which is not on the polynomial through the subset is bad.
+                let bad_shares: Vec<usize> = parsed
+                    .iter()
+                    .filter(|share| {
+                        usize::from(share.threshold) != threshold || !polynomial.fits(share)
+                    })
+                    .map(|share| share.position)
+                    .collect();
+                let fitting = candidates
+                    .iter()
+                    .filter(|share| !bad_shares.contains(&share.position))
+                    .count();
+
+                let combined = Combined { secret, bad_shares };
+                if fitting > threshold || fitting == candidates.len() {
+                    return Ok(Vec::from([(combined, fitting)]));
+                }
+                fits.push((combined, fitting));
+            }
+        }
+        if !next_combination(&mut subset, candidates.len()) {
+            break;
+        }
+    }
+    return Ok(fits);
+}
+
+// The most subsets of shares tried when combining, so that many bad shares can't make combining take forever.
+const MAX_SUBSETS: usize = 10_000;
+
+struct ParsedShare {
+    position: usize,
+    threshold: u16,
+    x: Scalar,
+    values: Vec<Scalar>,
+}
+
+fn most_common_threshold(shares: &[ParsedShare]) -> Option<u16> {
+    let mut counts: BTreeMap<u16, usize> = BTreeMap::new();
+    for share in shares.iter() {
+        *counts.entry(share.threshold).or_default() += 1;
+    }
+    return counts
+        .into_iter()
+        .max_by_key(|(_threshold, count)| *count)
+        .map(|(threshold, _count)| threshold);
+}
+
+// The polynomials (one per chunk) through a set of shares, for Lagrange interpolation.
+struct Polynomial<'a> {
+    points: Vec<&'a ParsedShare>,
+    // 1 / the product of (x_i - x_j) for each point i, over every other point j.
+    denominators: Vec<Scalar>,
+}
+
+impl<'a> Polynomial<'a> {
+    // None if two of the points have the same x.
+    fn through(points: Vec<&'a ParsedShare>) -> Option<Self> {
+        let mut denominators: Vec<Scalar> = Vec::with_capacity(points.len());
+        for (i, point) in points.iter().enumerate() {
+            let mut denominator = Scalar::ONE;
+            for (j, other) in points.iter().enumerate() {
+                if i != j {
+                    denominator *= point.x - other.x;
+                }
+            }
+            if denominator == Scalar::ZERO {
+                return None;
+            }
+            denominators.push(denominator);
+        }
+        Scalar::batch_invert(&mut denominators);
+        return Some(Polynomial {
+            points,
+            denominators,
+        });
+    }
+
+    // How much of each point's value goes into the value at x.
+    fn weights(&self, x: &Scalar) -> Vec<Scalar> {
+        // The product of (x - x_j) over every point but i, from the products before and after it.
+        let differences: Vec<Scalar> = self.points.iter().map(|point| x - point.x).collect();
+        let mut after = vec![Scalar::ONE; differences.len() + 1];
+        for i in (0..differences.len()).rev() {
+            after[i] = after[i + 1] * differences[i];
+        }
+        let mut before = Scalar::ONE;
+        let mut weights: Vec<Scalar> = Vec::with_capacity(differences.len());
+        for i in 0..differences.len() {
+            weights.push(before * after[i + 1] * self.denominators[i]);
+            before *= differences[i];
+        }
+        return weights;
+    }
+
+    fn value(&self, weights: &[Scalar], chunk: usize) -> Scalar {
+        return self
+            .points
+            .iter()
+            .zip(weights.iter())
+            .map(|(point, weight)| point.values[chunk] * weight)
+            .sum();
+    }
+
+    // The value of the first chunk at x = 0, for a secret which is a whole scalar.
+    fn constant(&self) -> Vec<u8> {
+        let weights = self.weights(&Scalar::ZERO);
+        return self.value(&weights, 0).to_bytes().to_vec();
+    }
+
+    // The secret at x = 0, if every chunk has zero bytes above the chunk lengths given.
+    fn secret(&self, chunk_lens: &[usize]) -> Option<Vec<u8>> {
+        let weights = self.weights(&Scalar::ZERO);
+        let mut secret: Vec<u8> = Vec::with_capacity(chunk_lens.iter().sum());
+        for (chunk, chunk_len) in chunk_lens.iter().copied().enumerate() {
+            let bytes = self.value(&weights, chunk).to_bytes();
+            if bytes[chunk_len..].iter().any(|b| *b != 0) {
+                return None;
+            }
+            secret.extend_from_slice(&bytes[..chunk_len]);
+        }
+        return Some(secret);
+    }
+
+    fn fits(&self, share: &ParsedShare) -> bool {
+        let weights = self.weights(&share.x);
+        return (0..share.values.len())
+            .all(|chunk| self.value(&weights, chunk) == share.values[chunk]);
+    }
+}
+
+/// Moves to the next combination of indexes below count, in lexicographic order. Returns false after the last one.
+pub(crate) fn next_combination(combination: &mut [usize], count: usize) -> bool {
+    let size = combination.len();
+    for i in (0..size).rev() {
+        if combination[i] < count - size + i {
+            combination[i] += 1;
+            for j in i + 1..size {
+                combination[j] = combination[j - 1] + 1;
+            }
+            return true;
+        }
+    }
+    return false;
+}
+
+// The length of each chunk of an old, chosen secret: Full chunks, then whatever is left.
+fn full_chunk_lens(secret_len: usize) -> Vec<usize> {
+    return (0..secret_len.div_ceil(CHUNK_LEN))
+        .map(|chunk| CHUNK_LEN.min(secret_len - chunk * CHUNK_LEN))
+        .collect();
+}
+
+fn random_scalar() -> Scalar {
+    let mut bytes = [0u8; 64];
+    OsRng.fill_bytes(&mut bytes);
+    return Scalar::from_bytes_mod_order_wide(&bytes);
+}
+
+// Horner's method.
+fn evaluate(coefficients: &[Scalar], x: &Scalar) -> Scalar {
+    let mut result = Scalar::ZERO;
+    for coefficient in coefficients.iter().rev() {
+        result = result * x + coefficient;
+    }
+    return result;
+}
+
+fn parse_share(share: &[u8], format: ShareFormat) -> Option<(u16, u16, Vec<Scalar>)> {
+    let header_len = format.header_len();
+    if share.len() <= header_len {
+        return None;
+    }
+    let (threshold, index) = match format {
+        ShareFormat::Narrow => (u16::from(share[0]), u16::from(share[1])),
+        ShareFormat::Wide => (
+            u16::from_be_bytes([share[0], share[1]]),
+            u16::from_be_bytes([share[2], share[3]]),
+        ),
+    };
+    let scalars = share[header_len..].chunks_exact(SCALAR_LEN);
+    if threshold == 0 || index == 0 || !scalars.remainder().is_empty() {
+        return None;
+    }
+
+    let mut values = Vec::new();
+    for bytes in scalars {
+        let bytes: [u8; SCALAR_LEN] = bytes.try_into().ok()?;
+        values.push(Option::from(Scalar::from_canonical_bytes(bytes))?);
+    }
+    return Some((threshold, index, values));
+}
+
+fn decompress(commitment: &[u8]) -> Option<RistrettoPoint> {
+    return CompressedRistretto::from_slice(commitment)
+        .ok()?
+        .decompress();
+}
+
+#[cfg(test)]
+mod tests {
+    use curve25519_dalek::{RistrettoPoint, Scalar};
+
+    use crate::error::AppResult;
+    use crate::vss::{
+        combine_random, combine_secret, decompress, full_chunk_lens, make_shares, random_share_len,
+        share_len, split_random, split_scalars, verify_share, ShareFormat, Split, SCALAR_LEN,
+    };
+
+    const SECRET: &[u8; 33] = b"a secret which spans two chunks!!";
+
+    // Splits a secret as vaults made before random secrets did, a chunk of bytes to each scalar.
+    fn split_secret(secret: &[u8], count: u16, threshold: u16) -> AppResult<Split> {
+        let mut chunks: Vec<Scalar> = Vec::new();
+        let mut rest = secret;
+        for chunk_len in full_chunk_lens(secret.len()) {
+            let (chunk, after) = rest.split_at(chunk_len);
+            rest = after;
+            chunks.push(chunk_to_scalar(chunk));
+        }
+        return split_scalars(&chunks, count, threshold);
+    }
+
+    fn chunk_to_scalar(chunk: &[u8]) -> Scalar {
+        let mut bytes = [0u8; SCALAR_LEN];
+        bytes[..chunk.len()].copy_from_slice(chunk);
+        return Scalar::from_bytes_mod_order(bytes);
+    }
+
+    #[test]
+    fn round_trip() {
+        let (shares, commitments, _coefficients) =
+            split_secret(SECRET, 5, 3).expect("Failed to split");
+        assert_eq!(shares.len(), 5);
+        assert_eq!(shares[0].len(), share_len(SECRET.len(), ShareFormat::Wide));
+        assert_eq!(commitments.chunks.len(), 2);
+        assert_eq!(commitments.chunks[0].len(), 3);
+
+        for share in shares.iter() {
+            assert!(verify_share(share, &commitments, ShareFormat::Wide));
+        }
+        for start in 0..3 {
+            let combined =
+                combine_secret(&shares[start..start + 3], SECRET.len(), ShareFormat::Wide)
+                    .expect("Failed to combine")
+                    .expect("Not combined");
+            assert_eq!(combined.secret, SECRET);
+            assert!(combined.bad_shares.is_empty());
+        }
+
+        // Not enough shares, including when one is given twice.
+        let two = [shares[0].clone(), shares[1].clone(), shares[1].clone()];
+        assert_eq!(
+            combine_secret(&two, SECRET.len(), ShareFormat::Wide).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn one_of_one() {
+        let (shares, commitments, _coefficients) =
+            split_secret(SECRET, 1, 1).expect("Failed to split");
+        assert!(verify_share(&shares[0], &commitments, ShareFormat::Wide));
+        let combined = combine_secret(&shares, SECRET.len(), ShareFormat::Wide)
+            .expect("Failed to combine")
+            .expect("Not combined");
+        assert_eq!(combined.secret, SECRET);
+
+        assert!(split_secret(SECRET, 2, 0).is_err());
+        assert!(split_secret(SECRET, 2, 3).is_err());
+    }
+
+    #[test]
+    fn bad_shares_caught() {
+        let (shares, commitments, _coefficients) =
+            split_secret(SECRET, 4, 2).expect("Failed to split");
+        let (other_shares, other_commitments, _other_coefficients) =
+            split_secret(SECRET, 4, 2).expect("Failed to split");
+
+        // Every single byte change is caught.
+        for i in 0..shares[1].len() {
+            let mut damaged = shares[1].clone();
+            damaged[i] ^= 0x01;
+            assert!(!verify_share(&damaged, &commitments, ShareFormat::Wide));
+        }
+        // Shares of the same secret split another time do not match.
+        assert!(!verify_share(
+            &other_shares[1],
+            &commitments,
+            ShareFormat::Wide
+        ));
+        assert!(verify_share(
+            &other_shares[1],
+            &other_commitments,
+            ShareFormat::Wide
+        ));
+        assert!(!verify_share(
+            &shares[1][..40],
+            &commitments,
+            ShareFormat::Wide
+        ));
+
+        // Mixing the two sets almost never gives a valid secret, and never the real one.
+        let mixed = [shares[0].clone(), other_shares[1].clone()];
+        let combined =
+            combine_secret(&mixed, SECRET.len(), ShareFormat::Wide).expect("Failed to combine");
+        assert_ne!(
+            combined.map(|c| c.secret).as_deref(),
+            Some(SECRET.as_slice())
+        );
+
+        assert!(combine_secret(&[vec![2, 1, 3]], SECRET.len(), ShareFormat::Wide).is_err());
+    }
+
+    #[test]
+    fn bad_shares_left_out() {
+        let (shares, _commitments, _coefficients) =
+            split_secret(SECRET, 6, 3).expect("Failed to split");
+        let (other_shares, _other_commitments, _other_coefficients) =
+            split_secret(SECRET, 6, 3).expect("Failed to split");
+
+        // Two damaged shares and one from another split, with enough good ones around them.
+        let mut given = shares.clone();
+        given[1][10] ^= 0x01;
+        given[4][40] ^= 0x80;
+        given.push(other_shares[2].clone());
+        let combined = combine_secret(&given, SECRET.len(), ShareFormat::Wide)
+            .expect("Failed to combine")
+            .expect("Not combined");
+        assert_eq!(combined.secret, SECRET);
+        assert_eq!(combined.bad_shares, Vec::from([1, 4, 6]));
+
+        // A share with the wrong threshold.
+        let mut given = shares[..4].to_vec();
+        given[0][1] = 2;
+        let combined = combine_secret(&given, SECRET.len(), ShareFormat::Wide)
+            .expect("Failed to combine")
+            .expect("Not combined");
+        assert_eq!(combined.secret, SECRET);
+        assert_eq!(combined.bad_shares, Vec::from([0]));
+
+        // Not enough good shares left.
+        let given = [
+            shares[0].clone(),
+            shares[1].clone(),
+            other_shares[2].clone(),
+        ];
+        assert_eq!(
+            combine_secret(&given, SECRET.len(), ShareFormat::Wide).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn many_shares() {
+        // More shares, and a higher threshold, than fit in a byte.
+        let (shares, commitments, _coefficients) =
+            split_secret(SECRET, 300, 260).expect("Failed to split");
+        assert_eq!(shares.len(), 300);
+        assert_eq!(commitments.chunks[0].len(), 260);
+        assert_eq!(&shares[299][..4], &[1, 4, 1, 44]);
+        assert!(verify_share(&shares[299], &commitments, ShareFormat::Wide));
+
+        let combined = combine_secret(&shares[40..], SECRET.len(), ShareFormat::Wide)
+            .expect("Failed to combine")
+            .expect("Not combined");
+        assert_eq!(combined.secret, SECRET);
+        let too_few = combine_secret(&shares[41..], SECRET.len(), ShareFormat::Wide);
+        assert_eq!(too_few.unwrap(), None);
+    }
+
+    #[test]
+    fn more_shares() {
+        let (shares, commitments, coefficients) =
+            split_secret(SECRET, 4, 3).expect("Failed to split");
+
+        // Shares made later check against the same commitments, and combine with the first ones.
+        let more = make_shares(&coefficients, 5..=6).expect("Failed to make shares");
+        assert_eq!(&more[1][..4], &[0, 3, 0, 6]);
+        assert!(verify_share(&more[0], &commitments, ShareFormat::Wide));
+        let given = [shares[0].clone(), more[0].clone(), more[1].clone()];
+        let combined = combine_secret(&given, SECRET.len(), ShareFormat::Wide)
+            .expect("Failed to combine")
+            .expect("Not combined");
+        assert_eq!(combined.secret, SECRET);
+        assert!(combined.bad_shares.is_empty());
+        assert_eq!(make_shares(&coefficients, 4..=4).unwrap()[0], shares[3]);
+
+        // The share at 0 would give the secret away.
+        assert!(make_shares(&coefficients, 0..=2).is_err());
+        let mut damaged = coefficients.clone();
+        damaged.chunks[1].pop();
+        assert!(make_shares(&damaged, 5..=5).is_err());
+    }
+
+    #[test]
+    fn narrow_shares() {
+        // Shares made before wide shares have a one byte threshold and index.
+        let (shares, commitments, _coefficients) =
+            split_secret(SECRET, 4, 2).expect("Failed to split");
+        let narrow: Vec<Vec<u8>> = shares
+            .iter()
+            .map(|share| [&[share[1], share[3]], &share[4..]].concat())
+            .collect();
+        assert_eq!(
+            narrow[0].len(),
+            share_len(SECRET.len(), ShareFormat::Narrow)
+        );
+        assert!(verify_share(&narrow[2], &commitments, ShareFormat::Narrow));
+        assert!(!verify_share(&narrow[2], &commitments, ShareFormat::Wide));
+
+        let combined = combine_secret(&narrow[1..3], SECRET.len(), ShareFormat::Narrow)
+            .expect("Failed to combine")
+            .expect("Not combined");
+        assert_eq!(combined.secret, SECRET);
+        assert!(combine_secret(&narrow, SECRET.len(), ShareFormat::Wide).is_err());
+    }
+
+    #[test]
+    fn random_round_trip() {
+        let (secret, (shares, commitments, _coefficients)) =
+            split_random(5, 3).expect("Failed to split");
+        assert_eq!(secret.len(), 32);
+        assert_eq!(shares[0].len(), random_share_len(ShareFormat::Wide));
+        assert_eq!(commitments.chunks.len(), 1);
+
+        // The commitment to the constant term is to the whole secret.
+        let scalar = Scalar::from_canonical_bytes(secret.clone().try_into().unwrap()).unwrap();
+        assert_eq!(
+            decompress(&commitments.chunks[0][0]),
+            Some(RistrettoPoint::mul_base(&scalar))
+        );
+        for share in shares.iter() {
+            assert!(verify_share(share, &commitments, ShareFormat::Wide));
+        }
+        let combined = combine_random(&shares[1..4], ShareFormat::Wide).expect("Failed to combine");
+        assert_eq!(combined.len(), 1);
+        assert_eq!(combined[0].secret, secret);
+
+        // Each split has its own secret.
+        let (other, _split) = split_random(5, 3).expect("Failed to split");
+        assert_ne!(other, secret);
+        assert!(combine_random(&shares[..2], ShareFormat::Wide)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn random_bad_shares() {
+        let (secret, (shares, _commitments, _coefficients)) =
+            split_random(5, 2).expect("Failed to split");
+
+        // With spare shares, a changed one is left out.
+        let mut given = shares[..4].to_vec();
+        given[1][10] ^= 0x01;
+        let combined = combine_random(&given, ShareFormat::Wide).expect("Failed to combine");
+        assert_eq!(combined.len(), 1);
+        assert_eq!(combined[0].secret, secret);
+        assert_eq!(combined[0].bad_shares, Vec::from([1]));
+
+        // Without, every secret the shares could give is returned.
+        let combined = combine_random(&given[..3], ShareFormat::Wide).expect("Failed to combine");
+        assert_eq!(combined.len(), 3);
+        assert!(combined
+            .iter()
+            .any(|c| c.secret == secret && c.bad_shares == [1]));
+    }
+}
@@ -1,14 +1,16 @@
 import {PageProps} from './index.tsx'
 import {byteArrayToKeyString, displayKey} from '../../util'
-import {useState} from 'react'
+import {useEffect, useState} from 'react'
 import {PageHeader, SectionHeader} from '../../shared/Headers.tsx'
 import {writeText} from '@tauri-apps/api/clipboard'
 import {ContinueButton, Footer, FooterButton} from '../../shared/Footer.tsx'
 import {PageWrapper} from '../../shared'
 import {CircleDisplay} from './shares/Circle.tsx'
 import {exit} from '@tauri-apps/api/process'
-import {saveKey} from "../../util/fileApi.ts";
+import {saveKey, selectFile} from "../../util/fileApi.ts";
 import {open} from '@tauri-apps/api/shell';
+import {invoke} from '@tauri-apps/api'
+import {castErr} from '../../shared/Errors.tsx'
 
 // Allows key pieces to be saved and shared. The user cannot progress beyond this page until all keys and saved, and it
 // Lets them either exit or go back to the welcome screen.
@@ -19,11 +21,78 @@ export function ShareKeysPage({vaultInfo, goBack}: PageProps) {
 
     const allCopied = copied.length >= totalKeys
 
+    // Keys are numbered from 1 across all circles. Pieces start with their envelope, so their bytes can't be used.
+    const firstKeyNumbers = vaultInfo.keys.share_keys.reduce((acc: number[], circle) => [...acc, acc[acc.length - 1] + (circle.keys?.length || 0)], [1])
+
+    // The result of checking each key against the commitments in the written vault, by key number. Empty means it is good.
+    const [checks, setChecks] = useState<Record<number, string>>({})
+    // The public info of the written vault, used to explain saved key files.
+    const [info, setInfo] = useState<unknown>()
+    // Pieces the owner has protected with a passphrase chosen by their recipient, by key number. These are shared
+    // in place of the piece itself.
+    const [protectedKeys, setProtectedKeys] = useState<Record<number, number[]>>({})
+    const [protecting, setProtecting] = useState<number | undefined>()
+    const [passphrase, setPassphrase] = useState<string>('')
+    useEffect(() => {
+        if (!vaultInfo.output || !vaultInfo.keys) return
+        const circles = vaultInfo.keys.share_keys
+        invoke('load_meta', {filePath: vaultInfo.output})
+            .then(async info => {
+                setInfo(info)
+                const results: Record<number, string> = {}
+                for (const [circleIndex, circle] of circles.entries()) {
+                    for (const [index, key] of (circle.keys || []).entries()) {
+                        results[firstKeyNumbers[circleIndex] + index] = await invoke('verify_piece', {info, key})
+                            .then(() => '')
+                            .catch(e => castErr(e).message)
+                    }
+                }
+                setChecks(results)
+            })
+            .catch(console.error)
+    }, [vaultInfo.output])
+
     function hasCopied(index: number) {
         const newCopied = [...copied, index];
         setCopied(newCopied)
     }
 
+    // Key pieces can also be written down as words.
+    const copyWords = (index: number, arr: number[]) => {
+        invoke('piece_words', {key: arr})
+            .then(words => writeText(words as string))
+            .then(() => hasCopied(index))
+            .catch(console.error)
+    }
+
+    // Key files are saved as armored text, which includes the comment and explains what the piece is.
+    const saveArmored = (index: number, arr: number[], comment: string) => {
+        if (!info) return handleCopyOrSave(saveKey, index, arr, comment)
+        invoke('armor_piece', {info, key: arr, cloud: !!vaultInfo.cloud_creds})
+            .then(text => saveKey(text as string, comment))
+            .then(() => hasCopied(index))
+            .catch(console.error)
+    }
+
+    // Pieces sealed to a member's public key are already text, and can only be opened by that member.
+    const saveSealed = (index: number, sealed: string, comment: string) => {
+        saveKey(sealed, `${comment}-sealed`, false)
+            .then(() => hasCopied(index))
+            .catch(console.error)
+    }
+
+    // The recipient chooses the passphrase, and must give it whenever the piece is used.
+    const protectKey = (n: number, arr: number[]) => {
+        invoke<number[]>('protect_piece', {key: arr, passphrase})
+            .then(key => {
+                setProtectedKeys({...protectedKeys, [n]: key})
+                setCopied(copied.filter(c => c !== n))
+                setProtecting(undefined)
+                setPassphrase('')
+            })
+            .catch(console.error)
+    }
+
     const handleCopyOrSave = (fn: Function, index: number, arr: number[], comment: string) => {
         const str = byteArrayToKeyString(arr);
         fn(str, comment).catch(console.error)
@@ -61,6 +130,22 @@ export function ShareKeysPage({vaultInfo, goBack}: PageProps) {
             .catch(console.error)
     }
 
+    /**
+     * Write a printable recovery sheet for every key piece into a chosen folder, to be opened in a browser and printed.
+     * Each sheet has a QR code of the piece, the piece as words and instructions for the recipient.
+     */
+    async function printSheets() {
+        if (!info || !vaultInfo.keys) return
+        const [folder] = await selectFile(true)
+        if (!folder) return
+        const circles = vaultInfo.keys.share_keys.map((circle, circleIndex) => ({
+            ...circle,
+            keys: circle.keys?.map((key, index) => protectedKeys[firstKeyNumbers[circleIndex] + index] || key)
+        }))
+        await invoke('recovery_sheets', {info, circles, folder})
+        setCopied([...Array(totalKeys).keys()].map(i => i + 1))
+    }
+
     /**
      * Exit the application when exit is clicked and all keys have been copied.
      */
@@ -80,7 +165,7 @@ export function ShareKeysPage({vaultInfo, goBack}: PageProps) {
                 Each keyfile is encoded in plaintext and includes a basic guide on how to access your information.<br/>
             </p>
 
-            {vaultInfo.keys.share_keys.map(circle => {
+            {vaultInfo.keys.share_keys.map((circle, circleIndex) => {
                 return (
                     <CircleDisplay title={circle.name} middle='' right={<div/>}>
                         <table className='table is-fullwidth'>
@@ -88,41 +173,81 @@ export function ShareKeysPage({vaultInfo, goBack}: PageProps) {
                             <tr>
                                 <th>Key</th>
                                 <th>Comment</th>
+                                <th>Checked</th>
                                 <th>Share options</th>
                                 <th>Key stored/shared</th>
                             </tr>
 
                             </thead>
                             <tbody>
-                            {circle.keys?.map((i, index) => <tr key={`key-${i[0]}-${index}`}>
+                            {circle.keys?.map((piece, index) => {
+                                const n = firstKeyNumbers[circleIndex] + index
+                                const i = protectedKeys[n] || piece
+                                return <tr key={`key-${n}`}>
                                 <td>
-                                    Key {i[0]} ({displayKey(i)})
+                                    Key {n} ({displayKey(i)})
 
                                 </td>
                                 <td>{circle.key_comments[index]}</td>
+                                <td title={checks[n] || ''}>
+                                    {checks[n] === undefined ? '' : (checks[n] ? '⚠️' : '✅')}
+                                </td>
                                 <td>
                                     <div className="buttons">
                                         <button className='button is-small'
-                                                onClick={() => handleCopyOrSave(writeText, i[0], i, circle.key_comments[index])}>Copy
+                                                onClick={() => handleCopyOrSave(writeText, n, i, circle.key_comments[index])}>Copy
                                             to clipboard
                                         </button>
+                                        {protectedKeys[n]
+                                            ? ''
+                                            : <button className='button is-small'
+                                                      onClick={() => copyWords(n, i)}>Copy as words
+                                            </button>}
+                                        {circle.sealed_keys?.[index]
+                                            ? <button className='button is-small'
+                                                      title='Sealed to the public key they gave, so it can be sent by email.'
+                                                      onClick={() => saveSealed(n, circle.sealed_keys?.[index] || '', circle.key_comments[index])}>Save
+                                                sealed piece
+                                            </button>
+                                            : ''}
                                         <button className='button is-small'
-                                                onClick={() => handleCopyOrSave(saveKey, i[0], i, circle.key_comments[index])}>Save
+                                                onClick={() => saveArmored(n, i, circle.key_comments[index])}>Save
                                             to file
                                         </button>
-                                        <button className="button is-small" onClick={() => whatsappShare(i[0], i)}>Share
+                                        <button className="button is-small" onClick={() => whatsappShare(n, i)}>Share
                                             in WhatsApp
                                         </button>
-                                        <button className="button is-small" onClick={() => emailShare(i[0], i)}>Share
+                                        <button className="button is-small" onClick={() => emailShare(n, i)}>Share
                                             via Email
                                         </button>
+                                        {protectedKeys[n]
+                                            ? <span className='tag is-info'>Passphrase protected</span>
+                                            : <button className='button is-small'
+                                                      title='So the piece can only be used with a passphrase they choose.'
+                                                      onClick={() => setProtecting(n)}>Add passphrase
+                                            </button>}
                                     </div>
+                                    {protecting === n
+                                        ? <div className='field has-addons'>
+                                            <div className='control'>
+                                                <input className='input is-small' type='password'
+                                                       placeholder="Recipient's passphrase" value={passphrase}
+                                                       onChange={e => setPassphrase(e.target.value)}/>
+                                            </div>
+                                            <div className='control'>
+                                                <button className='button is-small is-success' disabled={!passphrase}
+                                                        onClick={() => protectKey(n, piece)}>Protect
+                                                </button>
+                                            </div>
+                                        </div>
+                                        : ''}
 
                                 </td>
                                 <td>
-                                    {copied.includes(i[0]) ? '✅' : '❌'}
+                                    {copied.includes(n) ? '✅' : '❌'}
                                 </td>
-                            </tr>)}
+                            </tr>
+                            })}
 
                             </tbody>
 
@@ -132,6 +257,12 @@ export function ShareKeysPage({vaultInfo, goBack}: PageProps) {
                 )
             })}
 
+            <div className='buttons'>
+                <button className='button' disabled={!info} onClick={() => printSheets().catch(console.error)}>
+                    Make printable recovery sheets
+                </button>
+            </div>
+
             <div className='columns'>
                 <div className='column is-8'/>
             </div>
@@ -2,17 +2,17 @@ import {PageProps} from './index.tsx'
 import {PageHeader, SectionHeader, SectionSubHeader} from '../../shared/Headers.tsx'
 import {ContinueButton, Footer, FooterButton} from '../../shared/Footer.tsx'
 import {DurationUnit, secondsToUnits} from './5updates.tsx'
-import {validateEmail, validateName, VaultType} from './VaultInfo.tsx'
+import {Cipher, validateEmail, validateName, VaultType} from './VaultInfo.tsx'
 import {PageWrapper} from '../../shared'
 import {useEffect, useState} from 'react'
 import {ValidationError} from '../../util/constants.ts'
 import {ErrorText} from '../../shared/Errors.tsx'
 
 // Allows user to review choices and picks up errors (Such as missing name/email address) that they have made.
-export function ReviewPage({goNext, goBack, vaultInfo}: PageProps) {
+export function ReviewPage({goNext, goBack, vaultInfo, setVaultInfo}: PageProps) {
     const {
         alert_duration, share_config, reminder_period
-        , personal_info, vault_type
+        , personal_info, vault_type, cipher
     } = vaultInfo
     const {circles, required} = share_config || {circles: []}
     const requiredCircles: string = circles.filter(c => c.required).map(c => c.name).join(', ')
@@ -124,6 +124,18 @@ export function ReviewPage({goNext, goBack, vaultInfo}: PageProps) {
                     </div>
                 </div>
 
+                <SectionSubHeader>Encryption</SectionSubHeader>
+                <p>
+                    XChaCha20-Poly1305 is recommended. AES-256-GCM-SIV is also available, and stays secure even if
+                    a random value is ever repeated.
+                </p>
+                <select
+                    value={cipher || Cipher.XChaCha20Poly1305}
+                    onChange={e => setVaultInfo({cipher: e.target.value as Cipher})}
+                >
+                    <option value={Cipher.XChaCha20Poly1305}>XChaCha20-Poly1305</option>
+                    <option value={Cipher.Aes256GcmSiv}>AES-256-GCM-SIV</option>
+                </select>
 
             </div>
             <ErrorText>{error}</ErrorText>
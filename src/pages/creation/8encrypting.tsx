@@ -36,7 +36,8 @@ export function EncryptingPage({vaultInfo, setVaultInfo, goNext, goBack}: PagePr
                 }
 
 
-                const {keys, path: ret_path, cloud_keys: ret_cloud} = await createVault(vaultInfo as VaultInfo)
+                const {keys, path: ret_path, cloud_keys: ret_cloud, warnings} = await createVault(vaultInfo as VaultInfo)
+                warnings.forEach((warning) => console.error(warning))
                 setVaultInfo({keys, cloud_creds: ret_cloud || cloud_creds, output: ret_path})
 
                 // Update values in scope
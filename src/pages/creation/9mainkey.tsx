@@ -6,6 +6,7 @@ import {ContinueButton, Footer} from '../../shared/Footer.tsx'
 import {writeText} from '@tauri-apps/api/clipboard'
 import {PageWrapper} from '../../shared'
 import {saveKey} from "../../util/fileApi.ts";
+import {invoke} from '@tauri-apps/api'
 
 // Main key page allows the main keep to be saved.
 // This page was originally called the master key page but was renamed along with all other references to master key as that term can be problematic.
@@ -17,6 +18,23 @@ export function MainkeyPage({vaultInfo, goNext}: PageProps) {
     const fullKey = vaultInfo.cloud_creds ? [...vaultInfo.keys.main, ...vaultInfo.cloud_creds.owner_token] : vaultInfo.keys.main;
     const str = byteArrayToKeyString(fullKey)
     const [copied, setCopied] = useState<boolean>(false)
+    // An optional passphrase for the saved key file, so the file can't be used on its own.
+    const [passphrase, setPassphrase] = useState<string>('')
+
+    // Key files are saved as armored text, which explains what the key is to whoever finds it.
+    async function saveMainKey() {
+        let text = str
+        if (vaultInfo?.output) {
+            const info = await invoke('load_meta', {filePath: vaultInfo.output})
+            text = await invoke<string>('armor_main_key', {
+                info,
+                key: fullKey,
+                cloud: !!vaultInfo.cloud_creds,
+                passphrase: passphrase || undefined
+            })
+        }
+        await saveKey(text, "main", true)
+    }
 
     return (
         <PageWrapper>
@@ -36,6 +54,17 @@ export function MainkeyPage({vaultInfo, goNext}: PageProps) {
                     <SectionHeader>Keys</SectionHeader>
 
                     <p>Main ({displayKey(vaultInfo.keys.main)})</p>
+                    <div className='field'>
+                        <label className='label'>Passphrase for the key file (optional)</label>
+                        <div className='control'>
+                            <input className='input' type='password' value={passphrase}
+                                   onChange={e => setPassphrase(e.target.value)}/>
+                        </div>
+                        <p className='help'>
+                            If you set a passphrase, the saved key file can only be used with it. You will need both
+                            to open and update your vault, so choose one you will remember.
+                        </p>
+                    </div>
                     <div className="buttons">
                         <button className="button" onClick={() => {
                             writeText(str).catch(console.error)
@@ -45,7 +74,7 @@ export function MainkeyPage({vaultInfo, goNext}: PageProps) {
                         </button>
 
                         <button className="button" onClick={() => {
-                            saveKey(str, "main", true).catch(console.error)
+                            saveMainKey().catch(console.error)
                             setCopied(true)
                         }}
                         >Save keyfile
@@ -3,6 +3,7 @@
 import {EMAIL_REGEX, NAME_MAX, NAME_MIN, ValidationError} from '../../util/constants.ts'
 import {invoke} from '@tauri-apps/api'
 import {CircleData} from './shares'
+import {AppError} from '../../shared/Errors.tsx'
 
 export enum VaultType {
     Offline = 'Offline',
@@ -21,6 +22,23 @@ export interface PersonalInfo {
 export interface KeyCollection {
     share_keys: CircleData[]
     main: number[]
+    commitments?: ShareCommitments
+    // What the pieces were made from, so members can be added later. Only read by the backend.
+    coefficients?: ShareCoefficients
+    // The seed of the owner's signing key. As secret as the main key, and only read by the backend.
+    signing_seed?: number[]
+}
+
+// Commitments to the shares in the key pieces, so each piece can be checked on its own. Only read by the backend.
+export interface ShareCommitments {
+    individual: { chunks: number[][][] }
+    circles: { chunks: number[][][] }[]
+}
+
+// Laid out as ShareCommitments. These can make anyone's piece, so they are kept as secret as the main key.
+export interface ShareCoefficients {
+    individual: { chunks: number[][][] }
+    circles: { chunks: number[][][] }[]
 }
 
 export interface ShareConfig {
@@ -43,9 +61,34 @@ export interface VaultInfo {
     share_config: ShareConfig
     vault_folder: string
     vault_type: VaultType,
+    // The cipher for the vault's contents. The backend picks XChaCha20Poly1305 if this is not set.
+    cipher?: Cipher
+    // Set by the open command. The vault file this was opened from, which an update follows on from.
+    previous?: PreviousVersion
     output?: string
 }
 
+export interface Lineage {
+    vault_id: string
+    // Starts at 1, and goes up by one each time the vault is updated.
+    version: number
+    // The SHA-256 of the vault file this version replaced. Empty for the first version.
+    previous_hash: number[]
+}
+
+export interface PreviousVersion {
+    path: string
+    hash: number[]
+    lineage: Lineage
+}
+
+export enum Cipher {
+    XChaCha20Poly1305 = 'XChaCha20Poly1305',
+    Aes256GcmSiv = 'Aes256GcmSiv',
+    // Only used by vaults from older versions of the app.
+    ChaCha20Poly1305 = 'ChaCha20Poly1305'
+}
+
 export interface CloudKeyData {
     owner_token: number[]
     share_token: number[]
@@ -82,6 +125,11 @@ export interface CreateVaultResult {
     path: string
     keys: KeyCollection,
     cloud_keys?: CloudKeyData
+    lineage: Lineage
+    // Where the version this update replaced was copied to.
+    backup?: string
+    // Anything which went wrong after the vault was written. The vault was still made.
+    warnings: AppError[]
 }
 
 export async function createVault(vault: VaultInfo): Promise<CreateVaultResult> {
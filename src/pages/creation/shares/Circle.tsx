@@ -1,5 +1,6 @@
 import {SectionSubHeader} from '../../../shared/Headers.tsx'
 import {CircleData} from './index.tsx'
+import {MAX_KEYS} from '../../../util/constants.ts'
 import {InputWithButton} from '../../../shared/InputWithButton.tsx'
 import {PropsWithChildren} from 'react'
 
@@ -8,7 +9,8 @@ export interface CircleProps {
     setCircle: (circle: CircleData) => any
     deleteCircle: () => any,
     disabled?: boolean
-
+    // How many keys were already given out, when updating. These can't be changed, but more can be added.
+    existingKeys?: number
 }
 
 /**
@@ -17,15 +19,38 @@ export interface CircleProps {
  * @param setCircle Function to update the information for this circle.
  * @param circle Circle data.
  * @param disabled Whether this circle is disabled (i.e. when updating)
+ * @param existingKeys How many keys were already given out. Only keys added after these can be changed.
  */
-export function Circle({deleteCircle, setCircle, circle, disabled = false}: CircleProps) {
-    const {required, name, key_comments} = circle
+export function Circle({deleteCircle, setCircle, circle, disabled = false, existingKeys = 0}: CircleProps) {
+    const {required, name, key_comments, threshold = 1, weights = [], recipients = []} = circle
+    // Keys already given out stay as they are.
+    const keyDisabled = (ind: number) => disabled && ind < existingKeys
     const updateRequired = () => setCircle({...circle, required: !required})
+    const updateThreshold = (t: number) => {
+        if (t >= 1 && t <= MAX_KEYS) setCircle({...circle, threshold: t})
+    }
     const removeKey = (ind: number) => {
-        if (disabled) return;
+        if (keyDisabled(ind)) return;
         const newKeys = [...key_comments]
         newKeys.splice(ind, 1)
-        setCircle({...circle, key_comments: newKeys})
+        const newWeights = [...weights]
+        newWeights.splice(ind, 1)
+        const newRecipients = [...recipients]
+        newRecipients.splice(ind, 1)
+        setCircle({...circle, key_comments: newKeys, weights: newWeights, recipients: newRecipients})
+    }
+    const updateWeight = (ind: number, w: number) => {
+        if (keyDisabled(ind) || !(w >= 1 && w < 255)) return;
+        const newWeights = key_comments.map((_c, i) => weights[i] || 1)
+        newWeights[ind] = w
+        setCircle({...circle, weights: newWeights})
+    }
+    // Members can give a public key, so their piece can be sent to them sealed.
+    const updateRecipient = (ind: number, r: string) => {
+        if (keyDisabled(ind)) return;
+        const newRecipients = key_comments.map((_c, i) => recipients[i] || '')
+        newRecipients[ind] = r.trim()
+        setCircle({...circle, recipients: newRecipients})
     }
 
     const addBox = (
@@ -34,7 +59,11 @@ export function Circle({deleteCircle, setCircle, circle, disabled = false}: Circ
             checked={required}
             onChange={updateRequired}
             disabled={disabled}
-        /> At least one of this circle is needed to unlock.
+        /> At least <input
+            className='input is-small threshold-input' type='number' value={threshold}
+            onChange={e => updateThreshold(parseInt(e.target.value, 10))}
+            disabled={disabled || !required}
+        /> of this circle {threshold === 1 ? 'is' : 'are'} needed to unlock.
         </p>
     )
 
@@ -47,8 +76,21 @@ export function Circle({deleteCircle, setCircle, circle, disabled = false}: Circ
                 {key_comments.map((s, i) => <div key={s} className='key'>
                     <p>Key {i + 1}:</p>
                     <p className='has-text-weight-bold'>  {s}</p>
+                    <p>Counts as</p>
+                    <input
+                        className='input is-small threshold-input' type='number' value={weights[i] || 1}
+                        onChange={e => updateWeight(i, parseInt(e.target.value, 10))}
+                        disabled={keyDisabled(i)}
+                    />
+                    <p>{(weights[i] || 1) === 1 ? 'key' : 'keys'}</p>
+                    <input
+                        className='input is-small' placeholder='Public key (optional, age1...)'
+                        value={recipients[i] || ''}
+                        onChange={e => updateRecipient(i, e.target.value)}
+                        disabled={keyDisabled(i)}
+                    />
 
-                    <button className='delete is-small' disabled={disabled} onClick={() => removeKey(i)}/>
+                    <button className='delete is-small' disabled={keyDisabled(i)} onClick={() => removeKey(i)}/>
                 </div>)}
                 <br/>
 
@@ -59,10 +101,10 @@ export function Circle({deleteCircle, setCircle, circle, disabled = false}: Circ
                             <div>
                                 <InputWithButton
                                     placeholder='Key comment'
-                                    disabled={disabled}
                                     handleSubmit={k => setCircle({
                                         ...circle,
-                                        key_comments: [...key_comments, k || 'No comment']
+                                        key_comments: [...key_comments, k || 'No comment'],
+                                        weights: [...key_comments.map((_c, i) => weights[i] || 1), 1]
                                     })}
                                 />
                             </div>
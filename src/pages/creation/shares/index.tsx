@@ -4,6 +4,7 @@ import {ContinueButton, Footer, FooterButton} from '../../../shared/Footer.tsx'
 import {PageHeader} from '../../../shared/Headers.tsx'
 import {useState} from 'react'
 import {Circle} from './Circle.tsx'
+import {MAX_KEYS} from '../../../util/constants.ts'
 import {InputWithButton} from '../../../shared/InputWithButton.tsx'
 
 /**
@@ -15,6 +16,14 @@ export interface CircleData {
     name: string
     required: boolean
     keys?: number[][]
+    // How many members of a required circle are needed. Defaults to 1.
+    threshold?: number
+    // How many keys each member counts as, in the same order as key_comments. Defaults to 1.
+    weights?: number[]
+    // Each member's age public key (age1...), in the same order as key_comments. Their piece is also sealed to it.
+    recipients?: string[]
+    // Each member's piece sealed to their public key. Empty for members without one.
+    sealed_keys?: string[]
 }
 
 // Share configuration page.
@@ -33,7 +42,6 @@ export function SharesConfigPage({goNext, goBack, vaultInfo, setVaultInfo}: Page
      * @param c CircleData Circle to update
      */
     function updateCircle(c: CircleData) {
-        if (alreadySet) return;
         // Put it at the right place. Otherwise, the entries change order every render, which is very odd.
         const newCircles = [...circles]
         const index = circles.findIndex(i => i.name === c.name)
@@ -87,18 +95,21 @@ export function SharesConfigPage({goNext, goBack, vaultInfo, setVaultInfo}: Page
 
     const setKeysNeeded = (k: number) => {
         if (alreadySet) return;
-        if (k >= 0 && k < 255) _setKeysNeeded(k)
+        if (k >= 0 && k <= MAX_KEYS) _setKeysNeeded(k)
     }
 
     // Count stats for error handling
     let emptyCircles = 0
     let totalShares = 0
     let totalRequired = 0
+    let badThresholds = 0
     for (const circle of circles) {
         if (circle.required) {
             totalRequired++
+            const threshold = circle.threshold || 1
+            if (threshold < 1 || threshold > circle.key_comments.length) badThresholds++
         }
-        totalShares += circle.key_comments.length
+        totalShares += circle.key_comments.reduce((total, _c, i) => total + (circle.weights?.[i] || 1), 0)
         if (circle.key_comments.length === 0) emptyCircles++
     }
 
@@ -112,9 +123,15 @@ export function SharesConfigPage({goNext, goBack, vaultInfo, setVaultInfo}: Page
     } else if (totalShares < keysNeeded) {
         canContinue = false
         errorText = 'The total number of keys must be more than the number needed to release your data. Add some more keys.'
+    } else if (totalShares > MAX_KEYS) {
+        canContinue = false
+        errorText = `You have too many keys. The weights of all keys must add up to ${MAX_KEYS} or less.`
     } else if (emptyCircles > 0) {
         canContinue = false
         errorText = `You have ${emptyCircles} empty circles. Remove them or add some keys to them.`
+    } else if (badThresholds > 0) {
+        canContinue = false
+        errorText = 'A required circle needs more keys than it has. Add some more keys to it, or lower the number needed.'
     } else if (totalRequired === 0 && circles.length > 1) {
         warningText = `You have added multiple circles, but not marked any as required. Recipients will need ${keysNeeded} keys from any circle to unlock your data.`
     }
@@ -134,14 +151,16 @@ export function SharesConfigPage({goNext, goBack, vaultInfo, setVaultInfo}: Page
                 Recipients can be split into <span className="special-text">circles</span>, and a circle is a group like
                 "Friends", "Family", or "Colleagues".
                 A circle can be set as required. <br/>
-                That means that one or more of the keys in that circle are needed to unlock your data.
+                That means that one or more of the keys in that circle are needed to unlock your data, and you can
+                choose how many.
                 For example you generate 5 shares - 2 for friends and 3 for family - and a family member is required to
                 participate
                 to unlock your data.
             </p>
             {alreadySet ?
-                <p className="error-text">You cannot update your circles when editing an existing vault, as existing
-                    keys cannot be revoked. Please create a new one and delete this one if needed.</p> : ""}
+                <p className="error-text">You can add new keys to your circles when editing an existing vault, and
+                    everyone else keeps the key they have. Nothing else can be changed, as existing keys cannot be
+                    revoked. Please create a new one and delete this one if needed.</p> : ""}
 
             <p className='error-text has-text-right'>{addCircleError}</p>
 
@@ -177,10 +196,11 @@ export function SharesConfigPage({goNext, goBack, vaultInfo, setVaultInfo}: Page
             </div>
 
             <div>
-                {circles.map(c => <Circle
+                {circles.map((c, i) => <Circle
                     key={c.name} circle={c} setCircle={updateCircle}
                     deleteCircle={() => deleteCircle(c)}
                     disabled={alreadySet}
+                    existingKeys={vaultInfo.keys?.share_keys[i]?.key_comments.length}
                 />)}
             </div>
 
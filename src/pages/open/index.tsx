@@ -6,7 +6,7 @@ import {Footer, FooterButton} from '../../shared/Footer.tsx'
 import {useState} from 'react'
 import {AppError, castErr, ErrorDisplay} from '../../shared/Errors.tsx'
 import {invoke} from '@tauri-apps/api'
-import {keyStringToByteArray, MASTER_KEY_LENGTH} from '../../util'
+import {isArmoredKey, keyStringToByteArray, MASTER_KEY_LENGTH} from '../../util'
 import {VaultInfo} from '../creation/VaultInfo.tsx'
 import {downloadVault} from "../../util/cloudApi.ts";
 
@@ -27,11 +27,16 @@ export interface OpenProps {
  */
 export function Open({toOpen, handleCancel, handleUnlock, handleOpened}: OpenProps): JSX.Element {
     const [error, setError] = useState<AppError | undefined>()
+    // A key file protected by a passphrase is kept here while the owner enters it.
+    const [protectedKey, setProtectedKey] = useState<string | undefined>()
+    const [passphrase, setPassphrase] = useState<string>('')
 
-    function handleKeyInput(key: string) {
-        const keyBits = keyStringToByteArray(key);
-
+    function handleKeyInput(keyText: string, passphrase?: string) {
+        setError(undefined);
         (async function () {
+            // Saved key files are read by the backend, as cloud keys carry the owner token needed to find the vault.
+            // Protected key files are unwrapped there too.
+            const keyBits = isArmoredKey(keyText) ? await invoke<number[]>('read_main_key', {key: keyText, passphrase}) : keyStringToByteArray(keyText);
             let filePath: string = toOpen?.path || ""
             let key = keyBits;
             let token: number[] = []
@@ -55,11 +60,17 @@ export function Open({toOpen, handleCancel, handleUnlock, handleOpened}: OpenPro
                 filePath,
                 key
             })
-            const info = vaultInfo as VaultInfo;
-            const share_token = key.slice(0, 8)
-            handleOpened({...info, cloud_creds: token ? {owner_token: token, share_token} : undefined})
+            // The backend restores the keys and cloud credentials from the owner-only part of the vault.
+            handleOpened(vaultInfo as VaultInfo)
 
-        })().catch(e => setError(castErr(e)))
+        })().catch(e => {
+            const err = castErr(e)
+            if (err.error_type === 'passphrase_needed') {
+                setProtectedKey(keyText)
+            } else {
+                setError(err)
+            }
+        })
 
 
     }
@@ -79,7 +90,25 @@ export function Open({toOpen, handleCancel, handleUnlock, handleOpened}: OpenPro
             <br/>
             <div className='columns is-mobile'>
                 <div className='column is-6'>
-                    <EnterKey handleKey={handleKeyInput}/>
+                    {protectedKey
+                        ? <div>
+                            <p>This key file is protected by a passphrase.</p>
+                            <div className='field'>
+                                <label className='label'>Passphrase</label>
+                                <div className='control'>
+                                    <input className='input' type='password' value={passphrase}
+                                           onChange={e => setPassphrase(e.target.value)}/>
+                                </div>
+                            </div>
+                            <div className='buttons'>
+                                <button className='button is-success' disabled={!passphrase}
+                                        onClick={() => handleKeyInput(protectedKey, passphrase)}>Open vault
+                                </button>
+                                <button className='button' onClick={() => setProtectedKey(undefined)}>Use another key
+                                </button>
+                            </div>
+                        </div>
+                        : <EnterKey handleKey={key => handleKeyInput(key)}/>}
                 </div>
             </div>
 
@@ -1,11 +1,15 @@
-import {Fragment, useState} from 'react'
+import {Fragment, useEffect, useState} from 'react'
 import {PageHeader, SectionHeader, SectionSubHeader} from '../../shared/Headers.tsx'
 import {UnlockPageProps} from './index.tsx'
 import {ContinueButton, Footer, FooterButton} from '../../shared/Footer.tsx'
 import {PageWrapper} from '../../shared'
-import {displayKey, keyStringToByteArray} from '../../util'
+import {byteArrayToKeyString, displayKey, formatFingerprint, isSealedKey, keyStringToByteArray} from '../../util'
+import {IdentityFilter, selectFile} from "../../util/fileApi.ts";
+import {readTextFile} from "@tauri-apps/api/fs";
 import {EnterKey} from "../../shared/EnterKey.tsx";
-import {PublicVaultInfo} from "./Unlockinfo.tsx";
+import {PublicVaultInfo, UnlockAssessment} from "./Unlockinfo.tsx";
+import {invoke} from "@tauri-apps/api";
+import {AppError, castErr, ErrorDisplay} from "../../shared/Errors.tsx";
 
 type TopTextProps = {
     publicInfo?: PublicVaultInfo,
@@ -15,21 +19,27 @@ type TopTextProps = {
 
 function TopText({publicInfo, keyCount}: TopTextProps) {
     if (publicInfo) {
-        const {name, email_address, share_config: {circles, required}} = publicInfo
-        const requiredGroups = circles.filter(c => c.required).map(c => c.name).join(', ')
+        const {name, email_address, owner_fingerprint, share_config: {circles, required}} = publicInfo
+        const requiredGroups = circles.filter(c => c.required)
+            .map(c => `${c.name} (${c.threshold || 1} of ${c.key_comments.length})`).join(', ')
 
         return <Fragment>
             <PageHeader>Enter key pieces ({keyCount} of {required})</PageHeader>
             <SectionHeader>You can submit key pieces here. You need {required} to unlock the data.</SectionHeader>
             {requiredGroups.length
-                ? <p>The following circles are marked as required, and you will need at least one key from
-                    each: <code>{requiredGroups}</code>.
+                ? <p>The following circles are marked as required, and you will need at least the number of keys
+                    shown from each: <code>{requiredGroups}</code>.
                 </p>
                 : <p>No circles are marked as required - you need {required} keys from any circle.</p>}
 
             <p>
                 Vault Owner: {name}<br/>
-                Email address: {email_address}
+                Email address: {email_address}<br/>
+                {owner_fingerprint?.length
+                    ? <span>Signed by the owner's key <code>{formatFingerprint(owner_fingerprint)}</code>. Check this
+                        matches the fingerprint on your key piece.</span>
+                    : <span>This vault was made before vaults were signed, so it can't be checked that it came from
+                        the owner.</span>}
             </p>
         </Fragment>
     } else {
@@ -42,17 +52,80 @@ function TopText({publicInfo, keyCount}: TopTextProps) {
     }
 }
 
-export function EnterKeys({vaultInfo: {vaultInfo: publicInfo, keys}, setVaultInfo, goNext, goBack}: UnlockPageProps) {
+function Progress({assessment}: { assessment?: UnlockAssessment }) {
+    if (!assessment) return null
+    const {shares_held, shares_needed, more_needed, missing_circles, invalid_pieces} = assessment
+
+    return <Fragment>
+        <p>
+            You have {shares_held} of the {shares_needed} shares needed.
+            {more_needed > 0 ? ` ${more_needed} more are needed.` : ''}
+        </p>
+        {missing_circles.length
+            ? <p>Still needed from required circles: <code>{missing_circles
+                .map(c => `${c.name} (${c.more_needed} more)`).join(', ')}</code></p>
+            : ''}
+        {invalid_pieces.map(e => <ErrorDisplay key={e.message} error={e}/>)}
+    </Fragment>
+}
+
+export function EnterKeys({vaultInfo: {vaultInfo: publicInfo, keys, identities: savedIdentities}, setVaultInfo, goNext, goBack}: UnlockPageProps) {
     const [submittedKeys, setSubmittedKeys] = useState<string[]>(keys || [])
+    const [identities, setIdentities] = useState<string>(savedIdentities || '')
+    const [assessment, setAssessment] = useState<UnlockAssessment | undefined>()
+    // Key pieces protected by a passphrase, waiting for it to be entered. They are asked for one at a time.
+    const [lockedKeys, setLockedKeys] = useState<string[]>([])
+    const [passphrase, setPassphrase] = useState<string>('')
+    const [passphraseError, setPassphraseError] = useState<AppError | undefined>()
+
+    // Check progress whenever the keys change. Nothing is decrypted.
+    useEffect(() => {
+        if (!publicInfo) return
+        invoke('assess_unlock', {
+            info: publicInfo,
+            keys: submittedKeys.map(k => keyStringToByteArray(k)),
+            identities: identities || undefined
+        }).then(a => setAssessment(a as UnlockAssessment)).catch(e => console.error(castErr(e)))
+    }, [publicInfo, submittedKeys, identities])
 
     function submitTextKey(key: string) {
-        if (!submittedKeys.includes(key.trim())) {
-            setSubmittedKeys([...submittedKeys, key])
-        }
+        if (submittedKeys.includes(key.trim())) return
+        invoke<boolean>('is_protected_piece', {key: keyStringToByteArray(key)})
+            .then(isProtected => isProtected
+                ? setLockedKeys(locked => [...locked, key])
+                : setSubmittedKeys(keys => [...keys, key]))
+            .catch(console.error)
+    }
+
+    // The unprotected piece is kept in place of the protected one, so the passphrase is only asked for once.
+    function unlockKey() {
+        const [key] = lockedKeys
+        setPassphraseError(undefined)
+        invoke<number[]>('unprotect_piece', {key: keyStringToByteArray(key), passphrase})
+            .then(piece => {
+                const unlocked = byteArrayToKeyString(piece)
+                setSubmittedKeys(keys => keys.includes(unlocked) ? keys : [...keys, unlocked])
+                setLockedKeys(locked => locked.slice(1))
+                setPassphrase('')
+            })
+            .catch(e => setPassphraseError(castErr(e)))
+    }
+
+    function skipLockedKey() {
+        setLockedKeys(locked => locked.slice(1))
+        setPassphrase('')
+        setPassphraseError(undefined)
+    }
+
+    // Sealed key pieces can only be opened with the identity file of the person they were sealed to.
+    async function addIdentityFile() {
+        const paths = await selectFile(false, IdentityFilter)
+        const contents = await Promise.all(paths.map(p => readTextFile(p)))
+        setIdentities([identities, ...contents].filter(i => i).join('\n'))
     }
 
     function movePage() {
-        setVaultInfo({keys: submittedKeys})
+        setVaultInfo({keys: submittedKeys, identities: identities || undefined})
         goNext()
     }
 
@@ -60,10 +133,41 @@ export function EnterKeys({vaultInfo: {vaultInfo: publicInfo, keys}, setVaultInf
         <PageWrapper>
             <div>
                 <TopText publicInfo={publicInfo} keyCount={submittedKeys.length}/>
+                <Progress assessment={assessment}/>
 
                 <div className="columns is-mobile">
                     <div className="column is-6">
                         <EnterKey handleKey={submitTextKey}/>
+                        {lockedKeys.length
+                            ? <div className='box'>
+                                <p>
+                                    Key piece {displayKey(keyStringToByteArray(lockedKeys[0]))} is protected by a
+                                    passphrase. Ask the person it belongs to for their passphrase.
+                                </p>
+                                <ErrorDisplay error={passphraseError}/>
+                                <div className='field'>
+                                    <label className='label'>Passphrase</label>
+                                    <div className='control'>
+                                        <input className='input' type='password' value={passphrase}
+                                               onChange={e => setPassphrase(e.target.value)}/>
+                                    </div>
+                                </div>
+                                <div className='buttons'>
+                                    <button className='button is-success' disabled={!passphrase}
+                                            onClick={unlockKey}>Unlock key piece
+                                    </button>
+                                    <button className='button' onClick={skipLockedKey}>Skip</button>
+                                </div>
+                            </div>
+                            : ''}
+                        {submittedKeys.some(isSealedKey)
+                            ? <p>
+                                Some key pieces are sealed. Add the identity file for each, which was made with the
+                                public key given to the vault owner.{' '}
+                                <a onClick={() => addIdentityFile().catch(console.error)}>Add identity file</a>
+                                {identities ? ' (added)' : ''}
+                            </p>
+                            : ''}
 
                         <br/>
                         <br/>
@@ -79,9 +183,9 @@ export function EnterKeys({vaultInfo: {vaultInfo: publicInfo, keys}, setVaultInf
 
                             </thead>
                             <tbody>
-                            {submittedKeys.map((i) => <tr key={i}>
+                            {submittedKeys.map((i, index) => <tr key={i}>
                                 <td>
-                                    {i.split(':')[0]}
+                                    {index + 1}
                                 </td>
                                 <td>
                                     {displayKey(keyStringToByteArray(i))}
@@ -97,7 +201,7 @@ export function EnterKeys({vaultInfo: {vaultInfo: publicInfo, keys}, setVaultInf
             </div>
             <Footer>
                 <ContinueButton handleClick={movePage} text='Combine keys'
-                                disabled={submittedKeys.length < (publicInfo ? publicInfo.share_config.required : 0)}/>
+                                disabled={assessment ? !assessment.ready : submittedKeys.length === 0}/>
                 <FooterButton handleClick={goBack}/>
             </Footer>
         </PageWrapper>
@@ -10,6 +10,15 @@ export function AccessPage({vaultInfo}: UnlockPageProps) {
                     <PageHeader>Data unlocked</PageHeader>
                     <SectionHeader>The files have been saved in your chosen location.</SectionHeader>
                     <p>{vaultInfo.finalPath}</p>
+                    {(vaultInfo.badPieces != null && vaultInfo.badPieces.length !== 0)
+                        ? <div className='notification is-warning mt-4'>
+                            <p>Some key pieces were left out, as there were enough without them. Please ask their
+                                owners to check them:</p>
+                            <ul>
+                                {vaultInfo.badPieces.map((e, i) => <li key={i}>{e.message}</li>)}
+                            </ul>
+                        </div>
+                        : ''}
                 </div>
 
             </div>
@@ -1,4 +1,5 @@
-import {ShareConfig, VaultType} from '../creation/VaultInfo.tsx'
+import {Lineage, ShareCommitments, ShareConfig, VaultType} from '../creation/VaultInfo.tsx'
+import {AppError} from '../../shared/Errors.tsx'
 
 // Basic data types.
 export interface UnlockInfo {
@@ -6,7 +7,11 @@ export interface UnlockInfo {
     vault_type: VaultType
     vaultInfo?: PublicVaultInfo
     keys?: string[]
+    // The contents of the identity files for any sealed key pieces.
+    identities?: string
     finalPath?: string
+    // Pieces left out when unlocking, because they were damaged or did not fit with the others.
+    badPieces?: AppError[]
 }
 
 export interface PublicVaultInfo {
@@ -15,4 +20,32 @@ export interface PublicVaultInfo {
     email_address: string
     nonce: number[]
     path: string
+    key_id?: number[]
+    commitments?: ShareCommitments
+    // The fingerprint of the owner's signing key. load_meta checks the signature, so this can be trusted.
+    // Empty for vaults made before vaults were signed.
+    owner_fingerprint?: number[]
+    // Commits to the content key, so a wrong combined key is caught before decrypting.
+    key_commitment?: number[]
+    // Which vault this is, and which version of it. The id is all zeros for vaults made before versions.
+    lineage?: Lineage
+}
+
+// Result of the assess_unlock command.
+export interface UnlockAssessment {
+    shares_held: number
+    shares_needed: number
+    more_needed: number
+    missing_circles: { name: string, more_needed: number }[]
+    unidentified_pieces: number
+    invalid_pieces: AppError[]
+    ready: boolean
+}
+
+// Result of the unlock command.
+export interface UnlockResult {
+    path: string
+    bad_pieces: AppError[]
+    // The fingerprint of the owner who signed the vault.
+    signer: number[]
 }
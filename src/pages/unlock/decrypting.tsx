@@ -6,6 +6,7 @@ import {keyStringToByteArray} from '../../util'
 import {AppError, castErr, ErrorDisplay} from '../../shared/Errors.tsx'
 import {FooterButton} from '../../shared/Footer.tsx'
 import {open} from "@tauri-apps/api/dialog";
+import {UnlockResult} from './Unlockinfo.tsx'
 
 // Decryption page. Works like the encryption page in that it is rendered and then does the work in a useEffect.
 export function DecryptingPage({vaultInfo, goNext, setVaultInfo, goBack}: UnlockPageProps) {
@@ -27,13 +28,14 @@ export function DecryptingPage({vaultInfo, goNext, setVaultInfo, goBack}: Unlock
         });
         try {
 
-            const res = await invoke('unlock', {
+            const res: UnlockResult = await invoke('unlock', {
                 filePath: vaultInfo.vaultInfo?.path,
                 keys: rawKeys,
-                savePath: pathToSaveTo
+                savePath: pathToSaveTo,
+                identities: vaultInfo.identities
             })
 
-            setVaultInfo({finalPath: res as string})
+            setVaultInfo({finalPath: res.path, badPieces: res.bad_pieces})
 
             goNext()
         } catch (err) {
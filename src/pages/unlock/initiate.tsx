@@ -4,13 +4,17 @@ import {PageHeader, SectionHeader} from "../../shared/Headers.tsx";
 import {Footer, FooterButton, StandardFooter} from "../../shared/Footer.tsx";
 import {useState} from "react";
 import {AppError, castErr, ErrorDisplay} from "../../shared/Errors.tsx";
-import {downloadVault, requestVault, RequestVaultResponse} from "../../util/cloudApi.ts";
+import {CloudRequestError, downloadVault, requestVault, RequestVaultResponse} from "../../util/cloudApi.ts";
 import {invoke} from "@tauri-apps/api";
 import {keyStringToByteArray} from "../../util";
 import {isPermissionGranted, requestPermission, sendNotification} from "@tauri-apps/api/notification";
 
 
-export function Initiate({goNext, goBack, vaultInfo: {keys}, setVaultInfo}: UnlockPageProps) {
+interface CloudTokens {
+    token: number[]
+}
+
+export function Initiate({goNext, goBack, vaultInfo: {keys, identities}, setVaultInfo}: UnlockPageProps) {
     const [resp, setResp] = useState<RequestVaultResponse | undefined>();
     const [error, setError] = useState<AppError | undefined>();
     const [token, setToken] = useState<number[]>([])
@@ -37,18 +41,31 @@ export function Initiate({goNext, goBack, vaultInfo: {keys}, setVaultInfo}: Unlo
     function makeRequest() {
         setError(undefined);
         (async function () {
-            const token = await invoke("unlock_cloud", {
-                keys: (keys || []).map(k => keyStringToByteArray(k))
-            });
-            const res = await requestVault(token as number[]);
-            setToken(token as number[]);
+            const pieces = (keys || []).map(k => keyStringToByteArray(k));
+            const tokens = await invoke("unlock_cloud", {keys: pieces, identities, legacy: false}) as CloudTokens;
+
+            let token = tokens.token;
+            let res: RequestVaultResponse;
+            try {
+                res = await requestVault(token);
+            } catch (e) {
+                // Vaults uploaded by older versions of the app are known by the legacy token.
+                // It holds bytes of the secret, so only send it once the server says there is no vault for the token.
+                if (!(e instanceof CloudRequestError && e.status === 404)) {
+                    throw e;
+                }
+                const legacy = await invoke("unlock_cloud", {keys: pieces, identities, legacy: true}) as CloudTokens;
+                token = legacy.token;
+                res = await requestVault(token);
+            }
+            setToken(token);
             setResp(res);
 
             if (res.alert_duration === 0 || parseInt(res.open_at || "") < new Date().getTime()) {
                 // Move onto next stage - download
-                startDownload(token as number[])
+                startDownload(token)
             } else {
-                setTimeout(startDownload, parseInt(res.open_at || "", 10))
+                setTimeout(() => startDownload(token), parseInt(res.open_at || "", 10))
 
                 // Try to get notification permission
                 const hasPerm = await isPermissionGranted();
@@ -45,7 +45,7 @@ export function EnterKey({handleKey}: EnterKeyProps) {
                 <div className='control'>
           <textarea
               className='textarea'
-              placeholder='XX:XX:XX:XX:XX'
+              placeholder='XX:XX:XX:XX:XX, the words of a key piece, or a scanned recovery sheet code'
               value={text} onChange={e => setText(e.target.value)}
               rows={10}
           />
@@ -51,7 +51,7 @@ export interface RequestVaultResponse {
 }
 
 /**
- * Requests access given a vault token (first 8 bytes of a key). Returns a number indicating the timestamp at which the vault can be unlocked.
+ * Requests access given a vault token (derived from the combined key pieces). Returns a number indicating the timestamp at which the vault can be unlocked.
  * This is 0 for vaults with no unlock period that can be immediately downloaded.
  * @param token
  */
@@ -105,6 +105,19 @@ export function deleteVault(token: number[]): Promise<void> {
     });
 }
 
+/**
+ * A failed request to the cloud provider, with the HTTP status it failed with.
+ */
+export class CloudRequestError extends Error {
+    status: number
+
+    constructor(message: string, status: number) {
+        super(message);
+        this.name = `Cloud Error ${status}`;
+        this.status = status;
+    }
+}
+
 /**
  * HTTP Request helper to make the error handling a bit more hospitable and handle body parsing.
  * @param uri URI to request. Appended to the BACKEND_URL.
@@ -129,8 +142,9 @@ async function request(uri: string, opt: FetchOptions = {method: "GET"}): Promis
 
     } else {
         // Error handling. Tries to handle it in a sensible way whether it's empty, a string or json.
+        // The status is kept, so callers can tell a missing vault apart from other failures.
         if (!response.data) {
-            throw new Error(`Request failed with status ${response.status}. No error given.`)
+            throw new CloudRequestError(`Request failed with status ${response.status}. No error given.`, response.status)
         }
         // parse binary
         if (opt.responseType === ResponseType.Binary) {
@@ -142,10 +156,10 @@ async function request(uri: string, opt: FetchOptions = {method: "GET"}): Promis
             json = JSON.parse(response.data);
             console.error(json);
         } catch (err) {
-            throw castErr(response.data);
+            throw new CloudRequestError(castErr(response.data).message, response.status);
         }
 
-        throw castErr(json);
+        throw new CloudRequestError(castErr(json).message, response.status);
     }
 
 }
\ No newline at end of file
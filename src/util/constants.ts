@@ -1,5 +1,7 @@
 export const NAME_MIN = 2
 export const NAME_MAX = 50
+// The most key shares a vault can have, and so the highest threshold. Matches MAX_SHARES in crypto.rs.
+export const MAX_KEYS = 65535
 export const EMAIL_REGEX = /^[\w\-\.]+@([\w-]+\.)+[\w-]{2,}$/
 
 export class ValidationError extends Error {
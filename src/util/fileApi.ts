@@ -22,7 +22,8 @@ type FilterPart = { name: string, extensions: string[] }
 type Filter = FilterPart[]
 
 export const VaultFilter: Filter = [{name: 'Digital vault', extensions: ['vault']}];
-export const KeyFilter: Filter = [{name: 'Main key or keyshare', extensions: ['ks', "key"]}];
+export const KeyFilter: Filter = [{name: 'Main key or keyshare', extensions: ['ks', "key", "age"]}];
+export const IdentityFilter: Filter = [{name: 'age identity file', extensions: ['txt', 'key']}];
 
 /**
  * Allows a file or folder to be selected, with an optional filter. Filters could be for keys, vaults or other.
@@ -6,14 +6,54 @@ export function byteArrayToKeyString(byteArray: number[]) {
     return byteArray.map(i => i.toString(HEX)).join(':')
 }
 
+// Shows the end of a key, as key pieces all start with the same envelope header.
 export function displayKey(byteArray: number[]) {
-    return byteArrayToKeyString(byteArray).substring(0, 10)
+    const str = binaryArrayToString(byteArray)
+    if (isWordKey(str)) {
+        return str.trim().split(/\s+/).slice(-2).join(' ')
+    }
+    if (isArmoredKey(str)) {
+        return str.match(/CRC: (\w+)/)?.[0] || 'saved key'
+    }
+    if (isSealedKey(str)) {
+        return 'sealed key'
+    }
+    return byteArrayToKeyString(byteArray).slice(-10)
+}
+
+// Key pieces written as words are passed on as text, which the backend reads.
+export function isWordKey(str: string) {
+    return /^[a-zA-Z\s]+$/.test(str.trim()) && /\s/.test(str.trim())
+}
+
+// Keys saved to file are armored text, with an explanation around the key. The backend reads these too.
+export function isArmoredKey(str: string) {
+    return str.trim().startsWith('---- BEGIN DIGITAL VAULT ')
+}
+
+// Key pieces sealed to a recipient's public key. The backend opens them with the recipient's identity file.
+export function isSealedKey(str: string) {
+    return str.trim().startsWith('-----BEGIN AGE ENCRYPTED FILE-----')
+}
+
+// The text of a QR code scanned from a recovery sheet.
+export function isQrKey(str: string) {
+    return str.trim().startsWith('DLKP:')
 }
 
 export function keyStringToByteArray(str: string) {
+    if (isWordKey(str) || isArmoredKey(str) || isQrKey(str) || isSealedKey(str)) {
+        return Array.from(new TextEncoder().encode(str.trim()))
+    }
     return str.split(':').map(i => parseInt(i, HEX))
 }
 
 export function binaryArrayToString(arr: number[]) {
     return arr.map((r: number) => String.fromCharCode(r)).join("");
-}
\ No newline at end of file
+}
+
+// Fingerprints are shown as groups of hex digits, as on key files and recovery sheets.
+export function formatFingerprint(fingerprint: number[]) {
+    const hex = fingerprint.map(b => b.toString(16).padStart(2, '0').toUpperCase()).join('')
+    return hex.match(/.{1,4}/g)?.join(' ') || ''
+}